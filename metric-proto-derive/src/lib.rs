@@ -0,0 +1,90 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, parse_macro_input};
+
+/// Implements `LabelValue` and `Display` for fieldless enums and newtype wrappers.
+///
+/// Enum variants render as their name, are identified by their position among the variants of
+/// the same enum and are stored without allocating. Values of other types are compared by
+/// their rendering. Newtypes render, hash and are stored like the wrapped value, which has
+/// to implement `LabelValue` and `Clone`.
+/// The generated code refers to `::metric_proto::dimensions::LabelValue`. Use the derive
+/// through its re-export, `metric_proto::dimensions::LabelValue`.
+#[proc_macro_derive(LabelValue)]
+pub fn derive_label_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let (as_u64, display, value, inline, type_tag, label_eq) = match &input.data {
+        Data::Enum(data) => {
+            if let Some(variant) = data.variants.iter().find(|v| !matches!(v.fields, Fields::Unit)) {
+                return Err(syn::Error::new_spanned(variant, "LabelValue can only be derived for enums without fields"))
+            }
+            let variants = data.variants.iter().map(|v| &v.ident).collect::<Vec<_>>();
+            let names = variants.iter().map(|v| v.to_string());
+            let positions = 0..variants.len() as u64;
+            (
+                quote! { match self { #(Self::#variants => #positions,)* } },
+                quote! { f.write_str(match self { #(Self::#variants => #names,)* }) },
+                quote! { match self { #(Self::#variants => Self::#variants,)* } },
+                quote! { Some(::metric_proto::dimensions::StoredLabel::Static(match self { #(Self::#variants => &Self::#variants,)* })) },
+                // positions only tell variants of this enum apart
+                quote! { Some(::std::any::TypeId::of::<Self>()) },
+                None,
+            )
+        }
+        Data::Struct(data) if matches!(&data.fields, Fields::Unnamed(fields) if fields.unnamed.len() == 1) => (
+            quote! { ::metric_proto::dimensions::LabelValue::as_u64(&self.0) },
+            quote! { ::std::fmt::Display::fmt(&self.0, f) },
+            quote! { Self(::std::clone::Clone::clone(&self.0)) },
+            quote! { ::metric_proto::dimensions::LabelValue::inline(&self.0) },
+            quote! { ::metric_proto::dimensions::LabelValue::type_tag(&self.0) },
+            Some(quote! {
+                fn label_eq(&self, other: &dyn ::metric_proto::dimensions::LabelValue) -> bool {
+                    ::metric_proto::dimensions::LabelValue::label_eq(&self.0, other)
+                }
+            }),
+        ),
+        _ => return Err(syn::Error::new_spanned(input, "LabelValue can only be derived for fieldless enums and newtypes")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::std::fmt::Display for #name #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                #display
+            }
+        }
+
+        impl #impl_generics ::metric_proto::dimensions::LabelValue for #name #ty_generics #where_clause {
+            fn as_u64(&self) -> u64 {
+                #as_u64
+            }
+
+            fn boxed(&self) -> Box<dyn ::metric_proto::dimensions::LabelValue> {
+                Box::new(#value)
+            }
+
+            fn shared(&self) -> ::std::sync::Arc<dyn ::metric_proto::dimensions::LabelValue> {
+                ::std::sync::Arc::new(#value)
+            }
+
+            fn inline(&self) -> Option<::metric_proto::dimensions::StoredLabel> {
+                #inline
+            }
+
+            fn type_tag(&self) -> Option<::std::any::TypeId> {
+                #type_tag
+            }
+
+            #label_eq
+        }
+    })
+}
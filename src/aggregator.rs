@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::mem;
+use crate::dimensions::{CardinalityReport, ExemplarValue, ExpHistogramValue, HistogramValue, LabelFilter, LabelValue, MeterValue, MetricKind, MetricName, OverflowPolicy, SeriesValue, SketchValue, SummaryValue, UniqueValue};
+use crate::metadata::{self, Metadata};
+use crate::metrics::Snapshot;
+use crate::transport::{Flushed, FrozenSnapshot};
+use crate::trace;
+
+/// Merges snapshots sent by worker threads into a single view.
+///
+/// Besides the lifetime total, the aggregator can optionally track what was added during the
+/// current reporting interval. Pull exporters read totals, push exporters read the delta of
+/// the last completed interval, and both are served by the same aggregator instance.
+#[derive(Debug)]
+pub struct Aggregator {
+    total: Snapshot,
+    intervals: Option<Intervals>,
+    compaction_threshold: f64,
+    compaction: CompactionStats,
+    flushes: u64,
+    label_filter: Option<&'static LabelFilter>,
+    processors: Processors,
+}
+
+/// Custom processing plugged into the aggregator, e.g. to transform snapshots, forward them
+/// elsewhere or look for anomalies, without changing the aggregation loop.
+pub trait SnapshotProcessor: Send {
+    /// Called with every snapshot before it is merged. Changes made to it are merged.
+    fn on_merge(&mut self, _snapshot: &mut Snapshot) {}
+
+    /// Called with the merged totals whenever they are read through the aggregator.
+    fn on_read(&self, _total: &Snapshot) {}
+}
+
+#[derive(Default)]
+struct Processors(Vec<Box<dyn SnapshotProcessor>>);
+
+impl Debug for Processors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} processors", self.0.len())
+    }
+}
+
+/// Meta-metrics describing the aggregator's own store compactions.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    pub last_bytes_before: usize,
+    pub last_bytes_after: usize,
+    pub bytes_reclaimed: usize,
+}
+
+#[derive(Debug, Default)]
+struct Intervals {
+    current: Snapshot,
+    last: Option<Snapshot>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Aggregator {
+    /// Compact the store once less than this fraction of its capacity is occupied.
+    pub const DEFAULT_COMPACTION_THRESHOLD: f64 = 0.25;
+
+    pub fn new() -> Self {
+        Self {
+            total: Snapshot::new(),
+            intervals: None,
+            compaction_threshold: Self::DEFAULT_COMPACTION_THRESHOLD,
+            compaction: CompactionStats::default(),
+            flushes: 0,
+            label_filter: None,
+            processors: Processors::default(),
+        }
+    }
+
+    /// Creates an aggregator that retains interval deltas in addition to lifetime totals.
+    pub fn with_interval_deltas() -> Self {
+        Self {
+            intervals: Some(Intervals::default()),
+            ..Self::new()
+        }
+    }
+
+    pub fn set_compaction_threshold(&mut self, threshold: f64) {
+        self.compaction_threshold = threshold;
+    }
+
+    /// Drops `filter`'s labels from every snapshot before it is merged. Producers can do the
+    /// same with [`MetricsContext::set_label_filter`], which saves sending the series at all.
+    ///
+    /// [`MetricsContext::set_label_filter`]: crate::metrics::MetricsContext::set_label_filter
+    pub fn set_label_filter(&mut self, filter: Option<&'static LabelFilter>) {
+        self.label_filter = filter;
+    }
+
+    /// Applies to the lifetime total, the only place long-running counters can reach `u64::MAX`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.total.store_mut().set_overflow_policy(policy);
+    }
+
+    /// Caps the series every metric key can have in the lifetime total, folding the rest
+    /// into an overflow series, see [`MetricStore::set_cardinality_limit`].
+    ///
+    /// [`MetricStore::set_cardinality_limit`]: crate::dimensions::MetricStore::set_cardinality_limit
+    pub fn set_cardinality_limit(&mut self, max_series: Option<usize>) {
+        self.total.store_mut().set_cardinality_limit(max_series);
+    }
+
+    /// Processors run in the order they were added, after the label filter.
+    pub fn add_processor<P: SnapshotProcessor + 'static>(&mut self, processor: P) {
+        self.processors.0.push(Box::new(processor));
+    }
+
+    pub fn merge(&mut self, mut snapshot: Snapshot) {
+        if let Some(filter) = self.label_filter {
+            snapshot.store_mut().drop_labels(filter);
+        }
+        for processor in &mut self.processors.0 {
+            processor.on_merge(&mut snapshot);
+        }
+        if let Some(intervals) = &mut self.intervals {
+            intervals.current.merge_ref(&snapshot);
+        }
+        self.total.merge_drain(&mut snapshot);
+        snapshot.recycle();
+    }
+
+    /// Merges a snapshot shared by the producer. Only keys of series that are new to the
+    /// aggregator are cloned.
+    pub fn merge_frozen(&mut self, snapshot: &FrozenSnapshot) {
+        if self.label_filter.is_some() || !self.processors.0.is_empty() {
+            // shared snapshots can't be modified in place
+            return self.merge(snapshot.snapshot().clone());
+        }
+        if let Some(intervals) = &mut self.intervals {
+            intervals.current.merge_ref(snapshot.snapshot());
+        }
+        self.total.merge_ref(snapshot.snapshot());
+    }
+
+    pub fn merge_flushed(&mut self, flushed: Flushed) {
+        let _span = trace::span("merge");
+        self.flushes += 1;
+        match flushed {
+            Flushed::Owned(snapshot) => self.merge(snapshot),
+            Flushed::Shared(snapshot) => self.merge_frozen(&snapshot),
+        }
+    }
+
+    /// Closes the current interval. Its deltas become visible through [`Self::delta`] and
+    /// [`Self::last_interval`] until the next rotation.
+    pub fn rotate_interval(&mut self) {
+        if let Some(intervals) = &mut self.intervals {
+            let current = mem::take(&mut intervals.current);
+            intervals.last = Some(current);
+        }
+        self.maybe_compact();
+    }
+
+    /// Shrinks the merged store if its occupancy dropped below the compaction threshold.
+    /// Returns `true` if compaction happened.
+    pub fn maybe_compact(&mut self) -> bool {
+        let store = self.total.store_mut();
+        if store.capacity() == 0 || (store.len() as f64) >= store.capacity() as f64 * self.compaction_threshold {
+            return false
+        }
+
+        let before = store.footprint();
+        store.compact();
+        let after = store.footprint();
+        self.compaction.compactions += 1;
+        self.compaction.last_bytes_before = before;
+        self.compaction.last_bytes_after = after;
+        self.compaction.bytes_reclaimed += before.saturating_sub(after);
+
+        true
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction
+    }
+
+    /// Snapshots received from producers so far.
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    pub fn total(&self) -> &Snapshot {
+        for processor in &self.processors.0 {
+            processor.on_read(&self.total);
+        }
+
+        &self.total
+    }
+
+    pub fn last_interval(&self) -> Option<&Snapshot> {
+        self.intervals.as_ref().and_then(|i| i.last.as_ref())
+    }
+
+    pub fn get(&self, key: &MetricName) -> Option<&SeriesValue> {
+        self.total().get(key)
+    }
+
+    pub fn get_counter(&self, key: &MetricName) -> Option<u64> {
+        self.total().get_counter(key)
+    }
+
+    pub fn get_gauge(&self, key: &MetricName) -> Option<f64> {
+        self.total().get_gauge(key)
+    }
+
+    /// Sketch merged from every snapshot, for quantiles with bounded relative error.
+    pub fn get_sketch(&self, key: &MetricName) -> Option<&SketchValue> {
+        self.total().get_sketch(key)
+    }
+
+    pub fn get_unique(&self, key: &MetricName) -> Option<&UniqueValue> {
+        self.total().get_unique(key)
+    }
+
+    pub fn get_meter(&self, key: &MetricName) -> Option<&MeterValue> {
+        self.total().get_meter(key)
+    }
+
+    pub fn get_summary(&self, key: &MetricName) -> Option<&SummaryValue> {
+        self.total().get_summary(key)
+    }
+
+    pub fn get_exp_histogram(&self, key: &MetricName) -> Option<&ExpHistogramValue> {
+        self.total().get_exp_histogram(key)
+    }
+
+    /// Most recent exemplar of the series of `kind` across every merged snapshot.
+    pub fn get_exemplar(&self, key: &MetricName, kind: MetricKind) -> Option<&ExemplarValue> {
+        self.total().get_exemplar(key, kind)
+    }
+
+    pub fn get_flag(&self, key: &MetricName) -> Option<bool> {
+        self.total().get_flag(key)
+    }
+
+    pub fn get_absolute(&self, key: &MetricName) -> Option<u64> {
+        self.total().get_absolute(key)
+    }
+
+    pub fn get_histogram(&self, key: &MetricName) -> Option<&HistogramValue> {
+        self.total().get_histogram(key)
+    }
+
+    /// Unit and help text of `key`, if it was described.
+    pub fn metadata(&self, key: &str) -> Option<Metadata> {
+        metadata::metadata(key)
+    }
+
+    pub fn get_all_dims(&self, key: &'static str) -> Option<u64> {
+        self.total().get_all_dims(key)
+    }
+
+    /// Per-dimension breakdown: the sum of the counters of `key` with every label in `labels`.
+    pub fn get_matching(&self, key: &'static str, labels: &[(&'static str, &dyn LabelValue)]) -> Option<u64> {
+        self.total().get_matching(key, labels)
+    }
+
+    /// Per-value breakdown: the counters of `key` summed by the value of `label`.
+    pub fn group_by(&self, key: &'static str, label: &'static str) -> HashMap<String, u64> {
+        self.total().group_by(key, label)
+    }
+
+    pub fn cardinality_report(&self, top: usize) -> Vec<CardinalityReport> {
+        self.total().cardinality_report(top)
+    }
+
+    /// Value accumulated by the series during the last completed interval.
+    pub fn delta(&self, key: &MetricName) -> Option<u64> {
+        self.last_interval().and_then(|s| s.get_counter(key))
+    }
+
+    pub fn delta_all_dims(&self, key: &'static str) -> Option<u64> {
+        self.last_interval().and_then(|s| s.get_all_dims(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::aggregator::{Aggregator, SnapshotProcessor};
+    use crate::dimensions::{Exemplar, MetricKind, MetricName};
+    use crate::metrics::{Counter, Histogram, METRICS_CTX, Meter, OneDimensionCounter, Records, Sketch, Snapshot, Unique, WithExemplar};
+    use crate::dimensions::HelperIdentity;
+    use crate::transport::snapshot_channel;
+    use crate::test_utils::{exclusive_heap, shared_heap};
+
+    fn snapshot(v: u64) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.increment(Counter("foo", v));
+        snapshot
+    }
+
+    #[test]
+    fn total_and_delta() {
+        let _heap = shared_heap();
+        let key = MetricName::with_no_labels("foo");
+        let mut aggregator = Aggregator::with_interval_deltas();
+
+        aggregator.merge(snapshot(1));
+        aggregator.merge(snapshot(2));
+        assert_eq!(aggregator.delta(&key), None);
+        aggregator.rotate_interval();
+        assert_eq!(aggregator.delta(&key), Some(3));
+
+        aggregator.merge(snapshot(4));
+        aggregator.rotate_interval();
+        assert_eq!(aggregator.delta(&key), Some(4));
+        assert_eq!(aggregator.get_counter(&key), Some(7));
+
+        aggregator.rotate_interval();
+        assert_eq!(aggregator.delta(&key), None);
+        assert_eq!(aggregator.get_counter(&key), Some(7));
+    }
+
+    #[test]
+    fn sketch_quantiles() {
+        let _heap = shared_heap();
+        let key = MetricName::with_no_labels("latency");
+        let mut aggregator = Aggregator::new();
+        for thread in 0..4 {
+            let mut snapshot = Snapshot::new();
+            for v in 1..=250 {
+                snapshot.record(Sketch(MetricName::with_no_labels("latency"), (thread * 250 + v) as f64));
+            }
+            aggregator.merge(snapshot);
+        }
+
+        let sketch = aggregator.get_sketch(&key).unwrap();
+        assert_eq!(1000, sketch.count());
+        for (q, expected) in [(0.5, 500.0), (0.99, 990.0)] {
+            let actual = sketch.quantile(q).unwrap();
+            assert!((actual - expected).abs() <= expected * 0.01, "p{q}: {actual} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn unique_estimate() {
+        let _heap = shared_heap();
+        let key = MetricName::with_no_labels("unique_keys");
+        let mut aggregator = Aggregator::new();
+        // threads see overlapping key ranges, 0..1600 through 2400..4000
+        for thread in 0..4_u64 {
+            let mut snapshot = Snapshot::new();
+            for k in thread * 800..thread * 800 + 1600 {
+                snapshot.record(Unique(key, &k));
+            }
+            aggregator.merge(snapshot);
+        }
+
+        let estimate = aggregator.get_unique(&key).unwrap().estimate();
+        assert!((estimate - 4000.0).abs() <= 4000.0 * 0.02, "{estimate}");
+    }
+
+    #[test]
+    fn meter_rate() {
+        let _heap = shared_heap();
+        let key = MetricName::with_no_labels("requests");
+        let (mut a, mut b) = (Snapshot::new(), Snapshot::new());
+        a.record(Meter(key, 100));
+        b.record(Meter(key, 50));
+        assert_eq!(None, a.get_meter(&key).unwrap().rate());
+
+        sleep(Duration::from_millis(20));
+        let mut aggregator = Aggregator::new();
+        aggregator.merge(a.take());
+        aggregator.merge(b.take());
+        let meter = aggregator.get_meter(&key).unwrap();
+        assert_eq!(150, meter.count());
+        let rate = meter.rate().unwrap();
+        assert!(rate > 0.0 && rate <= 150.0 / 0.02, "{rate}");
+    }
+
+    #[test]
+    fn exemplar_survives_merge() {
+        let _heap = shared_heap();
+        let (key, latency) = (MetricName::with_no_labels("requests"), MetricName::with_no_labels("latency"));
+        let (slow, retried) = (Exemplar { trace_id: 1, span_id: 10 }, Exemplar { trace_id: 2, span_id: 20 });
+        let (mut a, mut b) = (Snapshot::new(), Snapshot::new());
+        a.record(WithExemplar(Counter("requests", 3), slow));
+        a.record(WithExemplar(Histogram::new(latency, 5e6), slow));
+        b.record(Counter("requests", 1));
+        b.record(WithExemplar(Counter("requests", 2), retried));
+
+        // the newer exemplar wins regardless of merge order
+        let mut aggregator = Aggregator::new();
+        aggregator.merge(b);
+        aggregator.merge(a);
+        assert_eq!(Some(6), aggregator.get_counter(&key));
+        assert_eq!((retried, 2.0), aggregator.get_exemplar(&key, MetricKind::Counter).map(|e| (e.exemplar, e.value)).unwrap());
+        assert_eq!((slow, 5e6), aggregator.get_exemplar(&latency, MetricKind::Histogram).map(|e| (e.exemplar, e.value)).unwrap());
+        assert!(aggregator.total().store().to_string().contains(
+            "requests # {trace_id=00000000000000000000000000000002,span_id=0000000000000014} 2"
+        ));
+    }
+
+    #[test]
+    fn processors() {
+        struct Double(Arc<AtomicUsize>);
+        impl SnapshotProcessor for Double {
+            fn on_merge(&mut self, snapshot: &mut Snapshot) {
+                let copy = snapshot.clone();
+                snapshot.merge(copy);
+            }
+
+            fn on_read(&self, _total: &Snapshot) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let _heap = shared_heap();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(Double(Arc::clone(&reads)));
+        aggregator.merge(snapshot(3));
+
+        assert_eq!(aggregator.get_counter(&MetricName::with_no_labels("foo")), Some(6));
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn steady_state_flush_reuses_snapshots() {
+        let _heap = exclusive_heap();
+        let (tx, _rx) = snapshot_channel(false);
+        METRICS_CTX.with(|m| m.connect(tx));
+        let mut aggregator = Aggregator::new();
+        let cycle = |aggregator: &mut Aggregator| {
+            for helper in [HelperIdentity::H1, HelperIdentity::H2, HelperIdentity::H3] {
+                METRICS_CTX.with(|m| m.increment(OneDimensionCounter("sent", helper, 1)));
+            }
+            aggregator.merge(METRICS_CTX.with(|m| m.take_snapshot()));
+        };
+        // grows the tables of the snapshot that goes around
+        cycle(&mut aggregator);
+        cycle(&mut aggregator);
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        cycle(&mut aggregator);
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 0, "{:?}", stats);
+        assert_eq!(Some(9), aggregator.total().get_all_dims("sent"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::aggregator::SnapshotProcessor;
+use crate::metrics::Snapshot;
+
+/// Checks the order snapshots of every thread arrive in, against their [`Snapshot::seq`],
+/// and how long they took from being taken to being merged, see [`Snapshot::captured`].
+#[derive(Clone, Debug, Default)]
+pub struct ArrivalCheck {
+    state: Arc<Mutex<Arrivals>>,
+}
+
+#[derive(Debug, Default)]
+struct Arrivals {
+    threads: Vec<ThreadArrivals>,
+    delays: u64,
+    total_delay: Duration,
+    max_delay: Duration,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThreadArrivals {
+    pub thread: ThreadId,
+    pub received: u64,
+    /// Highest sequence number received so far.
+    pub last_seq: u64,
+    /// Snapshots that arrived after one taken later on the same thread.
+    pub reordered: u64,
+}
+
+impl ThreadArrivals {
+    /// Snapshots taken before the latest one that never arrived.
+    pub fn missing(&self) -> u64 {
+        (self.last_seq + 1).saturating_sub(self.received)
+    }
+}
+
+impl ArrivalCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self) -> ArrivalReport {
+        let state = self.state.lock().unwrap();
+        ArrivalReport {
+            threads: state.threads.clone(),
+            mean_delay: Duration::from_nanos(state.total_delay.as_nanos().checked_div(u128::from(state.delays)).unwrap_or_default() as u64),
+            max_delay: state.max_delay,
+        }
+    }
+}
+
+impl SnapshotProcessor for ArrivalCheck {
+    fn on_merge(&mut self, snapshot: &mut Snapshot) {
+        // snapshots that were never taken, e.g. built by hand, say nothing about arrivals
+        let Some(captured) = snapshot.captured() else {
+            return
+        };
+        let mut state = self.state.lock().unwrap();
+        // the clock may have stepped back since, which reads as no delay
+        let delay = SystemTime::now().duration_since(captured).unwrap_or_default();
+        state.delays += 1;
+        state.total_delay += delay;
+        state.max_delay = state.max_delay.max(delay);
+        let Some(thread) = snapshot.thread() else {
+            return
+        };
+        let seq = snapshot.seq();
+        match state.threads.iter_mut().find(|arrivals| arrivals.thread == thread) {
+            Some(arrivals) => {
+                arrivals.received += 1;
+                if seq < arrivals.last_seq {
+                    arrivals.reordered += 1;
+                }
+                arrivals.last_seq = arrivals.last_seq.max(seq);
+            }
+            None => state.threads.push(ThreadArrivals { thread, received: 1, last_seq: seq, reordered: 0 }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArrivalReport {
+    pub threads: Vec<ThreadArrivals>,
+    pub mean_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Display for ArrivalReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let received = self.threads.iter().map(|arrivals| arrivals.received).sum::<u64>();
+        let reordered = self.threads.iter().map(|arrivals| arrivals.reordered).sum::<u64>();
+        let missing = self.threads.iter().map(ThreadArrivals::missing).sum::<u64>();
+        write!(f, "arrivals: {received} snapshots from {} threads, {reordered} reordered, {missing} missing, delay mean {:?}, max {:?}",
+               self.threads.len(), self.mean_delay, self.max_delay)
+    }
+}
+
+/// What [`CaptureWindows`] does with a late snapshot, one taken in a window older than the
+/// latest one seen.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LatePolicy {
+    /// Merged into the window it was taken in while that window is retained, dropped otherwise.
+    #[default]
+    Retain,
+    /// Merged into the latest window, so windows don't change once a later one started.
+    FoldIntoNext,
+    /// Dropped.
+    Drop,
+}
+
+/// Totals per window of capture time, rather than of arrival like interval deltas. A snapshot
+/// that arrives late is handled according to its [`LatePolicy`], and counted if dropped.
+/// Snapshots that were never taken, e.g. built by hand, are skipped.
+#[derive(Clone, Debug)]
+pub struct CaptureWindows {
+    width: Duration,
+    retain: usize,
+    late: LatePolicy,
+    windows: Arc<Mutex<Windows>>,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    totals: BTreeMap<u128, Snapshot>,
+    dropped: u64,
+}
+
+impl CaptureWindows {
+    /// Windows are `width` long, aligned to the Unix epoch. Only the latest `retain` are kept.
+    pub fn new(width: Duration, retain: usize) -> Self {
+        assert!(!width.is_zero(), "capture windows can't be empty");
+        Self { width, retain, late: LatePolicy::default(), windows: Arc::default() }
+    }
+
+    pub fn late(mut self, policy: LatePolicy) -> Self {
+        self.late = policy;
+        self
+    }
+
+    fn index(&self, at: SystemTime) -> u128 {
+        at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() / self.width.as_nanos()
+    }
+
+    /// Merged total of the window `at` falls into, if any snapshot was taken in it.
+    pub fn window(&self, at: SystemTime) -> Option<Snapshot> {
+        self.windows.lock().unwrap().totals.get(&self.index(at)).cloned()
+    }
+
+    /// Start of every retained window, oldest first.
+    pub fn starts(&self) -> Vec<SystemTime> {
+        self.windows.lock().unwrap().totals.keys()
+            .map(|&index| UNIX_EPOCH + Duration::from_nanos((index * self.width.as_nanos()) as u64))
+            .collect()
+    }
+
+    /// Late snapshots that were dropped, see [`LatePolicy`].
+    pub fn dropped(&self) -> u64 {
+        self.windows.lock().unwrap().dropped
+    }
+}
+
+impl SnapshotProcessor for CaptureWindows {
+    fn on_merge(&mut self, snapshot: &mut Snapshot) {
+        let Some(captured) = snapshot.captured() else {
+            return
+        };
+        let mut index = self.index(captured);
+        let mut windows = self.windows.lock().unwrap();
+        let latest = windows.totals.last_key_value().map(|(&latest, _)| latest);
+        if let Some(latest) = latest.filter(|&latest| index < latest) {
+            let retained = windows.totals.len() < self.retain
+                || windows.totals.first_key_value().is_some_and(|(&oldest, _)| index >= oldest);
+            match self.late {
+                LatePolicy::Retain if retained => {}
+                LatePolicy::FoldIntoNext => index = latest,
+                LatePolicy::Retain | LatePolicy::Drop => {
+                    windows.dropped += 1;
+                    return
+                }
+            }
+        }
+        windows.totals.entry(index).or_default().merge_ref(snapshot);
+        while windows.totals.len() > self.retain {
+            windows.totals.pop_first();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::aggregator::Aggregator;
+    use crate::arrival::{ArrivalCheck, CaptureWindows, LatePolicy};
+    use crate::metrics::{Counter, KEY, METRICS_CTX, MetricsContextConfig, Records, Snapshot};
+    use crate::test_utils::shared_heap;
+    use crate::transport::{bounded_snapshot_channel, WhenFull};
+
+    #[test]
+    fn detects_reordered_and_missing_snapshots() {
+        let _heap = shared_heap();
+        let check = ArrivalCheck::new();
+        let windows = CaptureWindows::new(Duration::from_secs(3600), 2);
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(check.clone());
+        aggregator.add_processor(windows.clone());
+
+        let mut taken = thread::spawn(|| {
+            let mut snapshot = Snapshot::new();
+            snapshot.set_thread(Some(thread::current().id()));
+            (0..5).map(|_| {
+                snapshot.record(Counter(KEY, 1));
+                snapshot.take()
+            }).collect::<Vec<_>>()
+        }).join().unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], taken.iter().map(Snapshot::seq).collect::<Vec<_>>());
+        // 1 arrives after 2, 3 never does
+        taken.swap(1, 2);
+        taken.remove(3);
+        taken.into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+
+        let report = check.report();
+        assert_eq!((1, 1), (report.threads[0].reordered, report.threads[0].missing()));
+        assert!(report.to_string().starts_with("arrivals: 4 snapshots from 1 threads, 1 reordered, 1 missing"));
+        // summed over windows, in case the test straddles the turn of an hour
+        let total = windows.starts().iter().map(|&start| windows.window(start).unwrap().get_all_dims(KEY).unwrap()).sum::<u64>();
+        assert_eq!(4, total);
+        assert!(windows.window(SystemTime::UNIX_EPOCH).is_none());
+    }
+
+    #[test]
+    fn coalesced_snapshots_are_not_missing() {
+        let _heap = shared_heap();
+        let (tx, rx) = bounded_snapshot_channel(1, WhenFull::Coalesce);
+        let (recorded_tx, recorded) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            METRICS_CTX.with(|m| m.connect_with(tx, MetricsContextConfig { flush_every_n: 1, ..Default::default() }));
+            // the first flush fills the channel, the other two are coalesced
+            for _ in 0..3 {
+                METRICS_CTX.with(|m| m.increment(Counter(KEY, 1)));
+            }
+            recorded_tx.send(()).unwrap();
+            METRICS_CTX.with(|m| m.disconnect());
+        });
+        recorded.recv().unwrap();
+        let check = ArrivalCheck::new();
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(check.clone());
+        while let Ok(flushed) = rx.recv_timeout(None) {
+            aggregator.merge_flushed(flushed);
+        }
+
+        let report = check.report();
+        assert_eq!((2, 0), (report.threads[0].received, report.threads[0].missing()));
+        assert_eq!(Some(3), aggregator.get_all_dims(KEY));
+    }
+
+    /// Windows of 10s, two retained, after snapshots taken at 5s, 15s and 25s and late ones
+    /// taken at 12s, whose window is retained, and 3s, whose window isn't.
+    fn late_arrivals(policy: LatePolicy) -> CaptureWindows {
+        let windows = CaptureWindows::new(Duration::from_secs(10), 2).late(policy);
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(windows.clone());
+        for (secs, value) in [(5, 1), (15, 2), (25, 4), (12, 8), (3, 16)] {
+            let mut snapshot = Snapshot::new();
+            snapshot.record(Counter(KEY, value));
+            snapshot.set_captured(Some(UNIX_EPOCH + Duration::from_secs(secs)));
+            aggregator.merge(snapshot);
+        }
+
+        windows
+    }
+
+    fn window_total(windows: &CaptureWindows, secs: u64) -> Option<u64> {
+        windows.window(UNIX_EPOCH + Duration::from_secs(secs)).and_then(|window| window.get_all_dims(KEY))
+    }
+
+    #[test]
+    fn late_snapshots_retained() {
+        let _heap = shared_heap();
+        let windows = late_arrivals(LatePolicy::Retain);
+        assert_eq!((None, Some(10), Some(4)), (window_total(&windows, 0), window_total(&windows, 10), window_total(&windows, 20)));
+        assert_eq!(1, windows.dropped());
+    }
+
+    #[test]
+    fn late_snapshots_fold_into_latest_window() {
+        let _heap = shared_heap();
+        let windows = late_arrivals(LatePolicy::FoldIntoNext);
+        assert_eq!((None, Some(2), Some(28)), (window_total(&windows, 0), window_total(&windows, 10), window_total(&windows, 20)));
+        assert_eq!(0, windows.dropped());
+    }
+
+    #[test]
+    fn late_snapshots_dropped() {
+        let _heap = shared_heap();
+        let windows = late_arrivals(LatePolicy::Drop);
+        assert_eq!((None, Some(2), Some(4)), (window_total(&windows, 0), window_total(&windows, 10), window_total(&windows, 20)));
+        assert_eq!(2, windows.dropped());
+    }
+}
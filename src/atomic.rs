@@ -1,10 +1,12 @@
 use std::cell::RefCell;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use crate::harness::Milestone;
+use crate::work::Work;
 
 
 pub struct AtomicContext {
-    inner: RefCell<Option<Arc<AtomicU64>>>
+    inner: RefCell<Option<(Arc<AtomicU64>, Arc<Milestone>)>>
 }
 
 impl AtomicContext {
@@ -14,12 +16,20 @@ impl AtomicContext {
         }
     }
 
-    pub fn connect(&self, v: Arc<AtomicU64>) {
-        *self.inner.borrow_mut() = Some(v);
+    pub fn connect(&self, v: Arc<AtomicU64>, milestone: Arc<Milestone>) {
+        *self.inner.borrow_mut() = Some((v, milestone));
     }
 
-    pub fn increment(&self) {
-        self.inner.borrow().as_ref().unwrap().fetch_add(1, Ordering::Relaxed);
+    pub fn increment(&self, value: u64) {
+        let inner = self.inner.borrow();
+        let (counter, milestone) = inner.as_ref().unwrap();
+        milestone.progress(counter.fetch_add(value, Ordering::Relaxed).saturating_add(value));
+    }
+}
+
+impl Default for AtomicContext {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -28,14 +38,16 @@ thread_local! {
 }
 
 /// Simple atomic increments
-pub async fn do_work_async() {
-    loop {
-        let mut iter = 0;
+pub async fn do_work_async(work: Work) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
         ATOMIC_CTX.with(|m| {
-            m.increment();
+            m.increment(values.next());
         });
         iter += 1;
-        if iter % 100 == 0 {
+        if iter.is_multiple_of(100) {
             tokio::task::yield_now().await
         }
     }
@@ -0,0 +1,126 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use crate::aggregator::SnapshotProcessor;
+use crate::metrics::Snapshot;
+
+/// Reconciles what every worker thread recorded against what the aggregator merged from it.
+///
+/// Each snapshot carries the number of events its thread recorded up to that point, see
+/// [`Snapshot::recorded`]. Workloads increment the benchmark metric by one per event, so once
+/// a snapshot is merged, the total merged from its thread must equal that number. Anything
+/// less means an earlier snapshot went missing. Runs as a [`SnapshotProcessor`], so the check
+/// costs nothing on the producer side.
+#[derive(Clone, Debug)]
+pub struct Audit {
+    key: &'static str,
+    threads: Arc<Mutex<Vec<ThreadAudit>>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThreadAudit {
+    pub thread: ThreadId,
+    /// Events recorded as of the latest snapshot merged from this thread.
+    pub recorded: u64,
+    pub merged: u64,
+}
+
+impl ThreadAudit {
+    pub fn is_consistent(&self) -> bool {
+        self.recorded == self.merged
+    }
+}
+
+impl Audit {
+    pub fn new(key: &'static str) -> Self {
+        Self { key, threads: Arc::default() }
+    }
+
+    pub fn report(&self) -> AuditReport {
+        AuditReport { threads: self.threads.lock().unwrap().clone() }
+    }
+}
+
+impl SnapshotProcessor for Audit {
+    fn on_merge(&mut self, snapshot: &mut Snapshot) {
+        let Some(thread) = snapshot.thread() else {
+            return
+        };
+        let merged = snapshot.get_all_dims(self.key).unwrap_or_default();
+        let mut threads = self.threads.lock().unwrap();
+        match threads.iter_mut().find(|audit| audit.thread == thread) {
+            Some(audit) => {
+                audit.recorded = audit.recorded.max(snapshot.recorded());
+                audit.merged += merged;
+            }
+            None => threads.push(ThreadAudit { thread, recorded: snapshot.recorded(), merged }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditReport {
+    pub threads: Vec<ThreadAudit>,
+}
+
+impl AuditReport {
+    pub fn discrepancies(&self) -> impl Iterator<Item = &ThreadAudit> {
+        self.threads.iter().filter(|audit| !audit.is_consistent())
+    }
+}
+
+impl Display for AuditReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let recorded = self.threads.iter().map(|audit| audit.recorded).sum::<u64>();
+        let merged = self.threads.iter().map(|audit| audit.merged).sum::<u64>();
+        write!(f, "audit: {} threads, {recorded} recorded, {merged} merged", self.threads.len())?;
+        for audit in self.discrepancies() {
+            write!(f, "\n  {:?}: recorded {}, merged {}", audit.thread, audit.recorded, audit.merged)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::aggregator::Aggregator;
+    use crate::audit::{Audit, ThreadAudit};
+    use crate::metrics::{Counter, KEY, Records, Snapshot};
+    use crate::test_utils::shared_heap;
+
+    /// Snapshots a thread took after recording `events`, one event per snapshot.
+    fn snapshots(events: u64) -> (thread::ThreadId, Vec<Snapshot>) {
+        thread::spawn(move || {
+            let mut snapshot = Snapshot::new();
+            snapshot.set_thread(Some(thread::current().id()));
+            let taken = (0..events).map(|_| {
+                snapshot.record(Counter(KEY, 1));
+                snapshot.take()
+            }).collect();
+            (thread::current().id(), taken)
+        }).join().unwrap()
+    }
+
+    #[test]
+    fn reconcile() {
+        let _heap = shared_heap();
+        let audit = Audit::new(KEY);
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(audit.clone());
+
+        let (complete, taken) = snapshots(3);
+        taken.into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+        let (lossy, mut taken) = snapshots(4);
+        taken.remove(1);
+        taken.into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+        // snapshots that don't come from a worker are not audited
+        aggregator.merge(Snapshot::new());
+
+        let report = audit.report();
+        assert_eq!(vec![complete, lossy], report.threads.iter().map(|audit| audit.thread).collect::<Vec<_>>());
+        assert_eq!(vec![ThreadAudit { thread: lossy, recorded: 4, merged: 3 }], report.discrepancies().copied().collect::<Vec<_>>());
+        assert!(report.to_string().starts_with("audit: 2 threads, 7 recorded, 6 merged\n"));
+    }
+}
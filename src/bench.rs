@@ -0,0 +1,204 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::time::Duration;
+use serde::Deserialize;
+use crate::results::RunResult;
+
+/// Runs every benchmark declared in a TOML config and checks its results against the bounds
+/// declared with it, failing if any is exceeded.
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// TOML file with a `[[run]]` table per benchmark, see `bench.example.toml`
+    config: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchConfig {
+    #[serde(rename = "run")]
+    runs: Vec<BenchRun>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchRun {
+    mode: String,
+    /// Further command line arguments, e.g. `["--tasks", "100"]`
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    expect: Expectations,
+}
+
+/// Bounds a run has to stay within. Unset bounds aren't checked.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expectations {
+    /// Increments per second
+    min_throughput: Option<f64>,
+    max_elapsed_ms: Option<u64>,
+    /// Runtime build, hook and recorder installation combined
+    max_setup_ms: Option<u64>,
+    max_shutdown_ms: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Check {
+    bound: &'static str,
+    expected: f64,
+    actual: f64,
+    passed: bool,
+}
+
+impl Expectations {
+    fn check(&self, result: &RunResult) -> Vec<Check> {
+        let ms = |ns: u64| Duration::from_nanos(ns).as_secs_f64() * 1e3;
+        let costs = result.costs;
+        let setup_ns = costs.runtime_build_ns + costs.hooks_ns + costs.recorder_ns;
+        let mut checks = Vec::new();
+        if let Some(min) = self.min_throughput {
+            let actual = result.metric as f64 / result.elapsed().as_secs_f64();
+            checks.push(Check { bound: "min_throughput", expected: min, actual, passed: actual >= min });
+        }
+        for (bound, max, actual) in [
+            ("max_elapsed_ms", self.max_elapsed_ms, ms(result.elapsed_ns)),
+            ("max_setup_ms", self.max_setup_ms, ms(setup_ns)),
+            ("max_shutdown_ms", self.max_shutdown_ms, ms(costs.shutdown_ns)),
+        ] {
+            if let Some(max) = max {
+                checks.push(Check { bound, expected: max as f64, actual, passed: actual <= max as f64 });
+            }
+        }
+
+        checks
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let verdict = if self.passed { "pass" } else { "FAIL" };
+        write!(f, "  {verdict}: {} {}, got {:.2}", self.bound, self.expected, self.actual)
+    }
+}
+
+/// Runs `run` in a child process of this binary and reads its JSON result.
+fn execute(run: &BenchRun) -> Result<RunResult, String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let output = Command::new(exe)
+        .args(["--mode", &run.mode, "--output", "json"])
+        .args(&run.args)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))
+    }
+    parse_result(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The JSON result at the end of a run's output. It is the only line starting an object that
+/// parses, with everything after it, as a [`RunResult`]; progress lines come before it.
+fn parse_result(stdout: &str) -> Result<RunResult, String> {
+    let starts = stdout.match_indices('{').map(|(i, _)| i).filter(|&i| i == 0 || stdout.as_bytes()[i - 1] == b'\n');
+    let mut error = "no result in output".to_string();
+    for start in starts {
+        match serde_json::from_str(&stdout[start..]) {
+            Ok(result) => return Ok(result),
+            Err(e) => error = e.to_string(),
+        }
+    }
+
+    Err(error)
+}
+
+pub fn run(args: BenchArgs) {
+    let config = std::fs::read_to_string(&args.config)
+        .map_err(|e| e.to_string())
+        .and_then(|config| toml::from_str::<BenchConfig>(&config).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| panic!("invalid bench config {}: {e}", args.config.display()));
+
+    let mut failed = 0;
+    for run in &config.runs {
+        match execute(run) {
+            Ok(result) => {
+                println!("{} {}: {} in {:?}", run.mode, run.args.join(" "), result.metric, result.elapsed());
+                for check in run.expect.check(&result) {
+                    println!("{check}");
+                    failed += usize::from(!check.passed);
+                }
+            }
+            Err(e) => {
+                println!("{} {}: FAIL: {e}", run.mode, run.args.join(" "));
+                failed += 1;
+            }
+        }
+    }
+    println!("{} runs, {failed} failed", config.runs.len());
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::bench::{BenchConfig, parse_result};
+    use crate::ids::{Identity, Ulid};
+    use crate::results::{RunResult, SeriesSample, SetupCosts};
+
+    #[test]
+    fn checks_declared_bounds() {
+        let config: BenchConfig = toml::from_str(r#"
+            [[run]]
+            mode = "dense"
+            expect = { min_throughput = 1e6, max_elapsed_ms = 500, max_shutdown_ms = 1 }
+
+            [[run]]
+            mode = "tlv"
+            args = ["--tasks", "10"]
+        "#).unwrap();
+        let result = RunResult {
+            identity: Identity { run_id: Ulid::from_parts(0, 0), process_id: Ulid::from_parts(0, 1) },
+            mode: "dense".to_string(),
+            tasks: 1000,
+            threads: None,
+            work_ns: 0,
+            metric: 1_000_000,
+            spawn_ns: 0,
+            elapsed_ns: 400_000_000,
+            costs: SetupCosts { shutdown_ns: 2_000_000, ..SetupCosts::default() },
+            interrupted: false,
+            store: None,
+            series: Vec::new(),
+        };
+
+        let checks = config.runs[0].expect.check(&result);
+        let verdicts = checks.iter().map(|c| (c.bound, c.passed)).collect::<Vec<_>>();
+        assert_eq!(vec![("min_throughput", true), ("max_elapsed_ms", true), ("max_shutdown_ms", false)], verdicts);
+        assert!(config.runs[1].expect.check(&result).is_empty());
+        assert!(toml::from_str::<BenchConfig>("[[run]]\nmode = \"tlv\"\nexpect = { max_latency = 1 }").is_err());
+    }
+
+    #[test]
+    fn result_after_progress_lines() {
+        let result = RunResult {
+            identity: Identity { run_id: Ulid::from_parts(0, 0), process_id: Ulid::from_parts(0, 1) },
+            mode: "tlv".to_string(),
+            tasks: 10,
+            threads: None,
+            work_ns: 0,
+            metric: 1_000,
+            spawn_ns: 0,
+            elapsed_ns: 1_000_000,
+            costs: SetupCosts::default(),
+            interrupted: false,
+            store: None,
+            series: vec![SeriesSample { key: "metric".to_string(), labels: BTreeMap::from([("dest".to_string(), "H1".to_string())]), value: 1_000, unit: None, description: None }],
+        };
+        let stdout = format!("tasks started in 1ms\n{{ not a result\ninterval delta: Some(5)\n{}\n", serde_json::to_string_pretty(&result).unwrap());
+
+        let parsed = parse_result(&stdout).unwrap();
+        assert_eq!((1_000, result.series), (parsed.metric, parsed.series));
+        assert!(parse_result("tasks started in 1ms\n").is_err());
+    }
+}
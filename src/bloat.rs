@@ -0,0 +1,146 @@
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+/// Builds this crate with every combination of its optional features and records the size of
+/// the binary and how long the crate took to compile, to weigh what a feature costs to adopt.
+#[derive(clap::Args, Debug)]
+pub struct BloatArgs {
+    /// Manifest of the crate to build
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))]
+    manifest_path: PathBuf,
+
+    /// Features to combine, every feature of the manifest but `default` if not set
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// Build profile
+    #[arg(long, default_value = "release")]
+    profile: String,
+
+    /// Append every result to this file, one JSON object per line
+    #[arg(long)]
+    append: Option<PathBuf>,
+}
+
+/// Cost of one feature combination. Compile time only covers this crate, its dependencies are
+/// built before the clock starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BloatResult {
+    pub features: Vec<String>,
+    pub binary_bytes: u64,
+    pub compile_ms: u64,
+}
+
+impl Display for BloatResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let features = if self.features.is_empty() { "(none)".to_string() } else { self.features.join(",") };
+        write!(f, "{features}: {} bytes, compiled in {:?}", self.binary_bytes, Duration::from_millis(self.compile_ms))
+    }
+}
+
+/// Every subset of `features`, the empty one first, then by size. Subsets keep the order of
+/// `features`.
+pub fn combinations<T: Clone>(features: &[T]) -> Vec<Vec<T>> {
+    assert!(features.len() < 16, "{} features make too many combinations to build", features.len());
+    let mut combinations = (0..1_u32 << features.len())
+        .map(|mask| features.iter().enumerate().filter(|(i, _)| mask & (1 << i) != 0).map(|(_, feature)| feature.clone()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    combinations.sort_by_key(Vec::len);
+
+    combinations
+}
+
+/// Optional features declared in the manifest at `path`.
+fn manifest_features(path: &Path) -> Result<Vec<String>, String> {
+    let manifest = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let manifest = toml::from_str::<toml::Table>(&manifest).map_err(|e| e.to_string())?;
+    let features = manifest.get("features").and_then(toml::Value::as_table)
+        .map(|features| features.keys().filter(|name| *name != "default").cloned().collect())
+        .unwrap_or_default();
+
+    Ok(features)
+}
+
+/// Builds the crate with `features` into `target_dir` and measures the binary, timing a
+/// rebuild of the crate alone.
+fn build(args: &BloatArgs, target_dir: &Path, features: &[String]) -> Result<BloatResult, String> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let cargo = |subcommand: &str| {
+        let mut command = Command::new(&cargo);
+        command.arg(subcommand).arg("--manifest-path").arg(&args.manifest_path).arg("--target-dir").arg(target_dir);
+        command
+    };
+    let run = |command: &mut Command| match command.output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!("exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr))),
+        Err(e) => Err(e.to_string()),
+    };
+
+    let features_arg = features.join(",");
+    let build = || {
+        let mut build = cargo("build");
+        build.args(["--bins", "--no-default-features", "--profile", &args.profile, "--features", &features_arg]);
+        build
+    };
+    // the first build brings the dependencies this combination needs up to date
+    run(&mut build())?;
+    run(cargo("clean").args(["--package", env!("CARGO_PKG_NAME"), "--profile", &args.profile]))?;
+    let start = Instant::now();
+    run(&mut build())?;
+    let compile_ms = start.elapsed().as_millis() as u64;
+    // cargo puts the dev profile into `debug`
+    let dir = if args.profile == "dev" { "debug" } else { &args.profile };
+    let binary = target_dir.join(dir).join(format!("{}{}", env!("CARGO_PKG_NAME"), std::env::consts::EXE_SUFFIX));
+    let binary_bytes = std::fs::metadata(&binary).map_err(|e| format!("{}: {e}", binary.display()))?.len();
+
+    Ok(BloatResult { features: features.to_vec(), binary_bytes, compile_ms })
+}
+
+pub fn run(args: BloatArgs) {
+    let features = if args.features.is_empty() {
+        manifest_features(&args.manifest_path).unwrap_or_else(|e| panic!("can't read features of {}: {e}", args.manifest_path.display()))
+    } else {
+        args.features.clone()
+    };
+    // apart from the regular target dir, so the builds don't invalidate each other
+    let target_dir = args.manifest_path.parent().unwrap_or(Path::new(".")).join("target").join("bloat");
+    let mut append = args.append.as_ref().map(|path| {
+        OpenOptions::new().create(true).append(true).open(path).unwrap_or_else(|e| panic!("can't open {}: {e}", path.display()))
+    });
+
+    let mut baseline = None;
+    for combination in combinations(&features) {
+        let result = match build(&args, &target_dir, &combination) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{}: FAIL: {e}", combination.join(","));
+                continue
+            }
+        };
+        let baseline = *baseline.get_or_insert(result.binary_bytes);
+        println!("{result} ({:+} bytes)", result.binary_bytes as i64 - baseline as i64);
+        if let Some(file) = &mut append {
+            writeln!(file, "{}", serde_json::to_string(&result).unwrap()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bloat::combinations;
+
+    #[test]
+    fn every_combination_once() {
+        assert_eq!(vec![Vec::<&str>::new()], combinations::<&str>(&[]));
+        let combinations = combinations(&["ahash", "hdr", "serde"]);
+        assert_eq!(8, combinations.len());
+        assert_eq!((&vec![], &vec!["ahash", "hdr", "serde"]), (&combinations[0], &combinations[7]));
+        assert_eq!(vec![vec!["ahash"], vec!["hdr"], vec!["serde"]], combinations[1..4]);
+        assert_eq!(vec![vec!["ahash", "hdr"], vec!["ahash", "serde"], vec!["hdr", "serde"]], combinations[4..7]);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use crate::aggregator::Aggregator;
+use crate::transfer::synthetic_snapshot;
+
+/// Estimates how many producer threads a single aggregator shard can keep up with.
+#[derive(clap::Args, Debug)]
+pub struct CapacityArgs {
+    /// Series in every snapshot a producer flushes
+    #[arg(long, default_value_t = 100)]
+    series: usize,
+
+    /// Snapshots per second flushed by each producer thread
+    #[arg(long, default_value_t = 10.0)]
+    rate: f64,
+
+    /// Producer threads to plan shards for
+    #[arg(long, default_value_t = 64)]
+    producers: u64,
+
+    /// Fraction of the aggregator thread that may be spent merging, the rest is headroom
+    #[arg(long, default_value_t = 0.7)]
+    utilization: f64,
+
+    /// How long to measure the merge rate for
+    #[arg(long, default_value_t = 1000)]
+    measure_ms: u64,
+}
+
+/// Snapshots merged together before reading the clock. Copies are made outside the timed
+/// section, since cloning happens on the producer side in a real deployment.
+const BATCH: usize = 256;
+
+/// Merges copies of a synthetic snapshot into one aggregator for at least `duration` and
+/// returns the observed rate in snapshots per second. Channel overhead is not included; it
+/// is negligible next to merging anything but the smallest snapshots (see `--mode transfer`).
+pub fn measure_merge_rate(series: usize, duration: Duration) -> f64 {
+    let template = synthetic_snapshot(series);
+    let mut aggregator = Aggregator::new();
+    let mut merged = 0;
+    let mut busy = Duration::ZERO;
+    while busy < duration {
+        let batch = vec![template.clone(); BATCH];
+        let start = Instant::now();
+        for snapshot in batch {
+            aggregator.merge(snapshot);
+        }
+        busy += start.elapsed();
+        merged += BATCH;
+    }
+
+    merged as f64 / busy.as_secs_f64()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CapacityEstimate {
+    /// Snapshots per second one aggregator shard can merge.
+    pub merge_rate: f64,
+    /// Producers one shard sustains without exceeding the target utilization.
+    pub producers_per_shard: u64,
+    /// Shards needed for the planned number of producers.
+    pub shards: u64,
+}
+
+impl CapacityEstimate {
+    pub fn new(merge_rate: f64, rate: f64, utilization: f64, producers: u64) -> Self {
+        let producers_per_shard = (merge_rate * utilization / rate).floor() as u64;
+        let shards = if producers_per_shard == 0 {
+            // a single producer overloads a shard, it has to flush less often or send smaller snapshots
+            producers
+        } else {
+            producers.div_ceil(producers_per_shard).max(1)
+        };
+
+        Self { merge_rate, producers_per_shard, shards }
+    }
+}
+
+impl Display for CapacityEstimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "merge rate: {:.0} snapshots/s, producers per shard: {}, shards: {}",
+               self.merge_rate, self.producers_per_shard, self.shards)
+    }
+}
+
+pub fn run(args: CapacityArgs) {
+    let merge_rate = measure_merge_rate(args.series, Duration::from_millis(args.measure_ms));
+    let estimate = CapacityEstimate::new(merge_rate, args.rate, args.utilization, args.producers);
+    println!("{} series per snapshot, {} snapshots/s per producer, {} producers, {:.0}% target utilization",
+             args.series, args.rate, args.producers, args.utilization * 100.0);
+    println!("{estimate}");
+    if estimate.producers_per_shard == 0 {
+        println!("a single producer exceeds one shard's capacity, consider flushing less often");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capacity::CapacityEstimate;
+
+    #[test]
+    fn shards() {
+        let estimate = CapacityEstimate::new(10_000.0, 10.0, 0.5, 1200);
+        assert_eq!(500, estimate.producers_per_shard);
+        assert_eq!(3, estimate.shards);
+
+        assert_eq!(1, CapacityEstimate::new(10_000.0, 10.0, 0.5, 1).shards);
+        assert_eq!(0, CapacityEstimate::new(10.0, 100.0, 0.5, 4).producers_per_shard);
+    }
+}
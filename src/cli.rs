@@ -0,0 +1,552 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use ::metrics::Key;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use metrics_util::{CompositeKey, MetricKind};
+use metrics_util::debugging::{DebuggingRecorder, DebugValue};
+use crate::atomic::ATOMIC_CTX;
+use crate::arrival::ArrivalCheck;
+use crate::audit::Audit;
+use crate::bench::BenchArgs;
+use crate::bloat::BloatArgs;
+use crate::aggregator::Aggregator;
+use crate::capacity::CapacityArgs;
+use crate::layout::LayoutArgs;
+use crate::dimensions::{LabelDomain, LabelFilter};
+use crate::flusher::BackgroundFlusher;
+use crate::handle::MetricsHandle;
+use crate::ids::{Identity, Ulid, UlidGenerator};
+use crate::results::{OutputFormat, RunResult, SeriesSample, SetupCosts, StoreStats};
+use crate::hooks::{ParkFlush, ThreadHooks};
+use crate::harness::{Backoff, Collector, drain, Milestone, poll, SystemClock, Termination, timed, TransferThroughput};
+use crate::metadata::Unit;
+use crate::query::{Expr, History};
+use crate::restarts::RestartCheck;
+use crate::metrics::{FLUSH_THRESHOLD, Info, KEY, Records, Snapshot, TimeSlice};
+use crate::transport::{bounded_snapshot_channel, snapshot_channel, WhenFull};
+use crate::strategy::StrategyRegistry;
+use crate::transfer::{Producers, TransferConfig};
+use crate::tree::Leaves;
+use crate::work::{ValueDist, Work};
+use crate::{atomic, bench, bloat, capacity, external_metrics, layout, metadata, metrics, trace};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long, default_value = "tlv")]
+    mode: String,
+
+    #[arg(long, default_value_t = 1000)]
+    tasks: u64,
+
+    #[arg(long, default_value_t = 100_000_000)]
+    max_val: u64,
+
+    #[arg(long)]
+    threads: Option<u64>,
+
+    /// Nanoseconds of synthetic CPU work performed between metric increments
+    #[arg(long, default_value_t = 0)]
+    work_ns: u64,
+
+    /// Values the benchmark metric is incremented by: `const:N`, `uniform:A..B` or `zipf[:N]`
+    #[arg(long, default_value = "const:1")]
+    value_dist: ValueDist,
+
+    /// Increments after which a worker flushes its thread-local snapshot
+    #[arg(long, default_value_t = FLUSH_THRESHOLD)]
+    flush_every: usize,
+
+    /// Flush a thread-local snapshot once its oldest increment is this old. Reads the clock on
+    /// every increment
+    #[arg(long)]
+    max_staleness_ms: Option<u64>,
+
+    /// Snapshots the channel to the aggregator holds before producers apply `--when-full`.
+    /// Unbounded if not set. Not supported by `tlv-arc`
+    #[arg(long)]
+    channel_capacity: Option<usize>,
+
+    /// What producers do with a snapshot when the channel is full
+    #[arg(long, value_enum, default_value_t = WhenFull::Block, requires = "channel_capacity")]
+    when_full: WhenFull,
+
+    /// Ask every worker for its thread-local snapshot this often, from a background thread.
+    /// Workers hand it over on their next increment
+    #[arg(long)]
+    background_flush_ms: Option<u64>,
+
+    /// Flush thread-local snapshots at least this often, even if the worker never parks
+    #[arg(long)]
+    flush_interval_ms: Option<u64>,
+
+    /// How many increments to do between clock reads when `--flush-interval-ms` is set
+    #[arg(long, default_value_t = 1024)]
+    clock_check_every: u32,
+
+    /// Flush thread-local snapshots only on every Nth park of a worker, never if 0
+    #[arg(long, default_value_t = 1)]
+    park_flush_every: u32,
+
+    /// Print the per-interval delta of the benchmark metric at this period
+    #[arg(long)]
+    report_interval_ms: Option<u64>,
+
+    /// Evaluate a query over the aggregated counters at the end of the run, e.g.
+    /// `sum(metric{helper="H1"}) / rate(other[30s])`. Ranges look back over the totals of
+    /// past report intervals. Repeatable
+    #[arg(long)]
+    query: Vec<Expr>,
+
+    /// Print series count and the top N label values per metric at the end of the run
+    #[arg(long)]
+    cardinality_report: Option<usize>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Join an existing run instead of starting a new one
+    #[arg(long)]
+    run_id: Option<Ulid>,
+
+    /// Label used by `tlv-dim-1` instead of the built-in helper identity, as `name=v1,v2,..`
+    /// or `name=N` for N generated values
+    #[arg(long)]
+    label_domain: Option<LabelDomain>,
+
+    /// Number of distinct request ids `tlv-high-card` cycles through
+    #[arg(long, default_value_t = 1_000_000)]
+    label_space: u64,
+
+    /// Most series every metric can have in the aggregated store. Label combinations past it
+    /// are folded into one `overflow=true` series of the metric
+    #[arg(long)]
+    max_series_per_metric: Option<usize>,
+
+    /// Labels to drop from every series, e.g. `request_id`
+    #[arg(long, value_delimiter = ',')]
+    drop_labels: Vec<String>,
+
+    /// Labels to keep, every other label is dropped. Combines with `--drop-labels`
+    #[arg(long, value_delimiter = ',')]
+    keep_labels: Option<Vec<String>>,
+
+    /// Where `--drop-labels` and `--keep-labels` are applied
+    #[arg(long, value_enum, default_value_t = DropLabelsAt::Producer)]
+    drop_labels_at: DropLabelsAt,
+
+    /// Distinct series in every synthetic snapshot sent in `transfer` mode
+    #[arg(long, default_value_t = 1)]
+    snapshot_series: usize,
+
+    /// Snapshots per second sent by each `transfer` producer thread, unlimited if not set
+    #[arg(long)]
+    snapshot_rate: Option<u64>,
+
+    /// Route `transfer` producers through this many intermediate aggregators, which forward
+    /// compacted snapshots to the root aggregator
+    #[arg(long, default_value_t = 0)]
+    leaves: usize,
+
+    /// How often leaves forward to the root aggregator
+    #[arg(long, default_value_t = 100)]
+    leaf_interval_ms: u64,
+
+    /// Reconcile events recorded by every TLV worker thread with what the aggregator merged
+    /// from it, printing threads that lost any. Requires `--value-dist const:1`
+    #[arg(long)]
+    audit: bool,
+
+    /// Check the order snapshots of every TLV worker thread arrive in, and how long they take
+    /// from being taken to being merged
+    #[arg(long)]
+    arrivals: bool,
+
+    /// Report producers whose recorded events went down, i.e. that restarted without taking
+    /// a new origin
+    #[arg(long)]
+    restarts: bool,
+
+    /// Write spawn, flush, send, merge and read spans to this file in Chrome trace-event
+    /// format, for chrome://tracing or Perfetto
+    #[arg(long)]
+    trace_out: Option<PathBuf>,
+
+    /// Write every series the TLV aggregator merged to stderr once the run is over, the way a
+    /// batch job embedding the library reports without an exporter
+    #[arg(long)]
+    summary_on_exit: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum DropLabelsAt {
+    /// Before the series is recorded in the thread-local snapshot
+    Producer,
+    /// When the snapshot is merged
+    Aggregator,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Estimate how many producers one aggregator shard sustains and how many shards to run
+    Capacity(CapacityArgs),
+    /// Compare memory layouts of the series store on multi-label series
+    Layout(LayoutArgs),
+    /// Run the benchmarks of a TOML config and check their results against its bounds
+    Bench(BenchArgs),
+    /// Build every combination of optional features and compare binary sizes and compile times
+    Bloat(BloatArgs),
+}
+
+/// Raised by SIGINT/SIGTERM, so the run can stop early and still report what it collected.
+/// A second signal terminates the process immediately, in case draining gets stuck.
+fn interrupt_flag() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&flag)).unwrap();
+        signal_hook::flag::register(signal, Arc::clone(&flag)).unwrap();
+    }
+
+    flag
+}
+
+/// Runs the benchmark harness with the arguments of the process. Modes not built into the
+/// harness are looked up in `strategies`, see [`StrategyRegistry`].
+pub fn run(strategies: StrategyRegistry) {
+    const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+    /// Longest wait for the workers' final flushes once the run is over.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Capacity(capacity)) => return capacity::run(capacity),
+        Some(Command::Layout(layout)) => return layout::run(layout),
+        Some(Command::Bench(bench)) => return bench::run(bench),
+        Some(Command::Bloat(bloat)) => return bloat::run(bloat),
+        None => {}
+    }
+    if args.mode == "tlv-arc" && args.channel_capacity.is_some() {
+        Args::command().error(ErrorKind::ArgumentConflict, "--channel-capacity is not supported by --mode tlv-arc").exit();
+    }
+    if args.audit && args.value_dist != ValueDist::Const(1) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--audit counts one per event and requires --value-dist const:1").exit();
+    }
+
+    let interrupted = interrupt_flag();
+    metadata::describe_counter(KEY, Some(Unit::Count), "Increments performed by benchmark tasks");
+    if args.trace_out.is_some() {
+        trace::enable();
+    }
+    let identity = Identity::generate(&UlidGenerator::default(), args.run_id);
+    println!("run: {}, process: {}", identity.run_id, identity.process_id);
+    let mut rt_builder = tokio::runtime::Builder::new_multi_thread();
+    rt_builder.enable_all();
+
+    if let Some(thread_count) = args.threads {
+        rt_builder.worker_threads(thread_count as usize);
+    }
+
+    let label_filter = match &args.keep_labels {
+        Some(keep) => LabelFilter::allow(keep.clone()).deny(args.drop_labels.clone()),
+        None => LabelFilter::new(args.drop_labels.clone()),
+    };
+    let label_filter: Option<&'static LabelFilter> = (!label_filter.is_empty()).then(|| &*Box::leak(Box::new(label_filter)));
+    let producer_filter = label_filter.filter(|_| args.drop_labels_at == DropLabelsAt::Producer);
+
+    let strategy = strategies.create(&args.mode);
+
+    let milestone = Arc::new(Milestone::new(args.max_val));
+    let mut costs = SetupCosts::default();
+    let (tx, rx, atomic_cnt, snapshotter) = if let Some(strategy) = &strategy {
+        strategy.set_milestone(Arc::clone(&milestone));
+        costs.hooks_ns = timed(|| {
+            rt_builder.on_thread_start({
+                let strategy = Arc::clone(strategy);
+                move || strategy.setup_thread()
+            }).on_thread_park({
+                let strategy = Arc::clone(strategy);
+                move || strategy.on_thread_park()
+            }).on_thread_stop({
+                let strategy = Arc::clone(strategy);
+                move || strategy.on_thread_stop()
+            });
+        }).1;
+        (None, None, None, None)
+    } else if args.mode == "atomic" {
+        let counter = Arc::new(AtomicU64::default());
+        costs.hooks_ns = timed(|| {
+            rt_builder.on_thread_start({
+                let counter = counter.clone();
+                let milestone = Arc::clone(&milestone);
+                move || {
+                    let (counter, milestone) = (Arc::clone(&counter), Arc::clone(&milestone));
+                    ATOMIC_CTX.with(move |m| m.connect(counter, milestone));
+                }
+            });
+        }).1;
+        (None, None, Some(counter), None)
+    } else if args.mode == "tlv" || args.mode == "tlv-macro" || args.mode == "tlv-registered" || args.mode == "tlv-dim-1" || args.mode == "tlv-arc" || args.mode == "tlv-high-card" {
+        let (tx, rx) = match args.channel_capacity {
+            Some(capacity) => bounded_snapshot_channel(capacity, args.when_full),
+            None => snapshot_channel(args.mode == "tlv-arc"),
+        };
+        let time_slice = args.flush_interval_ms.map(|ms| TimeSlice {
+            check_every: args.clock_check_every,
+            max_interval: Duration::from_millis(ms),
+        });
+        costs.hooks_ns = timed(|| {
+            ThreadHooks::new()
+                .origin(identity)
+                .label_filter(producer_filter)
+                .time_slice(time_slice)
+                .flush_every(args.flush_every)
+                .max_staleness(args.max_staleness_ms.map(Duration::from_millis))
+                .background_flush(args.background_flush_ms.is_some())
+                .park_flush(ParkFlush::every(args.park_flush_every))
+                .install(&mut rt_builder, tx.clone());
+        }).1;
+
+        (Some(tx), Some(rx), None, None)
+    } else if args.mode == "transfer" {
+        let (tx, rx) = snapshot_channel(false);
+        (Some(tx), Some(rx), None, None)
+    } else if args.mode == "ext-metrics" {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        costs.recorder_ns = timed(|| recorder.install().unwrap()).1;
+
+        (None, None, None, Some(snapshotter))
+    } else {
+        let registered = strategies.names().collect::<Vec<_>>().join(", ");
+        panic!("unsupported mode: {}. Registered strategies: {registered}", args.mode);
+    };
+    let (rt, runtime_build_ns) = timed(|| rt_builder.build().unwrap());
+    costs.runtime_build_ns = runtime_build_ns;
+    drop(rt_builder);
+
+    let work = Work::calibrate(args.work_ns).with_values(args.value_dist);
+    if args.work_ns > 0 {
+        println!("work: {}ns ~ {} checksum iterations", args.work_ns, work.iterations());
+    }
+
+    let label_domain: Option<&'static LabelDomain> = args.label_domain.clone().map(|d| &*Box::leak(Box::new(d)));
+    let start = Instant::now();
+    let producers = if args.mode == "transfer" {
+        let config = TransferConfig {
+            threads: args.threads.map_or_else(|| std::thread::available_parallelism().unwrap().get(), |t| t as usize),
+            series: args.snapshot_series,
+            rate: args.snapshot_rate,
+        };
+        let upstream = tx.clone().unwrap();
+        let (sinks, leaves) = match args.leaves {
+            0 => (vec![upstream], None),
+            leaves => {
+                let (sinks, leaves) = Leaves::spawn(leaves, Duration::from_millis(args.leaf_interval_ms), upstream);
+                (sinks, Some(leaves))
+            }
+        };
+        Some((Producers::spawn(config, &sinks), leaves))
+    } else {
+        for task in 0..args.tasks {
+            let _span = trace::span("spawn");
+            if let Some(strategy) = &strategy {
+                rt.spawn(Arc::clone(strategy).workload(work.clone()));
+                continue;
+            }
+            match args.mode.as_ref() {
+                "atomic" => { rt.spawn(atomic::do_work_async(work.clone())); },
+                "tlv" | "tlv-arc" => {
+                    rt.spawn(metrics::do_work_async(work.clone()));
+                },
+                "tlv-macro" => { rt.spawn(metrics::do_work_async_macro(work.clone())); },
+                "tlv-registered" => { rt.spawn(metrics::do_work_async_registered(work.clone())); },
+                "tlv-dim-1" => match label_domain {
+                    Some(domain) => { rt.spawn(metrics::do_work_async_domain(work.clone(), domain)); },
+                    None => { rt.spawn(metrics::do_work_async_one_dim(work.clone())); },
+                },
+                "tlv-high-card" => {
+                    // spread tasks over the id space, so they don't all report the same ids
+                    let space = args.label_space.max(1);
+                    let first = (task as u128 * space as u128 / args.tasks as u128) as u64;
+                    rt.spawn(metrics::do_work_async_high_cardinality(work.clone(), space, first));
+                },
+                "ext-metrics" => {
+                    rt.spawn(external_metrics::do_work_async(work.clone()));
+                }
+                _ => unreachable!()
+            }
+        }
+        None
+    };
+    let spawn_elapsed = start.elapsed();
+    println!("tasks started in {:?}", spawn_elapsed);
+    let mut rt = Some(rt);
+
+
+    let _flusher = args.background_flush_ms.map(|ms| BackgroundFlusher::start(Duration::from_millis(ms)));
+    let termination = Termination { max_val: args.max_val, interrupted: &interrupted };
+    // the TLV modes print reports once aggregation is done, which isn't part of the run
+    let mut reached = None;
+    let (metric, series, store) = if let Some(strategy) = &strategy {
+        let mut backoff = Backoff::default();
+        (poll(termination, || strategy.read_total(), || backoff.pause()), Vec::new(), None)
+    } else if args.mode == "atomic" {
+        let counter = atomic_cnt.unwrap();
+        let mut backoff = Backoff::default();
+        (poll(termination, || counter.load(Ordering::Relaxed), || backoff.pause()), Vec::new(), None)
+    } else if args.mode == "tlv" || args.mode == "tlv-macro" || args.mode == "tlv-registered" || args.mode == "tlv-dim-1" || args.mode == "tlv-arc" || args.mode == "tlv-high-card" || args.mode == "transfer" {
+        drop(tx);
+
+        let rx = rx.unwrap();
+        let report_interval = args.report_interval_ms.map(Duration::from_millis);
+        let mut aggregator = if report_interval.is_some() {
+            Aggregator::with_interval_deltas()
+        } else {
+            Aggregator::new()
+        };
+        if args.drop_labels_at == DropLabelsAt::Aggregator {
+            aggregator.set_label_filter(label_filter);
+        }
+        aggregator.set_cardinality_limit(args.max_series_per_metric);
+        let mut info = Snapshot::new();
+        let mode: &'static str = Box::leak(args.mode.clone().into_boxed_str());
+        info.record(Info("metric_proto_info", &[("version", env!("CARGO_PKG_VERSION")), ("mode", mode)]));
+        aggregator.merge(info);
+        let audit = args.audit.then(|| Audit::new(KEY));
+        if let Some(audit) = &audit {
+            aggregator.add_processor(audit.clone());
+        }
+        let arrivals = args.arrivals.then(ArrivalCheck::new);
+        if let Some(arrivals) = &arrivals {
+            aggregator.add_processor(arrivals.clone());
+        }
+        let restarts = args.restarts.then(RestartCheck::new);
+        if let Some(restarts) = &restarts {
+            aggregator.add_processor(restarts.clone());
+        }
+        let collector = Collector { termination, report_interval, poll: INTERRUPT_POLL, clock: SystemClock };
+        let retention = args.query.iter().map(Expr::max_range).max().unwrap_or_default();
+        let mut history = History::new(retention);
+        collector.run(&rx, &mut aggregator, |aggregator| {
+            println!("interval delta: {:?}", aggregator.delta_all_dims(KEY));
+            if !args.query.is_empty() {
+                history.record(Instant::now(), SeriesSample::from_snapshot(aggregator.total()));
+            }
+        });
+        reached = Some(Instant::now());
+        // the result is what the run reached, reports include everything flushed on the way out
+        let metric = aggregator.get_all_dims(KEY).unwrap_or_default();
+        if args.mode != "transfer" {
+            let rt = rt.take().unwrap();
+            let (drained, shutdown_ns) = timed(|| drain(rt, &work, &rx, &mut aggregator, DRAIN_TIMEOUT).map(|total| total.get_all_dims(KEY)));
+            costs.shutdown_ns = shutdown_ns;
+            match drained {
+                Ok(total) => println!("drained: {}", total.unwrap_or_default()),
+                Err(e) => println!("drain incomplete: {e}"),
+            }
+        }
+        if let Some(losses) = rx.losses() {
+            println!("snapshots dropped: {}, coalesced: {}", losses.dropped, losses.coalesced);
+        }
+
+        if let Some(top) = args.cardinality_report {
+            let store = aggregator.total().store();
+            println!("store: {} series, {} bytes", store.len(), store.footprint());
+            for report in aggregator.cardinality_report(top) {
+                println!("{report}");
+            }
+        }
+
+        let compaction = aggregator.compaction_stats();
+        if compaction.compactions > 0 {
+            println!("store compactions: {}, reclaimed {} bytes", compaction.compactions, compaction.bytes_reclaimed);
+        }
+
+        if let Some(audit) = &audit {
+            println!("{}", audit.report());
+        }
+        if let Some(arrivals) = &arrivals {
+            println!("{}", arrivals.report());
+        }
+        if let Some(restarts) = &restarts {
+            println!("{}", restarts.report());
+        }
+
+        let samples = SeriesSample::from_snapshot(aggregator.total());
+        if !args.query.is_empty() {
+            history.record(Instant::now(), samples.clone());
+        }
+        for query in &args.query {
+            match query.eval(&samples, &history) {
+                Ok(value) => println!("query: {value}"),
+                Err(e) => println!("query failed: {e}"),
+            }
+        }
+
+        let store = StoreStats { flushes: aggregator.flushes(), kinds: aggregator.total().store().kind_stats() };
+        if args.summary_on_exit {
+            drop(MetricsHandle::with_aggregator(rx, aggregator).report_on_drop());
+        }
+        (metric, samples, Some(store))
+    } else if args.mode == "ext-metrics" {
+        let snapshotter = snapshotter.unwrap();
+        let mut backoff = Backoff::default();
+        let total = poll(termination, || {
+            #[allow(clippy::mutable_key_type)]
+            let map = snapshotter.snapshot().into_hashmap();
+            match map.get(&CompositeKey::new(MetricKind::Counter, Key::from_static_name(external_metrics::KEY))) {
+                Some((_, _, DebugValue::Counter(cnt))) => *cnt,
+                Some(_) => unreachable!(),
+                None => 0,
+            }
+        }, || backoff.pause());
+        (total, Vec::new(), None)
+    } else {
+        unreachable!()
+    };
+    let elapsed = reached.unwrap_or_else(Instant::now).duration_since(start);
+    let (leaves, shutdown_ns) = timed(|| {
+        let leaves = producers.and_then(|(producers, leaves)| {
+            producers.stop();
+            leaves.map(Leaves::join)
+        });
+        if let Some(rt) = rt {
+            rt.shutdown_background();
+        }
+        leaves
+    });
+    costs.shutdown_ns += shutdown_ns;
+    if let Some(leaves) = leaves {
+        println!("{leaves}");
+    }
+
+    if args.mode == "transfer" {
+        println!("{}", TransferThroughput::new(metric, args.snapshot_series, elapsed));
+    }
+
+    let result = RunResult {
+        identity,
+        mode: args.mode,
+        tasks: args.tasks,
+        threads: args.threads,
+        work_ns: args.work_ns,
+        metric,
+        spawn_ns: spawn_elapsed.as_nanos() as u64,
+        elapsed_ns: elapsed.as_nanos() as u64,
+        costs,
+        interrupted: interrupted.load(Ordering::Relaxed),
+        store,
+        series,
+    };
+    result.print(args.output);
+    if let Some(path) = &args.trace_out {
+        trace::write(path).unwrap_or_else(|e| eprintln!("failed to write trace to {}: {e}", path.display()));
+    }
+}
@@ -0,0 +1,218 @@
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use crate::ids::Identity;
+use crate::metrics::{KEY, LocalStore, MetricsContext, Records, FLUSH_THRESHOLD};
+use crate::transport::{Flushed, snapshot_channel, SnapshotReceiver, SnapshotSender};
+use crate::strategy::{StorageStrategy, Workload};
+use crate::work::Work;
+
+/// Names of the metrics registered for the dense store, indexed by [`DenseId`].
+static REGISTERED: RwLock<Vec<&'static str>> = RwLock::new(Vec::new());
+
+/// Id of a metric in the dense store. Every registered metric owns a slot in [`DenseSnapshot`],
+/// so recording is an indexed add with no hashing and no labels, and merging is a vector add.
+/// This is the lower bound the dimensional store is measured against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DenseId(usize);
+
+impl DenseId {
+    /// Assigns the next id to `name`, or returns the one it already has.
+    pub fn register(name: &'static str) -> Self {
+        let mut registered = REGISTERED.write().unwrap();
+        let id = registered.iter().position(|registered| *registered == name).unwrap_or_else(|| {
+            registered.push(name);
+            registered.len() - 1
+        });
+
+        Self(id)
+    }
+
+    pub fn name(self) -> &'static str {
+        REGISTERED.read().unwrap()[self.0]
+    }
+}
+
+pub struct DenseCounter(pub DenseId, pub u64);
+
+#[derive(Clone, Debug)]
+pub struct DenseSnapshot {
+    /// Values by id, only as long as the highest id recorded so far.
+    values: Vec<u64>,
+    cnt: usize,
+    origin: Option<Identity>,
+    flush_threshold: usize,
+}
+
+impl Default for DenseSnapshot {
+    fn default() -> Self {
+        Self { values: Vec::new(), cnt: 0, origin: None, flush_threshold: FLUSH_THRESHOLD }
+    }
+}
+
+impl DenseSnapshot {
+    pub fn get(&self, id: DenseId) -> u64 {
+        self.values.get(id.0).copied().unwrap_or_default()
+    }
+
+    #[cold]
+    fn grow(&mut self, len: usize) {
+        self.values.resize(len, 0);
+    }
+}
+
+impl LocalStore for DenseSnapshot {
+    fn is_empty(&self) -> bool {
+        self.cnt == 0
+    }
+
+    /// Leaves as many zeroed slots in place, so the next snapshot doesn't grow again.
+    fn take(&mut self) -> Self {
+        let len = self.values.len();
+        Self {
+            values: mem::replace(&mut self.values, vec![0; len]),
+            cnt: mem::take(&mut self.cnt),
+            origin: self.origin,
+            flush_threshold: self.flush_threshold,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        if self.values.len() < other.values.len() {
+            self.grow(other.values.len());
+        }
+        for (a, b) in self.values.iter_mut().zip(other.values) {
+            *a += b;
+        }
+        self.cnt += other.cnt;
+    }
+
+    fn set_origin(&mut self, origin: Option<Identity>) {
+        self.origin = origin;
+    }
+
+    fn set_flush_threshold(&mut self, threshold: usize) {
+        self.flush_threshold = threshold;
+    }
+}
+
+impl Records<DenseCounter> for DenseSnapshot {
+    #[inline]
+    fn record(&mut self, metric: DenseCounter) -> bool {
+        let DenseCounter(DenseId(id), value) = metric;
+        if id >= self.values.len() {
+            self.grow(id + 1);
+        }
+        self.values[id] += value;
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+}
+
+thread_local! {
+    pub static DENSE_CTX: MetricsContext<DenseSnapshot> = const { MetricsContext::new() }
+}
+
+/// `dense` mode: the TLV pipeline with [`DenseSnapshot`] as the thread-local store.
+pub struct DenseStrategy {
+    requests: DenseId,
+    tx: SnapshotSender<DenseSnapshot>,
+    rx: SnapshotReceiver<DenseSnapshot>,
+    total: Mutex<DenseSnapshot>,
+}
+
+impl Default for DenseStrategy {
+    fn default() -> Self {
+        let (tx, rx) = snapshot_channel(false);
+        Self {
+            requests: DenseId::register(KEY),
+            tx,
+            rx,
+            total: Mutex::default(),
+        }
+    }
+}
+
+impl DenseStrategy {
+    fn flush(&self) {
+        let snapshot = DENSE_CTX.with(|m| m.take_snapshot());
+        if !snapshot.is_empty() {
+            self.tx.send(snapshot);
+        }
+    }
+}
+
+impl StorageStrategy for DenseStrategy {
+    fn setup_thread(&self) {
+        DENSE_CTX.with(|m| m.connect(self.tx.clone()));
+    }
+
+    fn on_thread_park(&self) {
+        self.flush();
+    }
+
+    fn on_thread_stop(&self) {
+        self.flush();
+    }
+
+    fn record(&self, value: u64) {
+        DENSE_CTX.with(|m| m.increment(DenseCounter(self.requests, value)));
+    }
+
+    fn read_total(&self) -> u64 {
+        let mut total = self.total.lock().unwrap();
+        while let Ok(flushed) = self.rx.recv_timeout(Some(Duration::ZERO)) {
+            match flushed {
+                Flushed::Owned(snapshot) => total.merge(snapshot),
+                Flushed::Shared(snapshot) => total.merge(snapshot.snapshot().clone()),
+            }
+        }
+
+        total.get(self.requests)
+    }
+
+    fn workload(self: Arc<Self>, work: Work) -> Workload {
+        Box::pin(do_work_async(work, self.requests))
+    }
+}
+
+pub async fn do_work_async(work: Work, requests: DenseId) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
+        DENSE_CTX.with(|m| {
+            m.increment(DenseCounter(requests, values.next()));
+        });
+        iter += 1;
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dense::{DenseCounter, DenseId, DenseSnapshot};
+    use crate::metrics::{LocalStore, Records};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn merge_adds_by_id() {
+        let _heap = shared_heap();
+        let (bytes, errors) = (DenseId::register("dense_bytes"), DenseId::register("dense_errors"));
+        assert_eq!(bytes, DenseId::register("dense_bytes"));
+        assert_eq!("dense_errors", errors.name());
+
+        let (mut a, mut b) = (DenseSnapshot::default(), DenseSnapshot::default());
+        a.record(DenseCounter(bytes, 100));
+        b.record(DenseCounter(errors, 1));
+        b.record(DenseCounter(bytes, 20));
+        let taken = b.take();
+        assert_eq!((0, 0), (b.get(bytes), b.get(errors)));
+
+        a.merge(taken);
+        assert_eq!((120, 1), (a.get(bytes), a.get(errors)));
+    }
+}
@@ -1,22 +1,228 @@
+use std::any::TypeId;
 use std::array;
-use std::fmt::{Debug, Display, Formatter};
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Display, Formatter, Write};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter::zip;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::mem;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use hashbrown::hash_map::RawEntryMut;
+use hyperloglogplus::{HyperLogLog, HyperLogLogPlus};
 use rustc_hash::FxBuildHasher;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use sketches_ddsketch::{Config, DDSketch};
+use crate::pool;
 
-pub trait LabelValue : Display + Send {
+/// Derives [`LabelValue`] for fieldless enums and newtypes:
+///
+/// ```
+/// use metric_proto::dimensions::{LabelValue, MetricName, MetricStore};
+///
+/// #[derive(LabelValue)]
+/// enum Step {
+///     Shuffle,
+///     Reveal,
+/// }
+///
+/// #[derive(LabelValue)]
+/// struct Shard(u8);
+///
+/// let mut store = MetricStore::default();
+/// store.update(&MetricName::from(("records", ("step", &Step::Reveal), ("shard", &Shard(3)))), 2);
+/// assert_eq!(Some(2), store.get_counter(&MetricName::from(("records", ("step", &Step::Reveal), ("shard", &Shard(3))))));
+/// ```
+pub use metric_proto_derive::LabelValue;
+
+pub mod soa;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod tlv;
+
+pub trait LabelValue : Display + Send + Sync {
     fn as_u64(&self) -> u64;
 
     fn boxed(&self) -> Box<dyn LabelValue>;
+
+    /// Copy of the value that stores share between series and snapshots. Override to
+    /// allocate once instead of boxing first.
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::from(self.boxed())
+    }
+
+    /// How stores can keep the value without allocating, if the type allows it. Values
+    /// without one are interned instead.
+    fn inline(&self) -> Option<StoredLabel> {
+        None
+    }
+
+    /// Type whose values [`as_u64`] identifies on its own, for types that don't hash their
+    /// values. Values of the same tag are equal once their u64 is.
+    ///
+    /// [`as_u64`]: LabelValue::as_u64
+    fn type_tag(&self) -> Option<TypeId> {
+        None
+    }
+
+    /// Whether `other`, whose [`as_u64`] already matched, is the same value. Stores only call
+    /// this on a match, so values told apart by a hash don't merge when hashes collide.
+    /// Values of the same [`type_tag`] are equal, anything else compares the rendered values
+    /// without allocating unless overridden.
+    ///
+    /// [`as_u64`]: LabelValue::as_u64
+    /// [`type_tag`]: LabelValue::type_tag
+    fn label_eq(&self, other: &dyn LabelValue) -> bool {
+        match self.type_tag() {
+            Some(tag) if other.type_tag() == Some(tag) => true,
+            _ => displays_eq(&self, other),
+        }
+    }
+}
+
+/// Label value as stores keep it. Common label types are kept without allocating.
+#[derive(Clone)]
+pub enum StoredLabel {
+    /// Integer ids, rendered as the number
+    Number(u64),
+    /// Text that lives for the rest of the process
+    Str(&'static str),
+    /// Values from a fixed set, e.g. enum variants
+    Static(&'static dyn LabelValue),
+    /// Anything else, interned per thread
+    Shared(Arc<dyn LabelValue>),
+}
+
+impl StoredLabel {
+    fn value(&self) -> &(dyn LabelValue + 'static) {
+        match self {
+            Self::Number(n) => n,
+            Self::Str(s) => s,
+            Self::Static(value) => *value,
+            Self::Shared(value) => &**value,
+        }
+    }
+
+    /// Whether both store the same value. Interned values are compared by pointer first, so
+    /// this is only a full comparison for values interned on different threads.
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::Shared(a), Self::Shared(b)) if Arc::ptr_eq(a, b) => true,
+            _ => self.value().label_eq(other.value()),
+        }
+    }
+}
+
+impl Display for StoredLabel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self.value(), f)
+    }
+}
+
+impl LabelValue for StoredLabel {
+    fn as_u64(&self) -> u64 {
+        self.value().as_u64()
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(self.clone())
+    }
+
+    fn inline(&self) -> Option<StoredLabel> {
+        (!matches!(self, Self::Shared(_))).then(|| self.clone())
+    }
+
+    fn type_tag(&self) -> Option<TypeId> {
+        self.value().type_tag()
+    }
+
+    fn label_eq(&self, other: &dyn LabelValue) -> bool {
+        self.value().label_eq(other)
+    }
+}
+
+/// Whether `value` renders as `expected`, without allocating.
+fn display_eq(expected: &str, value: &dyn Display) -> bool {
+    struct Matcher<'a>(&'a str);
+
+    impl fmt::Write for Matcher<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut matcher = Matcher(expected);
+    write!(matcher, "{value}").is_ok() && matcher.0.is_empty()
+}
+
+/// Whether `a` and `b` render the same, without allocating. Both are rendered a window at a
+/// time, which is a single pass for values shorter than a window.
+fn displays_eq(a: &dyn Display, b: &dyn Display) -> bool {
+    const WINDOW: usize = 64;
+
+    /// Keeps the bytes of one window of a rendering and counts all of them.
+    struct Window {
+        skip: usize,
+        bytes: [u8; WINDOW],
+        len: usize,
+    }
+
+    impl Window {
+        fn render(value: &dyn Display, skip: usize) -> Self {
+            let mut window = Self { skip, bytes: [0; WINDOW], len: 0 };
+            write!(window, "{value}").expect("rendering into a window doesn't fail");
+            window
+        }
+
+        fn kept(&self) -> &[u8] {
+            &self.bytes[..self.len.saturating_sub(self.skip).min(WINDOW)]
+        }
+    }
+
+    impl fmt::Write for Window {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let start = self.len.clamp(self.skip, self.skip + WINDOW);
+            let end = (self.len + s.len()).clamp(self.skip, self.skip + WINDOW);
+            if start < end {
+                self.bytes[start - self.skip..end - self.skip].copy_from_slice(&s.as_bytes()[start - self.len..end - self.len]);
+            }
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    let mut skip = 0;
+    loop {
+        let (a, b) = (Window::render(a, skip), Window::render(b, skip));
+        if a.len != b.len || a.kept() != b.kept() {
+            return false
+        }
+        skip += WINDOW;
+        if a.len <= skip {
+            return true
+        }
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct MetricName<'tag, const LABELS: usize = 5> {
     key: &'static str,
     labels: [Option<(&'static str, &'tag dyn LabelValue)>; LABELS],
 }
 
-impl <const LABELS: usize> MetricName<'static, LABELS> {
+/// The constructors below make names with the default number of label slots, so their type
+/// is known wherever they are passed to a store that takes names of any width.
+impl MetricName<'static> {
     pub fn with_no_labels(name: &'static str) -> Self {
         Self {
             key: name,
@@ -25,11 +231,18 @@ impl <const LABELS: usize> MetricName<'static, LABELS> {
     }
 }
 
-impl <'a, const LABELS: usize> MetricName<'a, LABELS> {
+impl MetricName<'static> {
+    /// Name without labels for a metric named at run time, see [`intern_key`].
+    pub fn with_dynamic_name(name: &str) -> Self {
+        Self::with_no_labels(intern_key(name))
+    }
+}
+
+impl <'a> MetricName<'a> {
 
     pub fn with_one_label<R: LabelValue + 'a>(name: &'static str, label_name: &'static str, label_value: &'a R) -> Self {
 
-        let labels: [_; LABELS] = array::from_fn(move |i| {
+        let labels: [_; 5] = array::from_fn(move |i| {
             if i == 0 {
                 Some((label_name, label_value as &dyn LabelValue))
             } else {
@@ -45,260 +258,3367 @@ impl <'a, const LABELS: usize> MetricName<'a, LABELS> {
         }
     }
 
-    /// this should be the majority of the cost for dimensionalities. This operation needs to happen
-    /// once per metric + all combination of dimensionalities.
-    fn clone_into_owned(&self) -> OwnedMetricName<LABELS> {
-        // todo: we computed hashes for labels already, so we could re-use them if it is expensive
-        // to recompute
-        OwnedMetricName {
-            key: self.key,
-            labels: self.labels.map(|v| v.map(|v| (v.0, v.1.as_u64(), v.1.boxed())))
+    /// Name with the given labels, sorted by label name. Fails if there are more than 5, use
+    /// [`MetricName::with_label_array`] for wider names.
+    pub fn with_labels(name: &'static str, labels: &[(&'static str, &'a dyn LabelValue)]) -> Result<Self, TooManyLabels> {
+        if labels.len() > 5 {
+            return Err(TooManyLabels { key: name, labels: labels.len() })
+        }
+
+        Ok(Self::sorted(name, array::from_fn(|i| labels.get(i).copied())))
+    }
+
+    /// Name with `labels`, which are sorted by label name. Empty slots are at the end.
+    fn sorted(name: &'static str, mut labels: [Option<(&'static str, &'a dyn LabelValue)>; 5]) -> Self {
+        let len = labels.iter().flatten().count();
+        labels[..len].sort_unstable_by_key(|label| label.map(|(name, _)| name));
+        Self {
+            key: name,
+            labels,
         }
     }
 }
 
-fn compute_label_hash<H: Hasher>(state: &mut H, label: &Option<(&'static str, &dyn LabelValue)>) {
-    if let Some((label_key, label_val)) = label {
-        state.write(label_key.as_bytes());
-        state.write_u64(label_val.as_u64());
+/// A name was given more labels than the 5 slots of [`MetricName`] hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyLabels {
+    pub key: &'static str,
+    pub labels: usize,
+}
+
+impl Display for TooManyLabels {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} has {} labels, at most 5 fit", self.key, self.labels)
     }
 }
 
-impl Hash for MetricName<'_> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write(self.key.as_bytes());
-        let [l0, l1, l2, l3, l4] = &self.labels;
-        compute_label_hash(state, l0);
-        compute_label_hash(state, l1);
-        compute_label_hash(state, l2);
-        compute_label_hash(state, l3);
-        compute_label_hash(state, l4);
-        // for x in self.labels {
-        //     if let Some((label_key, label_val)) = x {
-        //         state.write(label_key.as_bytes());
-        //         state.write_u64(label_val.as_u64());
-        //     }
-        // }
-    }
-}
-
-struct OwnedMetricName<const LABELS: usize = 5> {
+impl std::error::Error for TooManyLabels {}
+
+impl <'a> MetricName<'a> {
+    /// Name put together one label at a time, `MetricName::builder("requests").label("helper", &h).build()?`.
+    pub fn builder(name: &'static str) -> MetricNameBuilder<'a> {
+        MetricNameBuilder { key: name, labels: [None; 5], len: 0 }
+    }
+}
+
+/// See [`MetricName::builder`].
+#[derive(Clone, Copy)]
+#[must_use = "the name is only made by `build`"]
+pub struct MetricNameBuilder<'a> {
     key: &'static str,
-    labels: [Option<(&'static str, u64, Box<dyn LabelValue>)>; LABELS]
+    labels: [Option<(&'static str, &'a dyn LabelValue)>; 5],
+    len: usize,
 }
 
-impl <const LABELS: usize> Clone for OwnedMetricName<LABELS> {
-    fn clone(&self) -> Self {
+impl<'a> MetricNameBuilder<'a> {
+    /// Adds a label. Labels past 5 are only counted, for [`Self::build`] to fail.
+    pub fn label<R: LabelValue + 'a>(mut self, name: &'static str, value: &'a R) -> Self {
+        if let Some(slot) = self.labels.get_mut(self.len) {
+            *slot = Some((name, value));
+        }
+        self.len += 1;
+        self
+    }
+
+    /// Name with the labels added so far, sorted by label name. Fails if more than 5 were
+    /// added, like [`MetricName::with_labels`].
+    pub fn build(self) -> Result<MetricName<'a>, TooManyLabels> {
+        if self.len > self.labels.len() {
+            return Err(TooManyLabels { key: self.key, labels: self.len })
+        }
+
+        Ok(MetricName::sorted(self.key, self.labels))
+    }
+}
+
+impl <'a, const LABELS: usize> MetricName<'a, LABELS> {
+    /// Like [`MetricName::with_no_labels`], with `LABELS` empty slots rather than 5, so names
+    /// that never get labels can be as small as `MetricName::<0>::no_labels("requests")`.
+    pub fn no_labels(name: &'static str) -> Self {
+        Self {
+            key: name,
+            labels: [None; LABELS],
+        }
+    }
+
+    /// Like [`MetricName::with_one_label`], with `LABELS` slots rather than 5.
+    pub fn one_label<R: LabelValue + 'a>(name: &'static str, label_name: &'static str, label_value: &'a R) -> Self {
+        const { assert!(LABELS > 0, "a name with one label needs a slot for it") };
+        let mut labels = [None; LABELS];
+        labels[0] = Some((label_name, label_value as &dyn LabelValue));
+        Self {
+            key: name,
+            labels,
+        }
+    }
+
+    /// Name with exactly `LABELS` labels, for series with more labels than the default 5 slots.
+    /// Labels are sorted by name.
+    pub fn with_label_array(name: &'static str, mut labels: [(&'static str, &'a dyn LabelValue); LABELS]) -> Self {
+        labels.sort_unstable_by_key(|(name, _)| *name);
         Self {
+            key: name,
+            labels: labels.map(Some),
+        }
+    }
+
+    /// Copy of the name that borrows nothing, for names that live until the process exits.
+    /// Leaks a boxed copy of every label value.
+    pub fn leak(&self) -> MetricName<'static, LABELS> {
+        MetricName {
             key: self.key,
-            labels: self.labels.each_ref().map(|v| v.as_ref().map(|(label, hash, val)| (*label, *hash, val.boxed())))
+            labels: self.labels.map(|label| label.map(|(name, value)| (name, &*Box::leak(value.boxed())))),
         }
     }
-}
 
-impl <const LABELS: usize> OwnedMetricName<LABELS> {
-    pub fn same(&self, other: &Self) -> bool {
-        self.key.eq(other.key) && zip(&self.labels, &other.labels).all(|(a, b)| match (a, b) {
-            (Some(a), Some(b)) => {
-                a.0 == b.0 && a.1 == b.1
-            }
-            (None, None) => true,
-            _ => false,
+    /// Whether both name the same series, comparing label values in full.
+    pub fn same(&self, other: &MetricName<'_, LABELS>) -> bool {
+        self.key == other.key && zip(&self.labels, &other.labels).all(|(a, b)| match (a, b) {
+            (Some((a_label, a)), Some((b_label, b))) => a_label == b_label && a.as_u64() == b.as_u64() && a.label_eq(*b),
+            (a, b) => a.is_none() && b.is_none(),
         })
     }
-}
 
-impl <const LABELS: usize> Debug for OwnedMetricName<LABELS> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // TODO: labels
-        f.debug_struct("OwnedMetricName")
-            .field("key", &self.key)
-            .finish()
+    /// this should be the majority of the cost for dimensionalities. This operation needs to happen
+    /// once per metric + all combination of dimensionalities.
+    fn clone_into_owned(&self, kind: MetricKind) -> OwnedMetricName {
+        // todo: we computed hashes for labels already, so we could re-use them if it is expensive
+        // to recompute
+        OwnedMetricName {
+            key: Name::Static(self.key),
+            kind,
+            labels: self.labels.iter().flatten().map(|&(label, value)| (Name::Static(label), value.as_u64(), intern(label, value))).collect()
+        }
     }
 }
 
-/// This must be consistent with [`MetricName`] hash implementation
-impl <const LABELS: usize> Hash for OwnedMetricName<LABELS> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write(self.key.as_bytes());
-        for x in &self.labels {
-            if let Some((label_key, hash, _)) = x {
-                state.write(label_key.as_bytes());
-                state.write_u64(*hash);
-            }
-        }
-    }
+/// Labels removed from series before they are stored, e.g. request ids that would otherwise
+/// make every increment its own series. Series that only differed by a dropped label are merged.
+#[derive(Debug, Clone, Default)]
+pub struct LabelFilter {
+    drop: Vec<String>,
+    /// If set, every label not listed is dropped too.
+    keep: Option<Vec<String>>,
 }
 
+impl LabelFilter {
+    /// Drops the labels in `drop`, keeping any other.
+    pub fn new<I: IntoIterator<Item = String>>(drop: I) -> Self {
+        Self { drop: drop.into_iter().collect(), keep: None }
+    }
 
-impl PartialEq<MetricName<'_>> for &OwnedMetricName {
-    fn eq(&self, other: &MetricName) -> bool {
-        if !self.key.eq(other.key) {
-            return false
+    /// Keeps only the labels in `keep`, so labels added later are dropped until listed.
+    pub fn allow<I: IntoIterator<Item = String>>(keep: I) -> Self {
+        Self { drop: Vec::new(), keep: Some(keep.into_iter().collect()) }
+    }
+
+    /// Also drops labels in `drop`, even if allowed.
+    pub fn deny<I: IntoIterator<Item = String>>(mut self, drop: I) -> Self {
+        self.drop.extend(drop);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.keep.is_none()
+    }
+
+    fn drops(&self, label: &str) -> bool {
+        self.drop.iter().any(|d| d == label) || self.keep.as_ref().is_some_and(|keep| !keep.iter().any(|k| k == label))
+    }
+
+    /// Copy of `name` without the dropped labels. Remaining labels keep their order.
+    pub fn apply<'a, const LABELS: usize>(&self, name: &MetricName<'a, LABELS>) -> MetricName<'a, LABELS> {
+        let mut kept = name.labels.iter().flatten().filter(|(label, _)| !self.drops(label)).copied();
+        MetricName {
+            key: name.key,
+            labels: array::from_fn(|_| kept.next()),
         }
+    }
 
-        std::iter::zip(&self.labels, &other.labels).all(|(a, b)| {
-            match (a, b) {
-                (None, None) => true,
-                (Some(a), Some(b)) => {
-                    // compare the label name and hash of label value only, to avoid walking through
-                    // the owned string. Again, this is not fool-proof and assumes cooperation from
-                    // label implementors
-                    a.1.eq(&b.1.as_u64()) && a.0.eq(b.0)
-                },
-                _ => false
-            }
-        })
+    fn apply_owned(&self, name: OwnedMetricName) -> OwnedMetricName {
+        OwnedMetricName {
+            key: name.key,
+            kind: name.kind,
+            labels: name.labels.into_iter().filter(|(label, _, _)| !self.drops(label)).collect(),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-#[cfg_attr(not(feature = "ahash"), derive(Default))]
-pub struct MetricStore {
-    #[cfg(not(feature = "ahash"))]
-    buf: hashbrown::HashMap<OwnedMetricName, u64, FxBuildHasher>,
-    #[cfg(feature = "ahash")]
-    buf: hashbrown::HashMap<OwnedMetricName, u64, ahash::RandomState>,
+fn compute_label_hash<H: Hasher>(state: &mut H, label: &Option<(&'static str, &dyn LabelValue)>) {
+    if let Some((label_key, label_val)) = label {
+        state.write(label_key.as_bytes());
+        state.write_u64(label_val.as_u64());
+    }
 }
 
-#[cfg(feature = "ahash")]
-impl Default for MetricStore {
-    fn default() -> Self {
-        let state = ahash::RandomState::generate_with(0, 1, 2, 3);
-        Self {
-            buf: HashMap::with_hasher(state)
+impl<const LABELS: usize> Hash for MetricName<'_, LABELS> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.key.as_bytes());
+        for label in &self.labels {
+            compute_label_hash(state, label);
         }
     }
 }
 
+/// Data of a stored series that usually comes from a literal. Read back from other processes,
+/// it is counted instead, so decoding untrusted input leaks nothing. Hashes and compares as
+/// the data it points to.
+enum Shared<T: ?Sized + 'static> {
+    Static(&'static T),
+    Counted(Arc<T>),
+}
 
-impl MetricStore {
-    pub fn merge(&mut self, other: Self) {
-        for (k, v) in other.buf {
-            let hash = compute_hash(self.buf.hasher(), &k);
-            let raw_entry = self.buf.raw_entry_mut();
-            *raw_entry.from_hash(hash, |q| q.same(&k)).or_insert_with(|| (k, 0)).1 += v;
+impl<T: ?Sized> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Static(data) => Self::Static(data),
+            Self::Counted(data) => Self::Counted(Arc::clone(data)),
         }
     }
+}
 
-    pub fn update(&mut self, key: &MetricName, val: u64) {
-        let hash = compute_hash(self.buf.hasher(), &key);
-        let raw_entry = self.buf.raw_entry_mut();
-        match raw_entry.from_hash(hash, |q| q.eq(key)) {
-            RawEntryMut::Occupied(mut view) => {
-                *view.get_mut() += val;
-            }
-            RawEntryMut::Vacant(view) => {
-                view.insert(key.clone_into_owned(), val);
-            }
+/// Metric key or label name of a stored series.
+type Name = Shared<str>;
+
+impl<T: ?Sized> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Static(data) => data,
+            Self::Counted(data) => data,
         }
     }
+}
 
-    /// The cost of this operation can be higher than update and it is ok
-    pub fn get_counter(&self, key: &MetricName) -> Option<u64> {
-        let hash = compute_hash(self.buf.hasher(), &key);
-        let raw_entry = self.buf.raw_entry();
-        raw_entry.from_hash(hash, |q| q.eq(key)).map(|v| *v.1)
+impl<T: ?Sized> Borrow<T> for Shared<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + PartialEq> PartialEq for Shared<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
     }
+}
 
-    pub fn get_counter_all_dim(&self, key: &'static str) -> Option<u64> {
-        let mut res = None;
-        for (k, v) in &self.buf {
-            if k.key == key {
-                *res.get_or_insert(0) += v;
-            }
-        }
+impl<T: ?Sized + Eq> Eq for Shared<T> {}
 
-        res
-        // let hash = compute_hash(self.buf.hasher(), &key);
-        // let raw_entry = self.buf.raw_entry();
-        // raw_entry.from_hash(hash, |q| q.eq(key)).map(|v| *v.1)
+impl<T: ?Sized + Hash> Hash for Shared<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
     }
 }
 
-fn compute_hash<B: BuildHasher, K: Hash + ?Sized>(hash_builder: &B, key: &K) -> u64 {
-    let mut hasher = hash_builder.build_hasher();
-    key.hash(&mut hasher);
-    hasher.finish()
+impl<T: ?Sized + Debug> Debug for Shared<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
 }
 
+impl Display for Name {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self)
+    }
+}
 
-impl <'a, R: LabelValue> From<(&'static str, (&'static str, &'a R))> for MetricName<'a> {
-    fn from(value: (&'static str, (&'static str, &'a R))) -> Self {
-        Self {
-            key: value.0,
-            labels: [Some((value.1.0, value.1.1)), None, None, None, None],
+type OwnedLabel = (Name, u64, StoredLabel);
+
+/// Labels present on a stored series, in order. Up to 5 are kept inline, series with more
+/// labels spill to the heap.
+type OwnedLabels = SmallVec<[OwnedLabel; 5]>;
+
+/// Metric names built at run time, e.g. one per protocol step. Never freed, so names should
+/// come from a bounded set.
+static KEYS: LazyLock<RwLock<HashSet<&'static str>>> = LazyLock::new(RwLock::default);
+
+/// Name for metrics named at run time, usable wherever a `&'static str` key is. The first call
+/// with a name leaks a copy of it, later calls return that copy. Hashes and compares like a
+/// literal with the same text, so both end up in the same series.
+pub fn intern_key(name: &str) -> &'static str {
+    if let Some(&key) = KEYS.read().unwrap_or_else(PoisonError::into_inner).get(name) {
+        return key
+    }
+    let mut keys = KEYS.write().unwrap_or_else(PoisonError::into_inner);
+    match keys.get(name) {
+        Some(&key) => key,
+        None => {
+            let key: &'static str = Box::leak(name.into());
+            keys.insert(key);
+            key
         }
     }
 }
 
-#[cfg(test)]
-#[global_allocator]
-static ALLOC: dhat::Alloc = dhat::Alloc;
-
+/// Label value read back from its rendering, e.g. by a decoder. Keeps the hash of the value it
+/// was rendered from, so it names the same series as that value.
 #[derive(Clone)]
-#[repr(u8)]
-pub enum HelperIdentity {
-    H1 = 0,
-    H2,
-    H3
+struct Rendered {
+    text: Box<str>,
+    hash: u64,
 }
 
-impl Display for HelperIdentity {
+impl Display for Rendered {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HelperIdentity::H1 => write!(f, "H1"),
-            HelperIdentity::H2 => write!(f, "H2"),
-            HelperIdentity::H3 => write!(f, "H3"),
-        }
+        f.write_str(&self.text)
     }
 }
 
-impl LabelValue for HelperIdentity {
+impl LabelValue for Rendered {
     fn as_u64(&self) -> u64 {
-        match self {
-            HelperIdentity::H1 => 0,
-            HelperIdentity::H2 => 1,
-            HelperIdentity::H3 => 2,
-        }
+        self.hash
     }
 
     fn boxed(&self) -> Box<dyn LabelValue> {
-        Box::new(self.clone()) as Box<dyn LabelValue>
+        Box::new(self.clone())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    
-    use crate::dimensions::{HelperIdentity, MetricName, MetricStore};
+/// Names and label values read back from a snapshot that left the process, shared by the
+/// series that repeat them. Kept per snapshot, so they are freed with its series.
+#[derive(Default)]
+pub struct Restored {
+    names: HashSet<Arc<str>>,
+    values: HashMap<u64, Vec<Arc<dyn LabelValue>>>,
+}
 
+impl Restored {
+    fn name(&mut self, name: &str) -> Name {
+        if let Some(name) = self.names.get(name) {
+            return Shared::Counted(Arc::clone(name))
+        }
+        let shared: Arc<str> = name.into();
+        self.names.insert(Arc::clone(&shared));
+        Shared::Counted(shared)
+    }
 
-    #[test]
-    fn one_dimension() {
-        let mut store = MetricStore::default();
+    /// Label from its name, the hash of its value and the value rendered. Numbers rendered
+    /// from themselves are stored as numbers again, anything else as its rendering.
+    fn label(&mut self, label: &str, hash: u64, value: &str) -> OwnedLabel {
+        let label = self.name(label);
+        if value.parse() == Ok(hash) {
+            return (label, hash, StoredLabel::Number(hash))
+        }
+        let values = self.values.entry(hash).or_default();
+        let shared = match values.iter().find(|shared| display_eq(value, shared)) {
+            Some(shared) => Arc::clone(shared),
+            None => {
+                let shared: Arc<dyn LabelValue> = Arc::new(Rendered { text: value.into(), hash });
+                values.push(Arc::clone(&shared));
+                shared
+            }
+        };
 
+        (label, hash, StoredLabel::Shared(shared))
+    }
+}
 
-        let h1_metric: MetricName = ("foo", ("helper", &HelperIdentity::H1)).into();
-        let h2_metric = ("foo", ("helper", &HelperIdentity::H2)).into();
-        let h3_metric = ("foo", ("helper", &HelperIdentity::H3)).into();
-        store.update(&h1_metric, 0);
-        store.update(&h2_metric, 0);
+/// Name of a stored series. Series of different kinds can share a name, the kind tells them
+/// apart without being hashed, so a [`MetricName`] hashes the same as the series it names.
+#[derive(Clone)]
+struct OwnedMetricName {
+    key: Name,
+    kind: MetricKind,
+    labels: OwnedLabels,
+}
 
-        let _profiler = dhat::Profiler::builder().testing().build();
-        for i in 0..10 {
-            let h1_metric: MetricName = ("foo", ("helper", &HelperIdentity::H1)).into();
-            // this should not cause allocations
-            store.update(&h1_metric, i);
-        }
+/// Values interned per thread before [`intern`] stops taking new ones, so unbounded label
+/// domains like request ids can't grow the table without limit.
+const INTERNED_MAX: usize = 4096;
 
-        store.update(&h2_metric, 3);
+/// Values by label name and u64. Values with the same u64 are told apart by
+/// [`LabelValue::label_eq`].
+type Interned = hashbrown::HashMap<(&'static str, u64), Vec<Arc<dyn LabelValue>>, FxBuildHasher>;
 
-        let stats = dhat::HeapStats::get();
-        assert_eq!(stats.total_bytes, 0, "Some allocations occurred: {:?}", stats);
+thread_local! {
+    static INTERNED: RefCell<Interned> = RefCell::default();
+}
 
-        assert_eq!(store.get_counter(&h1_metric), Some(45));
-        assert_eq!(store.get_counter(&h2_metric), Some(3));
-        assert_eq!(store.get_counter(&h3_metric), None);
+/// Stored copy of `value` of label `label`. Values that can't be kept inline are shared, and
+/// values seen before on this thread are reused, so the handful of values most labels take
+/// are allocated once per thread rather than once per series of every snapshot.
+fn intern(label: &'static str, value: &dyn LabelValue) -> StoredLabel {
+    if let Some(inline) = value.inline() {
+        return inline
+    }
+    StoredLabel::Shared(intern_shared(label, value))
+}
+
+fn intern_shared(label: &'static str, value: &dyn LabelValue) -> Arc<dyn LabelValue> {
+    INTERNED.try_with(|interned| {
+        let mut interned = interned.borrow_mut();
+        let len = interned.len();
+        let values = match interned.get_mut(&(label, value.as_u64())) {
+            Some(values) => values,
+            None if len < INTERNED_MAX => interned.entry((label, value.as_u64())).or_default(),
+            None => return value.shared(),
+        };
+        match values.iter().find(|interned| value.label_eq(&***interned)) {
+            Some(interned) => Arc::clone(interned),
+            None => {
+                let shared = value.shared();
+                values.push(Arc::clone(&shared));
+                shared
+            }
+        }
+    }).unwrap_or_else(|_| value.shared())
+}
+
+impl OwnedMetricName {
+    /// Whether both name the same series. Label values with the same hash are compared in full,
+    /// so colliding values stay separate series.
+    pub fn same(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.key == other.key && self.labels.len() == other.labels.len()
+            && zip(&self.labels, &other.labels).all(|(a, b)| a.0 == b.0 && a.1 == b.1 && a.2.same(&b.2))
+    }
+}
+
+/// Names are equal when they name the same series, so maps can rehash them, e.g. to shrink.
+impl PartialEq for OwnedMetricName {
+    fn eq(&self, other: &Self) -> bool {
+        self.same(other)
+    }
+}
+
+impl Eq for OwnedMetricName {}
+
+impl Debug for OwnedMetricName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        struct Labels<'a>(&'a OwnedLabels);
+
+        impl Debug for Labels<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                f.debug_map().entries(self.0.iter().map(|(label, _, value)| (label, value.value()))).finish()
+            }
+        }
+
+        f.debug_struct("OwnedMetricName")
+            .field("key", &self.key)
+            .field("kind", &self.kind)
+            .field("labels", &Labels(&self.labels))
+            .finish()
+    }
+}
+
+/// Label values debug-print as they render.
+impl Debug for dyn LabelValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Renders as `key{label=value,..}`, or just `key` if there are no labels.
+impl Display for OwnedMetricName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.key)?;
+        if !self.labels.is_empty() {
+            f.write_str("{")?;
+            for (i, (label, _, value)) in self.labels.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(",")?;
+                }
+                write!(f, "{label}={value}")?;
+            }
+            f.write_str("}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// This must be consistent with [`MetricName`] hash implementation
+impl Hash for OwnedMetricName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.key.as_bytes());
+        for (label_key, hash, _) in &self.labels {
+            state.write(label_key.as_bytes());
+            state.write_u64(*hash);
+        }
+    }
+}
+
+
+impl OwnedMetricName {
+    /// Whether `other` names this series, whatever its kind. Empty label slots of `MetricName`
+    /// are skipped, as they are when hashing.
+    fn matches<const LABELS: usize>(&self, other: &MetricName<'_, LABELS>) -> bool {
+        if *self.key != *other.key {
+            return false
+        }
+
+        let mut theirs = other.labels.iter().flatten();
+        // the hash of the label value rules out almost every mismatch, values are only
+        // compared in full once it matches
+        self.labels.iter().all(|a| theirs.next().is_some_and(|b| a.1.eq(&b.1.as_u64()) && *a.0 == *b.0 && b.1.label_eq(&a.2)))
+            && theirs.next().is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "ahash"), derive(Default))]
+pub struct MetricStore {
+    /// Series of every kind, keyed by name and kind.
+    series: SeriesMap<SeriesValue>,
+    /// Side table of the series that have an exemplar, keyed by the name and kind of the series.
+    exemplars: SeriesMap<ExemplarValue>,
+    overflow: OverflowPolicy,
+    cardinality: Option<CardinalityLimit>,
+    counts: KindCounts,
+    /// When series were last merged into the store, if tracked, see [`MetricStore::evict_older_than`].
+    touched: Option<SeriesMap<Instant>>,
+}
+
+/// Kinds of series a [`MetricStore`] keeps apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    Counter,
+    UpDown,
+    Gauge,
+    Histogram,
+    ExpHistogram,
+    Absolute,
+    Flag,
+    Sketch,
+    Info,
+    Unique,
+    Meter,
+    Summary,
+    Exemplar,
+    Hdr,
+}
+
+impl MetricKind {
+    const ALL: [Self; 14] = [
+        Self::Counter, Self::UpDown, Self::Gauge, Self::Histogram, Self::ExpHistogram, Self::Absolute, Self::Flag,
+        Self::Sketch, Self::Info, Self::Unique, Self::Meter, Self::Summary, Self::Exemplar, Self::Hdr,
+    ];
+}
+
+/// Logical work done on one kind of series, so runs of different modes can be checked to
+/// have done the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindStats {
+    pub series: u64,
+    /// Values recorded, including those recorded by stores merged into this one
+    pub updates: u64,
+    /// Series merged in from other stores
+    pub merges: u64,
+}
+
+/// Updates and merges per [`MetricKind`], carried along when stores are merged.
+#[derive(Clone, Copy, Debug, Default)]
+struct KindCounts {
+    updates: [u64; MetricKind::ALL.len()],
+    merges: [u64; MetricKind::ALL.len()],
+}
+
+impl KindCounts {
+    fn updated(&mut self, kind: MetricKind) {
+        self.updates[kind as usize] += 1;
+    }
+
+    fn merged(&mut self, other: &Self, series: [usize; MetricKind::ALL.len()]) {
+        for (i, series) in series.into_iter().enumerate() {
+            self.updates[i] += other.updates[i];
+            self.merges[i] += other.merges[i] + series as u64;
+        }
+    }
+}
+
+#[cfg(not(feature = "ahash"))]
+type StoreHasher = FxBuildHasher;
+#[cfg(feature = "ahash")]
+type StoreHasher = ahash::RandomState;
+
+/// Every store hashes with the same state, so a hash computed once is valid for any store,
+/// see [`MetricStore::hash`].
+fn store_hasher() -> StoreHasher {
+    #[cfg(not(feature = "ahash"))]
+    return FxBuildHasher;
+    #[cfg(feature = "ahash")]
+    return ahash::RandomState::with_seeds(0, 1, 2, 3);
+}
+
+type SeriesMap<V> = hashbrown::HashMap<OwnedMetricName, V, StoreHasher>;
+
+/// Values of the same series coming from different snapshots are combined with this. Counts
+/// saturate, as values read back from other processes can hold any count.
+trait MergeValue: Clone {
+    fn merge(&mut self, other: &Self);
+}
+
+fn merge_value<V: MergeValue>(_: &str, into: &mut V, from: &V) {
+    into.merge(from);
+}
+
+/// Caps the series a metric key can have in a store, so a caller with unbounded label values
+/// can't grow it without limit. Series past the cap are folded into a single overflow series
+/// of the key, labelled `overflow=true`.
+#[derive(Debug, Clone)]
+struct CardinalityLimit {
+    max_series: usize,
+    series: hashbrown::HashMap<Name, usize, FxBuildHasher>,
+}
+
+impl CardinalityLimit {
+    /// Label of the series that new series past the limit are folded into.
+    const OVERFLOW_LABEL: (&'static str, &'static str) = ("overflow", "true");
+
+    /// Counts a new series of `key`, unless `key` has reached the limit.
+    fn admit(&mut self, key: &Name) -> bool {
+        let series = match self.series.get_mut(&**key) {
+            Some(series) => series,
+            None => self.series.entry(key.clone()).or_default(),
+        };
+        *series < self.max_series && {
+            *series += 1;
+            true
+        }
+    }
+
+    /// Gives back the place of an evicted series of `key`.
+    fn release(&mut self, key: &str) {
+        if let Some(series) = self.series.get_mut(key) {
+            *series = series.saturating_sub(1);
+        }
+    }
+
+    fn overflow(key: &Name, kind: MetricKind) -> OwnedMetricName {
+        let (label, value) = Self::OVERFLOW_LABEL;
+        OwnedMetricName { key: key.clone(), kind, labels: [(Name::Static(label), value.as_u64(), intern(label, &value))].into_iter().collect() }
+    }
+}
+
+/// What happens to a counter whose total no longer fits in `u64`. Overflow is checked on every
+/// increment and merge, the policy is only consulted when it happens.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    /// The counter stays at `u64::MAX`.
+    #[default]
+    Saturate,
+    /// The counter wraps around, like a hardware counter. Readers have to detect the reset.
+    Wrap,
+    /// The counter saturates and the callback gets the metric name, the total before the
+    /// overflow and the amount that didn't fit.
+    Report(fn(&str, u64, u64)),
+}
+
+impl OverflowPolicy {
+    /// Folds values of the same series into each other, see [`SeriesValue::merge`].
+    fn combine(self) -> impl Fn(&str, &mut SeriesValue, &SeriesValue) {
+        move |key, into, from| into.merge(key, from, self)
+    }
+
+    fn add(self, key: &str, total: &mut u64, delta: u64) {
+        *total = match total.checked_add(delta) {
+            Some(sum) => sum,
+            None => self.overflow(key, *total, delta),
+        }
+    }
+
+    #[cold]
+    fn overflow(self, key: &str, total: u64, delta: u64) -> u64 {
+        match self {
+            Self::Saturate => u64::MAX,
+            Self::Wrap => total.wrapping_add(delta),
+            Self::Report(callback) => {
+                callback(key, total, delta);
+                u64::MAX
+            }
+        }
+    }
+}
+
+impl MergeValue for i64 {
+    fn merge(&mut self, other: &Self) {
+        *self = self.saturating_add(*other);
+    }
+}
+
+/// How a gauge recording changes the gauge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GaugeUpdate {
+    Set(f64),
+    /// Adjusts the value. Negative deltas decrement it. Deltas recorded on different threads
+    /// add up when their snapshots are merged.
+    Add(f64),
+}
+
+/// Wall clock time of `at`, for instants that leave the process, as they only mean something
+/// within the process that took them.
+fn wall_time(at: Instant) -> SystemTime {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    match now.checked_duration_since(at) {
+        Some(ago) => wall - ago,
+        None => wall + at.duration_since(now),
+    }
+}
+
+/// Nanoseconds since the Unix epoch, saturating past the year 2554.
+fn unix_nanos(at: SystemTime) -> u64 {
+    u64::try_from(at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Instant of wall clock time `at`, see [`wall_time`].
+fn from_wall_time(at: SystemTime) -> Instant {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    match wall.duration_since(at) {
+        // instants can't go back further than the clock they come from, e.g. boot
+        Ok(ago) => now.checked_sub(ago).unwrap_or(now),
+        Err(ahead) => now + ahead.duration(),
+    }
+}
+
+/// Gauge value along with the time it was last written. A gauge that was only ever adjusted
+/// is `relative`: its value is a delta, added to the gauge it is merged into, so a gauge
+/// incremented on one thread and decremented on another reports the sum. A gauge that was set
+/// is merged last-write-wins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GaugeValue {
+    pub value: f64,
+    #[cfg_attr(feature = "serde", serde(with = "serialize::instant"))]
+    pub updated: Instant,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub relative: bool,
+}
+
+impl MergeValue for GaugeValue {
+    fn merge(&mut self, other: &Self) {
+        if other.relative {
+            self.value += other.value;
+            self.updated = self.updated.max(other.updated);
+        } else if other.updated >= self.updated {
+            *self = *other;
+        }
+    }
+}
+
+/// Cumulative total reported by an external source, e.g. a counter read from `/proc`. Snapshots
+/// are merged by taking the max, so a source that resets keeps reporting its old total until
+/// it catches up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AbsoluteValue(pub u64);
+
+impl MergeValue for AbsoluteValue {
+    fn merge(&mut self, other: &Self) {
+        self.0 = self.0.max(other.0);
+    }
+}
+
+/// High-resolution histogram with 3 significant digits over any range of values. Merging is
+/// lossless, unlike [`HistogramValue`], so it is suitable for p999 and beyond.
+#[cfg(feature = "hdr")]
+#[derive(Debug, Clone)]
+pub struct HdrValue(hdrhistogram::Histogram<u64>);
+
+#[cfg(feature = "hdr")]
+impl HdrValue {
+    pub const SIGNIFICANT_DIGITS: u8 = 3;
+
+    fn new() -> Self {
+        Self(hdrhistogram::Histogram::new(Self::SIGNIFICANT_DIGITS).unwrap())
+    }
+
+    pub fn histogram(&self) -> &hdrhistogram::Histogram<u64> {
+        &self.0
+    }
+}
+
+#[cfg(feature = "hdr")]
+impl MergeValue for HdrValue {
+    fn merge(&mut self, other: &Self) {
+        // histograms auto-resize, so adding never runs out of range
+        self.0.add(&other.0).unwrap();
+    }
+}
+
+/// Records that something happened at least once, e.g. a fallback path was taken. Once set on
+/// any thread, the merged value stays set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FlagValue(pub bool);
+
+impl FlagValue {
+    /// Flags are exported as gauges that are either 0 or 1.
+    pub fn as_gauge(&self) -> i64 {
+        i64::from(self.0)
+    }
+}
+
+impl MergeValue for FlagValue {
+    fn merge(&mut self, other: &Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// DDSketch quantile sketch. Quantiles are within 1% relative error of the true value, and the
+/// sketch size only depends on the range of recorded values, not on how many were recorded.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SketchValue(DDSketch);
+
+impl SketchValue {
+    fn new() -> Self {
+        Self(DDSketch::new(Config::defaults()))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.count()
+    }
+
+    /// `None` if nothing was recorded, or `q` is outside of `[0, 1]`.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        self.0.quantile(q).ok().flatten()
+    }
+}
+
+impl Debug for SketchValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SketchValue")
+            .field("count", &self.0.count())
+            .field("min", &self.0.min())
+            .field("max", &self.0.max())
+            .finish()
+    }
+}
+
+impl MergeValue for SketchValue {
+    fn merge(&mut self, other: &Self) {
+        // every sketch is created with the same config, and read back ones are checked to have
+        // it, which is the only reason merge can fail
+        self.0.merge(&other.0).unwrap();
+    }
+}
+
+/// HyperLogLog++ estimate of the number of distinct values recorded, e.g. unique keys seen.
+/// Every thread hashes values the same way, so estimates merge across snapshots. The sketch
+/// folds pending values in when estimating, which takes the lock rather than a copy of it.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UniqueValue(Mutex<HyperLogLogPlus<u64, UniqueHasher>>);
+
+/// Builds the hasher of [`UniqueValue`]s, the same as `BuildHasherDefault<DefaultHasher>` but
+/// serializable along with the sketch.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct UniqueHasher;
+
+impl BuildHasher for UniqueHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        DefaultHasher::new()
+    }
+}
+
+impl UniqueValue {
+    /// 2^14 registers, about 0.8% standard error and 16KB per series once it outgrows the
+    /// sparse representation.
+    pub const PRECISION: u8 = 14;
+
+    fn new() -> Self {
+        Self(Mutex::new(HyperLogLogPlus::new(Self::PRECISION, UniqueHasher).unwrap()))
+    }
+
+    fn sketch(&self) -> MutexGuard<'_, HyperLogLogPlus<u64, UniqueHasher>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn sketch_mut(&mut self) -> &mut HyperLogLogPlus<u64, UniqueHasher> {
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn estimate(&self) -> f64 {
+        self.sketch().count()
+    }
+}
+
+impl Clone for UniqueValue {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.sketch().clone()))
+    }
+}
+
+impl MergeValue for UniqueValue {
+    fn merge(&mut self, other: &Self) {
+        // precision is the same for every sketch, and read back ones are checked to have it,
+        // which is the only reason merge can fail
+        let other = other.sketch();
+        self.sketch_mut().merge(&*other).unwrap();
+    }
+}
+
+/// Events counted over the time windows of the snapshots they were recorded in. Windows are
+/// widened when merging, so the rate of a merged meter is the combined rate of every thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MeterValue {
+    count: u64,
+    #[cfg_attr(feature = "serde", serde(with = "serialize::instant"))]
+    since: Instant,
+    #[cfg_attr(feature = "serde", serde(with = "serialize::instant"))]
+    until: Instant,
+}
+
+impl MeterValue {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Events per second, `None` until the meter has been part of a taken snapshot.
+    pub fn rate(&self) -> Option<f64> {
+        let window = self.until.duration_since(self.since);
+        (!window.is_zero()).then(|| self.count as f64 / window.as_secs_f64())
+    }
+}
+
+impl MergeValue for MeterValue {
+    fn merge(&mut self, other: &Self) {
+        self.count = self.count.saturating_add(other.count);
+        self.since = self.since.min(other.since);
+        self.until = self.until.max(other.until);
+    }
+}
+
+/// Presence of an info series, static metadata carried in labels. Info series always report 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InfoValue;
+
+impl MergeValue for InfoValue {
+    fn merge(&mut self, _other: &Self) {}
+}
+
+/// Count, sum and extremes of recorded samples, e.g. payload sizes, when the distribution
+/// isn't worth a histogram. Count, min and max don't depend on the order snapshots arrive in.
+/// The sum is a float, so its last bits can differ between merge orders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SummaryValue {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl SummaryValue {
+    const EMPTY: Self = Self { count: 0, sum: 0.0, min: f64::INFINITY, max: f64::NEG_INFINITY };
+
+    fn new(value: f64) -> Self {
+        Self { count: 1, sum: value, min: value, max: value }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        self.merge(&Self::new(value));
+    }
+
+    /// `None` if nothing was recorded.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+impl MergeValue for SummaryValue {
+    fn merge(&mut self, other: &Self) {
+        self.count = self.count.saturating_add(other.count);
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// Identifies a trace that contributed to a series, so a spike can be followed to a
+/// representative request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Exemplar {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+/// Exemplar sampled for a series along with the value it was recorded with. The most recent
+/// one is kept, when snapshots are merged as well, so exports show a fresh trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExemplarValue {
+    pub exemplar: Exemplar,
+    pub value: f64,
+    #[cfg_attr(feature = "serde", serde(with = "serialize::instant"))]
+    pub recorded: Instant,
+}
+
+impl MergeValue for ExemplarValue {
+    fn merge(&mut self, other: &Self) {
+        if other.recorded >= self.recorded {
+            *self = *other;
+        }
+    }
+}
+
+/// Value of a series of any kind, as kept by [`MetricStore`] and returned by
+/// [`MetricStore::get`]. Use the typed getters, e.g. [`MetricStore::get_counter`], when the
+/// kind is known upfront. Values that take a lot of space are boxed, so they don't make every
+/// counter as large.
+#[derive(Debug, Clone)]
+pub enum SeriesValue {
+    Counter(u64),
+    UpDown(i64),
+    Gauge(GaugeValue),
+    Histogram(HistogramValue),
+    ExpHistogram(Box<ExpHistogramValue>),
+    Absolute(AbsoluteValue),
+    Flag(FlagValue),
+    Sketch(Box<SketchValue>),
+    Info(InfoValue),
+    Unique(Box<UniqueValue>),
+    Meter(MeterValue),
+    Summary(SummaryValue),
+    #[cfg(feature = "hdr")]
+    Hdr(Box<HdrValue>),
+}
+
+impl SeriesValue {
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            Self::Counter(_) => MetricKind::Counter,
+            Self::UpDown(_) => MetricKind::UpDown,
+            Self::Gauge(_) => MetricKind::Gauge,
+            Self::Histogram(_) => MetricKind::Histogram,
+            Self::ExpHistogram(_) => MetricKind::ExpHistogram,
+            Self::Absolute(_) => MetricKind::Absolute,
+            Self::Flag(_) => MetricKind::Flag,
+            Self::Sketch(_) => MetricKind::Sketch,
+            Self::Info(_) => MetricKind::Info,
+            Self::Unique(_) => MetricKind::Unique,
+            Self::Meter(_) => MetricKind::Meter,
+            Self::Summary(_) => MetricKind::Summary,
+            #[cfg(feature = "hdr")]
+            Self::Hdr(_) => MetricKind::Hdr,
+        }
+    }
+
+    pub fn as_counter(&self) -> Option<u64> {
+        u64::of(self).copied()
+    }
+
+    /// Whether `other`, a value of the same series, merges into this one without losing
+    /// samples, i.e. histograms have the same bounds.
+    fn fits(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Histogram(a), Self::Histogram(b)) => a.bounds == b.bounds,
+            _ => true,
+        }
+    }
+
+    /// Whether recording could have produced this value, for values read back from other
+    /// processes.
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::Histogram(v) => v.counts.len() == v.bounds.len() + 1,
+            Self::ExpHistogram(v) => v.is_valid(),
+            // configs aren't exposed, merging checks them before anything else
+            Self::Sketch(v) => SketchValue::new().0.merge(&v.0).is_ok(),
+            Self::Unique(v) => UniqueValue::new().sketch_mut().merge(&*v.sketch()).is_ok(),
+            _ => true,
+        }
+    }
+
+    /// Folds `other`, a value of the same series, into this one. Counters overflow according
+    /// to `overflow`, other kinds merge as their [`MergeValue`] says.
+    fn merge(&mut self, key: &str, other: &Self, overflow: OverflowPolicy) {
+        match (self, other) {
+            (Self::Counter(total), Self::Counter(delta)) => overflow.add(key, total, *delta),
+            (Self::UpDown(into), Self::UpDown(from)) => into.merge(from),
+            (Self::Gauge(into), Self::Gauge(from)) => into.merge(from),
+            (Self::Histogram(into), Self::Histogram(from)) => into.merge(from),
+            (Self::ExpHistogram(into), Self::ExpHistogram(from)) => into.merge(from),
+            (Self::Absolute(into), Self::Absolute(from)) => into.merge(from),
+            (Self::Flag(into), Self::Flag(from)) => into.merge(from),
+            (Self::Sketch(into), Self::Sketch(from)) => into.merge(from),
+            (Self::Info(into), Self::Info(from)) => into.merge(from),
+            (Self::Unique(into), Self::Unique(from)) => into.merge(from),
+            (Self::Meter(into), Self::Meter(from)) => into.merge(from),
+            (Self::Summary(into), Self::Summary(from)) => into.merge(from),
+            #[cfg(feature = "hdr")]
+            (Self::Hdr(into), Self::Hdr(from)) => into.merge(from),
+            // names carry the kind, so values of one series are always of the same kind
+            (into, from) => unreachable!("{key}: {:?} merged into {:?}", from.kind(), into.kind()),
+        }
+    }
+}
+
+/// Values of one kind of series, as kept in a [`SeriesValue`].
+trait KindValue: Sized {
+    const KIND: MetricKind;
+
+    fn into_series(self) -> SeriesValue;
+
+    fn of(value: &SeriesValue) -> Option<&Self>;
+
+    fn of_mut(value: &mut SeriesValue) -> Option<&mut Self>;
+}
+
+macro_rules! kind_value {
+    ($($ty:ty => $variant:ident $(($wrap:path))?),* $(,)?) => {$(
+        impl KindValue for $ty {
+            const KIND: MetricKind = MetricKind::$variant;
+
+            fn into_series(self) -> SeriesValue {
+                SeriesValue::$variant($($wrap)?(self))
+            }
+
+            fn of(value: &SeriesValue) -> Option<&Self> {
+                match value {
+                    SeriesValue::$variant(v) => {
+                        // boxed values deref to their kind
+                        let v: &Self = v;
+                        Some(v)
+                    }
+                    _ => None,
+                }
+            }
+
+            fn of_mut(value: &mut SeriesValue) -> Option<&mut Self> {
+                match value {
+                    SeriesValue::$variant(v) => {
+                        let v: &mut Self = v;
+                        Some(v)
+                    }
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+
+kind_value!(
+    u64 => Counter, i64 => UpDown, GaugeValue => Gauge, HistogramValue => Histogram,
+    ExpHistogramValue => ExpHistogram(Box::new), AbsoluteValue => Absolute, FlagValue => Flag,
+    SketchValue => Sketch(Box::new), InfoValue => Info, UniqueValue => Unique(Box::new),
+    MeterValue => Meter, SummaryValue => Summary,
+);
+#[cfg(feature = "hdr")]
+kind_value!(HdrValue => Hdr(Box::new));
+
+/// Upper bounds (inclusive) of latency buckets in nanoseconds, from 1µs to ~1s in powers of 2.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    1_024.0, 2_048.0, 4_096.0, 8_192.0, 16_384.0, 32_768.0, 65_536.0, 131_072.0, 262_144.0,
+    524_288.0, 1_048_576.0, 2_097_152.0, 4_194_304.0, 8_388_608.0, 16_777_216.0, 33_554_432.0,
+    67_108_864.0, 134_217_728.0, 268_435_456.0, 536_870_912.0, 1_073_741_824.0,
+];
+
+/// Bounds of histograms that left the process, read back. `None` unless they go up and none is
+/// NaN. The default bounds are the literal again.
+fn restore_bounds(bounds: Vec<f64>) -> Option<Shared<[f64]>> {
+    if bounds.iter().any(|bound| bound.is_nan()) || !bounds.is_sorted_by(|a, b| a < b) {
+        return None
+    }
+    if bounds == DEFAULT_BUCKETS {
+        return Some(Shared::Static(DEFAULT_BUCKETS))
+    }
+
+    Some(Shared::Counted(bounds.into()))
+}
+
+/// Fixed-bucket histogram of floating-point samples. `counts` has one slot per bound plus one
+/// for values above the last bound. Histograms of the same series can only be merged if they
+/// use the same bounds, samples of one with other bounds are dropped and counted as
+/// [`Self::mismatched`]. NaN and infinite values have no bucket, they are only counted as
+/// [`Self::non_finite`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HistogramValue {
+    #[cfg_attr(feature = "serde", serde(with = "serialize::bounds"))]
+    bounds: Shared<[f64]>,
+    counts: Box<[u64]>,
+    sum: f64,
+    count: u64,
+    max: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    mismatched: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    non_finite: u64,
+}
+
+impl HistogramValue {
+    /// Bucket counts come from the [`pool`] if it has some of the right length.
+    pub fn new(bounds: &'static [f64]) -> Self {
+        let len = bounds.len() + 1;
+        Self {
+            bounds: Shared::Static(bounds),
+            counts: pool::take_buckets(len).unwrap_or_else(|| vec![0; len].into_boxed_slice()),
+            sum: 0.0,
+            count: 0,
+            max: f64::NEG_INFINITY,
+            mismatched: 0,
+            non_finite: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        if !value.is_finite() {
+            self.non_finite += 1;
+            return
+        }
+        self.counts[self.bounds.partition_point(|&bound| bound < value)] += 1;
+        self.sum += value;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Samples dropped because they were merged from a histogram with other bounds.
+    pub fn mismatched(&self) -> u64 {
+        self.mismatched
+    }
+
+    /// NaN and infinite values that were recorded, and left out of the buckets and the sum.
+    pub fn non_finite(&self) -> u64 {
+        self.non_finite
+    }
+
+    /// Upper bound of the bucket the `q`-th quantile falls into, or the largest recorded value
+    /// if that is above every bound. `None` if nothing was recorded.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(self.bounds.get(i).map_or(self.max, |&bound| bound.min(self.max)))
+            }
+        }
+
+        unreachable!("bucket counts add up to {}", self.count)
+    }
+}
+
+/// Hands the bucket counts to the [`pool`], for the next new series to take.
+impl Drop for HistogramValue {
+    fn drop(&mut self) {
+        pool::put_buckets(mem::take(&mut self.counts));
+    }
+}
+
+impl MergeValue for HistogramValue {
+    fn merge(&mut self, other: &Self) {
+        self.mismatched = self.mismatched.saturating_add(other.mismatched);
+        self.non_finite = self.non_finite.saturating_add(other.non_finite);
+        if self.bounds != other.bounds {
+            self.mismatched = self.mismatched.saturating_add(other.count);
+            return
+        }
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count = count.saturating_add(*other);
+        }
+        self.sum += other.sum;
+        self.count = self.count.saturating_add(other.count);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// Base-2 exponential histogram, laid out as OpenTelemetry's exponential histogram so it can be
+/// exported without re-bucketing. At scale `s`, bucket `i` holds values in `(b^i, b^(i+1)]`
+/// where `b = 2^(2^-s)`. Recording starts at the finest scale and halves the resolution
+/// whenever the recorded range needs more than [`Self::MAX_BUCKETS`] buckets. Histograms at
+/// different scales merge at the coarser one. NaN and infinite values have no bucket, they are
+/// only counted as [`Self::non_finite`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExpHistogramValue {
+    scale: i8,
+    zero_count: u64,
+    positive: ExpBuckets,
+    negative: ExpBuckets,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    non_finite: u64,
+}
+
+/// Counts of consecutive buckets, starting at bucket index `offset`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExpBuckets {
+    offset: i32,
+    counts: Vec<u64>,
+}
+
+impl ExpBuckets {
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Index range of the buckets once they also hold `lo..=hi`.
+    fn range_with(&self, lo: i32, hi: i32) -> (i32, i32) {
+        match self.counts.len() {
+            0 => (lo, hi),
+            len => (self.offset.min(lo), (self.offset + len as i32 - 1).max(hi)),
+        }
+    }
+
+    fn add(&mut self, index: i32, count: u64) {
+        if self.counts.is_empty() {
+            self.offset = index;
+        }
+        if index < self.offset {
+            let prepend = (self.offset - index) as usize;
+            self.counts.splice(0..0, std::iter::repeat_n(0, prepend));
+            self.offset = index;
+        }
+        let i = (index - self.offset) as usize;
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] = self.counts[i].saturating_add(count);
+    }
+
+    /// Merges every `2^by` adjacent buckets into one.
+    fn downscale(&mut self, by: u32) {
+        if by == 0 || self.counts.is_empty() {
+            return
+        }
+        // `add` moves the offset, buckets are re-indexed from the one they were taken at
+        let offset = self.offset;
+        let original = mem::take(&mut self.counts);
+        for (i, count) in original.into_iter().enumerate() {
+            self.add((offset + i as i32) >> by, count);
+        }
+    }
+}
+
+impl ExpHistogramValue {
+    /// Finest resolution, about 6e-7 relative error.
+    pub const MAX_SCALE: i8 = 20;
+    pub const MIN_SCALE: i8 = -10;
+    /// Buckets per sign, the OpenTelemetry SDK default.
+    pub const MAX_BUCKETS: usize = 160;
+
+    pub fn new() -> Self {
+        Self {
+            scale: Self::MAX_SCALE,
+            zero_count: 0,
+            positive: ExpBuckets::default(),
+            negative: ExpBuckets::default(),
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            non_finite: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        if !value.is_finite() {
+            self.non_finite += 1;
+            return
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if value == 0.0 {
+            self.zero_count += 1;
+            return
+        }
+
+        let index = Self::index(value.abs(), self.scale);
+        let buckets = if value > 0.0 { &self.positive } else { &self.negative };
+        let (lo, hi) = buckets.range_with(index, index);
+        let by = Self::scale_change(self.scale, lo, hi);
+        self.downscale(by);
+        let buckets = if value > 0.0 { &mut self.positive } else { &mut self.negative };
+        buckets.add(index >> by, 1);
+    }
+
+    pub fn scale(&self) -> i8 {
+        self.scale
+    }
+
+    pub fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    pub fn positive(&self) -> &ExpBuckets {
+        &self.positive
+    }
+
+    pub fn negative(&self) -> &ExpBuckets {
+        &self.negative
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// NaN and infinite values recorded, which are not part of [`Self::count`].
+    pub fn non_finite(&self) -> u64 {
+        self.non_finite
+    }
+
+    /// Index of the bucket `value`, which must be positive and finite, falls into at `scale`.
+    /// Finite values span about `±1.1e9` buckets at [`Self::MAX_SCALE`], so indices fit `i32`.
+    fn index(value: f64, scale: i8) -> i32 {
+        let index = if scale > 0 {
+            ((value.log2() * f64::from(1 << scale)).ceil() - 1.0) as i64
+        } else {
+            // exact at scale 0 and below: powers of 2 are the upper bounds of their buckets
+            (value.log2().ceil() as i64 - 1) >> -scale
+        };
+        i32::try_from(index).unwrap_or_else(|_| panic!("{value} has no bucket at scale {scale}"))
+    }
+
+    /// How many times `scale` has to be halved for buckets `lo..=hi` to fit. The range is
+    /// wider than `i32` for buckets at both ends of the finite values.
+    fn scale_change(scale: i8, lo: i32, hi: i32) -> u32 {
+        let (lo, hi) = (i64::from(lo), i64::from(hi));
+        let mut by = 0;
+        while (hi >> by) - (lo >> by) + 1 > Self::MAX_BUCKETS as i64 && scale - (by as i8) > Self::MIN_SCALE {
+            by += 1;
+        }
+        by
+    }
+
+    /// Whether the scale and buckets are ones recording could produce, for histograms read
+    /// back from other processes. Buckets out of range would make merges allocate without
+    /// limit.
+    fn is_valid(&self) -> bool {
+        if !(Self::MIN_SCALE..=Self::MAX_SCALE).contains(&self.scale) {
+            return false
+        }
+        let (lo, hi) = (Self::index(f64::from_bits(1), self.scale), Self::index(f64::MAX, self.scale));
+        [&self.positive, &self.negative].into_iter().all(|buckets| {
+            buckets.counts.len() <= Self::MAX_BUCKETS
+                && (lo..=hi).contains(&buckets.offset)
+                && i64::from(buckets.offset) + buckets.counts.len() as i64 <= i64::from(hi) + 1
+        })
+    }
+
+    fn downscale(&mut self, by: u32) {
+        self.positive.downscale(by);
+        self.negative.downscale(by);
+        self.scale -= by as i8;
+    }
+}
+
+impl Default for ExpHistogramValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MergeValue for ExpHistogramValue {
+    fn merge(&mut self, other: &Self) {
+        let (mut other, scale) = (other.clone(), self.scale.min(other.scale));
+        self.downscale((self.scale - scale) as u32);
+        other.downscale((other.scale - scale) as u32);
+        let change = |into: &ExpBuckets, from: &ExpBuckets| match from.counts.len() {
+            0 => 0,
+            len => {
+                let (lo, hi) = into.range_with(from.offset, from.offset + len as i32 - 1);
+                Self::scale_change(scale, lo, hi)
+            }
+        };
+        let by = change(&self.positive, &other.positive).max(change(&self.negative, &other.negative));
+        self.downscale(by);
+        other.downscale(by);
+
+        for (into, from) in [(&mut self.positive, &other.positive), (&mut self.negative, &other.negative)] {
+            for (i, &count) in from.counts.iter().enumerate() {
+                into.add(from.offset + i as i32, count);
+            }
+        }
+        self.zero_count = self.zero_count.saturating_add(other.zero_count);
+        self.count = self.count.saturating_add(other.count);
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.non_finite = self.non_finite.saturating_add(other.non_finite);
+    }
+}
+
+#[cfg(feature = "ahash")]
+impl Default for MetricStore {
+    fn default() -> Self {
+        Self {
+            series: SeriesMap::with_hasher(store_hasher()),
+            exemplars: SeriesMap::with_hasher(store_hasher()),
+            overflow: OverflowPolicy::default(),
+            cardinality: None,
+            counts: KindCounts::default(),
+            touched: None,
+        }
+    }
+}
+
+impl MetricStore {
+    pub fn merge(&mut self, mut other: Self) {
+        self.merge_drain(&mut other);
+    }
+
+    /// Same as [`merge`], but leaves `other` empty with its tables allocated, so it can be
+    /// recorded into again.
+    ///
+    /// [`merge`]: Self::merge
+    pub fn merge_drain(&mut self, other: &mut Self) {
+        self.counts.merged(&other.counts, other.series_per_kind());
+        other.counts = KindCounts::default();
+        merge(&mut self.series, other.series.drain(), self.cardinality.as_mut(), self.touched.as_mut(), self.overflow.combine());
+        // exemplars follow their series, which are limited and touched already
+        merge(&mut self.exemplars, other.exemplars.drain(), None, None, merge_value);
+    }
+
+    /// Same as [`merge`], but keys are only cloned for series that don't exist in this store yet.
+    ///
+    /// [`merge`]: Self::merge
+    pub fn merge_ref(&mut self, other: &Self) {
+        self.counts.merged(&other.counts, other.series_per_kind());
+        merge_ref(&mut self.series, &other.series, self.cardinality.as_mut(), self.touched.as_mut(), self.overflow.combine());
+        merge_ref(&mut self.exemplars, &other.exemplars, None, None, merge_value);
+    }
+
+    /// Counters that overflow are handled according to [`OverflowPolicy`], saturating by default.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    /// Caps the series every metric key can have, see [`CardinalityLimit`]. The cap applies
+    /// to series merged from other stores, which is how aggregated stores grow. Series
+    /// already in the store don't count towards it.
+    pub fn set_cardinality_limit(&mut self, max_series: Option<usize>) {
+        self.cardinality = max_series.map(|max_series| CardinalityLimit { max_series, series: Default::default() });
+    }
+
+    /// Starts recording when every series was last merged into this store, so stale ones can
+    /// be removed with [`Self::evict_older_than`]. Costs a lookup per merged series. Series folded
+    /// into the overflow series under the cardinality limit are tracked as that series.
+    pub fn track_last_updated(&mut self) {
+        if self.touched.is_none() {
+            #[allow(clippy::clone_on_copy)] // ahash hasher is not `Copy`
+            let hasher = self.series.hasher().clone();
+            self.touched = Some(SeriesMap::with_hasher(hasher));
+        }
+    }
+
+    /// Removes series of every kind that no merge updated for `ttl`, e.g. those of transient
+    /// label values, and returns how many. Only series merged since
+    /// [`Self::track_last_updated`] have a last update, others are kept. Evicted series free
+    /// their place under the cardinality limit. The table keeps its capacity until
+    /// [`Self::compact`].
+    pub fn evict_older_than(&mut self, ttl: Duration) -> usize {
+        let Some(touched) = &mut self.touched else {
+            return 0
+        };
+        let Some(cutoff) = Instant::now().checked_sub(ttl) else {
+            return 0
+        };
+        #[allow(clippy::clone_on_copy)] // ahash hasher is not `Copy`
+        let mut stale = SeriesMap::with_hasher(touched.hasher().clone());
+        for (k, updated) in touched.extract_if(|_, updated| *updated < cutoff) {
+            let hash = compute_hash(stale.hasher(), &k);
+            if let RawEntryMut::Vacant(view) = stale.raw_entry_mut().from_hash(hash, |_| false) {
+                view.insert_hashed_nocheck(hash, k, updated);
+            }
+        }
+        if stale.is_empty() {
+            return 0
+        }
+        let evicted = evict(&mut self.series, &stale, self.cardinality.as_mut());
+        // not counted as series of their own
+        evict(&mut self.exemplars, &stale, None);
+
+        evicted
+    }
+
+    pub fn update<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, val: u64) {
+        self.counts.updated(MetricKind::Counter);
+        self.add_total(key, val);
+    }
+
+    /// Adds `val` to the counter of `key` like [`Self::update`], but as a total of updates
+    /// already counted with [`Self::count_updates`].
+    pub fn add_total<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, val: u64) {
+        let overflow = self.overflow;
+        overflow.add(key.key, self.value_mut(key, || 0), val);
+    }
+
+    /// Hash of `key` in any store, for callers that increment the same series repeatedly.
+    pub fn hash<const LABELS: usize>(key: &MetricName<'_, LABELS>) -> u64 {
+        compute_hash(&store_hasher(), key)
+    }
+
+    /// Same as [`Self::update`], with the hash of `key` computed upfront by [`Self::hash`].
+    pub fn update_hashed<const LABELS: usize>(&mut self, hash: u64, key: &MetricName<'_, LABELS>, val: u64) {
+        self.counts.updated(MetricKind::Counter);
+        debug_assert_eq!(hash, compute_hash(self.series.hasher(), key));
+        let overflow = self.overflow;
+        overflow.add(key.key, self.value_mut_hashed(hash, key, || 0), val);
+    }
+
+    /// Value of the series of kind `V` named `key`, added with the value `new` returns if the
+    /// store doesn't have it yet.
+    fn value_mut<V: KindValue, const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, new: impl FnOnce() -> V) -> &mut V {
+        let hash = compute_hash(self.series.hasher(), key);
+        self.value_mut_hashed(hash, key, new)
+    }
+
+    fn value_mut_hashed<V: KindValue, const LABELS: usize>(&mut self, hash: u64, key: &MetricName<'_, LABELS>, new: impl FnOnce() -> V) -> &mut V {
+        prefetch(&self.series, hash);
+        let value = match self.series.raw_entry_mut().from_hash(hash, |q| q.kind == V::KIND && q.matches(key)) {
+            RawEntryMut::Occupied(view) => view.into_mut(),
+            RawEntryMut::Vacant(view) => view.insert_hashed_nocheck(hash, key.clone_into_owned(V::KIND), new().into_series()).1,
+        };
+        V::of_mut(value).expect("series hold values of their kind")
+    }
+
+    /// Value of the series of kind `V` named `key`.
+    fn value<V: KindValue, const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&V> {
+        let hash = compute_hash(self.series.hasher(), key);
+        self.series.raw_entry().from_hash(hash, |q| q.kind == V::KIND && q.matches(key)).and_then(|(_, v)| V::of(v))
+    }
+
+    /// Adds the signed `delta` to the up-down counter of `key`, saturating at the `i64` bounds.
+    pub fn update_up_down<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, delta: i64) {
+        self.counts.updated(MetricKind::UpDown);
+        self.value_mut(key, || 0_i64).merge(&delta);
+    }
+
+    pub fn get_up_down<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<i64> {
+        self.value(key).copied()
+    }
+
+    pub fn update_gauge<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, update: GaugeUpdate, now: Instant) {
+        self.counts.updated(MetricKind::Gauge);
+        let gauge = self.value_mut(key, || GaugeValue { value: 0.0, updated: now, relative: true });
+        match update {
+            GaugeUpdate::Set(value) => {
+                gauge.value = value;
+                gauge.relative = false;
+            }
+            GaugeUpdate::Add(delta) => gauge.value += delta,
+        }
+        gauge.updated = now;
+    }
+
+    /// Records `value` into the histogram of `key`, creating it with `bounds` if it is new.
+    pub fn update_histogram<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, bounds: &'static [f64], value: f64) {
+        self.counts.updated(MetricKind::Histogram);
+        self.value_mut(key, || HistogramValue::new(bounds)).record(value);
+    }
+
+    pub fn get_histogram<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&HistogramValue> {
+        self.value(key)
+    }
+
+    /// Records the cumulative total of `key`. Totals lower than the one already stored are ignored.
+    pub fn update_absolute<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, total: u64) {
+        self.counts.updated(MetricKind::Absolute);
+        self.value_mut(key, AbsoluteValue::default).merge(&AbsoluteValue(total));
+    }
+
+    pub fn update_flag<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: bool) {
+        self.counts.updated(MetricKind::Flag);
+        self.value_mut(key, FlagValue::default).merge(&FlagValue(value));
+    }
+
+    /// Adds the info series `key{labels..}`, labels sorted by name. Recording the same
+    /// metadata again is a no-op.
+    pub fn update_info(&mut self, key: &'static str, labels: &[(&'static str, &'static str)]) {
+        self.counts.updated(MetricKind::Info);
+        let mut name = OwnedMetricName {
+            key: Name::Static(key),
+            kind: MetricKind::Info,
+            labels: labels.iter().map(|(label, value)| (Name::Static(label), value.as_u64(), intern(label, value))).collect(),
+        };
+        name.labels.sort_unstable_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        merge(&mut self.series, [(name, InfoValue.into_series())], None, None, self.overflow.combine());
+    }
+
+    pub fn update_sketch<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: f64) {
+        self.counts.updated(MetricKind::Sketch);
+        self.value_mut(key, SketchValue::new).0.add(value);
+    }
+
+    pub fn update_unique<T: Hash + ?Sized, const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: &T) {
+        self.counts.updated(MetricKind::Unique);
+        self.value_mut(key, UniqueValue::new).sketch_mut().insert_any(value);
+    }
+
+    pub fn get_unique<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&UniqueValue> {
+        self.value(key)
+    }
+
+    /// Counts `count` events for the meter of `key`. `since` is when the snapshot started, the
+    /// window is closed with [`Self::close_meters`].
+    pub fn update_meter<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, count: u64, since: Instant) {
+        self.counts.updated(MetricKind::Meter);
+        self.value_mut(key, || MeterValue { count: 0, since, until: since }).count += count;
+    }
+
+    /// Ends the window of every meter at `until`.
+    pub fn close_meters(&mut self, until: Instant) {
+        for meter in self.series.values_mut().filter_map(MeterValue::of_mut) {
+            meter.until = until;
+        }
+    }
+
+    pub fn get_meter<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&MeterValue> {
+        self.value(key)
+    }
+
+    /// Replaces the exemplar of the series of `kind` named `key`. Series of any kind can have one.
+    pub fn update_exemplar<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, kind: MetricKind, exemplar: Exemplar, value: f64, recorded: Instant) {
+        self.counts.updated(MetricKind::Exemplar);
+        let exemplar = ExemplarValue { exemplar, value, recorded };
+        let hash = compute_hash(self.exemplars.hasher(), &key);
+        match self.exemplars.raw_entry_mut().from_hash(hash, |q| q.kind == kind && q.matches(key)) {
+            RawEntryMut::Occupied(mut view) => {
+                *view.get_mut() = exemplar;
+            }
+            RawEntryMut::Vacant(view) => {
+                view.insert(key.clone_into_owned(kind), exemplar);
+            }
+        }
+    }
+
+    pub fn get_exemplar<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>, kind: MetricKind) -> Option<&ExemplarValue> {
+        let hash = compute_hash(self.exemplars.hasher(), key);
+        self.exemplars.raw_entry().from_hash(hash, |q| q.kind == kind && q.matches(key)).map(|(_, v)| v)
+    }
+
+    pub fn update_exp_histogram<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: f64) {
+        self.counts.updated(MetricKind::ExpHistogram);
+        self.value_mut(key, ExpHistogramValue::new).record(value);
+    }
+
+    pub fn get_exp_histogram<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&ExpHistogramValue> {
+        self.value(key)
+    }
+
+    pub fn update_summary<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: f64) {
+        self.counts.updated(MetricKind::Summary);
+        self.value_mut(key, || SummaryValue::EMPTY).record(value);
+    }
+
+    pub fn get_summary<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SummaryValue> {
+        self.value(key)
+    }
+
+    pub fn get_sketch<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SketchValue> {
+        self.value(key)
+    }
+
+    pub fn get_flag<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<bool> {
+        self.value::<FlagValue, LABELS>(key).map(|v| v.0)
+    }
+
+    pub fn get_absolute<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<u64> {
+        self.value::<AbsoluteValue, LABELS>(key).map(|v| v.0)
+    }
+
+    #[cfg(feature = "hdr")]
+    pub fn update_hdr<const LABELS: usize>(&mut self, key: &MetricName<'_, LABELS>, value: u64) {
+        self.counts.updated(MetricKind::Hdr);
+        let histogram = self.value_mut(key, HdrValue::new);
+        // auto-resizing only gives up on values beyond the trackable range, clamp those
+        if histogram.0.record(value).is_err() {
+            histogram.0.saturating_record(value);
+        }
+    }
+
+    #[cfg(feature = "hdr")]
+    pub fn get_hdr<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&HdrValue> {
+        self.value(key)
+    }
+
+    pub fn get_gauge<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<f64> {
+        self.value::<GaugeValue, LABELS>(key).map(|v| v.value)
+    }
+
+    /// Looks `key` up whatever its kind. Names are not expected to be shared across kinds, if
+    /// they are, any of the series may be found.
+    pub fn get<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SeriesValue> {
+        find(&self.series, key)
+    }
+
+    /// The cost of this operation can be higher than update and it is ok
+    pub fn get_counter<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<u64> {
+        self.value(key).copied()
+    }
+
+    /// Counter series, with their totals.
+    fn counters(&self) -> impl Iterator<Item = (&OwnedMetricName, u64)> + '_ {
+        self.series.iter().filter_map(|(k, v)| v.as_counter().map(|v| (k, v)))
+    }
+
+    /// Sum of the counters of `key` across all labels, saturating like
+    /// [`Self::get_counter_matching`]. `None` if `key` has no series.
+    pub fn get_counter_all_dim(&self, key: &'static str) -> Option<u64> {
+        let mut res = None;
+        for (k, v) in self.counters() {
+            if *k.key == *key {
+                let total = res.get_or_insert(0_u64);
+                *total = total.saturating_add(v);
+            }
+        }
+
+        res
+    }
+
+    /// Sum of the counters of `key` that have every label in `labels`, whatever their other
+    /// labels, e.g. everything recorded for `helper=H1`. `None` if no series matches.
+    pub fn get_counter_matching(&self, key: &'static str, labels: &[(&'static str, &dyn LabelValue)]) -> Option<u64> {
+        let matches = |name: &OwnedMetricName| labels.iter().all(|(label, value)| {
+            name.labels.iter().any(|(l, hash, v)| **l == **label && *hash == value.as_u64() && value.label_eq(v))
+        });
+        self.counters()
+            .filter(|(name, _)| *name.key == *key && matches(name))
+            .map(|(_, v)| v)
+            .reduce(u64::saturating_add)
+    }
+
+    /// Counters of `key` summed by the value of their `label`, e.g. requests per helper whatever
+    /// the other labels. Series without `label` are left out.
+    pub fn group_by(&self, key: &'static str, label: &'static str) -> HashMap<String, u64> {
+        let mut groups = HashMap::<String, u64>::new();
+        for (name, v) in self.counters().filter(|(name, _)| *name.key == *key) {
+            let Some((_, _, value)) = name.labels.iter().find(|(l, ..)| **l == *label) else {
+                continue
+            };
+            let total = groups.entry(value.to_string()).or_default();
+            *total = total.saturating_add(v);
+        }
+
+        groups
+    }
+
+    /// Walks every series in the store, yielding metric name, labels and value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, impl Iterator<Item = (&str, &dyn LabelValue)> + '_, &SeriesValue)> + '_ {
+        self.series.iter().map(|(k, v)| {
+            (&*k.key, k.labels.iter().map(|(label, _, value)| (&**label, value.value())), v)
+        })
+    }
+
+    /// Series of every kind, as [`Self::iter`] walks them. Exemplars aren't series of their own.
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    /// Series of every kind, in the order of [`MetricKind`].
+    fn series_per_kind(&self) -> [usize; MetricKind::ALL.len()] {
+        let mut series = [0; MetricKind::ALL.len()];
+        for name in self.series.keys() {
+            series[name.kind as usize] += 1;
+        }
+        series[MetricKind::Exemplar as usize] = self.exemplars.len();
+
+        series
+    }
+
+    /// Work done per kind of series, leaving out kinds that saw none.
+    pub fn kind_stats(&self) -> BTreeMap<MetricKind, KindStats> {
+        let series = self.series_per_kind();
+        MetricKind::ALL.into_iter()
+            .map(|kind| {
+                let i = kind as usize;
+                (kind, KindStats { series: series[i] as u64, updates: self.counts.updates[i], merges: self.counts.merges[i] })
+            })
+            .filter(|(_, stats)| *stats != KindStats::default())
+            .collect()
+    }
+
+    /// Counts `n` counter updates that were pre-aggregated before reaching the store, e.g.
+    /// by [`Self::add_total`].
+    pub fn count_updates(&mut self, n: u64) {
+        self.counts.updates[MetricKind::Counter as usize] += n;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty() && self.exemplars.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.series.capacity()
+    }
+
+    /// Approximate size of the hash tables in bytes. Boxed label values and series values are
+    /// not included as they are not affected by resizing.
+    pub fn footprint(&self) -> usize {
+        self.series.capacity() * (size_of::<(OwnedMetricName, SeriesValue)>() + 1)
+            + self.exemplars.capacity() * (size_of::<(OwnedMetricName, ExemplarValue)>() + 1)
+            + self.touched.as_ref().map_or(0, |touched| touched.capacity() * (size_of::<(OwnedMetricName, Instant)>() + 1))
+    }
+
+    /// Removes labels dropped by `filter` from every series, merging series that become equal.
+    pub fn drop_labels(&mut self, filter: &LabelFilter) {
+        drop_labels(&mut self.series, filter, self.overflow.combine());
+        drop_labels(&mut self.exemplars, filter, merge_value);
+    }
+
+    /// Adds `label=value` to every series, replacing the label where it is set already.
+    pub fn add_label(&mut self, label: &'static str, value: &dyn LabelValue) {
+        let added = (Name::Static(label), value.as_u64(), intern(label, value));
+        add_label(&mut self.series, &added, self.overflow.combine());
+        add_label(&mut self.exemplars, &added, merge_value);
+    }
+
+    /// Shrinks every table to the capacity required for the series it currently holds.
+    /// hashbrown never shrinks on its own, so without this a burst of series permanently
+    /// inflates the store.
+    pub fn compact(&mut self) {
+        self.series.shrink_to_fit();
+        self.exemplars.shrink_to_fit();
+        if let Some(touched) = &mut self.touched {
+            touched.shrink_to_fit();
+        }
+    }
+
+    /// Number of distinct label combinations per metric name and kind, along with the `top`
+    /// label values that appear in the most series. Metrics are sorted by series count, highest
+    /// first.
+    pub fn cardinality_report(&self, top: usize) -> Vec<CardinalityReport> {
+        // values with the same hash are told apart the way series are
+        type LabelValues<'a> = hashbrown::HashMap<(&'a str, u64), SmallVec<[(&'a StoredLabel, usize); 1]>>;
+        let mut by_key: hashbrown::HashMap<(&str, MetricKind), (usize, LabelValues)> = Default::default();
+        for (k, v) in &self.series {
+            let (series, label_values) = by_key.entry((&k.key, v.kind())).or_default();
+            *series += 1;
+            for (label_name, hash, value) in &k.labels {
+                let values = label_values.entry((label_name, *hash)).or_default();
+                match values.iter_mut().find(|(seen, _)| seen.same(value)) {
+                    Some((_, series)) => *series += 1,
+                    None => values.push((value, 1)),
+                }
+            }
+        }
+
+        let mut report = by_key.into_iter().map(|((key, kind), (series, label_values))| {
+            let mut top_labels = label_values.into_iter()
+                .flat_map(|((label, _), values)| values.into_iter()
+                    .map(move |(value, series)| LabelCardinality { label: label.to_string(), value: value.to_string(), series }))
+                .collect::<Vec<_>>();
+            top_labels.sort_by(|a, b| b.series.cmp(&a.series).then_with(|| (&a.label, &a.value).cmp(&(&b.label, &b.value))));
+            top_labels.truncate(top);
+
+            CardinalityReport { key: key.to_string(), kind, series, top_labels }
+        }).collect::<Vec<_>>();
+        report.sort_by(|a, b| b.series.cmp(&a.series).then_with(|| (&a.key, a.kind).cmp(&(&b.key, b.kind))));
+
+        report
+    }
+
+    /// Series that differ between this store and `other`, taking `other` as the newer side:
+    /// series only in `other` are added, series only here are removed. Values other than
+    /// counters are compared by their rendering, see [`SeriesValue`]'s `Display`.
+    pub fn diff(&self, other: &MetricStore) -> DiffReport {
+        let changed = |before: &SeriesValue, after: &SeriesValue| match (before, after) {
+            (SeriesValue::Counter(before), SeriesValue::Counter(after)) => before != after,
+            (before, after) => before.kind() != after.kind() || before.to_string() != after.to_string(),
+        };
+        let removed_or_changed = self.series.iter().filter_map(|(k, before)| match find_owned(&other.series, k) {
+            None => Some(SeriesDiff { series: k.to_string(), before: Some(before.clone()), after: None }),
+            Some(after) if changed(before, after) => Some(SeriesDiff { series: k.to_string(), before: Some(before.clone()), after: Some(after.clone()) }),
+            Some(_) => None,
+        });
+        let added = other.series.iter()
+            .filter(|(k, _)| find_owned(&self.series, k).is_none())
+            .map(|(k, after)| SeriesDiff { series: k.to_string(), before: None, after: Some(after.clone()) });
+        let mut series = removed_or_changed.chain(added).collect::<Vec<_>>();
+        series.sort_by(|a, b| a.series.cmp(&b.series));
+
+        DiffReport { series }
+    }
+}
+
+/// Series that differ between two stores, see [`MetricStore::diff`].
+#[derive(Debug, Default)]
+pub struct DiffReport {
+    pub series: Vec<SeriesDiff>,
+}
+
+#[derive(Debug)]
+pub struct SeriesDiff {
+    pub series: String,
+    pub before: Option<SeriesValue>,
+    pub after: Option<SeriesValue>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+}
+
+/// One line per series, `+` added, `-` removed and `~` changed along with the delta.
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no differences")
+        }
+        for (i, diff) in self.series.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match (&diff.before, &diff.after) {
+                (None, Some(after)) => write!(f, "+ {} {after}", diff.series)?,
+                (Some(before), None) => write!(f, "- {} {before}", diff.series)?,
+                (Some(SeriesValue::Counter(before)), Some(SeriesValue::Counter(after))) => {
+                    let delta = i128::from(*after) - i128::from(*before);
+                    write!(f, "~ {} {before} -> {after} ({delta:+})", diff.series)?
+                }
+                (Some(before), Some(after)) => write!(f, "~ {} {before} -> {after}", diff.series)?,
+                (None, None) => unreachable!("a diff has at least one side"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CardinalityReport {
+    pub key: String,
+    pub kind: MetricKind,
+    pub series: usize,
+    pub top_labels: Vec<LabelCardinality>,
+}
+
+#[derive(Debug)]
+pub struct LabelCardinality {
+    pub label: String,
+    pub value: String,
+    pub series: usize,
+}
+
+/// The value alone, as in a line of [`MetricStore`]'s dump.
+impl Display for SeriesValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Counter(v) => write!(f, "{v}"),
+            Self::Info(_) => write!(f, "1"),
+            Self::UpDown(v) => write!(f, "{v} (updown)"),
+            Self::Gauge(v) => write!(f, "{} (gauge)", v.value),
+            Self::Histogram(v) => write!(
+                f, "count={} sum={} p50={} p99={} max={}",
+                v.count(), v.sum(), v.quantile(0.5).unwrap_or_default(), v.quantile(0.99).unwrap_or_default(), v.max(),
+            ),
+            Self::ExpHistogram(v) => write!(
+                f, "count={} sum={} min={} max={} scale={} (exponential)", v.count(), v.sum(), v.min(), v.max(), v.scale(),
+            ),
+            Self::Absolute(v) => write!(f, "{} (absolute)", v.0),
+            Self::Flag(v) => write!(f, "{} (flag)", v.as_gauge()),
+            Self::Sketch(v) => write!(
+                f, "count={} p50={:.0} p99={:.0} (sketch)",
+                v.count(), v.quantile(0.5).unwrap_or_default(), v.quantile(0.99).unwrap_or_default(),
+            ),
+            Self::Unique(v) => write!(f, "{:.0} (unique)", v.estimate()),
+            Self::Meter(v) => write!(f, "count={} rate={:.0}/s (meter)", v.count(), v.rate().unwrap_or_default()),
+            Self::Summary(v) => write!(f, "count={} sum={} min={} max={} (summary)", v.count, v.sum, v.min, v.max),
+            #[cfg(feature = "hdr")]
+            Self::Hdr(v) => write!(
+                f, "count={} p50={} p99={} p999={} max={} (hdr)",
+                v.0.len(), v.0.value_at_quantile(0.5), v.0.value_at_quantile(0.99), v.0.value_at_quantile(0.999), v.0.max(),
+            ),
+        }
+    }
+}
+
+/// One line per series, sorted, for human-readable dumps of the whole store.
+impl Display for MetricStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let series = self.series.iter().map(|(k, v)| format!("{k} {v}"));
+        let exemplars = self.exemplars.iter().map(|(k, v)| format!(
+            "{k} # {{trace_id={:032x},span_id={:016x}}} {}", v.exemplar.trace_id, v.exemplar.span_id, v.value,
+        ));
+        let mut lines = series.chain(exemplars).collect::<Vec<_>>();
+        lines.sort();
+
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Display for CardinalityReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?}): {} series", self.key, self.kind, self.series)?;
+        for label in &self.top_labels {
+            write!(f, "\n  {}={}: {} series", label.label, label.value, label.series)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `combine` folds a value into the existing value of the same series, given the metric name.
+/// Series that end up in `into`, under their own name or the overflow one, are marked as
+/// updated in `touched`.
+fn merge<V>(into: &mut SeriesMap<V>, from: impl IntoIterator<Item = (OwnedMetricName, V)>, mut limit: Option<&mut CardinalityLimit>, mut touched: Option<&mut SeriesMap<Instant>>, combine: impl Fn(&str, &mut V, &V)) {
+    let now = Instant::now();
+    for (k, v) in from {
+        let hash = compute_hash(into.hasher(), &k);
+        match into.raw_entry_mut().from_hash(hash, |q| q.same(&k)) {
+            RawEntryMut::Occupied(mut view) => {
+                combine(&k.key, view.get_mut(), &v);
+                touch(touched.as_deref_mut(), &k, now);
+            }
+            RawEntryMut::Vacant(view) => {
+                if limit.as_deref_mut().is_none_or(|limit| limit.admit(&k.key)) {
+                    touch(touched.as_deref_mut(), &k, now);
+                    view.insert_hashed_nocheck(hash, k, v);
+                } else {
+                    merge_overflow(into, &k, v, touched.as_deref_mut(), now, &combine);
+                }
+            }
+        }
+    }
+}
+
+/// Merges `v`, the value of series `k`, into the overflow series of its key and kind, see
+/// [`CardinalityLimit`].
+fn merge_overflow<V>(into: &mut SeriesMap<V>, k: &OwnedMetricName, v: V, touched: Option<&mut SeriesMap<Instant>>, now: Instant, combine: &dyn Fn(&str, &mut V, &V)) {
+    let name = CardinalityLimit::overflow(&k.key, k.kind);
+    touch(touched, &name, now);
+    let hash = compute_hash(into.hasher(), &name);
+    match into.raw_entry_mut().from_hash(hash, |q| q.same(&name)) {
+        RawEntryMut::Occupied(mut view) => combine(&k.key, view.get_mut(), &v),
+        RawEntryMut::Vacant(view) => {
+            view.insert_hashed_nocheck(hash, name, v);
+        }
+    }
+}
+
+/// Value of the series `key`, whatever its kind.
+/// Starts loading the control bytes and the entry of the bucket `hash` probes first, so they
+/// are on their way to the cache while the probe is set up and the key compared.
+#[inline(always)]
+fn prefetch<V>(map: &SeriesMap<V>, hash: u64) {
+    let table = map.raw_table();
+    // tables that were never allocated have a single bucket and nothing to load
+    if table.buckets() < 2 {
+        return
+    }
+    // the first bucket probed, as hashbrown picks it
+    let index = hash as usize & (table.buckets() - 1);
+    // SAFETY: `index` is below the bucket count of an allocated table. Only the address is
+    // taken, the bucket is neither read nor written here.
+    let entry = unsafe { table.bucket(index) }.as_ptr();
+    // control bytes start where the entries end
+    let ctrl = table.data_end().as_ptr().cast::<u8>().wrapping_add(index);
+    prefetch_read(entry);
+    prefetch_read(ctrl);
+}
+
+#[inline(always)]
+fn prefetch_read<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    // SAFETY: prefetching is a hint, it doesn't fault on any address
+    unsafe {
+        std::arch::x86_64::_mm_prefetch::<{ std::arch::x86_64::_MM_HINT_T0 }>(ptr.cast());
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
+fn find<'a, V, const LABELS: usize>(map: &'a SeriesMap<V>, key: &MetricName<'_, LABELS>) -> Option<&'a V> {
+    let hash = compute_hash(map.hasher(), key);
+    map.raw_entry().from_hash(hash, |q| q.matches(key)).map(|v| v.1)
+}
+
+fn find_owned<'a, V>(map: &'a SeriesMap<V>, key: &OwnedMetricName) -> Option<&'a V> {
+    let hash = compute_hash(map.hasher(), key);
+    map.raw_entry().from_hash(hash, |q| q.same(key)).map(|v| v.1)
+}
+
+fn merge_ref<V: Clone>(into: &mut SeriesMap<V>, from: &SeriesMap<V>, mut limit: Option<&mut CardinalityLimit>, mut touched: Option<&mut SeriesMap<Instant>>, combine: impl Fn(&str, &mut V, &V)) {
+    let now = Instant::now();
+    for (k, v) in from {
+        let hash = compute_hash(into.hasher(), k);
+        match into.raw_entry_mut().from_hash(hash, |q| q.same(k)) {
+            RawEntryMut::Occupied(mut view) => {
+                combine(&k.key, view.get_mut(), v);
+                touch(touched.as_deref_mut(), k, now);
+            }
+            RawEntryMut::Vacant(view) => {
+                if limit.as_deref_mut().is_none_or(|limit| limit.admit(&k.key)) {
+                    view.insert_hashed_nocheck(hash, k.clone(), v.clone());
+                    touch(touched.as_deref_mut(), k, now);
+                } else {
+                    merge_overflow(into, k, v.clone(), touched.as_deref_mut(), now, &combine);
+                }
+            }
+        }
+    }
+}
+
+/// Marks series `k` as updated at `now`, if tracked.
+fn touch(touched: Option<&mut SeriesMap<Instant>>, k: &OwnedMetricName, now: Instant) {
+    let Some(touched) = touched else {
+        return
+    };
+    let hash = compute_hash(touched.hasher(), k);
+    match touched.raw_entry_mut().from_hash(hash, |q| q.same(k)) {
+        RawEntryMut::Occupied(mut view) => *view.get_mut() = now,
+        RawEntryMut::Vacant(view) => {
+            view.insert_hashed_nocheck(hash, k.clone(), now);
+        }
+    }
+}
+
+/// Removes the series in `stale` from `map`, giving their places under `limit` back.
+fn evict<V>(map: &mut SeriesMap<V>, stale: &SeriesMap<Instant>, mut limit: Option<&mut CardinalityLimit>) -> usize {
+    let before = map.len();
+    map.retain(|k, _| {
+        let evicted = find_owned(stale, k).is_some();
+        if let (true, Some(limit)) = (evicted, limit.as_deref_mut()) {
+            limit.release(&k.key);
+        }
+        !evicted
+    });
+
+    before - map.len()
+}
+
+fn drop_labels<V>(map: &mut SeriesMap<V>, filter: &LabelFilter, combine: impl Fn(&str, &mut V, &V)) {
+    #[allow(clippy::clone_on_copy)] // ahash hasher is not `Copy`
+    let stripped = SeriesMap::with_capacity_and_hasher(map.len(), map.hasher().clone());
+    let original = mem::replace(map, stripped);
+    merge(map, original.into_iter().map(|(k, v)| (filter.apply_owned(k), v)), None, None, combine);
+}
+
+fn add_label<V>(map: &mut SeriesMap<V>, added: &OwnedLabel, combine: impl Fn(&str, &mut V, &V)) {
+    #[allow(clippy::clone_on_copy)] // ahash hasher is not `Copy`
+    let labeled = SeriesMap::with_capacity_and_hasher(map.len(), map.hasher().clone());
+    let original = mem::replace(map, labeled);
+    merge(map, original.into_iter().map(|(mut k, v)| {
+        // labels stay sorted by name
+        k.labels.retain(|(label, _, _)| *label != added.0);
+        let at = k.labels.partition_point(|(label, _, _)| **label < *added.0);
+        k.labels.insert(at, added.clone());
+        (k, v)
+    }), None, None, combine);
+}
+
+fn compute_hash<B: BuildHasher, K: Hash + ?Sized>(hash_builder: &B, key: &K) -> u64 {
+    hash_builder.hash_one(key)
+}
+
+
+impl <'a, R: LabelValue> From<(&'static str, (&'static str, &'a R))> for MetricName<'a> {
+    fn from(value: (&'static str, (&'static str, &'a R))) -> Self {
+        Self {
+            key: value.0,
+            labels: [Some((value.1.0, value.1.1)), None, None, None, None],
+        }
+    }
+}
+
+impl <'a, R1: LabelValue, R2: LabelValue> From<(&'static str, (&'static str, &'a R1), (&'static str, &'a R2))> for MetricName<'a> {
+    fn from(value: (&'static str, (&'static str, &'a R1), (&'static str, &'a R2))) -> Self {
+        Self::sorted(value.0, [Some((value.1.0, value.1.1)), Some((value.2.0, value.2.1)), None, None, None])
+    }
+}
+
+impl <'a, R1: LabelValue, R2: LabelValue, R3: LabelValue> From<(&'static str, (&'static str, &'a R1), (&'static str, &'a R2), (&'static str, &'a R3))> for MetricName<'a> {
+    fn from(value: (&'static str, (&'static str, &'a R1), (&'static str, &'a R2), (&'static str, &'a R3))) -> Self {
+        Self::sorted(value.0, [Some((value.1.0, value.1.1)), Some((value.2.0, value.2.1)), Some((value.3.0, value.3.1)), None, None])
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[derive(Clone, LabelValue)]
+#[repr(u8)]
+pub enum HelperIdentity {
+    H1 = 0,
+    H2,
+    H3
+}
+
+/// Numeric label value with an unbounded domain, e.g. request or series ids.
+#[derive(Copy, Clone, LabelValue)]
+pub struct SeriesId(pub u64);
+
+/// Integers are their own u64, so they are only compared past it with other types. All
+/// widths share the tag of `u64`, the type stores keep them as.
+macro_rules! integer_label_value {
+    ($($ty:ty),*) => {$(
+        impl LabelValue for $ty {
+            fn as_u64(&self) -> u64 {
+                u64::from(*self)
+            }
+
+            fn boxed(&self) -> Box<dyn LabelValue> {
+                Box::new(*self)
+            }
+
+            fn shared(&self) -> Arc<dyn LabelValue> {
+                Arc::new(*self)
+            }
+
+            fn inline(&self) -> Option<StoredLabel> {
+                Some(StoredLabel::Number(u64::from(*self)))
+            }
+
+            fn type_tag(&self) -> Option<TypeId> {
+                Some(TypeId::of::<u64>())
+            }
+        }
+    )*};
+}
+
+integer_label_value!(u8, u16, u32, u64);
+
+impl LabelValue for bool {
+    fn as_u64(&self) -> u64 {
+        u64::from(*self)
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(*self)
+    }
+
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::new(*self)
+    }
+
+    fn inline(&self) -> Option<StoredLabel> {
+        Some(StoredLabel::Static(if *self { &true } else { &false }))
+    }
+
+    fn type_tag(&self) -> Option<TypeId> {
+        Some(TypeId::of::<Self>())
+    }
+}
+
+impl LabelValue for Ipv4Addr {
+    fn as_u64(&self) -> u64 {
+        u64::from(u32::from(*self))
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(*self)
+    }
+
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::new(*self)
+    }
+
+    fn type_tag(&self) -> Option<TypeId> {
+        Some(TypeId::of::<Self>())
+    }
+}
+
+/// IPv6 addresses don't fit a u64, so socket addresses are hashed and compared in full.
+impl LabelValue for SocketAddr {
+    fn as_u64(&self) -> u64 {
+        FxBuildHasher.hash_one(self)
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(*self)
+    }
+
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::new(*self)
+    }
+}
+
+/// Free-form text, e.g. versions in info series. Values are hashed, then compared in full.
+impl LabelValue for &'static str {
+    fn as_u64(&self) -> u64 {
+        FxBuildHasher.hash_one(self)
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(*self)
+    }
+
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::new(*self)
+    }
+
+    fn inline(&self) -> Option<StoredLabel> {
+        Some(StoredLabel::Str(self))
+    }
+
+    fn label_eq(&self, other: &dyn LabelValue) -> bool {
+        display_eq(self, other)
+    }
+}
+
+/// A categorical label with a fixed set of values, the configurable counterpart of
+/// [`HelperIdentity`]. Parsed from `name=v1,v2,..` or `name=N`, the latter generating values
+/// `name-0` to `name-{N-1}`. Names and values live for the rest of the process.
+#[derive(Debug, Clone)]
+pub struct LabelDomain {
+    pub name: &'static str,
+    values: Vec<&'static str>,
+}
+
+/// Value of a [`LabelDomain`] label, hashed as its position in the domain. Positions collide
+/// with other domains and integers, so values are compared by name past it.
+#[derive(Debug, Clone, Copy)]
+pub struct CategoryValue {
+    index: u64,
+    name: &'static str,
+}
+
+impl LabelDomain {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn value(&self, index: usize) -> CategoryValue {
+        CategoryValue { index: index as u64, name: self.values[index] }
+    }
+}
+
+impl FromStr for LabelDomain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s.split_once('=')
+            .ok_or_else(|| format!("expected name=values or name=count, got {s}"))?;
+        if name.is_empty() {
+            return Err(format!("label name is missing in {s}"))
+        }
+        let values: Vec<String> = match values.parse::<usize>() {
+            Ok(count) => (0..count).map(|i| format!("{name}-{i}")).collect(),
+            Err(_) => values.split(',').map(str::to_string).collect(),
+        };
+        if values.is_empty() || values.iter().any(String::is_empty) {
+            return Err(format!("label {name} must have at least one non-empty value"))
+        }
+
+        Ok(Self {
+            name: Box::leak(name.to_string().into_boxed_str()),
+            values: values.into_iter().map(|v| &*Box::leak(v.into_boxed_str())).collect(),
+        })
+    }
+}
+
+impl Display for CategoryValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl LabelValue for CategoryValue {
+    fn as_u64(&self) -> u64 {
+        self.index
+    }
+
+    fn boxed(&self) -> Box<dyn LabelValue> {
+        Box::new(*self)
+    }
+
+    fn shared(&self) -> Arc<dyn LabelValue> {
+        Arc::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    
+    use std::collections::HashMap;
+    use std::fmt::{Display, Formatter};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+    use crate::dimensions::{CardinalityLimit, DEFAULT_BUCKETS, ExpHistogramValue, find, find_owned, GaugeUpdate, HelperIdentity, HistogramValue, intern_key, KindStats, LabelDomain, LabelFilter, LabelValue, MergeValue, MetricKind, MetricName, MetricStore, OverflowPolicy, SeriesId, Shared, StoredLabel, SummaryValue, TooManyLabels};
+    use crate::test_utils::{exclusive_heap, shared_heap};
+
+
+    #[test]
+    fn one_dimension() {
+        let _heap = exclusive_heap();
+        let mut store = MetricStore::default();
+
+
+        let h1_metric: MetricName = ("foo", ("helper", &HelperIdentity::H1)).into();
+        let h2_metric = ("foo", ("helper", &HelperIdentity::H2)).into();
+        let h3_metric = ("foo", ("helper", &HelperIdentity::H3)).into();
+        store.update(&h1_metric, 0);
+        store.update(&h2_metric, 0);
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        for i in 0..10 {
+            let h1_metric: MetricName = ("foo", ("helper", &HelperIdentity::H1)).into();
+            // this should not cause allocations
+            store.update(&h1_metric, i);
+        }
+
+        store.update(&h2_metric, 3);
+
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_bytes, 0, "Some allocations occurred: {:?}", stats);
+
+        assert_eq!(store.get_counter(&h1_metric), Some(45));
+        assert_eq!(store.get_counter(&h2_metric), Some(3));
+        assert_eq!(store.get_counter(&h3_metric), None);
+    }
+
+    #[test]
+    fn first_touch_without_allocations() {
+        let _heap = exclusive_heap();
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_no_labels("warmup"), 1);
+        let name = MetricName::with_labels("foo", &[("helper", &HelperIdentity::H3), ("step", &SeriesId(7)), ("gate", &"mul"), ("ok", &true)]).unwrap();
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        store.update(&name, 1);
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_bytes, 0, "Some allocations occurred: {:?}", stats);
+
+        assert!(store.to_string().contains("foo{gate=mul,helper=H3,ok=true,step=7} 1"));
+    }
+
+    #[test]
+    fn new_histogram_series_reuse_merged_buckets() {
+        let _heap = exclusive_heap();
+        const BOUNDS: &[f64] = &[1.0, 2.0, 4.0, 8.0];
+        let name = |helper| MetricName::with_one_label("latency", "helper", helper);
+        let mut total = MetricStore::default();
+        total.update_histogram(&name(&HelperIdentity::H1), BOUNDS, 1.0);
+        let mut snapshot = MetricStore::default();
+        snapshot.update_histogram(&name(&HelperIdentity::H1), BOUNDS, 3.0);
+        snapshot.update(&MetricName::with_no_labels("warmup"), 1);
+        // merged into an existing series, the snapshot's buckets go back to the pool
+        total.merge_drain(&mut snapshot);
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        snapshot.update_histogram(&name(&HelperIdentity::H2), BOUNDS, 5.0);
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_bytes, 0, "Some allocations occurred: {:?}", stats);
+
+        let histogram = snapshot.get_histogram(&name(&HelperIdentity::H2)).unwrap();
+        assert_eq!((1, Some(5.0)), (histogram.count(), histogram.quantile(1.0)));
+    }
+
+    #[test]
+    fn unique_estimate_without_copies() {
+        let _heap = exclusive_heap();
+        let name = MetricName::with_no_labels("unique_keys");
+        let mut store = MetricStore::default();
+        // enough values for the sketch to outgrow the sparse representation
+        for k in 0..20_000_u64 {
+            store.update_unique(&name, &k);
+        }
+        let unique = store.get_unique(&name).unwrap();
+        unique.estimate();
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        let estimate = unique.estimate();
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_bytes, 0, "Some allocations occurred: {:?}", stats);
+        assert!((estimate - 20_000.0).abs() <= 20_000.0 * 0.02, "{estimate}");
+    }
+
+    #[test]
+    fn clones_share_label_values() {
+        let _heap = exclusive_heap();
+        let mut store = MetricStore::default();
+        for port in 0..100 {
+            let peer = SocketAddr::from(([10, 0, 0, 1], port));
+            store.update(&MetricName::from(("sent", ("peer", &peer), ("helper", &HelperIdentity::H1), ("step", &SeriesId(1)))), 1);
+        }
+
+        let _profiler = dhat::Profiler::builder().testing().build();
+        let copy = store.clone();
+        let stats = dhat::HeapStats::get();
+        // the table, but nothing per series or label
+        assert_eq!(stats.total_blocks, 1, "{:?}", stats);
+        assert_eq!(100, copy.len());
+    }
+
+    #[test]
+    fn cardinality_report() {
+        let _heap = shared_heap();
+        let mut store = MetricStore::default();
+        store.update(&("foo", ("helper", &HelperIdentity::H1)).into(), 1);
+        store.update(&("foo", ("helper", &HelperIdentity::H2)).into(), 1);
+        store.update(&("foo", ("helper", &HelperIdentity::H2)).into(), 1);
+        store.update(&MetricName::with_no_labels("foo"), 1);
+        store.update(&MetricName::with_no_labels("bar"), 1);
+        for shard in 0..4_u32 {
+            store.update_histogram(&("latency", ("shard", &shard)).into(), DEFAULT_BUCKETS, 1.0);
+        }
+
+        let report = store.cardinality_report(1);
+        assert_eq!(report.len(), 3);
+        assert_eq!((report[0].key.as_str(), report[0].kind, report[0].series), ("latency", MetricKind::Histogram, 4));
+        assert_eq!((report[1].key.as_str(), report[1].kind, report[1].series), ("foo", MetricKind::Counter, 3));
+        assert_eq!(report[1].top_labels.len(), 1);
+        assert_eq!((report[1].top_labels[0].label.as_str(), report[1].top_labels[0].value.as_str()), ("helper", "H1"));
+        assert_eq!((report[2].key.as_str(), report[2].series, report[2].top_labels.len()), ("bar", 1, 0));
+        assert_eq!("latency (Histogram): 4 series\n  shard=0: 1 series", report[0].to_string());
+
+        // values that hash the same but are different series count apart
+        store.update(&("flags", ("x", &true)).into(), 1);
+        store.update(&("flags", ("x", &1_u64)).into(), 1);
+        let report = store.cardinality_report(5);
+        let flags = report.iter().find(|report| report.key == "flags").unwrap();
+        let values = flags.top_labels.iter().map(|label| (label.value.as_str(), label.series)).collect::<Vec<_>>();
+        assert_eq!((2, vec![("1", 1), ("true", 1)]), (flags.series, values));
+    }
+
+    #[test]
+    fn label_domain() {
+        let domain = "dest=H1,H2,H3".parse::<LabelDomain>().unwrap();
+        assert_eq!(("dest", 3), (domain.name, domain.len()));
+        assert_eq!("H2", domain.value(1).to_string());
+
+        let domain = "shard=30".parse::<LabelDomain>().unwrap();
+        assert_eq!(30, domain.len());
+        assert_eq!("shard-29", domain.value(29).to_string());
+
+        assert!("dest".parse::<LabelDomain>().is_err());
+        assert!("dest=".parse::<LabelDomain>().is_err());
+        assert!("=3".parse::<LabelDomain>().is_err());
+    }
+
+    #[test]
+    fn category_values_are_compared_by_name() {
+        let _heap = shared_heap();
+        let (dest, shard) = ("dest=H1,H2".parse::<LabelDomain>().unwrap(), "shard=2".parse::<LabelDomain>().unwrap());
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_one_label("m", "x", &dest.value(1)), 1);
+        store.update(&MetricName::with_one_label("m", "x", &shard.value(1)), 10);
+        store.update(&MetricName::with_one_label("m", "x", &1_u64), 100);
+        store.update(&MetricName::with_one_label("m", "x", &dest.value(1)), 1000);
+
+        assert_eq!(3, store.len());
+        assert_eq!(Some(1001), store.get_counter(&MetricName::with_one_label("m", "x", &dest.value(1))));
+        assert_eq!(Some(10), store.get_counter(&MetricName::with_one_label("m", "x", &shard.value(1))));
+    }
+
+    #[test]
+    fn drop_labels() {
+        let _heap = shared_heap();
+        let filter = LabelFilter::new(["request_id".to_string()]);
+        let ids = [SeriesId(0), SeriesId(1), SeriesId(2)];
+        let name = |id: usize| MetricName {
+            key: "foo",
+            labels: [Some(("request_id", &ids[id] as _)), Some(("helper", &HelperIdentity::H1 as _)), None, None, None],
+        };
+        let stripped: MetricName = ("foo", ("helper", &HelperIdentity::H1)).into();
+
+        let mut producer = MetricStore::default();
+        let mut aggregator = MetricStore::default();
+        for id in 0..3 {
+            producer.update(&filter.apply(&name(id)), 1);
+            aggregator.update(&name(id), 1);
+        }
+        aggregator.drop_labels(&filter);
+
+        assert_eq!((1, Some(3)), (producer.len(), producer.get_counter(&stripped)));
+        assert_eq!((1, Some(3)), (aggregator.len(), aggregator.get_counter(&stripped)));
+    }
+
+    #[test]
+    fn more_than_five_labels() {
+        let _heap = shared_heap();
+        let ids = [0, 1, 2, 3, 4, 5, 6].map(SeriesId);
+        let name = |last: usize| {
+            let mut labels = ["l0", "l1", "l2", "l3", "l4", "l5", "l6"].map(|label| (label, &ids[0] as &dyn LabelValue));
+            labels[6].1 = &ids[last];
+            MetricName::with_label_array("wide", labels)
+        };
+        let mut store = MetricStore::default();
+        store.update(&name(6), 1);
+        store.update(&name(6), 2);
+        // differs only in the 7th label
+        store.update(&name(5), 4);
+
+        assert_eq!((2, Some(3), Some(4)), (store.len(), store.get_counter(&name(6)), store.get_counter(&name(5))));
+        // labels past the fifth are neither dropped nor ignored in names with fewer slots
+        assert_eq!(None, store.get_counter(&MetricName::with_no_labels("wide")));
+        assert!(store.to_string().contains("wide{l0=0,l1=0,l2=0,l3=0,l4=0,l5=0,l6=6} 3"));
+        store.update_info("wide_info", &[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5"), ("f", "6")]);
+        assert!(store.to_string().contains("wide_info{a=1,b=2,c=3,d=4,e=5,f=6} 1"));
+    }
+
+    #[test]
+    fn built_names() {
+        let _heap = shared_heap();
+        let (h1, step) = (HelperIdentity::H1, SeriesId(3));
+        let mut store = MetricStore::default();
+        store.update(&MetricName::builder("bytes").label("step", &step).label("helper", &h1).build().unwrap(), 1);
+        store.update(&MetricName::builder("bytes").build().unwrap(), 2);
+
+        assert_eq!(Some(1), store.get_counter(&MetricName::from(("bytes", ("helper", &h1), ("step", &step)))));
+        assert_eq!(Some(2), store.get_counter(&MetricName::with_no_labels("bytes")));
+        let wide = ["a", "b", "c", "d", "e", "f"].into_iter().fold(MetricName::builder("wide"), |builder, label| builder.label(label, &step));
+        assert_eq!(Err(TooManyLabels { key: "wide", labels: 6 }), wide.build().map(|_| ()));
+    }
+
+    #[test]
+    fn multi_label_constructors() {
+        let _heap = shared_heap();
+        let (h1, step) = (HelperIdentity::H1, SeriesId(3));
+        let mut store = MetricStore::default();
+        store.update(&MetricName::from(("bytes", ("helper", &h1), ("step", &step))), 1);
+        store.update(&MetricName::with_labels("bytes", &[("helper", &h1), ("step", &step)]).unwrap(), 2);
+        store.update(&MetricName::from(("bytes", ("helper", &h1), ("step", &step), ("gate", &SeriesId(0)))), 4);
+
+        assert_eq!(2, store.len());
+        assert_eq!(Some(3), store.get_counter(&MetricName::with_labels("bytes", &[("helper", &h1), ("step", &step)]).unwrap()));
+        let wide = [("a", &step as &dyn LabelValue), ("b", &step), ("c", &step), ("d", &step), ("e", &step), ("f", &step)];
+        let error = MetricName::with_labels("wide", &wide).map(|_| ()).unwrap_err();
+        assert_eq!("wide has 6 labels, at most 5 fit", error.to_string());
+    }
+
+    /// Hashes every value to the same u64.
+    struct Colliding(&'static str);
+
+    impl Display for Colliding {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl LabelValue for Colliding {
+        fn as_u64(&self) -> u64 {
+            0
+        }
+
+        fn boxed(&self) -> Box<dyn LabelValue> {
+            Box::new(Colliding(self.0))
+        }
+    }
+
+    #[test]
+    fn colliding_label_values() {
+        let _heap = shared_heap();
+        let (a, b) = (Colliding("a"), Colliding("ab"));
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_one_label("collide", "l", &a), 1);
+        store.update(&MetricName::with_one_label("collide", "l", &b), 2);
+        store.update(&MetricName::with_one_label("collide", "l", &a), 4);
+
+        assert_eq!(2, store.len());
+        assert_eq!(Some(5), store.get_counter(&MetricName::with_one_label("collide", "l", &a)));
+        assert_eq!(Some(2), store.get_counter(&MetricName::with_one_label("collide", "l", &b)));
+    }
+
+    #[test]
+    fn label_eq_compares_rendered_values() {
+        let long = "a".repeat(100);
+        let (same, longer) = (Colliding(long.clone().leak()), Colliding(format!("{long}b").leak()));
+        assert!(Colliding(long.clone().leak()).label_eq(&same));
+        assert!(!Colliding(long.clone().leak()).label_eq(&longer));
+        assert!(!longer.label_eq(&Colliding(format!("{long}c").leak())));
+        // rendered in pieces that don't line up with the other value's
+        let addr: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        assert!(addr.label_eq(&Colliding("[2001:db8::1]:8080")));
+        assert!(!addr.label_eq(&Colliding("[2001:db8::1]:808")));
+    }
+
+    #[test]
+    fn merge_keeps_colliding_series_apart() {
+        let _heap = shared_heap();
+        let (a, b) = (Colliding("a"), Colliding("b"));
+        fn name(value: &dyn LabelValue) -> MetricName<'_, 2> {
+            MetricName::with_label_array("collide", [("l", value), ("step", &SeriesId(1))])
+        }
+        let snapshot = |value: &dyn LabelValue, val| {
+            let mut snapshot = MetricStore::default();
+            snapshot.update(&name(value), val);
+            snapshot.update_gauge(&name(value), GaugeUpdate::Set(val as f64), Instant::now());
+            snapshot
+        };
+        let mut total = MetricStore::default();
+        total.merge(snapshot(&a, 1));
+        total.merge(snapshot(&b, 2));
+        total.merge_ref(&snapshot(&a, 4));
+        total.merge_ref(&snapshot(&b, 8));
+
+        // a counter and a gauge per label value
+        assert_eq!(4, total.len());
+        assert_eq!((Some(5), Some(10)), (total.get_counter(&name(&a)), total.get_counter(&name(&b))));
+        assert_eq!((Some(4.0), Some(8.0)), (total.get_gauge(&name(&a)), total.get_gauge(&name(&b))));
+        // merging series whose names lost a label still compares the remaining values
+        total.drop_labels(&LabelFilter::new(["step".to_string()]));
+        total.compact();
+        let short = |value| MetricName::with_one_label("collide", "l", value);
+        assert_eq!((Some(5), Some(10)), (total.get_counter(&short(&a)), total.get_counter(&short(&b))));
+    }
+
+    #[test]
+    fn kind_stats_follow_merges() {
+        let _heap = shared_heap();
+        let (h1, h2) = (HelperIdentity::H1, HelperIdentity::H2);
+        let mut producer = MetricStore::default();
+        producer.update(&MetricName::with_one_label("requests", "helper", &h1), 1);
+        producer.update(&MetricName::with_one_label("requests", "helper", &h2), 1);
+        producer.update(&MetricName::with_one_label("requests", "helper", &h1), 1);
+        producer.update_flag(&MetricName::with_no_labels("ready"), true);
+        let mut folded = MetricStore::default();
+        folded.count_updates(3);
+        folded.add_total(&MetricName::with_one_label("requests", "helper", &h1), 3);
+
+        let mut total = MetricStore::default();
+        total.merge_ref(&producer);
+        total.merge(producer);
+        total.merge(folded);
+        let stats = total.kind_stats();
+        assert_eq!(vec![MetricKind::Counter, MetricKind::Flag], stats.keys().copied().collect::<Vec<_>>());
+        assert_eq!(KindStats { series: 2, updates: 9, merges: 5 }, stats[&MetricKind::Counter]);
+        assert_eq!(KindStats { series: 1, updates: 2, merges: 2 }, stats[&MetricKind::Flag]);
+    }
+
+    #[test]
+    fn derived_label_values() {
+        #[derive(LabelValue)]
+        enum Step {
+            Shuffle,
+            Reveal,
+        }
+
+        #[derive(LabelValue)]
+        struct Dest(HelperIdentity);
+
+        assert_eq!((0, 1), (Step::Shuffle.as_u64(), Step::Reveal.as_u64()));
+        assert_eq!("Reveal", Step::Reveal.boxed().to_string());
+        assert_eq!((2, "H3".to_string()), (Dest(HelperIdentity::H3).as_u64(), Dest(HelperIdentity::H3).to_string()));
+        assert_eq!(HelperIdentity::H2.as_u64(), Dest(HelperIdentity::H2).boxed().as_u64());
+    }
+
+    #[test]
+    fn derived_enums_of_other_types_are_other_series() {
+        let _heap = shared_heap();
+        #[derive(LabelValue)]
+        enum Step {
+            Shuffle,
+            Reveal,
+        }
+
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_one_label("m", "x", &Step::Reveal), 1);
+        store.update(&MetricName::with_one_label("m", "x", &HelperIdentity::H2), 10);
+        store.update(&MetricName::with_one_label("m", "x", &1_u64), 100);
+        store.update(&MetricName::with_one_label("m", "x", &Step::Reveal), 1000);
+        store.update(&MetricName::with_one_label("m", "x", &Step::Shuffle), 10000);
+
+        assert_eq!(4, store.len());
+        assert_eq!(Some(1001), store.get_counter(&MetricName::with_one_label("m", "x", &Step::Reveal)));
+        assert_eq!(Some(10), store.get_counter(&MetricName::with_one_label("m", "x", &HelperIdentity::H2)));
+    }
+
+    #[test]
+    fn std_label_values() {
+        let _heap = shared_heap();
+        let (peer, other): (SocketAddr, SocketAddr) = ("10.0.0.1:443".parse().unwrap(), "[::1]:443".parse().unwrap());
+        let mut store = MetricStore::default();
+        store.update(&MetricName::from(("bytes", ("shard", &3_u8), ("ok", &true))), 1);
+        store.update(&MetricName::from(("bytes", ("shard", &3_u8), ("ok", &false))), 2);
+        store.update(&MetricName::from(("bytes", ("peer", &peer), ("ip", &Ipv4Addr::LOCALHOST))), 4);
+        store.update(&MetricName::from(("bytes", ("peer", &other), ("ip", &Ipv4Addr::LOCALHOST))), 8);
+
+        assert_eq!(4, store.len());
+        assert_eq!(Some(1), store.get_counter(&MetricName::from(("bytes", ("shard", &3_u8), ("ok", &true)))));
+        assert!(store.to_string().contains("bytes{ip=127.0.0.1,peer=10.0.0.1:443} 4"));
+        assert_eq!(u64::from(u32::MAX), 4_294_967_295_u32.as_u64());
+    }
+
+    #[test]
+    fn label_values_of_other_types_are_other_series() {
+        let _heap = shared_heap();
+        let addr = Ipv4Addr::new(0, 0, 0, 7);
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_one_label("m", "x", &addr), 1);
+        store.update(&MetricName::with_one_label("m", "x", &7_u32), 10);
+        // integers of any width are the same value
+        store.update(&MetricName::with_one_label("m", "x", &7_u8), 100);
+        store.update(&MetricName::with_one_label("m", "x", &1_u64), 1000);
+        store.update(&MetricName::with_one_label("m", "x", &true), 10000);
+        let mut total = MetricStore::default();
+        total.merge_ref(&store);
+        total.merge(store);
+
+        assert_eq!(4, total.len());
+        assert_eq!(Some(2), total.get_counter(&MetricName::with_one_label("m", "x", &addr)));
+        assert_eq!(Some(220), total.get_counter(&MetricName::with_one_label("m", "x", &7_u64)));
+        assert_eq!(Some(2000), total.get_counter(&MetricName::with_one_label("m", "x", &1_u8)));
+        assert_eq!(Some(20000), total.get_counter(&MetricName::with_one_label("m", "x", &true)));
+    }
+
+    #[test]
+    fn interned_label_values() {
+        let _heap = shared_heap();
+        let addr = Ipv4Addr::new(10, 0, 0, 1);
+        let name = MetricName::with_one_label("requests", "helper", &addr);
+        let (mut a, mut b) = (MetricStore::default(), MetricStore::default());
+        a.update(&name, 1);
+        b.update(&name, 1);
+        b.update(&MetricName::with_one_label("requests", "other", &addr), 1);
+
+        let value = |store: &MetricStore, label: &str| store.series.keys()
+            .find(|k| *k.labels[0].0 == *label)
+            .and_then(|k| match &k.labels[0].2 {
+                StoredLabel::Shared(value) => Some(Arc::clone(value)),
+                _ => None,
+            })
+            .unwrap();
+        assert!(Arc::ptr_eq(&value(&a, "helper"), &value(&b, "helper")));
+        assert!(!Arc::ptr_eq(&value(&b, "helper"), &value(&b, "other")));
+    }
+
+    #[test]
+    fn cardinality_limit_folds_into_overflow() {
+        let _heap = shared_heap();
+        let ids = (0..5).map(SeriesId).collect::<Vec<_>>();
+        let mut total = MetricStore::default();
+        total.set_cardinality_limit(Some(3));
+        for round in 0..2 {
+            let mut snapshot = MetricStore::default();
+            for id in &ids {
+                snapshot.update(&MetricName::with_one_label("requests", "request_id", id), 1);
+                snapshot.update(&MetricName::with_one_label("other", "request_id", id), 1);
+            }
+            if round == 0 {
+                total.merge(snapshot);
+            } else {
+                total.merge_ref(&snapshot);
+            }
+        }
+
+        // 3 series per key plus their overflow series
+        assert_eq!(8, total.len());
+        // which series make it depends on the order of the snapshot table
+        let requests = ids.iter().map(|id| total.get_counter(&MetricName::with_one_label("requests", "request_id", id))).collect::<Vec<_>>();
+        assert_eq!((3, 2), (requests.iter().filter(|&&v| v == Some(2)).count(), requests.iter().filter(|v| v.is_none()).count()));
+        assert_eq!(Some(4), total.get_counter(&MetricName::with_one_label("requests", "overflow", &"true")));
+    }
+
+    #[test]
+    fn label_order_does_not_split_series() {
+        let _heap = shared_heap();
+        let (h1, step) = (HelperIdentity::H1, SeriesId(3));
+        let mut store = MetricStore::default();
+        store.update(&MetricName::from(("bytes", ("helper", &h1), ("step", &step))), 1);
+        store.update(&MetricName::from(("bytes", ("step", &step), ("helper", &h1))), 2);
+        store.update(&MetricName::with_label_array("bytes", [("step", &step as &dyn LabelValue), ("helper", &h1)]), 4);
+        store.update_info("build", &[("version", "1"), ("mode", "tlv")]);
+        store.update_info("build", &[("mode", "tlv"), ("version", "1")]);
+
+        assert_eq!(2, store.len());
+        assert_eq!(Some(7), store.get_counter(&MetricName::with_labels("bytes", &[("step", &step), ("helper", &h1)]).unwrap()));
+        assert!(store.to_string().contains("build{mode=tlv,version=1} 1"));
+    }
+
+    #[test]
+    fn narrow_names_are_small() {
+        let _heap = shared_heap();
+        let h1 = HelperIdentity::H1;
+        let (bare, one) = (MetricName::<0>::no_labels("bytes"), MetricName::<1>::one_label("bytes", "helper", &h1));
+        assert_eq!(size_of::<&str>(), size_of_val(&bare));
+        assert_eq!(size_of::<&str>() + size_of::<(&str, &dyn LabelValue)>(), size_of_val(&one));
+        assert!(size_of_val(&one) < size_of::<MetricName>());
+
+        let mut store = MetricStore::default();
+        store.update(&bare, 1);
+        store.update(&MetricName::with_no_labels("bytes"), 2);
+        store.update(&one, 4);
+        store.update(&MetricName::<2>::one_label("bytes", "helper", &h1), 8);
+        assert_eq!(2, store.len());
+        assert_eq!(Some(3), store.get_counter(&MetricName::with_no_labels("bytes")));
+        assert_eq!(Some(12), store.get_counter(&MetricName::with_one_label("bytes", "helper", &h1)));
+    }
+
+    #[test]
+    fn counter_matching_some_labels() {
+        let _heap = shared_heap();
+        let mut store = MetricStore::default();
+        for (helper, step, value) in [(HelperIdentity::H1, 0, 1), (HelperIdentity::H1, 1, 2), (HelperIdentity::H2, 1, 4)] {
+            store.update(&MetricName::from(("bytes", ("helper", &helper), ("step", &SeriesId(step)))), value);
+        }
+
+        assert_eq!(Some(3), store.get_counter_matching("bytes", &[("helper", &HelperIdentity::H1)]));
+        assert_eq!(Some(6), store.get_counter_matching("bytes", &[("step", &SeriesId(1))]));
+        assert_eq!(Some(4), store.get_counter_matching("bytes", &[("step", &SeriesId(1)), ("helper", &HelperIdentity::H2)]));
+        assert_eq!(Some(7), store.get_counter_matching("bytes", &[]));
+        assert_eq!(None, store.get_counter_matching("bytes", &[("helper", &HelperIdentity::H3)]));
+    }
+
+    #[test]
+    fn readable_labels() {
+        let _heap = shared_heap();
+        let mut store = MetricStore::default();
+        store.update(&MetricName::from(("bytes", ("helper", &HelperIdentity::H2), ("step", &SeriesId(7)))), 3);
+
+        let series = store.iter()
+            .map(|(key, labels, value)| (key, labels.map(|(label, value)| format!("{label}={value}")).collect::<Vec<_>>(), value.as_counter()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("bytes", vec!["helper=H2".to_string(), "step=7".to_string()], Some(3))], series);
+        assert!(format!("{store:?}").contains(r#"OwnedMetricName { key: "bytes", kind: Counter, labels: {"helper": H2, "step": 7} }: Counter(3)"#));
+    }
+
+    #[test]
+    fn gauge_last_write_wins() {
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("queue_depth");
+        let start = Instant::now();
+        let (mut older, mut newer) = (MetricStore::default(), MetricStore::default());
+        newer.update_gauge(&name, GaugeUpdate::Set(7.0), start + Duration::from_millis(1));
+        older.update_gauge(&name, GaugeUpdate::Set(3.0), start);
+        older.update_gauge(&name, GaugeUpdate::Add(-0.5), start);
+        assert_eq!(Some(2.5), older.get_gauge(&name));
+
+        let mut merged = MetricStore::default();
+        merged.merge_ref(&newer);
+        merged.merge(older);
+        assert_eq!(Some(7.0), merged.get_gauge(&name));
+        assert_eq!(None, merged.get_counter(&name));
+
+        // deltas of different threads add up, on top of the last value set
+        let (mut inc, mut dec) = (MetricStore::default(), MetricStore::default());
+        inc.update_gauge(&name, GaugeUpdate::Add(2.0), start);
+        dec.update_gauge(&name, GaugeUpdate::Add(-1.0), start + Duration::from_millis(2));
+        merged.merge(inc);
+        merged.merge(dec);
+        assert_eq!(Some(8.0), merged.get_gauge(&name));
+    }
+
+    #[test]
+    fn histogram() {
+        let _heap = shared_heap();
+        const BOUNDS: &[f64] = &[10.0, 100.0, 1000.0];
+        let name = MetricName::with_no_labels("latency");
+        let (mut a, mut b) = (MetricStore::default(), MetricStore::default());
+        for v in 1..=90 {
+            a.update_histogram(&name, BOUNDS, v as f64);
+        }
+        for v in [500.0, 5000.5] {
+            b.update_histogram(&name, BOUNDS, v);
+        }
+        a.merge(b);
+
+        let histogram = a.get_histogram(&name).unwrap();
+        assert_eq!((92, 90.0 * 91.0 / 2.0 + 5500.5, 5000.5), (histogram.count(), histogram.sum(), histogram.max()));
+        assert_eq!(Some(100.0), histogram.quantile(0.5));
+        assert_eq!(Some(1000.0), histogram.quantile(0.98));
+        assert_eq!(Some(5000.5), histogram.quantile(1.0));
+        assert_eq!(None, HistogramValue::new(BOUNDS).quantile(0.5));
+
+        let mut other = MetricStore::default();
+        other.update_histogram(&name, &[1.0], 2.0);
+        a.merge(other);
+        let histogram = a.get_histogram(&name).unwrap();
+        assert_eq!((92, 1), (histogram.count(), histogram.mismatched()));
+
+        // NaN and infinities stay out of the buckets and the sum, through merges too
+        let mut non_finite = MetricStore::default();
+        for v in [f64::NAN, f64::INFINITY, 1.0] {
+            non_finite.update_histogram(&name, BOUNDS, v);
+        }
+        a.merge(non_finite);
+        let histogram = a.get_histogram(&name).unwrap();
+        assert_eq!((93, 90.0 * 91.0 / 2.0 + 5501.5, 2), (histogram.count(), histogram.sum(), histogram.non_finite()));
+        assert_eq!(Some(10.0), histogram.quantile(0.0));
+    }
+
+    #[test]
+    fn summary_merge_order() {
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("payload_bytes");
+        // small integers add up exactly, so even the sum is the same in either order
+        let stores = [[512.0, 64.0], [4096.0, 128.0], [8.0, 1024.0]].map(|values| {
+            let mut store = MetricStore::default();
+            values.into_iter().for_each(|v| store.update_summary(&name, v));
+            store
+        });
+
+        let [a, b, c] = stores.clone();
+        let mut left = a;
+        left.merge(b);
+        left.merge(c);
+        let [a, b, mut c] = stores;
+        c.merge(b);
+        c.merge(a);
+
+        let expected = SummaryValue { count: 6, sum: 5832.0, min: 8.0, max: 4096.0 };
+        assert_eq!((Some(&expected), Some(&expected)), (left.get_summary(&name), c.get_summary(&name)));
+        assert_eq!(Some(972.0), expected.mean());
+    }
+
+    #[test]
+    fn exp_histogram_merges_at_coarser_scale() {
+        let record = |values: &[f64]| {
+            let mut histogram = ExpHistogramValue::new();
+            values.iter().for_each(|&v| histogram.record(v));
+            histogram
+        };
+        let (fine, coarse) = (record(&[1000.0]), record(&[1.0, 1.5, 0.0, -2.0]));
+        assert_eq!(ExpHistogramValue::MAX_SCALE, fine.scale());
+        assert!(coarse.scale() < fine.scale());
+
+        let mut left = fine.clone();
+        left.merge(&coarse);
+        let mut right = coarse;
+        right.merge(&fine);
+        let expected = record(&[1.0, 1.5, 0.0, -2.0, 1000.0]);
+        assert_eq!((&expected, &expected), (&left, &right));
+        assert_eq!((5, 1, 1), (left.count(), left.zero_count(), left.negative().counts().iter().sum()));
+        assert!(left.positive().counts().len() <= ExpHistogramValue::MAX_BUCKETS);
+
+        // 1000 lands in the last positive bucket, (base^i, base^(i+1)]
+        let base = 2_f64.powf(2_f64.powi(-i32::from(left.scale())));
+        let last = left.positive().offset() + left.positive().counts().len() as i32 - 1;
+        assert!(base.powi(last) < 1000.0 && 1000.0 <= base.powi(last + 1));
+
+        // the extremes of finite values fit after downscaling, the others are only counted
+        let extremes = record(&[f64::from_bits(1), f64::MAX, f64::NAN, f64::INFINITY]);
+        assert_eq!((2, 2), (extremes.count(), extremes.non_finite()));
+        assert!(extremes.positive().counts().len() <= ExpHistogramValue::MAX_BUCKETS);
+        assert_eq!(f64::MAX, extremes.max());
+    }
+
+    #[test]
+    fn exp_histogram_downscale_keeps_values_in_their_buckets() {
+        let values = [1000.0, 1001.0, 2000.0, 5000.0, 100_000.0, 1e-3, 1e9];
+        let mut histogram = ExpHistogramValue::new();
+        values.iter().for_each(|&v| histogram.record(v));
+        let mut merged = ExpHistogramValue::new();
+        values.iter().for_each(|&v| {
+            let mut single = ExpHistogramValue::new();
+            single.record(v);
+            merged.merge(&single);
+        });
+        assert_eq!(histogram, merged);
+
+        let base = 2_f64.powf(2_f64.powi(-i32::from(histogram.scale())));
+        let positive = histogram.positive();
+        assert!(positive.counts().len() <= ExpHistogramValue::MAX_BUCKETS);
+        assert_eq!(values.len() as u64, positive.counts().iter().sum::<u64>());
+        for (i, &count) in positive.counts().iter().enumerate().filter(|(_, &count)| count > 0) {
+            let i = positive.offset() + i as i32;
+            let (lo, hi) = (base.powi(i), base.powi(i + 1));
+            let expected = values.iter().filter(|&&v| lo < v && v <= hi).count() as u64;
+            assert_eq!(expected, count, "bucket {i} holds ({lo}, {hi}]");
+        }
+    }
+
+    #[test]
+    fn absolute_takes_max() {
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("ctxt_switches");
+        let (mut a, mut b) = (MetricStore::default(), MetricStore::default());
+        a.update_absolute(&name, 10);
+        a.update_absolute(&name, 7);
+        b.update_absolute(&name, 8);
+        assert_eq!(Some(10), a.get_absolute(&name));
+
+        b.merge_ref(&a);
+        assert_eq!((Some(10), None), (b.get_absolute(&name), b.get_counter(&name)));
+    }
+
+    #[test]
+    fn counter_overflow() {
+        static OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("bytes_sent");
+        let mut store = MetricStore::default();
+        store.update(&name, u64::MAX - 1);
+        store.update(&name, 5);
+        assert_eq!(Some(u64::MAX), store.get_counter(&name));
+
+        let mut wrapping = MetricStore::default();
+        wrapping.set_overflow_policy(OverflowPolicy::Wrap);
+        wrapping.update(&name, 2);
+        wrapping.merge_ref(&store);
+        assert_eq!(Some(1), wrapping.get_counter(&name));
+
+        let mut reporting = MetricStore::default();
+        reporting.set_overflow_policy(OverflowPolicy::Report(|key, total, delta| {
+            assert_eq!(("bytes_sent", u64::MAX, 1), (key, total, delta));
+            OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        }));
+        reporting.merge(store.clone());
+        reporting.merge(wrapping);
+        assert_eq!((Some(u64::MAX), 1), (reporting.get_counter(&name), OVERFLOWS.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn sums_saturated_series() {
+        let _heap = shared_heap();
+        let mut store = MetricStore::default();
+        for helper in [HelperIdentity::H1, HelperIdentity::H2] {
+            let name = MetricName::with_one_label("bytes_sent", "helper", &helper);
+            store.update(&name, u64::MAX - 1);
+            store.update(&name, 5);
+        }
+        assert_eq!(Some(u64::MAX), store.get_counter_all_dim("bytes_sent"));
+        assert_eq!(Some(u64::MAX), store.get_counter_matching("bytes_sent", &[]));
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn hdr_merge_is_lossless() {
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("latency");
+        let (mut a, mut b) = (MetricStore::default(), MetricStore::default());
+        for v in 1..=1000 {
+            a.update_hdr(&name, v);
+            b.update_hdr(&name, 1_000_000 + v);
+        }
+        a.merge(b);
+
+        let histogram = a.get_hdr(&name).unwrap().histogram();
+        assert_eq!(2000, histogram.len());
+        assert!(histogram.equivalent(histogram.value_at_quantile(0.5), 1000));
+        assert!(histogram.equivalent(histogram.value_at_quantile(0.999), 1_000_998));
+    }
+
+    #[test]
+    fn dynamic_names() {
+        let _heap = shared_heap();
+        let step = 3;
+        let key = intern_key(&format!("step_{step}_records"));
+        assert!(std::ptr::eq(key, intern_key(&format!("step_{}_records", step))));
+
+        let mut store = MetricStore::default();
+        store.update(&MetricName::with_dynamic_name(&format!("step_{step}_records")), 2);
+        store.update(&MetricName::with_no_labels("step_3_records"), 3);
+        store.update(&MetricName::with_one_label(key, "dest", &HelperIdentity::H1), 4);
+
+        assert_eq!(Some(5), store.get_counter(&MetricName::with_no_labels("step_3_records")));
+        assert_eq!(Some(9), store.get_counter_all_dim("step_3_records"));
+        assert_eq!(2, store.len());
+    }
+
+    #[test]
+    fn evicts_series_not_merged_within_ttl() {
+        let _heap = shared_heap();
+        let ids = (0..3).map(SeriesId).collect::<Vec<_>>();
+        let name = |id| MetricName::with_one_label("requests", "peer", id);
+        let snapshot = |id| {
+            let mut snapshot = MetricStore::default();
+            snapshot.update(&name(id), 1);
+            snapshot.update_gauge(&name(id), GaugeUpdate::Set(1.0), Instant::now());
+            snapshot
+        };
+        let mut total = MetricStore::default();
+        // counted per key across kinds, so room for two series with a counter and a gauge each
+        total.set_cardinality_limit(Some(4));
+        total.track_last_updated();
+        total.update(&MetricName::with_no_labels("requests"), 1);
+        total.merge(snapshot(&ids[0]));
+        std::thread::sleep(Duration::from_millis(50));
+        total.merge_ref(&snapshot(&ids[1]));
+
+        // a counter and a gauge
+        assert_eq!(2, total.evict_older_than(Duration::from_millis(25)));
+        assert_eq!(None, total.get_counter(&name(&ids[0])));
+        assert_eq!(None, total.get_gauge(&name(&ids[0])));
+        assert_eq!(Some(1), total.get_counter(&name(&ids[1])));
+        // never merged, so never stale
+        assert_eq!(Some(1), total.get_counter(&MetricName::with_no_labels("requests")));
+        // the evicted series made room under the limit
+        total.merge(snapshot(&ids[2]));
+        assert_eq!(Some(1), total.get_counter(&name(&ids[2])));
+        assert_eq!(0, total.evict_older_than(Duration::from_secs(60)));
+
+        // folded into the overflow series, which is tracked in their place
+        let folded = SeriesId(3);
+        total.merge(snapshot(&folded));
+        let touched = total.touched.as_ref().unwrap();
+        assert!(find(touched, &name(&folded)).is_none());
+        assert!(find_owned(touched, &CardinalityLimit::overflow(&Shared::Static("requests"), MetricKind::Counter)).is_some());
+    }
+
+    #[test]
+    fn compact_shrinks_to_live_series() {
+        let _heap = shared_heap();
+        let ids = (0..1000).map(SeriesId).collect::<Vec<_>>();
+        let mut store = MetricStore::default();
+        for id in &ids {
+            store.update(&MetricName::with_one_label("requests", "request_id", id), 1);
+        }
+        store.drop_labels(&LabelFilter::new(["request_id".to_string()]));
+        let before = store.footprint();
+
+        store.compact();
+        assert!(store.footprint() < before / 10, "{} -> {}", before, store.footprint());
+        assert_eq!((1, Some(1000)), (store.len(), store.get_counter(&MetricName::with_no_labels("requests"))));
+    }
+
+    #[test]
+    fn group_counters_by_label() {
+        let _heap = shared_heap();
+        let (h1, h2) = (HelperIdentity::H1, HelperIdentity::H2);
+        let mut store = MetricStore::default();
+        for (helper, step, val) in [(&h1, 1, 1), (&h1, 2, 2), (&h2, 1, 4)] {
+            store.update(&MetricName::from(("records", ("helper", helper), ("step", &SeriesId(step)))), val);
+        }
+        store.update(&MetricName::with_one_label("records", "step", &SeriesId(1)), 8);
+        store.update(&MetricName::with_one_label("other", "helper", &h1), 16);
+
+        let by_helper = store.group_by("records", "helper");
+        assert_eq!(HashMap::from([("H1".to_string(), 3), ("H2".to_string(), 4)]), by_helper);
+        let by_step = store.group_by("records", "step");
+        assert_eq!(HashMap::from([("1".to_string(), 13), ("2".to_string(), 2)]), by_step);
+        assert!(store.group_by("missing", "helper").is_empty());
+    }
+
+    #[test]
+    fn allowed_labels() {
+        let _heap = shared_heap();
+        let (h1, h2) = (HelperIdentity::H1, HelperIdentity::H2);
+        let filter = LabelFilter::allow(["helper".to_string(), "step".to_string()]).deny(["step".to_string()]);
+        let mut store = MetricStore::default();
+        for (helper, request) in [(&h1, SeriesId(1)), (&h1, SeriesId(2)), (&h2, SeriesId(3))] {
+            let name = MetricName::from(("requests", ("helper", helper), ("request_id", &request), ("step", &SeriesId(0))));
+            store.update(&filter.apply(&name), 1);
+        }
+        store.update(&MetricName::from(("bytes", ("peer", &h1), ("request_id", &SeriesId(1)))), 4);
+        store.drop_labels(&filter);
+
+        assert_eq!(3, store.len());
+        assert_eq!(Some(2), store.get_counter(&MetricName::with_one_label("requests", "helper", &h1)));
+        assert_eq!(Some(4), store.get_counter(&MetricName::with_no_labels("bytes")));
+        assert!(LabelFilter::new([]).is_empty() && !LabelFilter::allow([]).is_empty());
     }
 }
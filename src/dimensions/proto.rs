@@ -0,0 +1,347 @@
+//! Protobuf messages of `proto/snapshot.proto`, for services that consume the aggregator's
+//! output in other languages. Written by hand to match the schema, as prost-build would
+//! generate them, so builds don't need `protoc`. Keep both in sync.
+//!
+//! Conversions only go one way. Sketches and HDR histograms are exported as [`QUANTILES`],
+//! as other services can't merge their internal state anyway.
+
+use crate::dimensions::{find_owned, MetricKind, MetricStore, OwnedMetricName, SeriesValue, unix_nanos, wall_time};
+use crate::metrics;
+
+/// Quantiles reported for sketches and HDR histograms.
+pub const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Snapshot {
+    #[prost(message, optional, tag = "1")]
+    pub origin: Option<Identity>,
+    #[prost(uint64, tag = "2")]
+    pub recorded: u64,
+    #[prost(uint64, tag = "3")]
+    pub seq: u64,
+    #[prost(uint64, optional, tag = "4")]
+    pub captured_unix_nanos: Option<u64>,
+    #[prost(message, repeated, tag = "5")]
+    pub series: Vec<Series>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Identity {
+    #[prost(string, tag = "1")]
+    pub run_id: String,
+    #[prost(string, tag = "2")]
+    pub process_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Kind {
+    Unspecified = 0,
+    Counter = 1,
+    UpDown = 2,
+    Gauge = 3,
+    Histogram = 4,
+    ExpHistogram = 5,
+    Absolute = 6,
+    Flag = 7,
+    Sketch = 8,
+    Info = 9,
+    Unique = 10,
+    Meter = 11,
+    Summary = 12,
+    Hdr = 14,
+}
+
+/// Numbered after the position of the kind in [`MetricKind`], which leaves exemplars, 13,
+/// reserved.
+impl From<MetricKind> for Kind {
+    fn from(kind: MetricKind) -> Self {
+        Self::try_from(kind as i32 + 1).unwrap_or(Self::Unspecified)
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Series {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, repeated, tag = "2")]
+    pub labels: Vec<Label>,
+    #[prost(enumeration = "Kind", tag = "3")]
+    pub kind: i32,
+    #[prost(message, optional, tag = "4")]
+    pub exemplar: Option<Exemplar>,
+    #[prost(oneof = "series::Value", tags = "10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22")]
+    pub value: Option<series::Value>,
+}
+
+pub mod series {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(uint64, tag = "10")]
+        Counter(u64),
+        #[prost(sint64, tag = "11")]
+        UpDown(i64),
+        #[prost(double, tag = "12")]
+        Gauge(f64),
+        #[prost(message, tag = "13")]
+        Histogram(super::Histogram),
+        #[prost(message, tag = "14")]
+        ExpHistogram(super::ExpHistogram),
+        #[prost(uint64, tag = "15")]
+        Absolute(u64),
+        #[prost(bool, tag = "16")]
+        Flag(bool),
+        #[prost(message, tag = "17")]
+        Info(super::Info),
+        #[prost(message, tag = "18")]
+        Sketch(super::Quantiles),
+        #[prost(double, tag = "19")]
+        Unique(f64),
+        #[prost(message, tag = "20")]
+        Meter(super::Meter),
+        #[prost(message, tag = "21")]
+        Summary(super::Summary),
+        #[prost(message, tag = "22")]
+        Hdr(super::Quantiles),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Histogram {
+    #[prost(double, repeated, tag = "1")]
+    pub bounds: Vec<f64>,
+    #[prost(uint64, repeated, tag = "2")]
+    pub counts: Vec<u64>,
+    #[prost(double, tag = "3")]
+    pub sum: f64,
+    #[prost(uint64, tag = "4")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub max: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExpHistogram {
+    #[prost(sint32, tag = "1")]
+    pub scale: i32,
+    #[prost(uint64, tag = "2")]
+    pub zero_count: u64,
+    #[prost(message, optional, tag = "3")]
+    pub positive: Option<exp_histogram::Buckets>,
+    #[prost(message, optional, tag = "4")]
+    pub negative: Option<exp_histogram::Buckets>,
+    #[prost(uint64, tag = "5")]
+    pub count: u64,
+    #[prost(double, tag = "6")]
+    pub sum: f64,
+    #[prost(double, tag = "7")]
+    pub min: f64,
+    #[prost(double, tag = "8")]
+    pub max: f64,
+}
+
+pub mod exp_histogram {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Buckets {
+        #[prost(sint32, tag = "1")]
+        pub offset: i32,
+        #[prost(uint64, repeated, tag = "2")]
+        pub counts: Vec<u64>,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Info {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Quantiles {
+    #[prost(uint64, tag = "1")]
+    pub count: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub quantiles: Vec<quantiles::Quantile>,
+}
+
+pub mod quantiles {
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct Quantile {
+        #[prost(double, tag = "1")]
+        pub quantile: f64,
+        #[prost(double, tag = "2")]
+        pub value: f64,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Meter {
+    #[prost(uint64, tag = "1")]
+    pub count: u64,
+    #[prost(uint64, tag = "2")]
+    pub since_unix_nanos: u64,
+    #[prost(uint64, tag = "3")]
+    pub until_unix_nanos: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Summary {
+    #[prost(uint64, tag = "1")]
+    pub count: u64,
+    #[prost(double, tag = "2")]
+    pub sum: f64,
+    #[prost(double, tag = "3")]
+    pub min: f64,
+    #[prost(double, tag = "4")]
+    pub max: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Exemplar {
+    #[prost(bytes = "vec", tag = "1")]
+    pub trace_id: Vec<u8>,
+    #[prost(fixed64, tag = "2")]
+    pub span_id: u64,
+    #[prost(double, tag = "3")]
+    pub value: f64,
+    #[prost(uint64, tag = "4")]
+    pub recorded_unix_nanos: u64,
+}
+
+fn quantiles(count: u64, quantile: impl Fn(f64) -> f64) -> Quantiles {
+    Quantiles {
+        count,
+        quantiles: QUANTILES.iter().map(|&q| quantiles::Quantile { quantile: q, value: quantile(q) }).collect(),
+    }
+}
+
+fn buckets(buckets: &crate::dimensions::ExpBuckets) -> exp_histogram::Buckets {
+    exp_histogram::Buckets { offset: buckets.offset, counts: buckets.counts.clone() }
+}
+
+impl MetricStore {
+    /// Every series as a protobuf message, with the exemplar sampled for it if there is one.
+    pub fn to_proto(&self) -> Vec<Series> {
+        self.series.iter().map(|(name, v)| Series {
+            key: name.key.to_string(),
+            labels: name.labels.iter()
+                .map(|(label, _, value)| Label { name: label.to_string(), value: value.to_string() })
+                .collect(),
+            kind: Kind::from(name.kind) as i32,
+            exemplar: self.proto_exemplar(name),
+            value: Some(proto_value(v)),
+        }).collect()
+    }
+
+    fn proto_exemplar(&self, name: &OwnedMetricName) -> Option<Exemplar> {
+        find_owned(&self.exemplars, name).map(|v| Exemplar {
+            trace_id: v.exemplar.trace_id.to_be_bytes().to_vec(),
+            span_id: v.exemplar.span_id,
+            value: v.value,
+            recorded_unix_nanos: unix_nanos(wall_time(v.recorded)),
+        })
+    }
+}
+
+fn proto_value(value: &SeriesValue) -> series::Value {
+    use series::Value;
+
+    match value {
+        SeriesValue::Counter(v) => Value::Counter(*v),
+        SeriesValue::UpDown(v) => Value::UpDown(*v),
+        SeriesValue::Gauge(v) => Value::Gauge(v.value),
+        SeriesValue::Histogram(v) => Value::Histogram(Histogram {
+            bounds: v.bounds.to_vec(),
+            counts: v.counts.to_vec(),
+            sum: v.sum,
+            count: v.count,
+            max: v.max,
+        }),
+        SeriesValue::ExpHistogram(v) => Value::ExpHistogram(ExpHistogram {
+            scale: i32::from(v.scale),
+            zero_count: v.zero_count,
+            positive: Some(buckets(&v.positive)),
+            negative: Some(buckets(&v.negative)),
+            count: v.count,
+            sum: v.sum,
+            min: v.min,
+            max: v.max,
+        }),
+        SeriesValue::Absolute(v) => Value::Absolute(v.0),
+        SeriesValue::Flag(v) => Value::Flag(v.0),
+        SeriesValue::Sketch(v) => Value::Sketch(quantiles(v.count() as u64, |q| v.quantile(q).unwrap_or_default())),
+        SeriesValue::Info(_) => Value::Info(Info {}),
+        SeriesValue::Unique(v) => Value::Unique(v.estimate()),
+        SeriesValue::Meter(v) => Value::Meter(Meter {
+            count: v.count,
+            since_unix_nanos: unix_nanos(wall_time(v.since)),
+            until_unix_nanos: unix_nanos(wall_time(v.until)),
+        }),
+        SeriesValue::Summary(v) => Value::Summary(Summary {
+            count: v.count,
+            sum: v.sum,
+            min: v.min,
+            max: v.max,
+        }),
+        #[cfg(feature = "hdr")]
+        SeriesValue::Hdr(v) => Value::Hdr(quantiles(v.0.len(), |q| v.0.value_at_quantile(q) as f64)),
+    }
+}
+
+impl From<&metrics::Snapshot> for Snapshot {
+    fn from(snapshot: &metrics::Snapshot) -> Self {
+        Self {
+            origin: snapshot.origin().map(|origin| Identity {
+                run_id: origin.run_id.to_string(),
+                process_id: origin.process_id.to_string(),
+            }),
+            recorded: snapshot.recorded(),
+            seq: snapshot.seq(),
+            captured_unix_nanos: snapshot.captured().map(unix_nanos),
+            series: snapshot.store().to_proto(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use prost::Message;
+    use crate::dimensions::{Exemplar, HelperIdentity, MetricKind, MetricName, SeriesId};
+    use crate::dimensions::proto::{self, Kind, series::Value};
+    use crate::metrics::{Counter, Records, Snapshot};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn snapshots_convert_to_messages() {
+        let _heap = shared_heap();
+        let sent = MetricName::from(("sent", ("helper", &HelperIdentity::H2), ("step", &SeriesId(3))));
+        let mut snapshot = Snapshot::new();
+        snapshot.record(Counter("plain", 2));
+        let store = snapshot.store_mut();
+        store.update(&sent, 5);
+        store.update_exemplar(&sent, MetricKind::Counter, Exemplar { trace_id: 1, span_id: 2 }, 5.0, Instant::now());
+        store.update_sketch(&MetricName::with_no_labels("latency"), 10.0);
+        let snapshot = snapshot.take();
+
+        let bytes = proto::Snapshot::from(&snapshot).encode_to_vec();
+        let mut message = proto::Snapshot::decode(bytes.as_slice()).unwrap();
+        message.series.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(snapshot.captured().is_some(), message.captured_unix_nanos.is_some());
+        assert_eq!(vec!["latency", "plain", "sent"], message.series.iter().map(|s| s.key.as_str()).collect::<Vec<_>>());
+
+        let sent = &message.series[2];
+        assert_eq!((Kind::Counter, Some(Value::Counter(5))), (sent.kind(), sent.value.clone()));
+        assert_eq!(vec![("helper", "H2"), ("step", "3")], sent.labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect::<Vec<_>>());
+        assert_eq!(&1_u128.to_be_bytes()[..], sent.exemplar.as_ref().unwrap().trace_id);
+        let Some(Value::Sketch(sketch)) = &message.series[0].value else {
+            panic!("{:?}", message.series[0])
+        };
+        assert_eq!((1, proto::QUANTILES.len()), (sketch.count, sketch.quantiles.len()));
+    }
+}
@@ -0,0 +1,287 @@
+//! Serde support for stores and their values. Stores serialize as the series of every kind,
+//! each one as `[key, [[label, hash, value], ..], value]` with label values rendered to
+//! strings. Labels are read back as their rendering, see [`Restored`]. Values are checked when
+//! read back, so a store deserialized from untrusted input merges like any other.
+
+use std::fmt::{self, Formatter};
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::dimensions::{AbsoluteValue, ExemplarValue, ExpHistogramValue, FlagValue, find_owned, GaugeValue, HistogramValue, InfoValue, KindValue, merge, merge_value, MeterValue, MetricKind, MetricStore, OwnedLabels, OwnedMetricName, Restored, SeriesMap, SeriesValue, SketchValue, SummaryValue, UniqueValue};
+
+struct Series<'a, V>(MetricKind, &'a SeriesMap<V>);
+
+struct Value<'a>(&'a SeriesValue);
+
+struct Labels<'a>(&'a OwnedLabels);
+
+impl Serialize for Series<'_, SeriesValue> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let series = self.1.iter().filter(|(name, _)| name.kind == self.0);
+        serializer.collect_seq(series.map(|(name, value)| (&*name.key, Labels(&name.labels), Value(value))))
+    }
+}
+
+/// Exemplars also carry the kind of the series they were sampled for.
+impl Serialize for Series<'_, ExemplarValue> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.1.iter().map(|(name, value)| (&*name.key, Labels(&name.labels), value, name.kind)))
+    }
+}
+
+/// Values serialize as the value of their kind, the kind is in the key they are listed under.
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            SeriesValue::Counter(v) => v.serialize(serializer),
+            SeriesValue::UpDown(v) => v.serialize(serializer),
+            SeriesValue::Gauge(v) => v.serialize(serializer),
+            SeriesValue::Histogram(v) => v.serialize(serializer),
+            SeriesValue::ExpHistogram(v) => v.serialize(serializer),
+            SeriesValue::Absolute(v) => v.serialize(serializer),
+            SeriesValue::Flag(v) => v.serialize(serializer),
+            SeriesValue::Sketch(v) => v.serialize(serializer),
+            SeriesValue::Info(v) => v.serialize(serializer),
+            SeriesValue::Unique(v) => v.serialize(serializer),
+            SeriesValue::Meter(v) => v.serialize(serializer),
+            SeriesValue::Summary(v) => v.serialize(serializer),
+            #[cfg(feature = "hdr")]
+            SeriesValue::Hdr(v) => v.serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for Labels<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|(label, hash, value)| (&**label, hash, value.to_string())))
+    }
+}
+
+/// Series of every kind that has any, by [`MetricKind`]. Cardinality limits, overflow policy
+/// and update counts are settings and statistics of the store, not its contents, so they are
+/// left out.
+impl Serialize for MetricStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let series = self.series_per_kind();
+        let mut map = serializer.serialize_map(None)?;
+        for kind in MetricKind::ALL {
+            match kind {
+                _ if series[kind as usize] == 0 => {}
+                MetricKind::Exemplar => map.serialize_entry(&kind, &Series(kind, &self.exemplars))?,
+                _ => map.serialize_entry(&kind, &Series(kind, &self.series))?,
+            }
+        }
+        map.end()
+    }
+}
+
+type LabelsRepr = Vec<(String, u64, String)>;
+
+type SeriesRepr<V> = Vec<(String, LabelsRepr, V)>;
+
+impl<'de> Deserialize<'de> for MetricStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StoreVisitor;
+
+        impl<'de> Visitor<'de> for StoreVisitor {
+            type Value = MetricStore;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("series by metric kind")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MetricStore, A::Error> {
+                let (mut store, mut restored) = (MetricStore::default(), Restored::default());
+                while let Some(kind) = map.next_key()? {
+                    let (store, restored) = (&mut store, &mut restored);
+                    match kind {
+                        MetricKind::Counter => restore::<u64, _>(store, restored, map.next_value()?)?,
+                        MetricKind::UpDown => restore::<i64, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Gauge => restore::<GaugeValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Histogram => restore::<HistogramValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::ExpHistogram => restore::<ExpHistogramValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Absolute => restore::<AbsoluteValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Flag => restore::<FlagValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Sketch => restore::<SketchValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Info => restore::<InfoValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Unique => restore::<UniqueValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Meter => restore::<MeterValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Summary => restore::<SummaryValue, _>(store, restored, map.next_value()?)?,
+                        MetricKind::Exemplar => {
+                            let exemplars = map.next_value::<Vec<(String, LabelsRepr, ExemplarValue, MetricKind)>>()?;
+                            if exemplars.iter().any(|(.., kind)| *kind == MetricKind::Exemplar) {
+                                return Err(de::Error::custom("exemplars are sampled for series of other kinds"))
+                            }
+                            let series = exemplars.into_iter().map(|(key, labels, value, kind)| (name(restored, &key, kind, &labels), value));
+                            merge(&mut store.exemplars, series, None, None, merge_value);
+                        }
+                        #[cfg(feature = "hdr")]
+                        MetricKind::Hdr => restore::<crate::dimensions::HdrValue, _>(store, restored, map.next_value()?)?,
+                        #[cfg(not(feature = "hdr"))]
+                        MetricKind::Hdr => return Err(de::Error::custom("hdr histograms need the `hdr` feature")),
+                    }
+                }
+
+                Ok(store)
+            }
+        }
+
+        deserializer.deserialize_map(StoreVisitor)
+    }
+}
+
+/// Adds deserialized series of kind `V` to `store`, merging repeated names. Fails on values
+/// recording can't produce and on repeated names whose values don't merge.
+fn restore<V: KindValue, E: de::Error>(store: &mut MetricStore, restored: &mut Restored, series: SeriesRepr<V>) -> Result<(), E> {
+    for (key, labels, value) in series {
+        let (name, value) = (name(restored, &key, V::KIND, &labels), value.into_series());
+        if !value.is_valid() {
+            return Err(E::custom(format_args!("{name} has an invalid {:?} value", V::KIND)))
+        }
+        if find_owned(&store.series, &name).is_some_and(|existing| !existing.fits(&value)) {
+            return Err(E::custom(format_args!("{name} is repeated with a value that doesn't merge")))
+        }
+        merge(&mut store.series, [(name, value)], None, None, store.overflow.combine());
+    }
+
+    Ok(())
+}
+
+fn name(restored: &mut Restored, key: &str, kind: MetricKind, labels: &LabelsRepr) -> OwnedMetricName {
+    let labels = labels.iter().map(|(label, hash, value)| restored.label(label, *hash, value)).collect();
+    OwnedMetricName { key: restored.name(key), kind, labels }
+}
+
+/// Recorded values and their counts, from the lowest.
+#[cfg(feature = "hdr")]
+impl Serialize for crate::dimensions::HdrValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter_recorded().map(|v| (v.value_iterated_to(), v.count_at_value())))
+    }
+}
+
+#[cfg(feature = "hdr")]
+impl<'de> Deserialize<'de> for crate::dimensions::HdrValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut histogram = Self::new();
+        for (value, count) in Vec::<(u64, u64)>::deserialize(deserializer)? {
+            histogram.0.record_n(value, count).map_err(de::Error::custom)?;
+        }
+
+        Ok(histogram)
+    }
+}
+
+/// Instants as the wall clock time they correspond to, see [`wall_time`].
+pub mod instant {
+    use std::time::{Instant, SystemTime};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use crate::dimensions::{from_wall_time, wall_time};
+
+    pub fn serialize<S: Serializer>(at: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        wall_time(*at).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        SystemTime::deserialize(deserializer).map(from_wall_time)
+    }
+}
+
+/// Histogram bounds, checked when read back, see [`restore_bounds`].
+pub mod bounds {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use crate::dimensions::{restore_bounds, Shared};
+
+    pub fn serialize<S: Serializer>(bounds: &Shared<[f64]>, serializer: S) -> Result<S::Ok, S::Error> {
+        (**bounds).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Shared<[f64]>, D::Error> {
+        restore_bounds(Vec::deserialize(deserializer)?).ok_or_else(|| de::Error::custom("histogram bounds must go up and can't be NaN"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use crate::dimensions::{DEFAULT_BUCKETS, GaugeUpdate, HelperIdentity, HistogramValue, KEYS, MetricName, MetricStore, SeriesId};
+    use crate::ids::{Identity, Ulid};
+    use crate::metrics::{Counter, Records, Snapshot};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn snapshots_round_trip_through_json() {
+        let _heap = shared_heap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 443));
+        let sent = MetricName::from(("sent", ("helper", &HelperIdentity::H2), ("peer", &peer), ("step", &SeriesId(3))));
+        let mut snapshot = Snapshot::new();
+        snapshot.set_origin(Some(Identity { run_id: Ulid::from_parts(0, 0), process_id: Ulid::from_parts(0, 1) }));
+        snapshot.record(Counter("plain", 2));
+        let store = snapshot.store_mut();
+        store.update(&sent, 5);
+        store.update_gauge(&sent, GaugeUpdate::Set(1.5), Instant::now());
+        store.update_histogram(&sent, DEFAULT_BUCKETS, 2_000.0);
+        store.update_histogram(&MetricName::with_no_labels("custom"), &[1.0, 2.0], 1.5);
+        store.update_histogram(&MetricName::with_no_labels("custom"), &[1.0, 2.0], f64::NAN);
+        store.update_unique(&sent, &"key");
+        store.update_meter(&sent, 7, Instant::now() - Duration::from_secs(1));
+        store.update_sketch(&sent, 10.0);
+        #[cfg(feature = "hdr")]
+        store.update_hdr(&sent, 1_234);
+        let original = snapshot.take();
+
+        let json = serde_json::to_string(&original).unwrap();
+        assert!(json.contains(r#"["helper",1,"H2"]"#), "{json}");
+        assert!(json.contains(r#""10.0.0.1:443"]"#), "{json}");
+        let restored: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!((original.origin(), original.seq(), original.captured(), original.recorded()),
+                   (restored.origin(), restored.seq(), restored.captured(), restored.recorded()));
+        assert_eq!(original.store().to_string(), restored.store().to_string());
+        let custom = MetricName::with_no_labels("custom");
+        assert_eq!(Some(1), restored.store().get_histogram(&custom).map(HistogramValue::non_finite));
+
+        // restored labels name the same series as the values they were rendered from
+        let mut merged = original.store().clone();
+        merged.merge_ref(restored.store());
+        assert_eq!(original.store().len(), merged.len());
+        assert_eq!(Some(10), merged.get_counter(&sent));
+        // the same key hashes the same way in the restored sketch
+        assert_eq!(Some(1.0), merged.get_unique(&sent).map(|unique| unique.estimate().round()));
+        assert!(serde_json::from_str::<MetricStore>(r#"{"counter": [["x", [], "1"]]}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_values_that_would_not_merge() {
+        let _heap = shared_heap();
+        let histogram = |bounds: &str, counts: &str| format!(
+            r#"["restored_latency", [], {{"bounds": {bounds}, "counts": {counts}, "sum": 0.0, "count": 0, "max": 0.0}}]"#,
+        );
+        let store = |series: &str| serde_json::from_str::<MetricStore>(series);
+        assert!(store(&format!(r#"{{"histogram": [{}]}}"#, histogram("[1.0]", "[0, 0]"))).is_ok());
+        assert!(store(&format!(r#"{{"histogram": [{}]}}"#, histogram("[2.0, 1.0]", "[0, 0, 0]"))).is_err());
+        assert!(store(&format!(r#"{{"histogram": [{}]}}"#, histogram("[1.0]", "[0]"))).is_err());
+        assert!(store(&format!(r#"{{"histogram": [{}, {}]}}"#, histogram("[1.0]", "[0, 0]"), histogram("[2.0]", "[0, 0]"))).is_err());
+        // restored names are freed with the store rather than leaked
+        assert!(!KEYS.read().unwrap().contains("restored_latency"));
+
+        let exp_histogram = |scale: i8| format!(
+            r#"{{"exp_histogram": [["restored_sizes", [], {{"scale": {scale}, "zero_count": 0, "positive": {{"offset": 0, "counts": [1]}},
+                "negative": {{"offset": 0, "counts": []}}, "count": 1, "sum": 1.0, "min": 1.0, "max": 1.0}}]]}}"#,
+        );
+        assert!(store(&exp_histogram(0)).is_ok());
+        assert!(store(&exp_histogram(i8::MIN)).is_err());
+    }
+
+    #[test]
+    fn saturates_repeated_series_with_max_counts() {
+        let _heap = shared_heap();
+        let summary = r#"["s", [], {"count": 18446744073709551615, "sum": 1.0, "min": 1.0, "max": 1.0}]"#;
+        let histogram = r#"["s", [], {"bounds": [1.0], "counts": [18446744073709551615, 0], "sum": 1.0, "count": 18446744073709551615, "max": 1.0}]"#;
+        let json = format!(r#"{{"summary": [{summary}, {summary}], "histogram": [{histogram}, {histogram}]}}"#);
+        let store = serde_json::from_str::<MetricStore>(&json).unwrap();
+        let name = MetricName::with_no_labels("s");
+        assert_eq!(Some(u64::MAX), store.get_summary(&name).map(|summary| summary.count));
+        assert_eq!(Some(u64::MAX), store.get_histogram(&name).map(HistogramValue::count));
+    }
+}
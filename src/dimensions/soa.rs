@@ -0,0 +1,131 @@
+use std::array;
+use std::mem::size_of;
+use hashbrown::HashTable;
+use crate::dimensions::{compute_hash, LabelValue, MetricName, SeriesId, StoreHasher};
+
+/// Experimental counter store that keeps series in a structure-of-arrays layout. Every label
+/// slot has its own columns of names, value hashes and boxed values, indexed by series, so
+/// comparing a name against a series only reads the name and hash columns, never the boxed
+/// values. Compare with [`MetricStore`] using `layout --store`.
+///
+/// [`MetricStore`]: crate::dimensions::MetricStore
+pub struct SoaStore {
+    index: HashTable<u32>,
+    hasher: StoreHasher,
+    hashes: Vec<u64>,
+    keys: Vec<&'static str>,
+    label_names: [Vec<Option<&'static str>>; 5],
+    label_hashes: [Vec<u64>; 5],
+    label_values: [Vec<Option<Box<dyn LabelValue>>>; 5],
+    counters: Vec<u64>,
+}
+
+impl Default for SoaStore {
+    fn default() -> Self {
+        Self {
+            index: HashTable::new(),
+            hasher: StoreHasher::default(),
+            hashes: Vec::new(),
+            keys: Vec::new(),
+            label_names: array::from_fn(|_| Vec::new()),
+            label_hashes: array::from_fn(|_| Vec::new()),
+            label_values: array::from_fn(|_| Vec::new()),
+            counters: Vec::new(),
+        }
+    }
+}
+
+impl SoaStore {
+    pub fn update(&mut self, key: &MetricName, val: u64) {
+        let hash = compute_hash(&self.hasher, key);
+        if let Some(&slot) = self.index.find(hash, |&slot| self.same(slot, key)) {
+            self.counters[slot as usize] += val;
+            return
+        }
+
+        let slot = u32::try_from(self.counters.len()).expect("too many series for the SoA store");
+        self.hashes.push(hash);
+        self.keys.push(key.key);
+        for (i, label) in key.labels.iter().enumerate() {
+            self.label_names[i].push(label.map(|(name, _)| name));
+            self.label_hashes[i].push(label.map_or(0, |(_, value)| value.as_u64()));
+            self.label_values[i].push(label.map(|(_, value)| value.boxed()));
+        }
+        self.counters.push(val);
+        let hashes = &self.hashes;
+        self.index.insert_unique(hash, slot, |&slot| hashes[slot as usize]);
+    }
+
+    pub fn get(&self, key: &MetricName) -> Option<u64> {
+        let hash = compute_hash(&self.hasher, key);
+        self.index.find(hash, |&slot| self.same(slot, key)).map(|&slot| self.counters[slot as usize])
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Approximate heap usage in bytes, boxed label values excluded as in
+    /// [`MetricStore::footprint`].
+    ///
+    /// [`MetricStore::footprint`]: crate::dimensions::MetricStore::footprint
+    pub fn footprint(&self) -> usize {
+        let per_slot = size_of::<Option<&'static str>>() + size_of::<u64>() + size_of::<Option<Box<dyn LabelValue>>>();
+        self.index.capacity() * (size_of::<u32>() + 1)
+            + self.counters.capacity() * (size_of::<u64>() * 2 + size_of::<&'static str>() + per_slot * 5)
+    }
+
+    fn same(&self, slot: u32, key: &MetricName) -> bool {
+        let slot = slot as usize;
+        self.keys[slot] == key.key && key.labels.iter().enumerate().all(|(i, label)| {
+            match (self.label_names[i][slot], label) {
+                (None, None) => true,
+                (Some(name), Some((other, value))) => self.label_hashes[i][slot] == value.as_u64() && name == *other,
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Name with one label per value, named `l0`, `l1` and so on, for layout benchmarks. At most
+/// 5 values are used.
+pub fn synthetic_name<'a>(key: &'static str, values: &'a [SeriesId]) -> MetricName<'a> {
+    const NAMES: [&str; 5] = ["l0", "l1", "l2", "l3", "l4"];
+    MetricName {
+        key,
+        labels: array::from_fn(|i| values.get(i).map(|value| (NAMES[i], value as &dyn LabelValue))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dimensions::{MetricStore, SeriesId};
+    use crate::dimensions::soa::{SoaStore, synthetic_name};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn same_counts_as_map_layout() {
+        let _heap = shared_heap();
+        let (mut soa, mut aos) = (SoaStore::default(), MetricStore::default());
+        let labels = (0..100).map(|id| [SeriesId(id % 3), SeriesId(id)]).collect::<Vec<_>>();
+        for round in 0..3 {
+            for (id, labels) in labels.iter().enumerate() {
+                // names with fewer labels are distinct series
+                let name = synthetic_name("requests", &labels[..1 + id % 2]);
+                soa.update(&name, round + 1);
+                aos.update(&name, round + 1);
+            }
+        }
+
+        assert_eq!(aos.len(), soa.len());
+        for (id, labels) in labels.iter().enumerate() {
+            let name = synthetic_name("requests", &labels[..1 + id % 2]);
+            assert_eq!(aos.get_counter(&name), soa.get(&name));
+        }
+        assert_eq!(None, soa.get(&synthetic_name("requests", &[SeriesId(7)])));
+    }
+}
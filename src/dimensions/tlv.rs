@@ -0,0 +1,755 @@
+//! Tag-length-value encoding of snapshots, compact enough to cross process boundaries.
+//!
+//! Every record is a 1 byte tag, the length of its value as a 4 byte little-endian integer,
+//! then the value. Integers and floats are little-endian, text is UTF-8 and instants are
+//! nanoseconds since the Unix epoch, as a u64. A snapshot is a single record, so a stream of
+//! them can be split by reading record headers:
+//!
+//! ```text
+//! 0x01 snapshot
+//!   0x02 origin     run id, then process id, as u128
+//!   0x03 recorded   u64, events recorded up to and including the snapshot
+//!   0x04 seq        u64
+//!   0x05 captured   instant
+//!   0x06 events     u64, events recorded into the snapshot
+//!   0x10 series     one per series
+//!     0x11 kind     u8, position of the kind in `MetricKind`
+//!     0x12 key      text
+//!     0x13 label    one per label, in order
+//!       0x14 name   text
+//!       0x15 hash   u64, `LabelValue::as_u64` of the value
+//!       0x16 value  text, the value rendered
+//!     0x17 value    depends on the kind, see below
+//! ```
+//!
+//! Series values are laid out as:
+//!
+//! ```text
+//! counter        u64
+//! up_down        i64
+//! gauge          f64 value, instant updated, u8 relative, 0 or 1
+//! histogram      u32 bound count n, n f64 bounds, n + 1 u64 counts, f64 sum, u64 count, f64 max,
+//!                u64 mismatched, u64 non-finite count
+//! exp_histogram  i8 scale, u64 zero count, positive then negative buckets, each an i32
+//!                offset, u32 count n and n u64 counts, then u64 count, f64 sum, min and max,
+//!                u64 non-finite count
+//! absolute       u64
+//! flag           u8, 0 or 1
+//! info           empty
+//! meter          u64 count, instants since and until
+//! summary        u64 count, f64 sum, min and max
+//! exemplar       u8 kind of the series it was sampled for, u128 trace id, u64 span id,
+//!                f64 value, instant recorded
+//! hdr            u64 value and u64 count of every recorded value, from the lowest
+//! ```
+//!
+//! Sketches and unique counts keep state their crates don't expose, and can't be encoded.
+//! Readers skip records with tags they don't know, so they accept snapshots of newer writers.
+//! Labels are read back as their rendering, see [`Restored`].
+
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use smallvec::SmallVec;
+use crate::dimensions::{AbsoluteValue, ExemplarValue, Exemplar, ExpBuckets, ExpHistogramValue, find_owned, FlagValue, from_wall_time, GaugeValue, HistogramValue, InfoValue, KindValue, merge, merge_value, MeterValue, MetricKind, MetricStore, OwnedMetricName, restore_bounds, Restored, SeriesValue, SummaryValue, unix_nanos, wall_time};
+
+pub const SNAPSHOT: u8 = 0x01;
+pub const ORIGIN: u8 = 0x02;
+pub const RECORDED: u8 = 0x03;
+pub const SEQ: u8 = 0x04;
+pub const CAPTURED: u8 = 0x05;
+pub const EVENTS: u8 = 0x06;
+pub const SERIES: u8 = 0x10;
+pub const KIND: u8 = 0x11;
+pub const KEY: u8 = 0x12;
+pub const LABEL: u8 = 0x13;
+pub const LABEL_NAME: u8 = 0x14;
+pub const LABEL_HASH: u8 = 0x15;
+pub const LABEL_VALUE: u8 = 0x16;
+pub const VALUE: u8 = 0x17;
+
+/// Bytes of a record header, the tag and the length.
+pub const HEADER: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlvError {
+    /// A record or value runs past the end of the record it is in.
+    Truncated,
+    /// A required record is missing, by tag.
+    Missing(u8),
+    /// A record, by tag, holds a value it can't have.
+    Invalid(u8),
+    /// Series of the kind can't be encoded, or were encoded with a feature this build lacks.
+    Unsupported(MetricKind),
+    /// Bytes left after the snapshot record.
+    Trailing(usize),
+}
+
+impl Display for TlvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "record runs past the end of its parent"),
+            Self::Missing(tag) => write!(f, "missing record {tag:#04x}"),
+            Self::Invalid(tag) => write!(f, "invalid value of record {tag:#04x}"),
+            Self::Unsupported(kind) => write!(f, "{kind:?} series can't be encoded"),
+            Self::Trailing(len) => write!(f, "{len} bytes after the snapshot"),
+        }
+    }
+}
+
+impl std::error::Error for TlvError {}
+
+/// Appends a record of `tag`, with the value `value` writes.
+pub fn record<R>(buf: &mut Vec<u8>, tag: u8, value: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    buf.push(tag);
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    let result = value(buf);
+    let len = u32::try_from(buf.len() - start - 4).expect("records are shorter than 4GiB");
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+
+    result
+}
+
+/// Records of a value, in order.
+pub struct Records<'a>(&'a [u8]);
+
+impl<'a> Records<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    /// Bytes after the records read so far.
+    pub fn rest(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<(u8, &'a [u8]), TlvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None
+        }
+        let Some((&[tag, l0, l1, l2, l3], rest)) = self.0.split_first_chunk::<HEADER>() else {
+            self.0 = &[];
+            return Some(Err(TlvError::Truncated))
+        };
+        let len = u32::from_le_bytes([l0, l1, l2, l3]) as usize;
+        if rest.len() < len {
+            self.0 = &[];
+            return Some(Err(TlvError::Truncated))
+        }
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+
+        Some(Ok((tag, value)))
+    }
+}
+
+/// Reads the fields of a value in order.
+pub struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], TlvError> {
+        let (&field, rest) = self.0.split_first_chunk::<N>().ok_or(TlvError::Truncated)?;
+        self.0 = rest;
+        Ok(field)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, TlvError> {
+        self.take().map(u8::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, TlvError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, TlvError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    pub fn u128(&mut self) -> Result<u128, TlvError> {
+        self.take().map(u128::from_le_bytes)
+    }
+
+    pub fn i8(&mut self) -> Result<i8, TlvError> {
+        self.take().map(i8::from_le_bytes)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, TlvError> {
+        self.take().map(i32::from_le_bytes)
+    }
+
+    pub fn i64(&mut self) -> Result<i64, TlvError> {
+        self.take().map(i64::from_le_bytes)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, TlvError> {
+        self.take().map(f64::from_le_bytes)
+    }
+
+    pub fn time(&mut self) -> Result<SystemTime, TlvError> {
+        self.u64().map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+
+    fn instant(&mut self) -> Result<Instant, TlvError> {
+        self.time().map(from_wall_time)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Fails if the value of record `tag` has bytes left past its fields.
+    pub fn finish(self, tag: u8) -> Result<(), TlvError> {
+        if self.0.is_empty() { Ok(()) } else { Err(TlvError::Invalid(tag)) }
+    }
+}
+
+/// Value of record `tag` that is a single u64.
+pub fn u64(tag: u8, value: &[u8]) -> Result<u64, TlvError> {
+    value.try_into().map(u64::from_le_bytes).map_err(|_| TlvError::Invalid(tag))
+}
+
+fn text(tag: u8, value: &[u8]) -> Result<&str, TlvError> {
+    std::str::from_utf8(value).map_err(|_| TlvError::Invalid(tag))
+}
+
+pub fn put_time(buf: &mut Vec<u8>, at: SystemTime) {
+    buf.extend_from_slice(&unix_nanos(at).to_le_bytes());
+}
+
+fn put_instant(buf: &mut Vec<u8>, at: Instant) {
+    put_time(buf, wall_time(at));
+}
+
+/// Series values as laid out in the value record of a series.
+trait TlvValue: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError>;
+}
+
+impl TlvValue for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        fields.u64()
+    }
+}
+
+impl TlvValue for i64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        fields.i64()
+    }
+}
+
+impl TlvValue for GaugeValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.value.to_le_bytes());
+        put_instant(buf, self.updated);
+        buf.push(u8::from(self.relative));
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        let (value, updated) = (fields.f64()?, fields.instant()?);
+        let relative = match fields.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(TlvError::Invalid(VALUE)),
+        };
+        Ok(Self { value, updated, relative })
+    }
+}
+
+impl TlvValue for HistogramValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.bounds.len() as u32).to_le_bytes());
+        self.bounds.iter().for_each(|bound| buf.extend_from_slice(&bound.to_le_bytes()));
+        self.counts.iter().for_each(|count| buf.extend_from_slice(&count.to_le_bytes()));
+        buf.extend_from_slice(&self.sum.to_le_bytes());
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(&self.max.to_le_bytes());
+        buf.extend_from_slice(&self.mismatched.to_le_bytes());
+        buf.extend_from_slice(&self.non_finite.to_le_bytes());
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        let bounds = fields.u32()?;
+        let bounds = restore_bounds((0..bounds).map(|_| fields.f64()).collect::<Result<_, _>>()?).ok_or(TlvError::Invalid(VALUE))?;
+        Ok(Self {
+            counts: (0..=bounds.len()).map(|_| fields.u64()).collect::<Result<_, _>>()?,
+            sum: fields.f64()?,
+            count: fields.u64()?,
+            max: fields.f64()?,
+            mismatched: fields.u64()?,
+            non_finite: fields.u64()?,
+            bounds,
+        })
+    }
+}
+
+impl TlvValue for ExpBuckets {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&(self.counts.len() as u32).to_le_bytes());
+        self.counts.iter().for_each(|count| buf.extend_from_slice(&count.to_le_bytes()));
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        let offset = fields.i32()?;
+        let len = fields.u32()?;
+        Ok(Self { offset, counts: (0..len).map(|_| fields.u64()).collect::<Result<_, _>>()? })
+    }
+}
+
+impl TlvValue for ExpHistogramValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.scale.to_le_bytes());
+        buf.extend_from_slice(&self.zero_count.to_le_bytes());
+        self.positive.encode(buf);
+        self.negative.encode(buf);
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        for v in [self.sum, self.min, self.max] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.non_finite.to_le_bytes());
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        Ok(Self {
+            scale: fields.i8()?,
+            zero_count: fields.u64()?,
+            positive: ExpBuckets::decode(fields)?,
+            negative: ExpBuckets::decode(fields)?,
+            count: fields.u64()?,
+            sum: fields.f64()?,
+            min: fields.f64()?,
+            max: fields.f64()?,
+            non_finite: fields.u64()?,
+        })
+    }
+}
+
+impl TlvValue for AbsoluteValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        fields.u64().map(Self)
+    }
+}
+
+impl TlvValue for FlagValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(self.0));
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        match fields.u8()? {
+            0 => Ok(Self(false)),
+            1 => Ok(Self(true)),
+            _ => Err(TlvError::Invalid(VALUE)),
+        }
+    }
+}
+
+impl TlvValue for InfoValue {
+    fn encode(&self, _buf: &mut Vec<u8>) {}
+
+    fn decode(_fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        Ok(Self)
+    }
+}
+
+impl TlvValue for MeterValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        put_instant(buf, self.since);
+        put_instant(buf, self.until);
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        Ok(Self { count: fields.u64()?, since: fields.instant()?, until: fields.instant()? })
+    }
+}
+
+impl TlvValue for SummaryValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        for v in [self.sum, self.min, self.max] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        Ok(Self { count: fields.u64()?, sum: fields.f64()?, min: fields.f64()?, max: fields.f64()? })
+    }
+}
+
+impl TlvValue for ExemplarValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.exemplar.trace_id.to_le_bytes());
+        buf.extend_from_slice(&self.exemplar.span_id.to_le_bytes());
+        buf.extend_from_slice(&self.value.to_le_bytes());
+        put_instant(buf, self.recorded);
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        Ok(Self {
+            exemplar: Exemplar { trace_id: fields.u128()?, span_id: fields.u64()? },
+            value: fields.f64()?,
+            recorded: fields.instant()?,
+        })
+    }
+}
+
+#[cfg(feature = "hdr")]
+impl TlvValue for crate::dimensions::HdrValue {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for v in self.0.iter_recorded() {
+            buf.extend_from_slice(&v.value_iterated_to().to_le_bytes());
+            buf.extend_from_slice(&v.count_at_value().to_le_bytes());
+        }
+    }
+
+    fn decode(fields: &mut Fields<'_>) -> Result<Self, TlvError> {
+        let mut histogram = Self::new();
+        while !fields.is_empty() {
+            let (value, count) = (fields.u64()?, fields.u64()?);
+            histogram.0.record_n(value, count).map_err(|_| TlvError::Invalid(VALUE))?;
+        }
+
+        Ok(histogram)
+    }
+}
+
+fn encode_series(buf: &mut Vec<u8>, kind: MetricKind, name: &OwnedMetricName, value: impl FnOnce(&mut Vec<u8>)) {
+    record(buf, SERIES, |buf| {
+        record(buf, KIND, |buf| buf.push(kind as u8));
+        record(buf, KEY, |buf| buf.extend_from_slice(name.key.as_bytes()));
+        for (label, hash, value) in &name.labels {
+            record(buf, LABEL, |buf| {
+                record(buf, LABEL_NAME, |buf| buf.extend_from_slice(label.as_bytes()));
+                record(buf, LABEL_HASH, |buf| buf.extend_from_slice(&hash.to_le_bytes()));
+                record(buf, LABEL_VALUE, |buf| write!(buf, "{value}").unwrap());
+            });
+        }
+        record(buf, VALUE, value);
+    });
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &SeriesValue) {
+    match value {
+        SeriesValue::Counter(v) => v.encode(buf),
+        SeriesValue::UpDown(v) => v.encode(buf),
+        SeriesValue::Gauge(v) => v.encode(buf),
+        SeriesValue::Histogram(v) => v.encode(buf),
+        SeriesValue::ExpHistogram(v) => v.encode(buf),
+        SeriesValue::Absolute(v) => v.encode(buf),
+        SeriesValue::Flag(v) => v.encode(buf),
+        SeriesValue::Info(v) => v.encode(buf),
+        SeriesValue::Meter(v) => v.encode(buf),
+        SeriesValue::Summary(v) => v.encode(buf),
+        #[cfg(feature = "hdr")]
+        SeriesValue::Hdr(v) => v.encode(buf),
+        SeriesValue::Sketch(_) | SeriesValue::Unique(_) => unreachable!("{:?} series are rejected before encoding", value.kind()),
+    }
+}
+
+fn decode_value<V: TlvValue>(value: &[u8]) -> Result<V, TlvError> {
+    let mut fields = Fields::new(value);
+    let value = V::decode(&mut fields)?;
+    fields.finish(VALUE)?;
+
+    Ok(value)
+}
+
+fn decode_series<V: TlvValue + KindValue>(value: &[u8]) -> Result<SeriesValue, TlvError> {
+    decode_value::<V>(value).map(V::into_series)
+}
+
+impl MetricStore {
+    /// Appends a series record for every series, see [`tlv`](self) for the layout. Fails
+    /// without writing anything if the store has series that can't be encoded.
+    pub fn encode_tlv(&self, buf: &mut Vec<u8>) -> Result<(), TlvError> {
+        if let Some(name) = self.series.keys().find(|name| matches!(name.kind, MetricKind::Sketch | MetricKind::Unique)) {
+            return Err(TlvError::Unsupported(name.kind))
+        }
+        for (name, value) in &self.series {
+            encode_series(buf, name.kind, name, |buf| encode_value(buf, value));
+        }
+        for (name, value) in &self.exemplars {
+            encode_series(buf, MetricKind::Exemplar, name, |buf| {
+                buf.push(name.kind as u8);
+                value.encode(buf);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Adds the series of a series record's value, merging it with the series of the same
+    /// name if there is one. Fails on values recording can't produce, and if that series has a
+    /// different shape, e.g. other histogram bounds. Names and label values are shared with the other series of the snapshot
+    /// through `restored`.
+    pub fn decode_tlv_series(&mut self, series: &[u8], restored: &mut Restored) -> Result<(), TlvError> {
+        let (mut kind, mut key, mut labels, mut value) = (None, None, SmallVec::new(), None);
+        for record in Records::new(series) {
+            match record? {
+                (KIND, &[kind_at]) => {
+                    kind = Some(*MetricKind::ALL.get(usize::from(kind_at)).ok_or(TlvError::Invalid(KIND))?);
+                }
+                (KIND, _) => return Err(TlvError::Invalid(KIND)),
+                (KEY, key_at) => key = Some(text(KEY, key_at)?),
+                (LABEL, label) => {
+                    let (mut name, mut hash, mut value) = (None, None, None);
+                    for record in Records::new(label) {
+                        match record? {
+                            (LABEL_NAME, name_at) => name = Some(text(LABEL_NAME, name_at)?),
+                            (LABEL_HASH, hash_at) => hash = Some(u64(LABEL_HASH, hash_at)?),
+                            (LABEL_VALUE, value_at) => value = Some(text(LABEL_VALUE, value_at)?),
+                            _ => {}
+                        }
+                    }
+                    labels.push(restored.label(
+                        name.ok_or(TlvError::Missing(LABEL_NAME))?,
+                        hash.ok_or(TlvError::Missing(LABEL_HASH))?,
+                        value.ok_or(TlvError::Missing(LABEL_VALUE))?,
+                    ));
+                }
+                (VALUE, value_at) => value = Some(value_at),
+                _ => {}
+            }
+        }
+        let kind = kind.ok_or(TlvError::Missing(KIND))?;
+        let mut name = OwnedMetricName { key: restored.name(key.ok_or(TlvError::Missing(KEY))?), kind, labels };
+        let value = value.ok_or(TlvError::Missing(VALUE))?;
+        let value = match kind {
+            MetricKind::Counter => decode_series::<u64>(value)?,
+            MetricKind::UpDown => decode_series::<i64>(value)?,
+            MetricKind::Gauge => decode_series::<GaugeValue>(value)?,
+            MetricKind::Histogram => decode_series::<HistogramValue>(value)?,
+            MetricKind::ExpHistogram => decode_series::<ExpHistogramValue>(value)?,
+            MetricKind::Absolute => decode_series::<AbsoluteValue>(value)?,
+            MetricKind::Flag => decode_series::<FlagValue>(value)?,
+            MetricKind::Info => decode_series::<InfoValue>(value)?,
+            MetricKind::Meter => decode_series::<MeterValue>(value)?,
+            MetricKind::Summary => decode_series::<SummaryValue>(value)?,
+            MetricKind::Exemplar => {
+                // exemplars are keyed by the kind of their series
+                let (&series_kind, value) = value.split_first().ok_or(TlvError::Truncated)?;
+                name.kind = *MetricKind::ALL.get(usize::from(series_kind)).filter(|&&kind| kind != MetricKind::Exemplar).ok_or(TlvError::Invalid(VALUE))?;
+                merge(&mut self.exemplars, [(name, decode_value::<ExemplarValue>(value)?)], None, None, merge_value);
+                return Ok(())
+            }
+            #[cfg(feature = "hdr")]
+            MetricKind::Hdr => decode_series::<crate::dimensions::HdrValue>(value)?,
+            #[cfg(not(feature = "hdr"))]
+            MetricKind::Hdr => return Err(TlvError::Unsupported(kind)),
+            MetricKind::Sketch | MetricKind::Unique => return Err(TlvError::Unsupported(kind)),
+        };
+        if !value.is_valid() || find_owned(&self.series, &name).is_some_and(|existing| !existing.fits(&value)) {
+            return Err(TlvError::Invalid(VALUE))
+        }
+        merge(&mut self.series, [(name, value)], None, None, self.overflow.combine());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use crate::dimensions::{Exemplar, ExpHistogramValue, GaugeUpdate, HelperIdentity, HistogramValue, KEYS, MetricKind, MetricName, MetricStore, SeriesId};
+    use crate::dimensions::tlv::{self, TlvError};
+    use crate::ids::{Identity, Ulid};
+    use crate::metrics::{Counter, Records, Snapshot};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn snapshots_round_trip() {
+        let _heap = shared_heap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 443));
+        let sent = MetricName::from(("sent", ("helper", &HelperIdentity::H2), ("peer", &peer), ("step", &SeriesId(3))));
+        let mut snapshot = Snapshot::new();
+        snapshot.set_origin(Some(Identity { run_id: Ulid::from_parts(1, 2), process_id: Ulid::from_parts(3, 4) }));
+        snapshot.record(Counter("plain", 2));
+        let store = snapshot.store_mut();
+        store.update(&sent, 5);
+        store.update_up_down(&sent, -3);
+        store.update_gauge(&sent, GaugeUpdate::Set(1.5), Instant::now());
+        store.update_histogram(&MetricName::with_no_labels("custom"), &[1.0, 2.0], 1.5);
+        store.update_histogram(&MetricName::with_no_labels("custom"), &[1.0, 2.0], f64::NAN);
+        store.update_exp_histogram(&sent, -4.0);
+        store.update_absolute(&sent, 9);
+        store.update_flag(&sent, true);
+        store.update_info("build", &[("version", "1.0")]);
+        store.update_meter(&sent, 7, Instant::now() - Duration::from_secs(1));
+        store.update_summary(&sent, 2.5);
+        store.update_exemplar(&sent, MetricKind::Counter, Exemplar { trace_id: u128::MAX, span_id: 1 }, 5.0, Instant::now());
+        #[cfg(feature = "hdr")]
+        store.update_hdr(&sent, 1_234);
+        let original = snapshot.take();
+
+        let mut buf = Vec::new();
+        original.encode_tlv(&mut buf).unwrap();
+        let decoded = Snapshot::decode_tlv(&buf).unwrap();
+        assert_eq!((original.origin(), original.seq(), original.captured(), original.recorded(), original.is_empty()),
+                   (decoded.origin(), decoded.seq(), decoded.captured(), decoded.recorded(), decoded.is_empty()));
+        assert_eq!(original.store().to_string(), decoded.store().to_string());
+        let custom = MetricName::with_no_labels("custom");
+        assert_eq!(Some(1), decoded.store().get_histogram(&custom).map(HistogramValue::non_finite));
+        // decoded labels name the same series as the values they were rendered from
+        let mut merged = original.store().clone();
+        merged.merge_ref(decoded.store());
+        assert_eq!(original.store().len(), merged.len());
+        assert_eq!(Some(10), merged.get_counter(&sent));
+
+        assert_eq!(Err(TlvError::Truncated), Snapshot::decode_tlv(&buf[..buf.len() - 1]).map(|_| ()));
+        buf.push(0);
+        assert_eq!(Err(TlvError::Trailing(1)), Snapshot::decode_tlv(&buf).map(|_| ()));
+    }
+
+    #[test]
+    fn skips_unknown_records_and_rejects_sketches() {
+        let _heap = shared_heap();
+        let mut buf = Vec::new();
+        tlv::record(&mut buf, tlv::SNAPSHOT, |buf| {
+            tlv::record(buf, 0x7f, |buf| buf.extend_from_slice(b"from a newer writer"));
+            tlv::record(buf, tlv::SEQ, |buf| buf.extend_from_slice(&7_u64.to_le_bytes()));
+        });
+        assert_eq!(7, Snapshot::decode_tlv(&buf).unwrap().seq());
+
+        let mut snapshot = Snapshot::new();
+        snapshot.store_mut().update_sketch(&MetricName::with_no_labels("latency"), 1.0);
+        let mut buf = vec![1, 2, 3];
+        assert_eq!(Err(TlvError::Unsupported(MetricKind::Sketch)), snapshot.encode_tlv(&mut buf));
+        assert_eq!(vec![1, 2, 3], buf);
+    }
+
+    #[test]
+    fn rejects_values_that_would_not_merge() {
+        let _heap = shared_heap();
+        let snapshot = |series: &[u8]| {
+            let mut buf = Vec::new();
+            tlv::record(&mut buf, tlv::SNAPSHOT, |buf| buf.extend_from_slice(series));
+            Snapshot::decode_tlv(&buf).map(|_| ())
+        };
+        let name = MetricName::with_no_labels("decoded_latency");
+        let mut series = Vec::new();
+        for bounds in [&[1.0][..], &[2.0]] {
+            let mut store = MetricStore::default();
+            store.update_histogram(&name, bounds, 1.0);
+            store.encode_tlv(&mut series).unwrap();
+        }
+        assert_eq!(Err(TlvError::Invalid(tlv::VALUE)), snapshot(&series));
+        // decoded names are freed with the snapshot rather than leaked
+        assert!(!KEYS.read().unwrap().contains("decoded_latency"));
+
+        let exp_histogram = |scale: i8, offset: i32| {
+            let mut series = Vec::new();
+            tlv::record(&mut series, tlv::SERIES, |buf| {
+                tlv::record(buf, tlv::KIND, |buf| buf.push(MetricKind::ExpHistogram as u8));
+                tlv::record(buf, tlv::KEY, |buf| buf.extend_from_slice(b"decoded_sizes"));
+                tlv::record(buf, tlv::VALUE, |buf| {
+                    buf.extend_from_slice(&scale.to_le_bytes());
+                    buf.extend_from_slice(&0_u64.to_le_bytes());
+                    for (offset, counts) in [(offset, &[1_u64][..]), (0, &[])] {
+                        buf.extend_from_slice(&offset.to_le_bytes());
+                        buf.extend_from_slice(&(counts.len() as u32).to_le_bytes());
+                        counts.iter().for_each(|count| buf.extend_from_slice(&count.to_le_bytes()));
+                    }
+                    buf.extend_from_slice(&1_u64.to_le_bytes());
+                    [1.0_f64; 3].iter().for_each(|v| buf.extend_from_slice(&v.to_le_bytes()));
+                    buf.extend_from_slice(&0_u64.to_le_bytes());
+                });
+            });
+            series
+        };
+        assert_eq!(Ok(()), snapshot(&exp_histogram(0, 0)));
+        assert_eq!(Err(TlvError::Invalid(tlv::VALUE)), snapshot(&exp_histogram(i8::MIN, 0)));
+        assert_eq!(Err(TlvError::Invalid(tlv::VALUE)), snapshot(&exp_histogram(ExpHistogramValue::MAX_SCALE + 1, 0)));
+        assert_eq!(Err(TlvError::Invalid(tlv::VALUE)), snapshot(&exp_histogram(0, 1 << 20)));
+    }
+
+    #[test]
+    fn saturates_repeated_series_with_max_counts() {
+        type Value<'a> = &'a dyn Fn(&mut Vec<u8>);
+        let _heap = shared_heap();
+        let max = u64::MAX.to_le_bytes();
+        let floats = |buf: &mut Vec<u8>, values: &[f64]| values.iter().for_each(|v| buf.extend_from_slice(&v.to_le_bytes()));
+        let series = |buf: &mut Vec<u8>, kind: MetricKind, value: Value<'_>| tlv::record(buf, tlv::SERIES, |buf| {
+            tlv::record(buf, tlv::KIND, |buf| buf.push(kind as u8));
+            tlv::record(buf, tlv::KEY, |buf| buf.extend_from_slice(b"saturated"));
+            tlv::record(buf, tlv::VALUE, value);
+        });
+        let summary = |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(&max);
+            floats(buf, &[1.0; 3]);
+        };
+        let meter = |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(&max);
+            tlv::put_time(buf, std::time::SystemTime::now());
+            tlv::put_time(buf, std::time::SystemTime::now());
+        };
+        let histogram = |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(&1_u32.to_le_bytes());
+            floats(buf, &[1.0]);
+            [max, max].iter().for_each(|count| buf.extend_from_slice(count));
+            floats(buf, &[1.0]);
+            buf.extend_from_slice(&max);
+            floats(buf, &[1.0]);
+            [max, max].iter().for_each(|count| buf.extend_from_slice(count));
+        };
+        let exp_histogram = |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(&0_i8.to_le_bytes());
+            buf.extend_from_slice(&max);
+            for counts in [&[u64::MAX][..], &[]] {
+                buf.extend_from_slice(&0_i32.to_le_bytes());
+                buf.extend_from_slice(&(counts.len() as u32).to_le_bytes());
+                counts.iter().for_each(|count| buf.extend_from_slice(&count.to_le_bytes()));
+            }
+            buf.extend_from_slice(&max);
+            floats(buf, &[1.0; 3]);
+            buf.extend_from_slice(&max);
+        };
+        let mut buf = Vec::new();
+        tlv::record(&mut buf, tlv::SNAPSHOT, |buf| {
+            tlv::record(buf, tlv::RECORDED, |buf| buf.extend_from_slice(&max));
+            tlv::record(buf, tlv::SEQ, |buf| buf.extend_from_slice(&max));
+            tlv::record(buf, tlv::EVENTS, |buf| buf.extend_from_slice(&max));
+            let values: [(MetricKind, Value<'_>); 4] = [(MetricKind::Summary, &summary), (MetricKind::Meter, &meter), (MetricKind::Histogram, &histogram), (MetricKind::ExpHistogram, &exp_histogram)];
+            for (kind, value) in values {
+                series(buf, kind, value);
+                series(buf, kind, value);
+            }
+        });
+
+        let mut decoded = Snapshot::decode_tlv(&buf).unwrap();
+        let name = MetricName::with_no_labels("saturated");
+        let store = decoded.store();
+        assert_eq!(Some(u64::MAX), store.get_summary(&name).map(|summary| summary.count));
+        assert_eq!(Some(u64::MAX), store.get_meter(&name).map(|meter| meter.count()));
+        assert_eq!(Some(u64::MAX), store.get_histogram(&name).map(HistogramValue::count));
+        assert_eq!(Some(u64::MAX), store.get_exp_histogram(&name).map(ExpHistogramValue::count));
+        // events past the recorded total don't wrap it
+        let again = Snapshot::decode_tlv(&buf).unwrap();
+        decoded.merge_recorded(again);
+        assert_eq!(u64::MAX, decoded.take().recorded());
+    }
+}
@@ -1,14 +1,17 @@
 use metrics::counter;
+use crate::work::Work;
 
 pub const KEY: &str = "metric";
 
-pub async fn do_work_async() {
-    loop {
-        let mut iter = 0;
-        counter!(KEY).increment(1);
+pub async fn do_work_async(work: Work) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
+        counter!(KEY).increment(values.next());
 
         iter += 1;
-        if iter % 100 == 0 {
+        if iter.is_multiple_of(100) {
             tokio::task::yield_now().await
         }
     }
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Flush requests of every [`MetricsContext`] connected with background flushing. Contexts
+/// live in thread-locals that other threads can't reach, so a request only raises a flag the
+/// owning thread checks on its next recording. Contexts of threads that exited drop their
+/// mailbox, and are pruned on the next round.
+///
+/// [`MetricsContext`]: crate::metrics::MetricsContext
+static MAILBOXES: Mutex<Vec<Weak<Mailbox>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    flush_requested: AtomicBool,
+}
+
+impl Mailbox {
+    /// Whether a flush was requested since the last call. A load on the common path, the flag
+    /// is only written when it is set.
+    #[inline]
+    pub fn take_request(&self) -> bool {
+        self.flush_requested.load(Ordering::Relaxed) && self.flush_requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Mailbox of a newly connected context, reached by every [`BackgroundFlusher`].
+pub fn register() -> Arc<Mailbox> {
+    let mailbox = Arc::new(Mailbox::default());
+    MAILBOXES.lock().unwrap_or_else(PoisonError::into_inner).push(Arc::downgrade(&mailbox));
+
+    mailbox
+}
+
+/// Asks every live context to flush on its next recording, returning how many there are.
+pub fn request_flush() -> usize {
+    let mut mailboxes = MAILBOXES.lock().unwrap_or_else(PoisonError::into_inner);
+    mailboxes.retain(|mailbox| match mailbox.upgrade() {
+        Some(mailbox) => {
+            mailbox.flush_requested.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    });
+
+    mailboxes.len()
+}
+
+/// Thread that asks every context for its snapshot each `interval`, so threads that record
+/// all the time and never park still flush. Stops when dropped.
+pub struct BackgroundFlusher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundFlusher {
+    pub fn start(interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("metrics-flusher".into())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || while !stop.load(Ordering::Relaxed) {
+                    thread::park_timeout(interval);
+                    request_flush();
+                }
+            })
+            .unwrap();
+
+        Self { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for BackgroundFlusher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crossbeam::channel::unbounded;
+    use crate::flusher::BackgroundFlusher;
+    use crate::metrics::{Counter, METRICS_CTX, MetricsContextConfig, Snapshot};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn busy_thread_flushes_on_request() {
+        let _heap = shared_heap();
+        let (tx, rx) = unbounded::<Snapshot>();
+        let _flusher = BackgroundFlusher::start(Duration::from_millis(5));
+        thread::spawn(move || {
+            let config = MetricsContextConfig { flush_every_n: usize::MAX, background_flush: true, ..Default::default() };
+            METRICS_CTX.with(|m| m.connect_with(tx, config));
+            // never reaches the flush threshold, and never parks
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(200) {
+                METRICS_CTX.with(|m| m.increment(Counter("busy", 1)));
+            }
+        }).join().unwrap();
+
+        assert!(rx.try_iter().filter_map(|s| s.get_all_dims("busy")).count() > 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use crate::aggregator::Aggregator;
+use crate::transport::SnapshotReceiver;
+
+/// Aggregating end of the pipeline for applications that embed the metrics engine without an
+/// exporter, e.g. batch jobs. Snapshots are only merged when [`Self::collect`] is called, or
+/// when the handle is dropped.
+///
+/// With [`Self::report_on_drop`], a summary of every series is written to stderr when the handle
+/// goes away, typically at process exit. Worker threads flush their last snapshot when they stop,
+/// so the handle should outlive the runtime: declare it before the runtime, or drop the runtime
+/// first.
+pub struct MetricsHandle {
+    rx: SnapshotReceiver,
+    aggregator: Aggregator,
+    report_on_drop: bool,
+}
+
+impl MetricsHandle {
+    pub fn new(rx: SnapshotReceiver) -> Self {
+        Self::with_aggregator(rx, Aggregator::new())
+    }
+
+    /// Takes over `aggregator`, e.g. one configured with processors or a cardinality limit, or
+    /// one that already merged part of the run.
+    pub fn with_aggregator(rx: SnapshotReceiver, aggregator: Aggregator) -> Self {
+        Self {
+            rx,
+            aggregator,
+            report_on_drop: false,
+        }
+    }
+
+    pub fn report_on_drop(mut self) -> Self {
+        self.report_on_drop = true;
+        self
+    }
+
+    /// Merges every snapshot that has been sent so far, without waiting for more.
+    pub fn collect(&mut self) -> &Aggregator {
+        while let Ok(flushed) = self.rx.recv_timeout(Some(Duration::ZERO)) {
+            self.aggregator.merge_flushed(flushed);
+        }
+
+        &self.aggregator
+    }
+
+    pub fn aggregator(&self) -> &Aggregator {
+        &self.aggregator
+    }
+}
+
+impl Drop for MetricsHandle {
+    fn drop(&mut self) {
+        if self.report_on_drop {
+            let summary = self.collect().total().store().to_string();
+            eprintln!("metrics summary:\n{summary}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dimensions::{HelperIdentity, MetricName};
+    use crate::handle::MetricsHandle;
+    use crate::metrics::{Flag, Gauge, Info, OneDimensionCounter, Records, Snapshot};
+    use crate::transport::snapshot_channel;
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn collects_pending_snapshots() {
+        let _heap = shared_heap();
+        let (tx, rx) = snapshot_channel(false);
+        let mut handle = MetricsHandle::new(rx);
+
+        let mut snapshot = Snapshot::new();
+        snapshot.record(OneDimensionCounter("requests", HelperIdentity::H2, 3));
+        snapshot.record(Gauge::set(MetricName::with_no_labels("queue_depth"), 5.0));
+        tx.send(snapshot.take());
+        snapshot.record(Flag(MetricName::with_no_labels("fallback"), false));
+        tx.send(snapshot.take());
+        snapshot.record(Flag::set(MetricName::with_no_labels("fallback")));
+        snapshot.record(Info("build_info", &[("version", "1.2.3")]));
+        tx.send(snapshot);
+
+        let total = handle.collect().total().store();
+        assert_eq!("build_info{version=1.2.3} 1\nfallback 1 (flag)\nqueue_depth 5 (gauge)\nrequests{dest=H2} 3", total.to_string());
+    }
+}
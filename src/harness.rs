@@ -0,0 +1,389 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use crossbeam::channel::RecvTimeoutError;
+use tokio::runtime::Runtime;
+use crate::aggregator::Aggregator;
+use crate::metrics::{KEY, Snapshot};
+use crate::transport::SnapshotReceiver;
+use crate::trace;
+use crate::work::Work;
+
+/// Time source of the aggregation loop, so interval reporting can be tested without sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A run ends once the benchmark metric reaches `max_val`, or early if `interrupted` is raised.
+#[derive(Copy, Clone, Debug)]
+pub struct Termination<'a> {
+    pub max_val: u64,
+    pub interrupted: &'a AtomicBool,
+}
+
+impl Termination<'_> {
+    pub fn reached(&self, total: u64) -> bool {
+        total >= self.max_val
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+}
+
+/// Signals the thread waiting for the run to end once a producer sees the benchmark metric
+/// reach the target, so it can sleep instead of polling. Created on the waiting thread.
+#[derive(Debug)]
+pub struct Milestone {
+    target: u64,
+    reached: AtomicBool,
+    observer: Thread,
+}
+
+impl Milestone {
+    pub fn new(target: u64) -> Self {
+        Self { target, reached: AtomicBool::new(false), observer: thread::current() }
+    }
+
+    /// Producers call this with the total they observed after an increment. It costs a
+    /// comparison until the target is reached.
+    #[inline]
+    pub fn progress(&self, total: u64) {
+        if total >= self.target {
+            self.reach();
+        }
+    }
+
+    #[cold]
+    fn reach(&self) {
+        if !self.reached.swap(true, Ordering::Relaxed) {
+            self.observer.unpark();
+        }
+    }
+}
+
+/// Pause between reads of a shared total: spins first, as the target is often only a few
+/// reads away, then parks for exponentially longer, up to [`Self::MAX_PARK`]. Parking ends
+/// early when a [`Milestone`] is reached, so modes that signal one don't pay for the cap.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    const SPINS: u32 = 64;
+    /// Longest sleep between reads, which bounds the error of elapsed time in modes that
+    /// don't signal a milestone, and how late interrupts are noticed.
+    pub const MAX_PARK: Duration = Duration::from_millis(1);
+
+    pub fn pause(&mut self) {
+        if self.step < Self::SPINS {
+            std::hint::spin_loop();
+        } else {
+            let park = Duration::from_micros(1 << (self.step - Self::SPINS).min(10));
+            thread::park_timeout(park.min(Self::MAX_PARK));
+        }
+        self.step = self.step.saturating_add(1);
+    }
+}
+
+/// Reads `total` until the run terminates, calling `pause` between reads. Returns the last
+/// value read. Used by modes that expose the benchmark metric as a single shared value.
+pub fn poll(termination: Termination<'_>, mut total: impl FnMut() -> u64, mut pause: impl FnMut()) -> u64 {
+    loop {
+        let value = {
+            let _span = trace::span("read");
+            total()
+        };
+        if termination.reached(value) || termination.interrupted() {
+            return value
+        }
+        pause();
+    }
+}
+
+/// Runs `f`, returning its result and how long it took in nanoseconds.
+pub fn timed<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed().as_nanos() as u64)
+}
+
+/// Aggregation loop of the TLV modes.
+pub struct Collector<'a, C = SystemClock> {
+    pub termination: Termination<'a>,
+    /// Completed intervals are reported at this period. The aggregator must track interval
+    /// deltas, see [`Aggregator::with_interval_deltas`].
+    pub report_interval: Option<Duration>,
+    /// Longest time to wait for a snapshot before checking for interrupts again.
+    pub poll: Duration,
+    pub clock: C,
+}
+
+impl<C: Clock> Collector<'_, C> {
+    /// Merges snapshots from `rx` until the run terminates or every producer is gone, passing
+    /// the aggregator to `report` after every completed interval. When interrupted,
+    /// snapshots already in the channel are merged before returning, so partial results
+    /// include everything workers managed to flush.
+    pub fn run(&self, rx: &SnapshotReceiver, aggregator: &mut Aggregator, mut report: impl FnMut(&Aggregator)) {
+        let mut interval_start = self.clock.now();
+        loop {
+            let timeout = self.report_interval
+                .map(|interval| interval.saturating_sub(self.clock.now().duration_since(interval_start)))
+                .map_or(self.poll, |timeout| timeout.min(self.poll));
+            match rx.recv_timeout(Some(timeout)) {
+                Ok(flushed) => aggregator.merge_flushed(flushed),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if self.report_interval.is_some_and(|interval| self.clock.now().duration_since(interval_start) >= interval) {
+                aggregator.rotate_interval();
+                report(aggregator);
+                interval_start = self.clock.now();
+            }
+            let total = {
+                let _span = trace::span("read");
+                aggregator.get_all_dims(KEY).unwrap_or_default()
+            };
+            if self.termination.reached(total) {
+                break
+            }
+            if self.termination.interrupted() {
+                merge_pending(rx, aggregator);
+                break
+            }
+        }
+    }
+}
+
+/// Merges every snapshot that is already in the channel, without waiting for more.
+pub fn merge_pending(rx: &SnapshotReceiver, aggregator: &mut Aggregator) {
+    while let Ok(flushed) = rx.recv_timeout(Some(Duration::ZERO)) {
+        aggregator.merge_flushed(flushed);
+    }
+}
+
+/// Why [`drain`] can't vouch for its total.
+#[derive(Debug, PartialEq)]
+pub enum DrainError {
+    /// Producers were still connected when the timeout expired, e.g. a task that never returns.
+    TimedOut(Duration),
+    /// A bounded channel dropped snapshots during the run.
+    Dropped(u64),
+}
+
+impl Display for DrainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TimedOut(timeout) => write!(f, "producers still connected after {timeout:?}"),
+            Self::Dropped(dropped) => write!(f, "{dropped} snapshots dropped by a full channel"),
+        }
+    }
+}
+
+impl std::error::Error for DrainError {}
+
+/// Stops the workloads of `work`, shuts `rt` down and merges everything its threads flush on the
+/// way out. Contexts hold their sender until the thread exits, after the final flush of
+/// `on_thread_stop`, so once `rx` disconnects every recording is in the returned total. The
+/// caller must not hold a sender itself. Fails if producers are still around after `timeout`.
+pub fn drain<'a>(rt: Runtime, work: &Work, rx: &SnapshotReceiver, aggregator: &'a mut Aggregator, timeout: Duration) -> Result<&'a Snapshot, DrainError> {
+    let _span = trace::span("drain");
+    let deadline = Instant::now() + timeout;
+    work.stop();
+    rt.shutdown_timeout(timeout);
+    loop {
+        match rx.recv_timeout(Some(deadline.saturating_duration_since(Instant::now()))) {
+            Ok(flushed) => aggregator.merge_flushed(flushed),
+            Err(RecvTimeoutError::Timeout) => return Err(DrainError::TimedOut(timeout)),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    match rx.losses() {
+        Some(losses) if losses.dropped > 0 => Err(DrainError::Dropped(losses.dropped)),
+        _ => Ok(aggregator.total()),
+    }
+}
+
+/// Aggregator throughput of a `transfer` run. Every synthetic snapshot adds `series` to the
+/// benchmark metric, which gives away how many snapshots were merged.
+#[derive(Debug, PartialEq)]
+pub struct TransferThroughput {
+    pub series: usize,
+    pub snapshots: u64,
+    pub per_sec: f64,
+}
+
+impl TransferThroughput {
+    pub fn new(metric: u64, series: usize, elapsed: Duration) -> Self {
+        let snapshots = metric / series.max(1) as u64;
+        Self { series, snapshots, per_sec: snapshots as f64 / elapsed.as_secs_f64() }
+    }
+}
+
+impl Display for TransferThroughput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "merged {} snapshots of {} series, {:.0} snapshots/s", self.snapshots, self.series, self.per_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::aggregator::Aggregator;
+    use crate::harness::{Backoff, Clock, Collector, drain, Milestone, poll, Termination, TransferThroughput};
+    use crate::hooks::{ParkFlush, ThreadHooks};
+    use crate::metrics::{Counter, KEY, METRICS_CTX, Records, Snapshot};
+    use crate::transport::{loopback, snapshot_channel};
+    use crate::test_utils::shared_heap;
+    use crate::work::Work;
+
+    /// Moves forward by `step` every time it is read.
+    struct StepClock {
+        now: Cell<Instant>,
+        step: Duration,
+    }
+
+    impl Clock for StepClock {
+        fn now(&self) -> Instant {
+            let now = self.now.get();
+            self.now.set(now + self.step);
+            now
+        }
+    }
+
+    fn snapshot(value: u64) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.record(Counter(KEY, value));
+        snapshot
+    }
+
+    fn collector(termination: Termination<'_>, report_interval: Option<Duration>) -> Collector<'_, StepClock> {
+        Collector {
+            termination,
+            report_interval,
+            poll: Duration::from_millis(10),
+            clock: StepClock { now: Cell::new(Instant::now()), step: Duration::from_secs(1) },
+        }
+    }
+
+    #[test]
+    fn poll_until_target() {
+        let interrupted = AtomicBool::new(false);
+        let termination = Termination { max_val: 10, interrupted: &interrupted };
+        let (mut total, mut pauses) = (0, 0);
+        assert_eq!(12, poll(termination, || { total += 3; total }, || pauses += 1));
+        assert_eq!(3, pauses);
+
+        interrupted.store(true, Ordering::Relaxed);
+        assert_eq!(15, poll(termination, || { total += 3; total }, || pauses += 1));
+        assert_eq!(3, pauses);
+    }
+
+    #[test]
+    fn milestone_wakes_observer() {
+        let interrupted = AtomicBool::new(false);
+        let termination = Termination { max_val: 100, interrupted: &interrupted };
+        let milestone = Arc::new(Milestone::new(100));
+        let total = Arc::new(AtomicU64::new(0));
+        let producer = thread::spawn({
+            let (milestone, total) = (Arc::clone(&milestone), Arc::clone(&total));
+            move || for _ in 0..100 {
+                milestone.progress(total.fetch_add(1, Ordering::Relaxed) + 1);
+            }
+        });
+
+        let mut backoff = Backoff::default();
+        assert_eq!(100, poll(termination, || total.load(Ordering::Relaxed), || backoff.pause()));
+        producer.join().unwrap();
+        assert!(milestone.reached.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn stops_at_target() {
+        let _heap = shared_heap();
+        let interrupted = AtomicBool::new(false);
+        let (tx, rx) = loopback();
+        for _ in 0..3 {
+            tx.send(snapshot(6));
+        }
+
+        let mut aggregator = Aggregator::new();
+        collector(Termination { max_val: 10, interrupted: &interrupted }, None).run(&rx, &mut aggregator, |_| {});
+        assert_eq!(Some(12), aggregator.get_all_dims(KEY));
+        assert!(rx.recv_timeout(Some(Duration::ZERO)).is_ok());
+    }
+
+    #[test]
+    fn drains_when_interrupted() {
+        let _heap = shared_heap();
+        let interrupted = AtomicBool::new(true);
+        let (tx, rx) = snapshot_channel(true);
+        for _ in 0..3 {
+            tx.send(snapshot(6));
+        }
+
+        let mut aggregator = Aggregator::new();
+        collector(Termination { max_val: u64::MAX, interrupted: &interrupted }, None).run(&rx, &mut aggregator, |_| {});
+        assert_eq!(Some(18), aggregator.get_all_dims(KEY));
+    }
+
+    #[test]
+    fn reports_intervals_until_disconnected() {
+        let _heap = shared_heap();
+        let interrupted = AtomicBool::new(false);
+        let (tx, rx) = loopback();
+        tx.send(snapshot(5));
+        tx.send(snapshot(7));
+        drop(tx);
+
+        let mut aggregator = Aggregator::with_interval_deltas();
+        let mut reports = Vec::new();
+        collector(Termination { max_val: u64::MAX, interrupted: &interrupted }, Some(Duration::from_secs(1)))
+            .run(&rx, &mut aggregator, |aggregator| reports.push(aggregator.delta_all_dims(KEY)));
+        assert_eq!(vec![Some(5), Some(7)], reports);
+        assert_eq!(Some(12), aggregator.get_all_dims(KEY));
+    }
+
+    #[test]
+    fn drain_merges_final_flushes() {
+        let _heap = shared_heap();
+        let (tx, rx) = snapshot_channel(false);
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(2).enable_all();
+        // nothing is flushed before the threads stop
+        ThreadHooks::new().flush_every(usize::MAX).park_flush(ParkFlush::Never).install(&mut builder, tx);
+        let rt = builder.build().unwrap();
+        drop(builder);
+        rt.block_on(async {
+            for _ in 0..10 {
+                rt.spawn(async { METRICS_CTX.with(|m| m.increment(Counter(KEY, 3))) }).await.unwrap();
+            }
+        });
+
+        let mut aggregator = Aggregator::new();
+        let total = drain(rt, &Work::default(), &rx, &mut aggregator, Duration::from_secs(10)).unwrap();
+        assert_eq!(Some(30), total.get_all_dims(KEY));
+    }
+
+    #[test]
+    fn transfer_throughput() {
+        let throughput = TransferThroughput::new(1_000, 100, Duration::from_millis(500));
+        assert_eq!(TransferThroughput { series: 100, snapshots: 10, per_sec: 20.0 }, throughput);
+        assert_eq!("merged 10 snapshots of 100 series, 20 snapshots/s", throughput.to_string());
+    }
+}
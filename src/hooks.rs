@@ -0,0 +1,233 @@
+use std::cell::Cell;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use crate::dimensions::LabelFilter;
+use crate::ids::Identity;
+use crate::metrics::{METRICS_CTX, MetricsContextConfig, TimeSlice};
+use crate::transport::SnapshotSender;
+use crate::trace;
+
+/// What a worker does with its snapshot when it runs out of tasks and parks.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParkFlush {
+    /// Send whatever was recorded, so idle workers don't sit on their metrics.
+    #[default]
+    Always,
+    /// Keep recording into the same snapshot. It is sent once full, when the time slice
+    /// elapses, or when the worker stops.
+    Never,
+    /// Send on every Nth park of the worker only, so the cost of flushing on busy runtimes
+    /// that park constantly can be measured and tuned.
+    Every(NonZeroU32),
+}
+
+impl ParkFlush {
+    /// Flushes on every `n`th park, never if `n` is 0.
+    pub fn every(n: u32) -> Self {
+        match NonZeroU32::new(n) {
+            None => Self::Never,
+            Some(n) if n.get() == 1 => Self::Always,
+            Some(n) => Self::Every(n),
+        }
+    }
+}
+
+thread_local! {
+    static PARKS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Counts a park of this thread, returning `true` on every `n`th one.
+fn park_due(n: NonZeroU32) -> bool {
+    PARKS.with(|parks| {
+        let next = parks.get() + 1;
+        let due = next == n.get();
+        parks.set(if due { 0 } else { next });
+        due
+    })
+}
+
+/// Connects every worker thread of a Tokio runtime to the snapshot channel and flushes what
+/// the thread recorded on park and on stop.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ThreadHooks {
+    origin: Option<Identity>,
+    label_filter: Option<&'static LabelFilter>,
+    config: MetricsContextConfig,
+    park_flush: ParkFlush,
+}
+
+impl ThreadHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn origin(mut self, origin: Identity) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    pub fn label_filter(mut self, filter: Option<&'static LabelFilter>) -> Self {
+        self.label_filter = filter;
+        self
+    }
+
+    pub fn time_slice(mut self, time_slice: Option<TimeSlice>) -> Self {
+        self.config.time_slice = time_slice;
+        self
+    }
+
+    /// Flushes every thread's snapshot once its oldest recording is `max_staleness` old, see
+    /// [`MetricsContextConfig`].
+    pub fn max_staleness(mut self, max_staleness: Option<Duration>) -> Self {
+        self.config.max_staleness = max_staleness;
+        self
+    }
+
+    /// Lets a [`BackgroundFlusher`] ask every thread for its snapshot.
+    ///
+    /// [`BackgroundFlusher`]: crate::flusher::BackgroundFlusher
+    pub fn background_flush(mut self, enabled: bool) -> Self {
+        self.config.background_flush = enabled;
+        self
+    }
+
+    /// Flushes every thread's snapshot after `n` recordings, see [`MetricsContextConfig`].
+    pub fn flush_every(mut self, n: usize) -> Self {
+        self.config.flush_every_n = n;
+        self
+    }
+
+    pub fn park_flush(mut self, park_flush: ParkFlush) -> Self {
+        self.park_flush = park_flush;
+        self
+    }
+
+    /// Replaces any start, park and stop callbacks previously set on `builder`.
+    pub fn install(self, builder: &mut Builder, sink: SnapshotSender) {
+        builder.on_thread_start({
+            let sink = sink.clone();
+            move || {
+                let sink = sink.clone();
+                METRICS_CTX.with(move |m| {
+                    m.connect_with(sink, self.config);
+                    if let Some(origin) = self.origin {
+                        m.set_origin(origin);
+                    }
+                    m.set_label_filter(self.label_filter);
+                });
+            }
+        }).on_thread_stop({
+            let sink = sink.clone();
+            move || flush_on_stop(&sink)
+        });
+        match self.park_flush {
+            ParkFlush::Always => {
+                builder.on_thread_park(move || flush(&sink));
+            }
+            ParkFlush::Every(n) => {
+                builder.on_thread_park(move || if park_due(n) { flush(&sink) });
+            }
+            ParkFlush::Never => {}
+        }
+    }
+}
+
+/// Flushes on park. A full channel that coalesces hands the snapshot back to the thread
+/// instead of blocking the worker until the aggregator catches up.
+fn flush(sink: &SnapshotSender) {
+    let _span = trace::span("flush");
+    let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+    if !snapshot.is_empty() {
+        if let Some(returned) = sink.send_or_return(snapshot) {
+            METRICS_CTX.with(|m| m.coalesce(returned));
+        }
+    }
+}
+
+/// The last flush of the thread, which waits for room as nothing is recorded after it.
+fn flush_on_stop(sink: &SnapshotSender) {
+    let _span = trace::span("flush");
+    let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+    if !snapshot.is_empty() {
+        sink.send(snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::thread;
+    use std::time::Duration;
+    use crate::hooks::{park_due, ParkFlush, ThreadHooks};
+    use crate::metrics::{Counter, METRICS_CTX};
+    use crate::transport::{bounded_snapshot_channel, Flushed, snapshot_channel, WhenFull};
+    use crate::test_utils::shared_heap;
+
+    fn run(park_flush: ParkFlush) -> Vec<u64> {
+        let (tx, rx) = snapshot_channel(false);
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(1).enable_all();
+        ThreadHooks::new().park_flush(park_flush).install(&mut builder, tx);
+        let rt = builder.build().unwrap();
+        rt.block_on(async {
+            rt.spawn(async { METRICS_CTX.with(|m| m.increment(Counter("requests", 1))) }).await.unwrap();
+            // give the worker time to park before recording again
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            rt.spawn(async { METRICS_CTX.with(|m| m.increment(Counter("requests", 2))) }).await.unwrap();
+        });
+        drop(rt);
+
+        let mut flushed = Vec::new();
+        while let Ok(Flushed::Owned(snapshot)) = rx.recv_timeout(Some(Duration::ZERO)) {
+            flushed.push(snapshot.get_all_dims("requests").unwrap());
+        }
+        flushed
+    }
+
+    #[test]
+    fn flush_on_park() {
+        let _heap = shared_heap();
+        assert_eq!(vec![1, 2], run(ParkFlush::Always));
+        assert_eq!(vec![3], run(ParkFlush::Never));
+    }
+
+    #[test]
+    fn park_coalesces_into_full_channel() {
+        let _heap = shared_heap();
+        let (tx, rx) = bounded_snapshot_channel(1, WhenFull::Coalesce);
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(1).enable_all();
+        ThreadHooks::new().install(&mut builder, tx);
+        let rt = builder.build().unwrap();
+        rt.block_on(async {
+            // the receiver is idle, so the first park fills the channel and the rest coalesce
+            for requests in [1, 2, 4] {
+                rt.spawn(async move { METRICS_CTX.with(|m| m.increment(Counter("requests", requests))) }).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        assert!(rx.losses().unwrap().coalesced >= 2);
+
+        let mut flushed = Vec::new();
+        let Ok(Flushed::Owned(snapshot)) = rx.recv_timeout(Some(Duration::ZERO)) else {
+            panic!("the first park didn't flush")
+        };
+        flushed.push(snapshot.get_all_dims("requests").unwrap());
+        drop(rt);
+        while let Ok(Flushed::Owned(snapshot)) = rx.recv_timeout(Some(Duration::ZERO)) {
+            flushed.push(snapshot.get_all_dims("requests").unwrap());
+        }
+        assert_eq!(vec![1, 6], flushed);
+    }
+
+    #[test]
+    fn flush_every_nth_park() {
+        assert_eq!(ParkFlush::Never, ParkFlush::every(0));
+        assert_eq!(ParkFlush::Always, ParkFlush::every(1));
+        let third = NonZeroU32::new(3).unwrap();
+        assert_eq!(ParkFlush::Every(third), ParkFlush::every(3));
+        let due = thread::spawn(move || (0..7).map(|_| park_due(third)).collect::<Vec<_>>()).join().unwrap();
+        assert_eq!(vec![false, false, true, false, false, true, false], due);
+    }
+}
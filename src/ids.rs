@@ -0,0 +1,172 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{BuildHasher, RandomState};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// 128-bit identifier laid out as a [ULID]: 48 bits of unix time in milliseconds followed by
+/// 80 random bits, rendered as 26 Crockford base32 characters.
+///
+/// [ULID]: https://github.com/ulid/spec
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ulid(u128);
+
+impl Ulid {
+    pub fn from_parts(timestamp_ms: u64, random: u128) -> Self {
+        Self((u128::from(timestamp_ms & 0xFFFF_FFFF_FFFF) << 80) | (random & ((1 << 80) - 1)))
+    }
+
+    pub fn timestamp_ms(&self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+
+    pub fn to_u128(self) -> u128 {
+        self.0
+    }
+
+    pub fn from_u128(v: u128) -> Self {
+        Self(v)
+    }
+}
+
+impl Display for Ulid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut buf = [0_u8; 26];
+        for (i, c) in buf.iter_mut().enumerate() {
+            let shift = 125 - 5 * i;
+            *c = CROCKFORD[((self.0 >> shift) & 0x1F) as usize];
+        }
+
+        f.write_str(std::str::from_utf8(&buf).unwrap())
+    }
+}
+
+impl Debug for Ulid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ulid({self})")
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseUlidError;
+
+impl Display for ParseUlidError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected 26 Crockford base32 characters")
+    }
+}
+
+impl std::error::Error for ParseUlidError {}
+
+impl FromStr for Ulid {
+    type Err = ParseUlidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the first character only carries 3 bits
+        if s.len() != 26 || s.as_bytes()[0] > b'7' {
+            return Err(ParseUlidError)
+        }
+
+        let mut v = 0_u128;
+        for c in s.bytes() {
+            let digit = CROCKFORD.iter()
+                .position(|&d| d == c.to_ascii_uppercase())
+                .ok_or(ParseUlidError)?;
+            v = (v << 5) | digit as u128;
+        }
+
+        Ok(Self(v))
+    }
+}
+
+impl Serialize for Ulid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ulid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Source of identifiers for runs and processes. Pluggable so tests (or external tooling
+/// joining data across systems) can supply deterministic ids.
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> Ulid;
+}
+
+/// Generates ULIDs from the system clock and the std hasher's random seed. Good enough to tell
+/// runs apart, not suitable for anything security sensitive.
+#[derive(Default)]
+pub struct UlidGenerator {
+    state: RandomState,
+    counter: AtomicU64,
+}
+
+impl IdGenerator for UlidGenerator {
+    fn next_id(&self) -> Ulid {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let hi = self.state.hash_one((n, now.as_nanos()));
+        let lo = self.state.hash_one((hi, n));
+
+        Ulid::from_parts(now.as_millis() as u64, (u128::from(hi) << 64) | u128::from(lo))
+    }
+}
+
+/// Run and process that produced a snapshot or a result, used to join data across the
+/// aggregator, result sinks and logs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub run_id: Ulid,
+    pub process_id: Ulid,
+}
+
+impl Identity {
+    /// Creates a new identity for this process, joining `run_id` if one is given.
+    pub fn generate(ids: &dyn IdGenerator, run_id: Option<Ulid>) -> Self {
+        Self {
+            run_id: run_id.unwrap_or_else(|| ids.next_id()),
+            process_id: ids.next_id(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::{IdGenerator, Ulid, UlidGenerator};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn ulid_roundtrip() {
+        let _heap = shared_heap();
+        let ids = UlidGenerator::default();
+        let id = ids.next_id();
+        let s = id.to_string();
+        assert_eq!(s.len(), 26);
+        assert_eq!(s.parse::<Ulid>().unwrap(), id);
+        assert_eq!(s.to_lowercase().parse::<Ulid>().unwrap(), id);
+        assert_ne!(ids.next_id(), id);
+
+        let id = Ulid::from_parts(1_469_918_176_403, 0);
+        assert_eq!(id.to_string(), "01ARYZ6S4K0000000000000000");
+        assert_eq!(id.timestamp_ms(), 1_469_918_176_403);
+        assert!("81ARYZ6S4K0000000000000000".parse::<Ulid>().is_err());
+        assert!("01ARYZ6S4K".parse::<Ulid>().is_err());
+    }
+
+    #[test]
+    fn ulid_from_reader() {
+        let _heap = shared_heap();
+        let id = Ulid::from_parts(1_469_918_176_403, 7);
+        let json = serde_json::to_vec(&id).unwrap();
+        // neither readers nor escaped strings can be borrowed from the input
+        assert_eq!(id, serde_json::from_reader::<_, Ulid>(json.as_slice()).unwrap());
+        assert_eq!(id, serde_json::from_str::<Ulid>(r#""01ARYZ6S4K\u0030000000000000007""#).unwrap());
+    }
+}
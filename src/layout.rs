@@ -0,0 +1,114 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use clap::ValueEnum;
+use crate::dimensions::{MetricName, MetricStore, SeriesId};
+use crate::dimensions::soa::{SoaStore, synthetic_name};
+use crate::metrics::KEY;
+
+/// How series names are laid out in memory.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StoreLayout {
+    /// Hash map of owned names, every label a (name, hash, shared value) tuple, as in `MetricStore`
+    Aos,
+    /// Parallel columns per label slot, see `SoaStore`
+    Soa,
+}
+
+/// Measures increments into a store of multi-label series, to compare name layouts.
+#[derive(clap::Args, Debug)]
+pub struct LayoutArgs {
+    #[arg(long, value_enum, default_value_t = StoreLayout::Aos)]
+    store: StoreLayout,
+
+    /// Distinct series in the store
+    #[arg(long, default_value_t = 100_000)]
+    series: u64,
+
+    /// Labels on every series, at most 5. Only the last one tells series apart, the others
+    /// take a few values, so comparisons have to walk every slot
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=5))]
+    labels: u8,
+
+    #[arg(long, default_value_t = 10_000_000)]
+    increments: u64,
+}
+
+/// Common surface of the layouts being compared.
+trait LayoutStore: Default {
+    fn update(&mut self, key: &MetricName, val: u64);
+    fn len(&self) -> usize;
+    fn footprint(&self) -> usize;
+}
+
+impl LayoutStore for MetricStore {
+    fn update(&mut self, key: &MetricName, val: u64) {
+        MetricStore::update(self, key, val)
+    }
+
+    fn len(&self) -> usize {
+        MetricStore::len(self)
+    }
+
+    fn footprint(&self) -> usize {
+        MetricStore::footprint(self)
+    }
+}
+
+impl LayoutStore for SoaStore {
+    fn update(&mut self, key: &MetricName, val: u64) {
+        SoaStore::update(self, key, val)
+    }
+
+    fn len(&self) -> usize {
+        SoaStore::len(self)
+    }
+
+    fn footprint(&self) -> usize {
+        SoaStore::footprint(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct LayoutResult {
+    pub series: usize,
+    pub footprint: usize,
+    pub per_increment: Duration,
+}
+
+/// Fills a store with every series once, then increments them in a scattered order.
+fn measure<S: LayoutStore>(series: u64, labels: usize, increments: u64) -> LayoutResult {
+    let values = (0..series)
+        .map(|id| {
+            let mut values = [SeriesId(id % 4); 5];
+            values[labels - 1] = SeriesId(id);
+            values
+        })
+        .collect::<Vec<_>>();
+    let names = values.iter().map(|values| synthetic_name(KEY, &values[..labels])).collect::<Vec<_>>();
+
+    let mut store = S::default();
+    names.iter().for_each(|name| store.update(name, 1));
+    // scattered to defeat the prefetcher; a prime stride visits every series unless it divides the count
+    const STRIDE: usize = 7_919;
+    let start = Instant::now();
+    for i in 0..increments as usize {
+        store.update(black_box(&names[i.wrapping_mul(STRIDE) % names.len()]), 1);
+    }
+    let elapsed = start.elapsed();
+
+    LayoutResult {
+        series: store.len(),
+        footprint: store.footprint(),
+        per_increment: elapsed.div_f64(increments.max(1) as f64),
+    }
+}
+
+pub fn run(args: LayoutArgs) {
+    let labels = usize::from(args.labels);
+    let result = match args.store {
+        StoreLayout::Aos => measure::<MetricStore>(args.series.max(1), labels, args.increments),
+        StoreLayout::Soa => measure::<SoaStore>(args.series.max(1), labels, args.increments),
+    };
+    println!("store: {:?}, {} series of {labels} labels, {} bytes, {:?} per increment",
+             args.store, result.series, result.footprint, result.per_increment);
+}
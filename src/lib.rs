@@ -0,0 +1,47 @@
+//! Prototype of a low-overhead, dimensional metrics store and the harness that benchmarks it.
+//! Storage strategies written outside this crate implement [`strategy::StorageStrategy`] and
+//! are registered as modes of the harness:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use metric_proto::strategy::{MutexStrategy, StrategyRegistry};
+//! let mut strategies = StrategyRegistry::builtin();
+//! strategies.register("candidate", || Arc::new(MutexStrategy::default()));
+//! metric_proto::cli::run(strategies);
+//! ```
+
+// lets `derive(LabelValue)` name this crate the same way inside and outside of it
+extern crate self as metric_proto;
+
+pub mod aggregator;
+pub mod arrival;
+pub mod audit;
+pub mod bench;
+pub mod bloat;
+pub mod metrics;
+pub mod atomic;
+pub mod cli;
+pub mod dimensions;
+pub mod external_metrics;
+pub mod flusher;
+pub mod work;
+pub mod results;
+pub mod ids;
+pub mod strategy;
+pub mod dense;
+pub mod transfer;
+pub mod tree;
+pub mod capacity;
+pub mod layout;
+pub mod handle;
+pub mod harness;
+pub mod hooks;
+pub mod metadata;
+pub mod pool;
+pub mod query;
+pub mod relaxed;
+pub mod restarts;
+pub mod trace;
+pub mod transport;
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{LazyLock, PoisonError, RwLock};
+use crate::dimensions::MetricKind;
+
+/// Unit and help text of every described metric, by key. Shared by every thread, like metric
+/// keys themselves, so producers can describe metrics where they are defined and the
+/// aggregator or exporters look them up when rendering.
+static REGISTRY: LazyLock<RwLock<HashMap<&'static str, Metadata>>> = LazyLock::new(RwLock::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Count,
+    Bytes,
+    Seconds,
+    Nanoseconds,
+    Percent,
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Unit::Count => "count",
+            Unit::Bytes => "bytes",
+            Unit::Seconds => "seconds",
+            Unit::Nanoseconds => "nanoseconds",
+            Unit::Percent => "percent",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: MetricKind,
+    pub unit: Option<Unit>,
+    pub description: &'static str,
+}
+
+/// Describing a key again replaces its metadata.
+fn describe(key: &'static str, kind: MetricKind, unit: Option<Unit>, description: &'static str) {
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    registry.insert(key, Metadata { kind, unit, description });
+}
+
+pub fn describe_counter(key: &'static str, unit: Option<Unit>, description: &'static str) {
+    describe(key, MetricKind::Counter, unit, description);
+}
+
+pub fn describe_gauge(key: &'static str, unit: Option<Unit>, description: &'static str) {
+    describe(key, MetricKind::Gauge, unit, description);
+}
+
+pub fn describe_histogram(key: &'static str, unit: Option<Unit>, description: &'static str) {
+    describe(key, MetricKind::Histogram, unit, description);
+}
+
+pub fn metadata(key: &str) -> Option<Metadata> {
+    REGISTRY.read().unwrap_or_else(PoisonError::into_inner).get(key).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dimensions::MetricKind;
+    use crate::metadata::{describe_counter, describe_histogram, metadata, Metadata, Unit};
+
+    #[test]
+    fn describe() {
+        describe_counter("bytes_sent", Some(Unit::Bytes), "Payload bytes sent to peers");
+        describe_histogram("merge_latency", None, "Time to merge one snapshot");
+        describe_histogram("merge_latency", Some(Unit::Nanoseconds), "Time to merge one snapshot");
+
+        assert_eq!(Some(MetricKind::Counter), metadata("bytes_sent").map(|m| m.kind));
+        assert_eq!(
+            Some(Metadata { kind: MetricKind::Histogram, unit: Some(Unit::Nanoseconds), description: "Time to merge one snapshot" }),
+            metadata("merge_latency"),
+        );
+        assert_eq!(None, metadata("undescribed"));
+    }
+}
@@ -1,71 +1,399 @@
-use std::cell::{RefCell};
+use std::array;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::ops::{Add, AddAssign};
-use crossbeam::channel::Sender;
-use crate::dimensions::{HelperIdentity, MetricName, MetricStore};
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Once, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant, SystemTime};
+use crate::dimensions::{CardinalityReport, CategoryValue, DEFAULT_BUCKETS, DiffReport, Exemplar, ExemplarValue, ExpHistogramValue, GaugeUpdate, HelperIdentity, HistogramValue, LabelDomain, LabelFilter, LabelValue, MetricKind, MetricName, MetricStore, MeterValue, Restored, SeriesId, SeriesValue, SketchValue, SummaryValue, UniqueValue};
+use crate::dimensions::tlv::{self, TlvError};
+use crate::flusher::{self, Mailbox};
+use crate::ids::{Identity, Ulid};
+use crate::metadata::{self, Unit};
+use crate::pool;
+use crate::relaxed;
+use crate::trace;
+use crate::transport::SnapshotSender;
+use crate::work::Work;
 
-pub struct MetricsContext {
-    snapshot: RefCell<Option<Snapshot>>,
-    tx: RefCell<Option<Sender<Snapshot>>>,
+/// Flushes the thread-local snapshot once `max_interval` has passed since the last flush, so
+/// tasks that rarely yield don't hold on to their metrics until the worker parks. The clock
+/// is read only once every `check_every` increments to keep the cost of the check amortized.
+#[derive(Copy, Clone, Debug)]
+pub struct TimeSlice {
+    pub check_every: u32,
+    pub max_interval: Duration,
 }
 
-impl MetricsContext {
+/// How a thread's [`MetricsContext`] decides to flush, given to [`MetricsContext::connect_with`].
+#[derive(Copy, Clone, Debug)]
+pub struct MetricsContextConfig {
+    /// Recordings after which the thread's snapshot is flushed. Lower values keep the
+    /// aggregator fresher, at the cost of more snapshots through the channel.
+    pub flush_every_n: usize,
+    pub time_slice: Option<TimeSlice>,
+    /// Flushes once the oldest recording not yet flushed is this old, so a thread that
+    /// records rarely doesn't hold on to it. Reads the clock on every recording.
+    pub max_staleness: Option<Duration>,
+    /// Lets a [`BackgroundFlusher`] ask for the snapshot, which is flushed on the next
+    /// recording after it asked.
+    ///
+    /// [`BackgroundFlusher`]: crate::flusher::BackgroundFlusher
+    pub background_flush: bool,
+}
+
+impl Default for MetricsContextConfig {
+    fn default() -> Self {
+        Self { flush_every_n: FLUSH_THRESHOLD, time_slice: None, max_staleness: None, background_flush: false }
+    }
+}
+
+/// Thread-local buffer that [`MetricsContext`] records into and periodically hands over to
+/// the aggregator. [`Snapshot`] is the dimensional implementation; experiments with other
+/// key types plug in here and reuse the flush and transport machinery.
+pub trait LocalStore: Default + Send + 'static {
+    fn is_empty(&self) -> bool;
+
+    /// Hands over recorded metrics, leaving an empty store in place.
+    fn take(&mut self) -> Self;
+
+    fn merge(&mut self, other: Self);
+
+    /// Takes back a snapshot that a full channel coalesces, to be sent with what is recorded
+    /// next.
+    fn coalesce(&mut self, returned: Self) {
+        self.merge(returned);
+    }
+
+    fn set_origin(&mut self, _origin: Option<Identity>) {}
+
+    fn set_label_filter(&mut self, _filter: Option<&'static LabelFilter>) {}
+
+    fn set_thread(&mut self, _thread: Option<ThreadId>) {}
+
+    /// Recordings after which [`Records::record`] asks for a flush.
+    fn set_flush_threshold(&mut self, _threshold: usize) {}
+
+    /// Records increments queued on this thread with [`try_increment_relaxed`]. Stores that
+    /// don't support them leave them queued.
+    ///
+    /// [`try_increment_relaxed`]: crate::relaxed::try_increment_relaxed
+    fn absorb_relaxed(&mut self) {}
+}
+
+/// Stores that know how to record metrics of type `M`.
+pub trait Records<M>: LocalStore {
+    /// Returns `true` once the store has accumulated enough to be flushed.
+    fn record(&mut self, metric: M) -> bool;
+}
+
+/// Set by [`set_strict`].
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Makes recording on a thread whose context was never connected panic, instead of keeping
+/// the metrics until it connects. Meant for tracking down threads that are missing hooks.
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+/// What a context records into, and where it flushes to once connected.
+struct Local<S> {
+    snapshot: S,
+    tx: Option<SnapshotSender<S>>,
+}
+
+pub struct MetricsContext<S = Snapshot> {
+    /// Created on connect, or on the first recording of a thread that isn't connected. One
+    /// borrow covers both the snapshot and the sender, so increments borrow once.
+    local: RefCell<Option<Local<S>>>,
+    /// Whether time slicing, max staleness or background flushing is on. Increments only
+    /// check any of them if one is.
+    triggers: Cell<bool>,
+    time_slice: Cell<Option<TimeSlice>>,
+    since_clock_check: Cell<u32>,
+    last_flush: Cell<Option<Instant>>,
+    max_staleness: Cell<Option<Duration>>,
+    /// First recording since the last flush, tracked only with `max_staleness`.
+    oldest_unflushed: Cell<Option<Instant>>,
+    background_flush: Cell<bool>,
+    /// Registered the first time `background_flush` is enabled, kept until the thread exits.
+    mailbox: OnceCell<Arc<Mailbox>>,
+}
+
+impl<S: LocalStore> MetricsContext<S> {
     pub const fn new() -> Self {
         Self {
-            snapshot: RefCell::new(None),
-            tx: RefCell::new(None),
+            local: RefCell::new(None),
+            triggers: Cell::new(false),
+            time_slice: Cell::new(None),
+            since_clock_check: Cell::new(0),
+            last_flush: Cell::new(None),
+            max_staleness: Cell::new(None),
+            oldest_unflushed: Cell::new(None),
+            background_flush: Cell::new(false),
+            mailbox: OnceCell::new(),
         }
     }
 
-    pub fn take_snapshot(&self) -> Snapshot {
-        self.snapshot.borrow_mut().as_mut().unwrap().take()
+    pub fn take_snapshot(&self) -> S {
+        self.last_flush.set(Some(Instant::now()));
+        self.oldest_unflushed.set(None);
+        let mut local = self.local.borrow_mut();
+        let snapshot = &mut Self::local(&mut local).snapshot;
+        snapshot.absorb_relaxed();
+        snapshot.take()
+    }
+
+    /// Takes back a snapshot taken with [`Self::take_snapshot`] that a full channel returned,
+    /// to be sent with what this thread records next.
+    pub fn coalesce(&self, returned: S) {
+        Self::local(&mut self.local.borrow_mut()).snapshot.coalesce(returned);
     }
 
     // #[inline]
-    pub fn increment<M: Metric>(&self, metric: M) {
-        let mut snapshot = self.snapshot.borrow_mut();
-        let snapshot_mut = snapshot.as_mut().unwrap();
-        if snapshot_mut.increment(metric) && self.tx.borrow().is_some() {
-            let copy = snapshot_mut.take();
-            let _ = self.tx.borrow().as_ref().unwrap().send(copy);
+    pub fn increment<M>(&self, metric: M) where S: Records<M> {
+        let mut local = self.local.borrow_mut();
+        let local = Self::local(&mut local);
+        if local.snapshot.record(metric) | (self.triggers.get() && self.triggered()) {
+            self.flush(local);
         }
     }
 
-    pub fn connect(&self, tx: Sender<Snapshot>) {
-        *self.tx.borrow_mut() = Some(tx);
-        *self.snapshot.borrow_mut() = Some(Snapshot::new());
+    /// Snapshot and sender of this thread. Threads that never connected get a snapshot on
+    /// first use, which keeps what they record until they connect, see [`set_strict`]. It
+    /// never reaches the flush threshold, so recordings don't keep calling a flush that has
+    /// nowhere to go.
+    #[inline]
+    fn local(local: &mut Option<Local<S>>) -> &mut Local<S> {
+        local.get_or_insert_with(Self::unconnected)
     }
-}
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
-pub struct MetricKey;
+    #[cold]
+    #[inline(never)]
+    fn unconnected() -> Local<S> {
+        if STRICT.load(Ordering::Relaxed) {
+            panic!("metrics recorded on thread {:?}, which isn't connected", std::thread::current().name().unwrap_or("<unnamed>"))
+        }
+        // nothing to flush to, so nothing asks for a flush until the thread connects
+        let mut snapshot = S::default();
+        snapshot.set_flush_threshold(usize::MAX);
+        Local { snapshot, tx: None }
+    }
 
-#[derive(Copy, Clone, Debug, Default, Ord, PartialOrd, Eq, PartialEq)]
-pub struct MetricValue(pub u64);
+    /// Kept out of line, so `increment` stays a straight-line update on the common path.
+    #[cold]
+    #[inline(never)]
+    fn flush(&self, local: &mut Local<S>) {
+        if let Some(tx) = &local.tx {
+            let _span = trace::span("flush");
+            self.last_flush.set(Some(Instant::now()));
+            self.oldest_unflushed.set(None);
+            let snapshot = &mut local.snapshot;
+            snapshot.absorb_relaxed();
+            if let Some(returned) = tx.send_or_return(snapshot.take()) {
+                snapshot.coalesce(returned);
+            }
+        }
+    }
+
+    pub fn connect<T: Into<SnapshotSender<S>>>(&self, tx: T) {
+        self.connect_with(tx, MetricsContextConfig::default());
+    }
+
+    pub fn connect_with<T: Into<SnapshotSender<S>>>(&self, tx: T, config: MetricsContextConfig) {
+        // keeps what was recorded before connecting, or not flushed on the previous connection
+        let mut snapshot = self.local.borrow_mut().take().map(|local| local.snapshot).unwrap_or_default();
+        snapshot.set_origin(None);
+        snapshot.set_label_filter(None);
+        snapshot.set_thread(Some(std::thread::current().id()));
+        snapshot.set_flush_threshold(config.flush_every_n);
+        self.set_time_slice(config.time_slice);
+        self.max_staleness.set(config.max_staleness);
+        self.oldest_unflushed.set(None);
+        self.background_flush.set(config.background_flush);
+        if config.background_flush {
+            self.mailbox.get_or_init(flusher::register);
+        }
+        *self.local.borrow_mut() = Some(Local { snapshot, tx: Some(tx.into()) });
+        self.update_triggers();
+        self.last_flush.set(Some(Instant::now()));
+    }
+
+    /// Flushes what is left and drops the sender, as if the context was never connected.
+    pub fn disconnect(&self) {
+        let Some(Local { mut snapshot, tx }) = self.local.borrow_mut().take() else {
+            return
+        };
+        self.background_flush.set(false);
+        self.update_triggers();
+        if let Some(tx) = tx {
+            snapshot.absorb_relaxed();
+            if !snapshot.is_empty() {
+                tx.send(snapshot.take());
+            }
+        }
+    }
+
+    /// Tags every snapshot flushed from this thread with `origin`.
+    pub fn set_origin(&self, origin: Identity) {
+        Self::local(&mut self.local.borrow_mut()).snapshot.set_origin(Some(origin));
+    }
+
+    /// Drops `filter`'s labels from everything recorded on this thread, before it is stored.
+    pub fn set_label_filter(&self, filter: Option<&'static LabelFilter>) {
+        Self::local(&mut self.local.borrow_mut()).snapshot.set_label_filter(filter);
+    }
 
-impl Add for MetricValue {
-    type Output = Self;
+    /// Enables (or disables with `None`) time-sliced flushing on this thread.
+    pub fn set_time_slice(&self, time_slice: Option<TimeSlice>) {
+        self.time_slice.set(time_slice);
+        self.since_clock_check.set(0);
+        self.update_triggers();
+    }
+
+    /// Contexts that aren't connected have nowhere to flush to, and check none of them.
+    fn update_triggers(&self) {
+        let connected = self.local.borrow().as_ref().is_some_and(|local| local.tx.is_some());
+        self.triggers.set(connected && (self.time_slice.get().is_some() || self.max_staleness.get().is_some() || self.background_flush.get()));
+    }
+
+    /// Whether time slicing, max staleness or a background flush request asks for a flush.
+    /// Checks all of them, as staleness tracks the first recording after a flush.
+    fn triggered(&self) -> bool {
+        self.time_slice_elapsed() | self.stale() | self.flush_requested()
+    }
+
+    fn flush_requested(&self) -> bool {
+        self.background_flush.get() && self.mailbox.get().is_some_and(|mailbox| mailbox.take_request())
+    }
+
+    /// Whether the oldest recording not flushed yet, counting the one just made, is older than
+    /// `max_staleness`.
+    fn stale(&self) -> bool {
+        let Some(max_staleness) = self.max_staleness.get() else {
+            return false
+        };
+        let now = Instant::now();
+        match self.oldest_unflushed.get() {
+            Some(oldest) => now.duration_since(oldest) >= max_staleness,
+            None => {
+                self.oldest_unflushed.set(Some(now));
+                false
+            }
+        }
+    }
+
+    fn time_slice_elapsed(&self) -> bool {
+        let Some(time_slice) = self.time_slice.get() else {
+            return false
+        };
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self(self.0 + rhs.0)
+        let since_check = self.since_clock_check.get() + 1;
+        if since_check < time_slice.check_every {
+            self.since_clock_check.set(since_check);
+            return false
+        }
+
+        self.since_clock_check.set(0);
+        match self.last_flush.get() {
+            Some(last_flush) => last_flush.elapsed() >= time_slice.max_interval,
+            None => true,
+        }
     }
 }
 
-impl AddAssign for MetricValue {
-    fn add_assign(&mut self, rhs: Self) {
-        self.0 += rhs.0;
+impl<S: LocalStore> Default for MetricsContext<S> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+impl MetricsContext<Snapshot> {
+    /// Merges what a [`task_scope`] recorded into this thread's snapshot, flushing if that
+    /// takes it past the threshold.
+    fn roll_up(&self, task: Snapshot) {
+        let mut local = self.local.borrow_mut();
+        let local = Self::local(&mut local);
+        if local.snapshot.merge_recorded(task) {
+            self.flush(local);
+        }
+    }
+
+    /// Registers counter `name` and returns a handle that increments it without hashing the
+    /// name. Handles work with any thread's context. Registering a series again returns the
+    /// handle it already has, only the first registration leaks the name's label values.
+    pub fn register_counter(&self, name: &MetricName) -> CounterHandle {
+        let mut registered = REGISTERED.write().unwrap();
+        let handle = registered.iter().position(|registered| registered.same(name)).unwrap_or_else(|| {
+            registered.push(name.leak());
+            registered.len() - 1
+        });
+
+        CounterHandle(handle)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct MetricKey;
+
+/// Counter increment. Gauges, histograms and sketches take `f64` samples instead, see [`Gauge`]
+/// and [`Histogram`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MetricValue {
+    Counter(u64),
+    /// Signed delta of an [`UpDownCounter`].
+    UpDown(i64),
+}
+
+/// Number of increments after which a thread-local snapshot is handed over to the aggregator.
+pub const FLUSH_THRESHOLD: usize = 50_000;
+
+/// Serializes with the [`MetricStore`] it carries and where it came from. Thread, label filter
+/// and flush threshold only mean something to the producer, and are left out. Counts of
+/// registered counters are moved into the store by [`Snapshot::take`], so only taken
+/// snapshots serialize all of their metrics.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Snapshot {
     store: MetricStore,
-    cnt: usize
+    cnt: usize,
+    origin: Option<Identity>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    thread: Option<ThreadId>,
+    /// When the snapshot was created, the start of the window its meters cover.
+    #[cfg_attr(feature = "serde", serde(skip, default = "Instant::now"))]
+    started: Instant,
+    /// Events recorded into this snapshot and every snapshot taken before it.
+    recorded: u64,
+    /// Position among the snapshots taken on the producing thread, starting at 0.
+    seq: u64,
+    /// When the snapshot was taken, `None` until then.
+    captured: Option<SystemTime>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    label_filter: Option<&'static LabelFilter>,
+    /// Counts of registered counters, by [`CounterHandle`], not yet moved into `store`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    registered: Vec<u64>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "flush_threshold"))]
+    flush_threshold: usize,
+}
+
+#[cfg(feature = "serde")]
+fn flush_threshold() -> usize {
+    FLUSH_THRESHOLD
 }
 
 impl Debug for Snapshot {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Snapshot")
+            .field("origin", &self.origin)
+            .field("thread", &self.thread)
+            .field("recorded", &self.recorded)
+            .field("seq", &self.seq)
             .field("cnt", &self.cnt)
             .field("store", &self.store)
             .finish()
@@ -73,100 +401,1456 @@ impl Debug for Snapshot {
 }
 
 pub trait Metric: Sized {
-    fn into_metric(&self) -> (MetricName, MetricValue);
+    #[allow(clippy::wrong_self_convention)]
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue);
 }
 
 #[allow(dead_code)]
 pub struct Counter(pub &'static str, pub u64);
 
+impl Counter {
+    /// Records the cumulative `total` of a counter maintained outside this process.
+    pub fn absolute(name: &'static str, total: u64) -> AbsoluteCounter<'static> {
+        AbsoluteCounter(MetricName::with_no_labels(name), total)
+    }
+}
+
 impl Metric for Counter {
-    fn into_metric(&self) -> (MetricName, MetricValue) {
-        (MetricName::with_no_labels(self.0), MetricValue(self.1))
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue) {
+        (MetricName::with_no_labels(self.0), MetricValue::Counter(self.1))
+    }
+}
+
+/// Counter increment into a series of any number of labels. [`Metric`] implementations always
+/// name series with the default 5 label slots, this takes names that need fewer or more.
+pub struct LabeledCounter<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub u64);
+
+/// Counter that can go down, e.g. open connections. Unlike a [`Gauge`], deltas recorded on
+/// different threads add up.
+pub struct UpDownCounter(pub &'static str, pub i64);
+
+impl Metric for UpDownCounter {
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue) {
+        (MetricName::with_no_labels(self.0), MetricValue::UpDown(self.1))
+    }
+}
+
+/// Counter whose cumulative total is tracked elsewhere, e.g. scraped from `/proc`. Records the
+/// total itself rather than a delta.
+pub struct AbsoluteCounter<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub u64);
+
+/// Value recorded into a high-resolution [`HdrValue`] histogram, e.g. a latency in nanoseconds.
+///
+/// [`HdrValue`]: crate::dimensions::HdrValue
+#[cfg(feature = "hdr")]
+pub struct HdrHistogram<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub u64);
+
+/// Fact that holds if it was observed at least once, e.g. that a fallback path was taken.
+pub struct Flag<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub bool);
+
+impl<'a, const LABELS: usize> Flag<'a, LABELS> {
+    pub fn set(name: MetricName<'a, LABELS>) -> Self {
+        Self(name, true)
+    }
+}
+
+/// Value recorded into a [`SketchValue`], for quantiles with bounded relative error at a
+/// fixed cost per series.
+///
+/// [`SketchValue`]: crate::dimensions::SketchValue
+pub struct Sketch<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub f64);
+
+/// Value recorded into a [`UniqueValue`], which estimates how many distinct values were seen.
+///
+/// [`UniqueValue`]: crate::dimensions::UniqueValue
+pub struct Unique<'a, T: ?Sized, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub &'a T);
+
+/// Events counted along with the time they were counted over, see [`MeterValue`].
+///
+/// [`MeterValue`]: crate::dimensions::MeterValue
+pub struct Meter<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub u64);
+
+/// Sample recorded into a [`SummaryValue`], which keeps count, sum, min and max only.
+///
+/// [`SummaryValue`]: crate::dimensions::SummaryValue
+pub struct Summary<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub f64);
+
+/// Static metadata, e.g. version or benchmark mode, exported as a `name{label=value,..} 1` series.
+/// By convention `name` ends with `_info`.
+pub struct Info<'a>(pub &'static str, pub &'a [(&'static str, &'static str)]);
+
+/// Point-in-time value, such as queue depth or the number of active tasks.
+pub struct Gauge<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub GaugeUpdate);
+
+impl<'a, const LABELS: usize> Gauge<'a, LABELS> {
+    pub fn set(name: MetricName<'a, LABELS>, value: f64) -> Self {
+        Self(name, GaugeUpdate::Set(value))
+    }
+
+    pub fn increment(name: MetricName<'a, LABELS>, delta: f64) -> Self {
+        Self(name, GaugeUpdate::Add(delta))
+    }
+
+    pub fn decrement(name: MetricName<'a, LABELS>, delta: f64) -> Self {
+        Self(name, GaugeUpdate::Add(-delta))
+    }
+}
+
+/// Distribution of values, e.g. latencies, over fixed buckets.
+pub struct Histogram<'a, const LABELS: usize = 5> {
+    pub name: MetricName<'a, LABELS>,
+    pub bounds: &'static [f64],
+    pub value: f64,
+}
+
+impl<'a, const LABELS: usize> Histogram<'a, LABELS> {
+    /// Records `value` using [`DEFAULT_BUCKETS`], meant for latencies in nanoseconds.
+    pub fn new(name: MetricName<'a, LABELS>, value: f64) -> Self {
+        Self::with_buckets(name, DEFAULT_BUCKETS, value)
+    }
+
+    pub fn with_buckets(name: MetricName<'a, LABELS>, bounds: &'static [f64], value: f64) -> Self {
+        Self { name, bounds, value }
+    }
+}
+
+impl<'a> Histogram<'a> {
+    /// Records `value` into the series of `name` labeled with `label`, using [`DEFAULT_BUCKETS`].
+    pub fn labeled<R: LabelValue>(name: &'static str, label: (&'static str, &'a R), value: f64) -> Self {
+        Self::new(MetricName::from((name, label)), value)
+    }
+}
+
+/// Histogram broken down by the helper a sample relates to, the histogram counterpart of
+/// [`OneDimensionCounter`].
+pub struct OneDimensionHistogram(pub &'static str, pub HelperIdentity, pub f64);
+
+/// Counter increment or histogram sample that carries an exemplar, e.g. the trace of the
+/// request being counted. The series keeps the most recent exemplar.
+pub struct WithExemplar<M>(pub M, pub Exemplar);
+
+/// Value recorded into an [`ExpHistogramValue`], whose buckets adapt to the recorded range.
+pub struct ExpHistogram<'a, const LABELS: usize = 5>(pub MetricName<'a, LABELS>, pub f64);
+
+/// Records the time between its creation and drop, in nanoseconds, into a [`Histogram`] on the
+/// thread it is dropped on. Timers dropped while the thread's context is being destroyed record
+/// nothing.
+#[must_use = "the timer records when dropped, dropping it right away measures nothing"]
+pub struct Timer<'a, const LABELS: usize = 5> {
+    name: MetricName<'a, LABELS>,
+    start: Instant,
+}
+
+impl<'a, const LABELS: usize> Timer<'a, LABELS> {
+    pub fn start(name: MetricName<'a, LABELS>) -> Self {
+        Self { name, start: Instant::now() }
+    }
+}
+
+impl<const LABELS: usize> Drop for Timer<'_, LABELS> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_nanos() as f64;
+        let _ = METRICS_CTX.try_with(|m| m.increment(Histogram::new(self.name, elapsed)));
     }
 }
 
 pub struct OneDimensionCounter(pub &'static str, pub HelperIdentity, pub u64);
 
+/// Counter with one label, defined once. Children resolve the series name and its hash for a
+/// label value upfront, so hot loops that keep them around only pay for the lookup.
+#[derive(Copy, Clone, Debug)]
+pub struct CounterFamily {
+    key: &'static str,
+    label: &'static str,
+}
+
+impl CounterFamily {
+    pub const fn new(key: &'static str, label: &'static str) -> Self {
+        Self { key, label }
+    }
+
+    pub fn with<'a, L: LabelValue>(&self, value: &'a L) -> CounterChild<'a> {
+        let name = MetricName::with_one_label(self.key, self.label, value);
+        CounterChild { name, hash: MetricStore::hash(&name) }
+    }
+}
+
+/// Series of a [`CounterFamily`].
+#[derive(Copy, Clone)]
+pub struct CounterChild<'a> {
+    name: MetricName<'a>,
+    hash: u64,
+}
+
+impl CounterChild<'_> {
+    /// Increments the series in the calling thread's context.
+    pub fn increment(&self, value: u64) {
+        METRICS_CTX.with(|m| m.increment(ChildIncrement(self, value)));
+    }
+}
+
+pub struct ChildIncrement<'a, 'b>(pub &'a CounterChild<'b>, pub u64);
+
+/// Names of the counters registered with [`MetricsContext::register_counter`], by handle.
+static REGISTERED: RwLock<Vec<MetricName<'static>>> = RwLock::new(Vec::new());
+
+/// Counter registered upfront. Increments are an indexed add into the thread's snapshot, the
+/// name is only looked up when the snapshot is taken, once per flush.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CounterHandle(usize);
+
+impl CounterHandle {
+    /// Increments the counter in the calling thread's context.
+    pub fn increment(self, value: u64) {
+        METRICS_CTX.with(|m| m.increment(HandleIncrement(self, value)));
+    }
+}
+
+pub struct HandleIncrement(pub CounterHandle, pub u64);
+
+/// Counter at the call site, in the style of the `metrics` crate's `counter!`:
+/// `tlv_counter!("requests", "dest" => "H1").increment(1)`. The name, and label value if it is
+/// a literal, are registered as a [`CounterHandle`] the first time the call site runs. Label
+/// values only known at run time are resolved on every call, like a [`CounterFamily`] child.
+///
+/// ```
+/// use metric_proto::dimensions::MetricName;
+/// use metric_proto::metrics::METRICS_CTX;
+///
+/// for dest in ["H1", "H2"] {
+///     metric_proto::tlv_counter!("requests").increment(1);
+///     metric_proto::tlv_counter!("requests", "dest" => dest).increment(2);
+/// }
+/// let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+/// assert_eq!(Some(2), snapshot.get_counter(&MetricName::with_no_labels("requests")));
+/// assert_eq!(Some(2), snapshot.get_counter(&MetricName::with_one_label("requests", "dest", &"H2")));
+/// ```
+#[macro_export]
+macro_rules! tlv_counter {
+    ($name:expr) => {{
+        static HANDLE: ::std::sync::OnceLock<$crate::metrics::CounterHandle> = ::std::sync::OnceLock::new();
+        *HANDLE.get_or_init(|| $crate::metrics::METRICS_CTX.with(|m| {
+            m.register_counter(&$crate::dimensions::MetricName::with_no_labels($name))
+        }))
+    }};
+    ($name:expr, $label:literal => $value:literal) => {{
+        static HANDLE: ::std::sync::OnceLock<$crate::metrics::CounterHandle> = ::std::sync::OnceLock::new();
+        *HANDLE.get_or_init(|| $crate::metrics::METRICS_CTX.with(|m| {
+            m.register_counter(&$crate::dimensions::MetricName::with_one_label($name, $label, &$value))
+        }))
+    }};
+    ($name:expr, $label:literal => $value:expr) => {
+        $crate::metrics::CounterFamily::new($name, $label).with(&$value)
+    };
+}
+
+/// Counter declared once, with its labels and unit, see [`register_counter!`].
+#[derive(Debug)]
+pub struct CounterDescriptor<const N: usize> {
+    pub key: &'static str,
+    pub labels: [&'static str; N],
+    pub unit: Option<Unit>,
+    pub description: &'static str,
+    described: Once,
+    /// Only used without labels, whose single series can be registered.
+    handle: OnceLock<CounterHandle>,
+}
+
+impl<const N: usize> CounterDescriptor<N> {
+    pub const fn new(key: &'static str, labels: [&'static str; N], unit: Option<Unit>, description: &'static str) -> Self {
+        Self { key, labels, unit, description, described: Once::new(), handle: OnceLock::new() }
+    }
+
+    /// Makes the unit and description known to exporters, see [`metadata::describe_counter`].
+    pub fn describe(&'static self) -> &'static Self {
+        self.described.call_once(|| metadata::describe_counter(self.key, self.unit, self.description));
+        self
+    }
+
+    /// Increments the series with one value per declared label, in the order they were
+    /// declared. Counters without labels increment a registered [`CounterHandle`].
+    pub fn increment(&self, values: [&dyn LabelValue; N], value: u64) {
+        if N == 0 {
+            let handle = *self.handle.get_or_init(|| METRICS_CTX.with(|m| m.register_counter(&MetricName::with_no_labels(self.key))));
+            return handle.increment(value)
+        }
+        let name: MetricName<'_, N> = MetricName::with_label_array(self.key, array::from_fn(|i| (self.labels[i], values[i])));
+        METRICS_CTX.with(|m| m.increment(LabeledCounter(name, value)));
+    }
+}
+
+/// Declares a counter at the call site and returns its `&'static` [`CounterDescriptor`]:
+/// `register_counter!("requests", labels = ["helper"], unit = Count).increment([&h], 1)`.
+/// The number of label values is checked when compiling, and the unit and description are
+/// registered the first time the call site runs. Labels, unit and description are optional,
+/// in that order.
+///
+/// ```
+/// use metric_proto::dimensions::MetricName;
+/// use metric_proto::metrics::METRICS_CTX;
+///
+/// for helper in ["H1", "H2", "H1"] {
+///     metric_proto::register_counter!("sent", labels = ["helper"], unit = Count).increment([&helper], 1);
+/// }
+/// let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+/// assert_eq!(Some(2), snapshot.get_counter(&MetricName::with_one_label("sent", "helper", &"H1")));
+/// ```
+#[macro_export]
+macro_rules! register_counter {
+    (@unit) => { None };
+    (@unit $unit:ident) => { Some($crate::metadata::Unit::$unit) };
+    (@description) => { "" };
+    (@description $description:literal) => { $description };
+    ($name:expr $(, labels = [$($label:literal),* $(,)?])? $(, unit = $unit:ident)? $(, description = $description:literal)? $(,)?) => {{
+        const LABELS: usize = <[&str]>::len(&[$($($label),*)?]);
+        static COUNTER: $crate::metrics::CounterDescriptor<LABELS> = $crate::metrics::CounterDescriptor::new(
+            $name,
+            [$($($label),*)?],
+            $crate::register_counter!(@unit $($unit)?),
+            $crate::register_counter!(@description $($description)?),
+        );
+        COUNTER.describe()
+    }};
+}
+
+/// Counter with one label drawn from a configurable [`LabelDomain`].
+pub struct CategoryCounter(pub &'static str, pub &'static str, pub CategoryValue, pub u64);
+
+impl Metric for CategoryCounter {
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue) {
+        (MetricName::with_one_label(self.0, self.1, &self.2), MetricValue::Counter(self.3))
+    }
+}
+
 impl Metric for OneDimensionCounter {
-    fn into_metric(&self) -> (MetricName, MetricValue) {
-        (MetricName::with_one_label(self.0, "dest", &self.1), MetricValue(self.2))
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue) {
+        (MetricName::with_one_label(self.0, "dest", &self.1), MetricValue::Counter(self.2))
+    }
+}
+
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalStore for Snapshot {
+    fn is_empty(&self) -> bool {
+        Snapshot::is_empty(self)
+    }
+
+    fn take(&mut self) -> Self {
+        Snapshot::take(self)
+    }
+
+    fn merge(&mut self, other: Self) {
+        Snapshot::merge(self, other)
+    }
+
+    /// The returned snapshot was never delivered, so the next one reuses its sequence number.
+    fn coalesce(&mut self, returned: Self) {
+        let seq = returned.seq;
+        Snapshot::merge(self, returned);
+        self.seq = seq;
+    }
+
+    fn set_origin(&mut self, origin: Option<Identity>) {
+        Snapshot::set_origin(self, origin)
+    }
+
+    fn set_label_filter(&mut self, filter: Option<&'static LabelFilter>) {
+        Snapshot::set_label_filter(self, filter)
+    }
+
+    fn set_thread(&mut self, thread: Option<ThreadId>) {
+        Snapshot::set_thread(self, thread)
+    }
+
+    fn set_flush_threshold(&mut self, threshold: usize) {
+        self.flush_threshold = threshold;
+    }
+
+    fn absorb_relaxed(&mut self) {
+        relaxed::drain(|key, value| {
+            self.increment(Counter(key, value));
+        });
+    }
+}
+
+impl<M: Metric> Records<M> for Snapshot {
+    fn record(&mut self, metric: M) -> bool {
+        self.increment(metric)
+    }
+}
+
+impl<const LABELS: usize> Records<LabeledCounter<'_, LABELS>> for Snapshot {
+    fn record(&mut self, counter: LabeledCounter<'_, LABELS>) -> bool {
+        self.update_counter(counter)
+    }
+}
+
+impl<const LABELS: usize> Records<Gauge<'_, LABELS>> for Snapshot {
+    fn record(&mut self, gauge: Gauge<'_, LABELS>) -> bool {
+        self.update_gauge(gauge)
+    }
+}
+
+impl<const LABELS: usize> Records<AbsoluteCounter<'_, LABELS>> for Snapshot {
+    fn record(&mut self, counter: AbsoluteCounter<'_, LABELS>) -> bool {
+        self.update_absolute(counter)
+    }
+}
+
+#[cfg(feature = "hdr")]
+impl<const LABELS: usize> Records<HdrHistogram<'_, LABELS>> for Snapshot {
+    fn record(&mut self, histogram: HdrHistogram<'_, LABELS>) -> bool {
+        self.update_hdr(histogram)
+    }
+}
+
+impl<const LABELS: usize> Records<Flag<'_, LABELS>> for Snapshot {
+    fn record(&mut self, flag: Flag<'_, LABELS>) -> bool {
+        self.update_flag(flag)
     }
 }
 
+impl<const LABELS: usize> Records<Sketch<'_, LABELS>> for Snapshot {
+    fn record(&mut self, sketch: Sketch<'_, LABELS>) -> bool {
+        self.update_sketch(sketch)
+    }
+}
+
+impl<T: Hash + ?Sized, const LABELS: usize> Records<Unique<'_, T, LABELS>> for Snapshot {
+    fn record(&mut self, unique: Unique<'_, T, LABELS>) -> bool {
+        self.update_unique(unique)
+    }
+}
+
+impl<const LABELS: usize> Records<Meter<'_, LABELS>> for Snapshot {
+    fn record(&mut self, meter: Meter<'_, LABELS>) -> bool {
+        self.update_meter(meter)
+    }
+}
+
+impl<const LABELS: usize> Records<Summary<'_, LABELS>> for Snapshot {
+    fn record(&mut self, summary: Summary<'_, LABELS>) -> bool {
+        self.update_summary(summary)
+    }
+}
+
+impl Records<Info<'_>> for Snapshot {
+    fn record(&mut self, info: Info<'_>) -> bool {
+        self.update_info(info)
+    }
+}
+
+impl<const LABELS: usize> Records<Histogram<'_, LABELS>> for Snapshot {
+    fn record(&mut self, histogram: Histogram<'_, LABELS>) -> bool {
+        self.update_histogram(histogram)
+    }
+}
+
+impl<M: Metric> Records<WithExemplar<M>> for Snapshot {
+    fn record(&mut self, metric: WithExemplar<M>) -> bool {
+        let WithExemplar(metric, exemplar) = metric;
+        let (name, value) = metric.into_metric();
+        let (kind, value) = match value {
+            MetricValue::Counter(value) => (MetricKind::Counter, value as f64),
+            MetricValue::UpDown(delta) => (MetricKind::UpDown, delta as f64),
+        };
+        self.update_exemplar(&name, kind, exemplar, value);
+        self.increment(metric)
+    }
+}
+
+impl<const LABELS: usize> Records<WithExemplar<Histogram<'_, LABELS>>> for Snapshot {
+    fn record(&mut self, histogram: WithExemplar<Histogram<'_, LABELS>>) -> bool {
+        let WithExemplar(histogram, exemplar) = histogram;
+        self.update_exemplar(&histogram.name, MetricKind::Histogram, exemplar, histogram.value);
+        self.update_histogram(histogram)
+    }
+}
+
+impl Records<ChildIncrement<'_, '_>> for Snapshot {
+    fn record(&mut self, increment: ChildIncrement<'_, '_>) -> bool {
+        let ChildIncrement(child, value) = increment;
+        self.increment_hashed(child.hash, &child.name, value)
+    }
+}
+
+impl Records<HandleIncrement> for Snapshot {
+    fn record(&mut self, increment: HandleIncrement) -> bool {
+        let HandleIncrement(CounterHandle(slot), value) = increment;
+        if slot >= self.registered.len() {
+            self.registered.resize(slot + 1, 0);
+        }
+        self.registered[slot] = self.registered[slot].saturating_add(value);
+        self.store.count_updates(1);
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+}
+
+impl<const LABELS: usize> Records<ExpHistogram<'_, LABELS>> for Snapshot {
+    fn record(&mut self, histogram: ExpHistogram<'_, LABELS>) -> bool {
+        self.update_exp_histogram(histogram)
+    }
+}
+
+impl Records<OneDimensionHistogram> for Snapshot {
+    fn record(&mut self, histogram: OneDimensionHistogram) -> bool {
+        self.update_histogram(Histogram::labeled(histogram.0, ("dest", &histogram.1), histogram.2))
+    }
+}
 
 impl Snapshot {
     pub fn new() -> Self {
         Self {
             store: Default::default(),
             cnt: 0,
+            origin: None,
+            thread: None,
+            started: Instant::now(),
+            recorded: 0,
+            seq: 0,
+            captured: None,
+            label_filter: None,
+            registered: Vec::new(),
+            flush_threshold: FLUSH_THRESHOLD,
         }
     }
 
+    /// Whether nothing was recorded, nor merged in, e.g. a snapshot coalesced by a full channel.
     pub fn is_empty(&self) -> bool {
-        self.cnt == 0
+        self.cnt == 0 && self.store.is_empty()
+    }
+
+    /// Run and process that recorded this snapshot, if the producer set one.
+    pub fn origin(&self) -> Option<Identity> {
+        self.origin
     }
 
+    pub fn set_origin(&mut self, origin: Option<Identity>) {
+        self.origin = origin;
+    }
+
+    /// Position of this snapshot among those taken on its thread. A gap means a snapshot went
+    /// missing. One coalesced by a full channel passes its number on to the next one taken.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// When the snapshot was handed over by [`Self::take`].
+    pub fn captured(&self) -> Option<SystemTime> {
+        self.captured
+    }
+
+    pub fn set_captured(&mut self, captured: Option<SystemTime>) {
+        self.captured = captured;
+    }
+
+    /// Worker thread that recorded this snapshot, set when a [`MetricsContext`] connects.
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.thread
+    }
+
+    pub fn set_thread(&mut self, thread: Option<ThreadId>) {
+        self.thread = thread;
+    }
+
+    pub fn set_label_filter(&mut self, filter: Option<&'static LabelFilter>) {
+        self.label_filter = filter;
+    }
+
+    /// Events recorded on the producing thread up to and including this snapshot. Comparing it
+    /// with what was merged from the thread tells whether any of its snapshots went missing.
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    /// Appends the snapshot as a single TLV record, see [`tlv`] for the layout. Fails, leaving
+    /// `buf` as it was, if the snapshot has series that can't be encoded. Counts of registered
+    /// counters are moved into the store by [`Self::take`], so only taken snapshots encode all
+    /// of their metrics.
+    pub fn encode_tlv(&self, buf: &mut Vec<u8>) -> Result<(), TlvError> {
+        let start = buf.len();
+        tlv::record(buf, tlv::SNAPSHOT, |buf| {
+            if let Some(origin) = self.origin {
+                tlv::record(buf, tlv::ORIGIN, |buf| {
+                    buf.extend_from_slice(&origin.run_id.to_u128().to_le_bytes());
+                    buf.extend_from_slice(&origin.process_id.to_u128().to_le_bytes());
+                });
+            }
+            tlv::record(buf, tlv::RECORDED, |buf| buf.extend_from_slice(&self.recorded.to_le_bytes()));
+            tlv::record(buf, tlv::SEQ, |buf| buf.extend_from_slice(&self.seq.to_le_bytes()));
+            if let Some(captured) = self.captured {
+                tlv::record(buf, tlv::CAPTURED, |buf| tlv::put_time(buf, captured));
+            }
+            tlv::record(buf, tlv::EVENTS, |buf| buf.extend_from_slice(&(self.cnt as u64).to_le_bytes()));
+            self.store.encode_tlv(buf)
+        }).inspect_err(|_| buf.truncate(start))
+    }
+
+    /// Snapshot encoded by [`Self::encode_tlv`]. `bytes` must hold exactly one snapshot record.
+    pub fn decode_tlv(bytes: &[u8]) -> Result<Self, TlvError> {
+        let mut records = tlv::Records::new(bytes);
+        let snapshot = match records.next() {
+            Some(Ok((tlv::SNAPSHOT, snapshot))) => snapshot,
+            Some(Ok(_)) | None => return Err(TlvError::Missing(tlv::SNAPSHOT)),
+            Some(Err(e)) => return Err(e),
+        };
+        if !records.rest().is_empty() {
+            return Err(TlvError::Trailing(records.rest().len()))
+        }
+
+        let (mut decoded, mut restored) = (Self::new(), Restored::default());
+        for record in tlv::Records::new(snapshot) {
+            match record? {
+                (tlv::ORIGIN, origin) => {
+                    let mut fields = tlv::Fields::new(origin);
+                    decoded.origin = Some(Identity {
+                        run_id: Ulid::from_u128(fields.u128()?),
+                        process_id: Ulid::from_u128(fields.u128()?),
+                    });
+                    fields.finish(tlv::ORIGIN)?;
+                }
+                (tlv::RECORDED, recorded) => decoded.recorded = tlv::u64(tlv::RECORDED, recorded)?,
+                (tlv::SEQ, seq) => decoded.seq = tlv::u64(tlv::SEQ, seq)?,
+                (tlv::CAPTURED, captured) => {
+                    let mut fields = tlv::Fields::new(captured);
+                    decoded.captured = Some(fields.time()?);
+                    fields.finish(tlv::CAPTURED)?;
+                }
+                (tlv::EVENTS, events) => {
+                    decoded.cnt = usize::try_from(tlv::u64(tlv::EVENTS, events)?).map_err(|_| TlvError::Invalid(tlv::EVENTS))?;
+                }
+                (tlv::SERIES, series) => decoded.store.decode_tlv_series(series, &mut restored)?,
+                _ => {}
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Hands over recorded metrics, leaving an empty snapshot with the same origin, thread,
+    /// label filter and flush threshold in place. The empty snapshot comes from the [`pool`]
+    /// if the aggregator returned one, so its tables don't have to grow again. The snapshot
+    /// handed over is stamped with the time and the next sequence number of the thread.
     pub fn take(&mut self) -> Self {
-        std::mem::replace(self, Self::new())
+        self.fold_registered();
+        let (origin, thread, label_filter, flush_threshold) = (self.origin, self.thread, self.label_filter, self.flush_threshold);
+        let recorded = self.recorded.saturating_add(self.cnt as u64);
+        let seq = self.seq;
+        let registered = std::mem::take(&mut self.registered);
+        let mut taken = std::mem::replace(self, pool::take().unwrap_or_default());
+        taken.recorded = recorded;
+        taken.captured = Some(SystemTime::now());
+        self.seq = seq.saturating_add(1);
+        self.started = Instant::now();
+        taken.store.close_meters(self.started);
+        self.origin = origin;
+        self.thread = thread;
+        self.recorded = recorded;
+        self.label_filter = label_filter;
+        self.flush_threshold = flush_threshold;
+        self.registered = registered;
+
+        taken
+    }
+
+    /// Moves the counts of registered counters into the store, keeping the slots allocated.
+    fn fold_registered(&mut self) {
+        if self.registered.iter().all(|&total| total == 0) {
+            return
+        }
+        let names = REGISTERED.read().unwrap();
+        for (name, total) in names.iter().zip(&mut self.registered) {
+            if *total > 0 {
+                match self.label_filter {
+                    Some(filter) => self.store.add_total(&filter.apply(name), *total),
+                    None => self.store.add_total(name, *total),
+                }
+                *total = 0;
+            }
+        }
     }
 
     // #[inline]
     pub fn increment<M: Metric>(&mut self, metric: M) -> bool {
         let (key, value) = metric.into_metric();
-        self.store.update(&key, value.0);
+        let filtered;
+        let key = match self.label_filter {
+            Some(filter) => {
+                filtered = filter.apply(&key);
+                &filtered
+            }
+            None => &key,
+        };
+        // resolved at compile time, `into_metric` always returns the same variant for a metric type
+        match value {
+            MetricValue::Counter(value) => self.store.update(key, value),
+            MetricValue::UpDown(delta) => self.store.update_up_down(key, delta),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    /// Counter increment with the hash of `key` known upfront, see [`MetricStore::hash`].
+    pub fn increment_hashed<const LABELS: usize>(&mut self, hash: u64, key: &MetricName<'_, LABELS>, value: u64) -> bool {
+        match self.label_filter {
+            // filtering changes the name, and with it the hash
+            Some(filter) => self.store.update(&filter.apply(key), value),
+            None => self.store.update_hashed(hash, key, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_counter<const LABELS: usize>(&mut self, counter: LabeledCounter<'_, LABELS>) -> bool {
+        let LabeledCounter(name, value) = counter;
+        match self.label_filter {
+            Some(filter) => self.store.update(&filter.apply(&name), value),
+            None => self.store.update(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_gauge<const LABELS: usize>(&mut self, gauge: Gauge<'_, LABELS>) -> bool {
+        let Gauge(key, update) = gauge;
+        let now = Instant::now();
+        match self.label_filter {
+            Some(filter) => self.store.update_gauge(&filter.apply(&key), update, now),
+            None => self.store.update_gauge(&key, update, now),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_absolute<const LABELS: usize>(&mut self, counter: AbsoluteCounter<'_, LABELS>) -> bool {
+        let AbsoluteCounter(name, total) = counter;
+        match self.label_filter {
+            Some(filter) => self.store.update_absolute(&filter.apply(&name), total),
+            None => self.store.update_absolute(&name, total),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    #[cfg(feature = "hdr")]
+    pub fn update_hdr<const LABELS: usize>(&mut self, histogram: HdrHistogram<'_, LABELS>) -> bool {
+        let HdrHistogram(name, value) = histogram;
+        match self.label_filter {
+            Some(filter) => self.store.update_hdr(&filter.apply(&name), value),
+            None => self.store.update_hdr(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_flag<const LABELS: usize>(&mut self, flag: Flag<'_, LABELS>) -> bool {
+        let Flag(name, value) = flag;
+        match self.label_filter {
+            Some(filter) => self.store.update_flag(&filter.apply(&name), value),
+            None => self.store.update_flag(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_sketch<const LABELS: usize>(&mut self, sketch: Sketch<'_, LABELS>) -> bool {
+        let Sketch(name, value) = sketch;
+        match self.label_filter {
+            Some(filter) => self.store.update_sketch(&filter.apply(&name), value),
+            None => self.store.update_sketch(&name, value),
+        }
         self.cnt += 1;
 
-        self.cnt >= 50_000
+        self.cnt >= self.flush_threshold
     }
 
-    pub fn merge(&mut self, other: Self) {
-        self.store.merge(other.store);
+    pub fn update_unique<T: Hash + ?Sized, const LABELS: usize>(&mut self, unique: Unique<'_, T, LABELS>) -> bool {
+        let Unique(name, value) = unique;
+        match self.label_filter {
+            Some(filter) => self.store.update_unique(&filter.apply(&name), value),
+            None => self.store.update_unique(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_meter<const LABELS: usize>(&mut self, meter: Meter<'_, LABELS>) -> bool {
+        let Meter(name, count) = meter;
+        match self.label_filter {
+            Some(filter) => self.store.update_meter(&filter.apply(&name), count, self.started),
+            None => self.store.update_meter(&name, count, self.started),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    /// Doesn't count towards the flush threshold, exemplars come with a recording that does.
+    fn update_exemplar<const LABELS: usize>(&mut self, name: &MetricName<'_, LABELS>, kind: MetricKind, exemplar: Exemplar, value: f64) {
+        let now = Instant::now();
+        match self.label_filter {
+            Some(filter) => self.store.update_exemplar(&filter.apply(name), kind, exemplar, value, now),
+            None => self.store.update_exemplar(name, kind, exemplar, value, now),
+        }
+    }
+
+    pub fn update_exp_histogram<const LABELS: usize>(&mut self, histogram: ExpHistogram<'_, LABELS>) -> bool {
+        let ExpHistogram(name, value) = histogram;
+        match self.label_filter {
+            Some(filter) => self.store.update_exp_histogram(&filter.apply(&name), value),
+            None => self.store.update_exp_histogram(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_summary<const LABELS: usize>(&mut self, summary: Summary<'_, LABELS>) -> bool {
+        let Summary(name, value) = summary;
+        match self.label_filter {
+            Some(filter) => self.store.update_summary(&filter.apply(&name), value),
+            None => self.store.update_summary(&name, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_info(&mut self, info: Info<'_>) -> bool {
+        let Info(name, labels) = info;
+        self.store.update_info(name, labels);
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn update_histogram<const LABELS: usize>(&mut self, histogram: Histogram<'_, LABELS>) -> bool {
+        let Histogram { name, bounds, value } = histogram;
+        match self.label_filter {
+            Some(filter) => self.store.update_histogram(&filter.apply(&name), bounds, value),
+            None => self.store.update_histogram(&name, bounds, value),
+        }
+        self.cnt += 1;
+
+        self.cnt >= self.flush_threshold
+    }
+
+    pub fn merge(&mut self, mut other: Self) {
+        self.merge_drain(&mut other);
     }
 
-    pub fn get(&self, key: &MetricName) -> Option<u64> {
+    /// Same as [`Self::merge`], but leaves `other` empty with its tables allocated, ready to
+    /// be handed to [`pool::recycle`].
+    pub fn merge_drain(&mut self, other: &mut Self) {
+        other.fold_registered();
+        self.store.merge_drain(&mut other.store);
+    }
+
+    /// Merges `other` and counts its events as recorded here, unlike [`Self::merge`]. Returns
+    /// `true` once the snapshot should be flushed, as [`Records::record`] does.
+    pub fn merge_recorded(&mut self, mut other: Self) -> bool {
+        self.cnt = self.cnt.saturating_add(other.cnt);
+        self.merge_drain(&mut other);
+        self.cnt >= self.flush_threshold
+    }
+
+    /// Returns a snapshot drained by [`Self::merge_drain`] to the [`pool`], for a producer to
+    /// record into. Snapshots that still hold series are dropped.
+    pub fn recycle(mut self) {
+        if !self.store.is_empty() {
+            return
+        }
+        self.cnt = 0;
+        self.recorded = 0;
+        self.seq = 0;
+        self.captured = None;
+        self.origin = None;
+        self.thread = None;
+        self.label_filter = None;
+        self.registered.clear();
+        pool::put(self);
+    }
+
+    pub fn merge_ref(&mut self, other: &Self) {
+        self.store.merge_ref(&other.store);
+        if self.registered.len() < other.registered.len() {
+            self.registered.resize(other.registered.len(), 0);
+        }
+        for (total, &other) in self.registered.iter_mut().zip(&other.registered) {
+            *total = total.saturating_add(other);
+        }
+    }
+
+    pub fn get<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SeriesValue> {
+        self.store.get(key)
+    }
+
+    pub fn get_counter<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<u64> {
         self.store.get_counter(key)
     }
 
     pub fn get_all_dims(&self, key: &'static str) -> Option<u64> {
         self.store.get_counter_all_dim(key)
     }
+
+    /// Sum of the counters of `key` that have every label in `labels`, see
+    /// [`MetricStore::get_counter_matching`].
+    pub fn get_matching(&self, key: &'static str, labels: &[(&'static str, &dyn LabelValue)]) -> Option<u64> {
+        self.store.get_counter_matching(key, labels)
+    }
+
+    /// Counters of `key` summed by the value of `label`, see [`MetricStore::group_by`].
+    pub fn group_by(&self, key: &'static str, label: &'static str) -> HashMap<String, u64> {
+        self.store.group_by(key, label)
+    }
+
+    pub fn get_gauge<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<f64> {
+        self.store.get_gauge(key)
+    }
+
+    pub fn get_absolute<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<u64> {
+        self.store.get_absolute(key)
+    }
+
+    pub fn get_up_down<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<i64> {
+        self.store.get_up_down(key)
+    }
+
+    pub fn get_flag<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<bool> {
+        self.store.get_flag(key)
+    }
+
+    pub fn get_sketch<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SketchValue> {
+        self.store.get_sketch(key)
+    }
+
+    pub fn get_unique<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&UniqueValue> {
+        self.store.get_unique(key)
+    }
+
+    pub fn get_meter<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&MeterValue> {
+        self.store.get_meter(key)
+    }
+
+    pub fn get_summary<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&SummaryValue> {
+        self.store.get_summary(key)
+    }
+
+    pub fn get_exp_histogram<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&ExpHistogramValue> {
+        self.store.get_exp_histogram(key)
+    }
+
+    pub fn get_exemplar<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>, kind: MetricKind) -> Option<&ExemplarValue> {
+        self.store.get_exemplar(key, kind)
+    }
+
+    #[cfg(feature = "hdr")]
+    pub fn get_hdr<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&crate::dimensions::HdrValue> {
+        self.store.get_hdr(key)
+    }
+
+    pub fn get_histogram<const LABELS: usize>(&self, key: &MetricName<'_, LABELS>) -> Option<&HistogramValue> {
+        self.store.get_histogram(key)
+    }
+
+    pub fn cardinality_report(&self, top: usize) -> Vec<CardinalityReport> {
+        self.store.cardinality_report(top)
+    }
+
+    /// Counter series that differ from `other`, e.g. to see where counts went missing between
+    /// what was recorded and what was merged. See [`MetricStore::diff`].
+    pub fn diff_report(&self, other: &Snapshot) -> DiffReport {
+        self.store.diff(&other.store)
+    }
+
+    pub fn store(&self) -> &MetricStore {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut MetricStore {
+        &mut self.store
+    }
 }
 
 thread_local! {
     pub static METRICS_CTX: MetricsContext = const { MetricsContext::new() }
 }
 
+/// Connects [`METRICS_CTX`] on threads the runtime hooks don't reach, e.g. plain std threads,
+/// rayon workers or tests, until dropped. Dropping it flushes what the thread recorded and
+/// disconnects it, so the sender doesn't outlive the guard. Installing a guard replaces any
+/// connection the thread had.
+#[must_use]
+pub struct MetricsGuard {
+    /// Not `Send`, as dropping disconnects the current thread, which must be the one it was
+    /// installed on.
+    _thread: PhantomData<*const ()>,
+}
+
+impl MetricsGuard {
+    pub fn install<T: Into<SnapshotSender>>(tx: T) -> Self {
+        Self::install_with(tx, MetricsContextConfig::default())
+    }
+
+    pub fn install_with<T: Into<SnapshotSender>>(tx: T, config: MetricsContextConfig) -> Self {
+        METRICS_CTX.with(|m| m.connect_with(tx, config));
+        Self { _thread: PhantomData }
+    }
+}
+
+impl Drop for MetricsGuard {
+    fn drop(&mut self) {
+        let _ = METRICS_CTX.try_with(|m| m.disconnect());
+    }
+}
+
+tokio::task_local! {
+    static TASK_SNAPSHOT: RefCell<Snapshot>;
+}
+
+/// Label that tells series recorded in different [`task_scope`]s apart.
+pub const TASK_LABEL: &str = "task";
+
+/// Runs `future` with a snapshot of its own, which follows it across worker threads. Metrics
+/// recorded through [`increment_in_task`] inside it are kept there until it completes, then
+/// labelled `task=scope` and rolled up into the context of the thread that completed it.
+/// Scopes don't flush on their own, and what a cancelled scope recorded is lost.
+pub async fn task_scope<F: Future>(scope: u64, future: F) -> F::Output {
+    let (output, mut snapshot) = TASK_SNAPSHOT.scope(RefCell::default(), async move {
+        let output = future.await;
+        (output, TASK_SNAPSHOT.with(RefCell::take))
+    }).await;
+    snapshot.store_mut().add_label(TASK_LABEL, &SeriesId(scope));
+    METRICS_CTX.with(|m| m.roll_up(snapshot));
+
+    output
+}
+
+/// Records `metric` into the snapshot of the enclosing [`task_scope`], or into the thread's
+/// context outside of one.
+pub fn increment_in_task<M>(metric: M) where Snapshot: Records<M> {
+    if TASK_SNAPSHOT.try_with(|_| ()).is_ok() {
+        TASK_SNAPSHOT.with(|snapshot| snapshot.borrow_mut().record(metric));
+    } else {
+        METRICS_CTX.with(|m| m.increment(metric));
+    }
+}
+
 pub const KEY: &str = "metric";
 
-pub async fn do_work_async() {
-    loop {
-        let mut iter = 0;
+pub async fn do_work_async(work: Work) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
         METRICS_CTX.with(|m| {
-            m.increment(Counter(KEY, 1));
+            m.increment(Counter(KEY, values.next()));
         });
         iter += 1;
-        if iter % 100 == 0 {
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// `tlv-macro` mode: [`do_work_async`] through [`tlv_counter!`], the same call site as
+/// `ext-metrics`.
+pub async fn do_work_async_macro(work: Work) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
+        tlv_counter!(KEY).increment(values.next());
+        iter += 1;
+        if iter.is_multiple_of(100) {
             tokio::task::yield_now().await;
         }
     }
 }
 
-pub async fn do_work_async_one_dim() {
-    loop {
-        let mut iter = 0;
+/// `tlv-registered` mode: increments labeled by helper through a [`register_counter!`] call
+/// site, to compare with the [`CounterFamily`] children of `tlv-dim-1`.
+pub async fn do_work_async_registered(work: Work) {
+    let helpers = [HelperIdentity::H1, HelperIdentity::H2, HelperIdentity::H3];
+    let mut values = work.values();
+    let mut iter = 0;
+    while !work.stopped() {
+        work.run();
+        register_counter!(KEY, labels = ["dest"], unit = Count).increment([&helpers[iter % 3]], values.next());
+        iter += 1;
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+pub async fn do_work_async_one_dim(work: Work) {
+    const FAMILY: CounterFamily = CounterFamily::new(KEY, "dest");
+    let helpers = [HelperIdentity::H1, HelperIdentity::H2, HelperIdentity::H3];
+    let [h1, h2, h3] = helpers.each_ref().map(|helper| FAMILY.with(helper));
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
+        if iter.is_multiple_of(3) {
+            h3.increment(values.next());
+        } else if iter & (iter - 1) == 0 {
+            h2.increment(values.next());
+        } else {
+            h1.increment(values.next());
+        }
+        iter += 1;
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// Counter labeled with a request id, standing in for labels that should never be used as
+/// dimensions but end up there anyway.
+pub struct RequestCounter(pub &'static str, pub SeriesId, pub u64);
+
+impl Metric for RequestCounter {
+    fn into_metric(&self) -> (MetricName<'_>, MetricValue) {
+        (MetricName::with_one_label(self.0, "request_id", &self.1), MetricValue::Counter(self.2))
+    }
+}
+
+/// Cycles through every value of `domain`, so the number of series is set by the domain size.
+pub async fn do_work_async_domain(work: Work, domain: &'static LabelDomain) {
+    let mut values = work.values();
+    let mut iter = 0;
+    while !work.stopped() {
+        work.run();
         METRICS_CTX.with(|m| {
-            if iter % 3 == 0 {
-                m.increment(OneDimensionCounter(KEY, HelperIdentity::H3, 1));
-            } else if iter & (iter - 1) == 0 {
-                m.increment(OneDimensionCounter(KEY, HelperIdentity::H2, 1));
-            } else {
-                m.increment(OneDimensionCounter(KEY, HelperIdentity::H1, 1));
-            }
+            m.increment(CategoryCounter(KEY, domain.name, domain.value(iter % domain.len()), values.next()));
+        });
+        iter += 1;
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// Labels every increment with the next request id out of `space` distinct ids, starting from
+/// `first`, to grow the store to pathological sizes.
+pub async fn do_work_async_high_cardinality(work: Work, space: u64, first: u64) {
+    let mut values = work.values();
+    let mut iter = 0;
+    while !work.stopped() {
+        work.run();
+        METRICS_CTX.with(|m| {
+            m.increment(RequestCounter(KEY, SeriesId((first + iter) % space), values.next()));
         });
         iter += 1;
-        if iter % 100 == 0 {
+        if iter.is_multiple_of(100) {
             tokio::task::yield_now().await;
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::array;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use crossbeam::channel::unbounded;
+    use crate::dimensions::{HelperIdentity, LabelFilter, MetricName, SeriesId, SeriesValue};
+    use crate::metrics::{ChildIncrement, Counter, CounterFamily, FLUSH_THRESHOLD, Gauge, HandleIncrement, Histogram, increment_in_task, LabeledCounter, METRICS_CTX, MetricsContextConfig, MetricsGuard, OneDimensionCounter, set_strict, OneDimensionHistogram, Records, Snapshot, task_scope, TASK_LABEL, Timer, UpDownCounter};
+    use crate::metadata::{self, Unit};
+    use crate::test_utils::{exclusive_heap, shared_heap};
+
+    #[test]
+    fn timer_records_on_drop() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx));
+
+        {
+            let _timer = Timer::start(MetricName::with_no_labels("latency"));
+            sleep(Duration::from_millis(1));
+        }
+
+        let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+        let histogram = snapshot.get_histogram(&MetricName::with_no_labels("latency")).unwrap();
+        assert_eq!(1, histogram.count());
+        assert!(histogram.sum() >= 1_000_000.0);
+    }
+
+    #[test]
+    fn up_down_counter() {
+        let _heap = shared_heap();
+        let name = MetricName::with_no_labels("open_connections");
+        let (mut a, mut b) = (Snapshot::new(), Snapshot::new());
+        a.record(UpDownCounter("open_connections", 3));
+        b.record(UpDownCounter("open_connections", 1));
+        b.record(UpDownCounter("open_connections", -5));
+        assert_eq!(Some(-4), b.get_up_down(&name));
+
+        a.merge(b);
+        assert_eq!((Some(-1), None), (a.get_up_down(&name), a.get_counter(&name)));
+        // untyped lookups find the series whatever its kind
+        assert!(matches!(a.get(&name), Some(SeriesValue::UpDown(-1))));
+    }
+
+    #[test]
+    fn diff_report() {
+        let _heap = shared_heap();
+        let (mut recorded, mut merged) = (Snapshot::new(), Snapshot::new());
+        for (snapshot, h1, h2) in [(&mut recorded, 5, 3), (&mut merged, 5, 7)] {
+            snapshot.record(OneDimensionCounter("requests", HelperIdentity::H1, h1));
+            snapshot.record(OneDimensionCounter("requests", HelperIdentity::H2, h2));
+        }
+        recorded.record(OneDimensionCounter("requests", HelperIdentity::H3, 2));
+        merged.record(OneDimensionCounter("errors", HelperIdentity::H1, 1));
+        recorded.record(Histogram::labeled("latency", ("dest", &HelperIdentity::H1), 1.0));
+        merged.record(Histogram::labeled("latency", ("dest", &HelperIdentity::H1), 1.0));
+        merged.record(Histogram::labeled("latency", ("dest", &HelperIdentity::H1), 2.0));
+
+        assert_eq!(
+            "+ errors{dest=H1} 1\n\
+             ~ latency{dest=H1} count=1 sum=1 p50=1 p99=1 max=1 -> count=2 sum=3 p50=2 p99=2 max=2\n\
+             ~ requests{dest=H2} 3 -> 7 (+4)\n\
+             - requests{dest=H3} 2",
+            recorded.diff_report(&merged).to_string(),
+        );
+        assert!(merged.diff_report(&merged).is_empty());
+    }
+
+    #[test]
+    fn counter_family() {
+        let _heap = shared_heap();
+        let family = CounterFamily::new("requests", "dest");
+        let h2 = family.with(&HelperIdentity::H2);
+        let mut snapshot = Snapshot::new();
+        snapshot.record(ChildIncrement(&h2, 2));
+        snapshot.record(OneDimensionCounter("requests", HelperIdentity::H2, 3));
+        assert_eq!(1, snapshot.store().len());
+        assert_eq!(Some(5), snapshot.get_counter(&MetricName::with_one_label("requests", "dest", &HelperIdentity::H2)));
+
+        // children are resolved before filtering, which then has to rehash
+        snapshot.set_label_filter(Some(Box::leak(Box::new(LabelFilter::new(["dest".to_string()])))));
+        snapshot.record(ChildIncrement(&h2, 4));
+        assert_eq!(Some(4), snapshot.get_counter(&MetricName::with_no_labels("requests")));
+    }
+
+    #[test]
+    fn counter_handle() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx.clone()));
+        let name = MetricName::with_one_label("registered", "dest", &HelperIdentity::H3);
+        let (other, handle) = METRICS_CTX.with(|m| {
+            (m.register_counter(&MetricName::with_no_labels("other")), m.register_counter(&name))
+        });
+        assert_eq!(handle, METRICS_CTX.with(|m| m.register_counter(&MetricName::with_one_label("registered", "dest", &HelperIdentity::H3))));
+        assert_ne!(handle, METRICS_CTX.with(|m| m.register_counter(&MetricName::with_one_label("registered", "dest", &HelperIdentity::H2))));
+
+        handle.increment(2);
+        METRICS_CTX.with(|m| m.increment(OneDimensionCounter("registered", HelperIdentity::H3, 3)));
+        let mut total = METRICS_CTX.with(|m| m.take_snapshot());
+        assert_eq!(Some(5), total.get_counter(&name));
+
+        // handles are valid in every thread's context
+        total.merge(std::thread::spawn(move || {
+            METRICS_CTX.with(|m| m.connect(tx));
+            handle.increment(4);
+            METRICS_CTX.with(|m| m.take_snapshot())
+        }).join().unwrap());
+        assert_eq!(Some(9), total.get_counter(&name));
+
+        // snapshots that were never taken are folded when merged
+        let mut snapshot = Snapshot::new();
+        snapshot.record(HandleIncrement(handle, 1));
+        snapshot.record(HandleIncrement(other, 0));
+        total.merge(snapshot);
+        assert_eq!((Some(10), None), (total.get_counter(&name), total.get_counter(&MetricName::with_no_labels("other"))));
+    }
+
+    #[test]
+    fn configured_flush_threshold() {
+        let _heap = shared_heap();
+        let (tx, rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect_with(tx, MetricsContextConfig { flush_every_n: 3, ..Default::default() }));
+        for _ in 0..7 {
+            METRICS_CTX.with(|m| m.increment(Counter("flushed_every_3", 1)));
+        }
+
+        let flushed = rx.try_iter().map(|s: Snapshot| s.get_all_dims("flushed_every_3")).collect::<Vec<_>>();
+        assert_eq!(vec![Some(3), Some(3)], flushed);
+        assert_eq!(Some(1), METRICS_CTX.with(|m| m.take_snapshot()).get_all_dims("flushed_every_3"));
+    }
+
+    #[test]
+    fn flush_stale_recordings() {
+        let _heap = shared_heap();
+        let (tx, rx) = unbounded();
+        let config = MetricsContextConfig { max_staleness: Some(Duration::from_millis(20)), ..Default::default() };
+        METRICS_CTX.with(|m| m.connect_with(tx, config));
+        let increment = || METRICS_CTX.with(|m| m.increment(Counter("rare", 1)));
+        increment();
+        increment();
+        assert!(rx.is_empty());
+        sleep(Duration::from_millis(30));
+        increment();
+
+        let flushed: Snapshot = rx.try_recv().unwrap();
+        assert_eq!(Some(3), flushed.get_all_dims("rare"));
+        // staleness counts from the first recording after the flush
+        increment();
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn register_counter_macro() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx));
+        let (h1, h2) = (HelperIdentity::H1, HelperIdentity::H2);
+        for helper in [&h1, &h2, &h1] {
+            register_counter!("registered_requests", labels = ["helper", "dest"], unit = Count).increment([helper, &"H3"], 1);
+            register_counter!("registered_retries", description = "Requests sent again").increment([], 2);
+        }
+
+        let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+        let name = MetricName::from(("registered_requests", ("dest", &"H3"), ("helper", &h1)));
+        assert_eq!(Some(2), snapshot.get_counter(&name));
+        assert_eq!(Some(6), snapshot.get_counter(&MetricName::with_no_labels("registered_retries")));
+        assert_eq!(Some(Unit::Count), metadata::metadata("registered_requests").and_then(|m| m.unit));
+        assert_eq!(Some("Requests sent again"), metadata::metadata("registered_retries").map(|m| m.description));
+    }
+
+    #[test]
+    fn tlv_counter_macro() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx));
+        for dest in ["H1", "H2", "H1"] {
+            tlv_counter!("macro_requests").increment(1);
+            tlv_counter!("macro_requests", "dest" => "H1").increment(2);
+            tlv_counter!("macro_requests", "dest" => dest).increment(3);
+        }
+
+        let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+        assert_eq!(Some(3), snapshot.get_counter(&MetricName::with_no_labels("macro_requests")));
+        // literal and run time values of the same series end up together
+        assert_eq!(Some(12), snapshot.get_counter(&MetricName::with_one_label("macro_requests", "dest", &"H1")));
+        assert_eq!(Some(3), snapshot.get_counter(&MetricName::with_one_label("macro_requests", "dest", &"H2")));
+    }
+
+    #[test]
+    fn histogram_per_helper() {
+        let _heap = shared_heap();
+        let mut snapshot = Snapshot::new();
+        snapshot.record(OneDimensionHistogram("latency", HelperIdentity::H1, 1_000.0));
+        snapshot.record(OneDimensionHistogram("latency", HelperIdentity::H1, 3_000.0));
+        snapshot.record(Histogram::labeled("latency", ("dest", &HelperIdentity::H2), 5_000.0));
+
+        let (h1, h2) = (HelperIdentity::H1, HelperIdentity::H2);
+        let h1 = snapshot.get_histogram(&MetricName::with_one_label("latency", "dest", &h1)).unwrap();
+        let h2 = snapshot.get_histogram(&MetricName::with_one_label("latency", "dest", &h2)).unwrap();
+        assert_eq!((2, 4_000.0), (h1.count(), h1.sum()));
+        assert_eq!((1, 5_000.0), (h2.count(), h2.sum()));
+        assert_eq!(None, snapshot.get_histogram(&MetricName::with_no_labels("latency")));
+    }
+
+    #[test]
+    fn names_of_any_width() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx));
+        const LABELS: [&str; 8] = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let ids = [HelperIdentity::H1, HelperIdentity::H2, HelperIdentity::H3];
+        let narrow = MetricName::with_label_array("narrow", [("dest", &ids[0] as _)]);
+        let wide = MetricName::with_label_array("wide", array::from_fn::<_, 8, _>(|i| (LABELS[i], &ids[i % 3] as _)));
+        METRICS_CTX.with(|m| {
+            m.increment(LabeledCounter(narrow, 2));
+            m.increment(LabeledCounter(wide, 3));
+            m.increment(LabeledCounter(wide, 4));
+            m.increment(Gauge::set(wide, 1.5));
+            m.increment(Histogram::new(narrow, 10.0));
+        });
+
+        let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+        assert_eq!(Some(2), snapshot.get_counter(&narrow));
+        assert_eq!(Some(7), snapshot.get_counter(&wide));
+        assert_eq!(Some(1.5), snapshot.get_gauge(&wide));
+        assert_eq!(1, snapshot.get_histogram(&narrow).unwrap().count());
+        assert_eq!(8, snapshot.store().iter().find(|(key, ..)| *key == "wide").unwrap().1.count());
+    }
+
+    #[test]
+    fn guard_connects_std_thread() {
+        let _heap = shared_heap();
+        let (tx, rx) = unbounded::<Snapshot>();
+        {
+            let _guard = MetricsGuard::install(tx);
+            METRICS_CTX.with(|m| m.increment(Counter("background", 2)));
+        }
+
+        assert_eq!(Some(2), rx.try_recv().unwrap().get_all_dims("background"));
+        // the thread is still alive, but the guard took the last sender with it
+        assert!(rx.try_recv().unwrap_err().is_disconnected());
+    }
+
+    #[test]
+    fn task_scopes_roll_up_labelled() {
+        let _heap = shared_heap();
+        let (tx, _rx) = unbounded();
+        METRICS_CTX.with(|m| m.connect(tx));
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let record = |scope, n| task_scope(scope, async move {
+                for _ in 0..n {
+                    increment_in_task(Counter("requests", 1));
+                    // lets the other scope record in between
+                    tokio::task::yield_now().await;
+                }
+            });
+            tokio::join!(record(1, 2), record(2, 3));
+        });
+        increment_in_task(Counter("requests", 10));
+
+        let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+        let task = |scope| MetricName::with_one_label("requests", TASK_LABEL, &SeriesId(scope)).leak();
+        assert_eq!((Some(2), Some(3)), (snapshot.get_counter(&task(1)), snapshot.get_counter(&task(2))));
+        assert_eq!(Some(10), snapshot.get_counter(&MetricName::with_no_labels("requests")));
+        assert_eq!(6, snapshot.recorded());
+    }
+
+    #[test]
+    fn unconnected_thread_keeps_metrics_until_connected() {
+        // strict mode is process-wide, no other test may record while it is on
+        let _heap = exclusive_heap();
+        let (tx, rx) = unbounded::<Snapshot>();
+        std::thread::spawn(move || {
+            for _ in 0..FLUSH_THRESHOLD + 1 {
+                METRICS_CTX.with(|m| m.increment(Counter("early", 1)));
+            }
+            METRICS_CTX.with(|m| m.connect(tx));
+            METRICS_CTX.with(|m| m.increment(Counter("early", 1)));
+            METRICS_CTX.with(|m| m.disconnect());
+        }).join().unwrap();
+        assert_eq!(Some(FLUSH_THRESHOLD as u64 + 2), rx.recv().unwrap().get_all_dims("early"));
+
+        set_strict(true);
+        let strict = std::thread::spawn(|| METRICS_CTX.with(|m| m.increment(Counter("early", 1)))).join();
+        set_strict(false);
+        assert!(strict.is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use crate::metrics::Snapshot;
+
+/// Snapshots the aggregator drained, waiting to be recorded into again. Producers take one in
+/// place of every snapshot they flush, so once flushing reaches a steady state it reuses tables
+/// grown by earlier cycles instead of allocating new ones.
+static POOL: Mutex<Vec<Snapshot>> = Mutex::new(Vec::new());
+
+/// Snapshots kept at most, more are dropped when they are returned.
+pub const CAPACITY: usize = 64;
+
+/// An empty snapshot from the pool, if there is one.
+pub fn take() -> Option<Snapshot> {
+    POOL.lock().unwrap_or_else(PoisonError::into_inner).pop()
+}
+
+/// Keeps an empty snapshot for [`take`], unless the pool is full.
+pub fn put(snapshot: Snapshot) {
+    debug_assert!(snapshot.is_empty());
+    let mut pool = POOL.lock().unwrap_or_else(PoisonError::into_inner);
+    if pool.len() < CAPACITY {
+        pool.push(snapshot);
+    }
+}
+
+/// Bucket counts of histograms dropped after they were merged, by the aggregator mostly, by
+/// length. Producers take them for series they see for the first time, so after warmup a new
+/// label combination doesn't allocate any more than a new counter does. Counts are zeroed when
+/// they are returned, off the recording path.
+///
+/// Arrays are dropped on the thread that merges and needed on the threads that record, so
+/// they have to cross threads through a shared pool. Every thread keeps its own cache in front
+/// of it and only takes the lock to move [`BUCKETS_BATCH`] arrays at once.
+static BUCKETS: Mutex<BTreeMap<usize, Vec<Box<[u64]>>>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    static LOCAL_BUCKETS: RefCell<BTreeMap<usize, Vec<Box<[u64]>>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Bucket arrays of every length kept at most by the shared pool, more are dropped when they
+/// are returned.
+pub const BUCKETS_CAPACITY: usize = 1024;
+
+/// Bucket arrays moved between a thread's cache and the shared pool at once. A thread caches up
+/// to twice as many of every length.
+pub const BUCKETS_BATCH: usize = 32;
+
+/// Zeroed bucket counts of length `len` from the pool, if there are any.
+pub fn take_buckets(len: usize) -> Option<Box<[u64]>> {
+    // threads being torn down go to the shared pool directly
+    LOCAL_BUCKETS.try_with(|local| {
+        let mut local = local.borrow_mut();
+        if let Some(buckets) = local.get_mut(&len).and_then(Vec::pop) {
+            return Some(buckets)
+        }
+        let mut shared = BUCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+        let shared = shared.get_mut(&len)?;
+        let local = local.entry(len).or_default();
+        local.extend(shared.drain(shared.len().saturating_sub(BUCKETS_BATCH)..));
+        local.pop()
+    }).unwrap_or_else(|_| BUCKETS.lock().unwrap_or_else(PoisonError::into_inner).get_mut(&len)?.pop())
+}
+
+/// Keeps `buckets` for [`take_buckets`], unless the pool is full.
+pub fn put_buckets(mut buckets: Box<[u64]>) {
+    if buckets.is_empty() {
+        return
+    }
+    buckets.fill(0);
+    let len = buckets.len();
+    let mut buckets = Some(buckets);
+    let spilled = LOCAL_BUCKETS.try_with(|local| {
+        let mut local = local.borrow_mut();
+        let local = local.entry(len).or_default();
+        local.extend(buckets.take());
+        (local.len() > 2 * BUCKETS_BATCH).then(|| local.split_off(local.len() - BUCKETS_BATCH))
+    }).unwrap_or_default();
+    // `buckets` is still there if the thread is being torn down
+    if spilled.is_none() && buckets.is_none() {
+        return
+    }
+    let mut shared = BUCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+    let shared = shared.entry(len).or_default();
+    let room = BUCKETS_CAPACITY.saturating_sub(shared.len());
+    shared.extend(spilled.into_iter().flatten().chain(buckets).take(room));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::pool::{BUCKETS_BATCH, put_buckets, take_buckets};
+    use crate::test_utils::shared_heap;
+
+    #[test]
+    fn buckets_cross_threads_in_batches() {
+        let _heap = shared_heap();
+        // a length no histogram in other tests has
+        const LEN: usize = 997;
+        // the thread keeps what is left after spilling a batch, and drops it when it exits
+        thread::spawn(|| (0..=2 * BUCKETS_BATCH).for_each(|_| put_buckets(vec![1; LEN].into_boxed_slice()))).join().unwrap();
+
+        let taken = thread::spawn(|| (0..=BUCKETS_BATCH).map(|_| take_buckets(LEN)).collect::<Vec<_>>()).join().unwrap();
+        assert!(taken[..BUCKETS_BATCH].iter().all(|buckets| buckets.as_deref() == Some(&[0; LEN][..])));
+        assert_eq!(None, taken[BUCKETS_BATCH]);
+    }
+}
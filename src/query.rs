@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crate::results::SeriesSample;
+
+/// Tiny PromQL-like expression over the aggregated counters, for ad-hoc checks during a run,
+/// e.g. `sum(metric{helper="H1"}) / rate(other[30s])`.
+///
+/// Supported: selectors with `=` and `!=` label matchers, `sum`, `min`, `max` and `count`
+/// over a selector, `rate` and `increase` over a range selector, numbers, parentheses and
+/// `+ - * /`. Vectors combine with scalars element-wise and with each other by equal labels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Aggregate(Aggregation, Box<Expr>),
+    Range(RangeFunction, Selector, Duration),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub metric: String,
+    pub matchers: Vec<Matcher>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matcher {
+    pub label: String,
+    pub value: String,
+    pub equal: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Sum,
+    Min,
+    Max,
+    Count,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeFunction {
+    /// Per-second increase over the range.
+    Rate,
+    Increase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Result of evaluating an [`Expr`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryValue {
+    Scalar(f64),
+    Vector(Vec<(BTreeMap<String, String>, f64)>),
+}
+
+/// Totals recorded at the end of past intervals, which range functions look back into.
+#[derive(Debug)]
+pub struct History {
+    retention: Duration,
+    samples: VecDeque<Recording>,
+}
+
+type Recording = (Instant, Vec<SeriesSample>);
+
+impl History {
+    pub fn new(retention: Duration) -> Self {
+        Self { retention, samples: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, at: Instant, samples: Vec<SeriesSample>) {
+        while self.samples.front().is_some_and(|(first, _)| at.saturating_duration_since(*first) > self.retention) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, samples));
+    }
+
+    /// The oldest and latest recordings no more than `range` apart, if there are two.
+    fn window(&self, range: Duration) -> Option<(&Recording, &Recording)> {
+        let last = self.samples.back()?;
+        let first = self.samples.iter().find(|(at, _)| last.0.saturating_duration_since(*at) <= range)?;
+        (first.0 < last.0).then_some((first, last))
+    }
+}
+
+impl Selector {
+    fn matches(&self, sample: &SeriesSample) -> bool {
+        sample.key == self.metric && self.matchers.iter().all(|m| {
+            (sample.labels.get(&m.label).map_or("", String::as_str) == m.value) == m.equal
+        })
+    }
+
+    fn select<'a>(&'a self, samples: &'a [SeriesSample]) -> impl Iterator<Item = &'a SeriesSample> + 'a {
+        samples.iter().filter(|sample| self.matches(sample))
+    }
+}
+
+impl Expr {
+    /// Evaluates against the `current` totals, looking back into `history` for range functions.
+    /// Ranges with fewer than two recordings evaluate to an empty vector.
+    pub fn eval(&self, current: &[SeriesSample], history: &History) -> Result<QueryValue, String> {
+        Ok(match self {
+            Expr::Number(n) => QueryValue::Scalar(*n),
+            Expr::Selector(selector) => QueryValue::Vector(
+                selector.select(current).map(|s| (s.labels.clone(), s.value as f64)).collect(),
+            ),
+            Expr::Aggregate(aggregation, inner) => {
+                let QueryValue::Vector(series) = inner.eval(current, history)? else {
+                    return Err(format!("{aggregation:?} expects a vector"))
+                };
+                let values = series.iter().map(|(_, v)| *v);
+                QueryValue::Scalar(match aggregation {
+                    Aggregation::Sum => values.sum(),
+                    Aggregation::Min => values.fold(f64::NAN, f64::min),
+                    Aggregation::Max => values.fold(f64::NAN, f64::max),
+                    Aggregation::Count => series.len() as f64,
+                })
+            }
+            Expr::Range(function, selector, range) => {
+                let Some(((start, first), (end, last))) = history.window(*range) else {
+                    return Ok(QueryValue::Vector(Vec::new()))
+                };
+                let elapsed = end.duration_since(*start).as_secs_f64();
+                QueryValue::Vector(selector.select(last).map(|sample| {
+                    let before = selector.select(first).find(|s| s.labels == sample.labels).map_or(0, |s| s.value);
+                    let increase = sample.value.saturating_sub(before) as f64;
+                    let value = match function {
+                        RangeFunction::Rate => increase / elapsed,
+                        RangeFunction::Increase => increase,
+                    };
+                    (sample.labels.clone(), value)
+                }).collect())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let apply = |a: f64, b: f64| match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                };
+                match (lhs.eval(current, history)?, rhs.eval(current, history)?) {
+                    (QueryValue::Scalar(a), QueryValue::Scalar(b)) => QueryValue::Scalar(apply(a, b)),
+                    (QueryValue::Vector(v), QueryValue::Scalar(b)) => {
+                        QueryValue::Vector(v.into_iter().map(|(labels, a)| (labels, apply(a, b))).collect())
+                    }
+                    (QueryValue::Scalar(a), QueryValue::Vector(v)) => {
+                        QueryValue::Vector(v.into_iter().map(|(labels, b)| (labels, apply(a, b))).collect())
+                    }
+                    (QueryValue::Vector(l), QueryValue::Vector(r)) => QueryValue::Vector(
+                        l.into_iter()
+                            .filter_map(|(labels, a)| {
+                                let b = r.iter().find(|(other, _)| *other == labels)?.1;
+                                Some((labels, apply(a, b)))
+                            })
+                            .collect(),
+                    ),
+                }
+            }
+        })
+    }
+
+    /// Longest range any range function looks back.
+    pub fn max_range(&self) -> Duration {
+        match self {
+            Expr::Number(_) | Expr::Selector(_) => Duration::ZERO,
+            Expr::Aggregate(_, inner) => inner.max_range(),
+            Expr::Range(_, _, range) => *range,
+            Expr::Binary(_, lhs, rhs) => lhs.max_range().max(rhs.max_range()),
+        }
+    }
+}
+
+impl Display for QueryValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryValue::Scalar(v) => write!(f, "{v}"),
+            QueryValue::Vector(series) if series.is_empty() => write!(f, "(empty)"),
+            QueryValue::Vector(series) => {
+                for (i, (labels, v)) in series.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    let labels = labels.iter().map(|(k, v)| format!("{k}=\"{v}\"")).collect::<Vec<_>>();
+                    write!(f, "{{{}}} {v}", labels.join(","))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { input: s, pos: 0 };
+        let expr = parser.expr()?;
+        parser.skip_ws();
+        if parser.pos < s.len() {
+            return Err(format!("unexpected '{}' at {}", &s[parser.pos..], parser.pos))
+        }
+
+        Ok(expr)
+    }
+}
+
+/// Recursive descent over the grammar
+///
+/// ```text
+/// expr     := term (('+' | '-') term)*
+/// term     := factor (('*' | '/') factor)*
+/// factor   := number | '(' expr ')' | agg '(' expr ')' | range '(' selector '[' duration ']' ')' | selector
+/// selector := ident ('{' (ident ('=' | '!=') string),* '}')?
+/// ```
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs)
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Mul
+            } else if self.eat("/") {
+                BinaryOp::Div
+            } else {
+                return Ok(lhs)
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        self.skip_ws();
+        if self.eat("(") {
+            let inner = self.expr()?;
+            self.expect(")")?;
+            return Ok(inner)
+        }
+        if self.rest().starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            return Ok(Expr::Number(self.number()?))
+        }
+
+        let ident = self.ident()?;
+        let aggregation = match ident {
+            "sum" => Some(Aggregation::Sum),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "count" => Some(Aggregation::Count),
+            _ => None,
+        };
+        let range = match ident {
+            "rate" => Some(RangeFunction::Rate),
+            "increase" => Some(RangeFunction::Increase),
+            _ => None,
+        };
+        if let Some(aggregation) = aggregation.filter(|_| self.eat("(")) {
+            let inner = self.expr()?;
+            self.expect(")")?;
+            Ok(Expr::Aggregate(aggregation, Box::new(inner)))
+        } else if let Some(function) = range.filter(|_| self.eat("(")) {
+            let ident = self.ident()?;
+            let selector = self.selector(ident)?;
+            self.expect("[")?;
+            let range = self.duration()?;
+            self.expect("]")?;
+            self.expect(")")?;
+            Ok(Expr::Range(function, selector, range))
+        } else {
+            Ok(Expr::Selector(self.selector(ident)?))
+        }
+    }
+
+    fn selector(&mut self, metric: &str) -> Result<Selector, String> {
+        let mut matchers = Vec::new();
+        if self.eat("{") {
+            while !self.eat("}") {
+                let label = self.ident()?.to_string();
+                let equal = if self.eat("!=") {
+                    false
+                } else {
+                    self.expect("=")?;
+                    true
+                };
+                let value = self.string()?;
+                matchers.push(Matcher { label, value, equal });
+                if !self.eat(",") {
+                    self.expect("}")?;
+                    break
+                }
+            }
+        }
+
+        Ok(Selector { metric: metric.to_string(), matchers })
+    }
+
+    fn duration(&mut self) -> Result<Duration, String> {
+        let n = self.number()?;
+        let unit = if self.eat("ms") {
+            0.001
+        } else if self.eat("s") {
+            1.0
+        } else if self.eat("m") {
+            60.0
+        } else if self.eat("h") {
+            3600.0
+        } else {
+            return Err(format!("expected a duration unit (ms, s, m, h) at {}", self.pos))
+        };
+
+        Duration::try_from_secs_f64(n * unit).map_err(|e| e.to_string())
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        self.skip_ws();
+        let len = self.rest().find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(self.rest().len());
+        let number = &self.rest()[..len];
+        self.pos += len;
+        number.parse().map_err(|_| format!("invalid number '{number}'"))
+    }
+
+    fn ident(&mut self) -> Result<&'a str, String> {
+        self.skip_ws();
+        let len = self.rest().find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(self.rest().len());
+        if len == 0 || self.rest().starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("expected a name at {}", self.pos))
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.input[start..self.pos])
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let len = self.rest().find('"').ok_or("unterminated string")?;
+        let value = self.rest()[..len].to_string();
+        self.pos += len + 1;
+        Ok(value)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        self.pos = self.input.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        let matched = self.rest().starts_with(token);
+        if matched {
+            self.pos += token.len();
+        }
+        matched
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("expected '{token}' at {}", self.pos))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+    use crate::query::{Expr, History, QueryValue};
+    use crate::results::SeriesSample;
+
+    fn sample(key: &str, helper: &str, value: u64) -> SeriesSample {
+        SeriesSample {
+            key: key.to_string(),
+            labels: BTreeMap::from([("helper".to_string(), helper.to_string())]),
+            value,
+            unit: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn sum_over_rate() {
+        let expr: Expr = r#"sum(metric{helper="H1"}) / sum(rate(other[30s]))"#.parse().unwrap();
+        let current = vec![sample("metric", "H1", 30), sample("metric", "H2", 100), sample("other", "H1", 50)];
+        let start = Instant::now();
+        let mut history = History::new(expr.max_range());
+        // too old to be in range
+        history.record(start, vec![sample("other", "H1", 0)]);
+        history.record(start + Duration::from_secs(20), vec![sample("other", "H1", 10), sample("other", "H2", 5)]);
+        history.record(start + Duration::from_secs(30), vec![sample("other", "H1", 20), sample("other", "H2", 15)]);
+        history.record(start + Duration::from_secs(40), vec![sample("other", "H1", 40), sample("other", "H2", 15)]);
+
+        // (40 - 10) / 20s + (15 - 5) / 20s
+        assert_eq!(QueryValue::Scalar(30.0 / 2.0), expr.eval(&current, &history).unwrap());
+        assert!("sum(metric{helper=\"H1\"".parse::<Expr>().is_err());
+        assert!("rate(other[30])".parse::<Expr>().is_err());
+    }
+}
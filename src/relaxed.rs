@@ -0,0 +1,135 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, compiler_fence, Ordering};
+
+/// Counter increments waiting to be moved into the thread's snapshot, for code that must not
+/// touch [`MetricsContext`], e.g. signal handlers or sections that can't afford to flush.
+///
+/// Only the owning thread uses its queue, but a signal handler may interrupt it at any point,
+/// including in the middle of a drain. The handler only writes slots past `head`, the drain
+/// only reads slots before the `head` it loaded, and compiler fences keep the slot writes
+/// ordered before `head` moves, so neither side ever sees a partially written slot.
+///
+/// [`MetricsContext`]: crate::metrics::MetricsContext
+pub struct RelaxedQueue {
+    slots: [Cell<(&'static str, u64)>; Self::CAPACITY],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    lost: AtomicU64,
+}
+
+thread_local! {
+    static RELAXED: RelaxedQueue = const { RelaxedQueue::new() }
+}
+
+impl RelaxedQueue {
+    pub const CAPACITY: usize = 256;
+
+    const fn new() -> Self {
+        Self {
+            slots: [const { Cell::new(("", 0)) }; Self::CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            lost: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, key: &'static str, value: u64) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Relaxed)) >= Self::CAPACITY {
+            self.lost.fetch_add(1, Ordering::Relaxed);
+            return false
+        }
+        self.slots[head % Self::CAPACITY].set((key, value));
+        compiler_fence(Ordering::Release);
+        self.head.store(head.wrapping_add(1), Ordering::Relaxed);
+
+        true
+    }
+
+    fn drain(&self, mut f: impl FnMut(&'static str, u64)) -> u64 {
+        let head = self.head.load(Ordering::Relaxed);
+        compiler_fence(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        while tail != head {
+            let (key, value) = self.slots[tail % Self::CAPACITY].get();
+            f(key, value);
+            tail = tail.wrapping_add(1);
+        }
+        compiler_fence(Ordering::Release);
+        self.tail.store(tail, Ordering::Relaxed);
+
+        self.lost.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Queues an increment of counter `key` on this thread, without blocking, allocating or
+/// borrowing the thread's [`MetricsContext`]. It is recorded the next time the thread's
+/// snapshot is flushed or taken.
+///
+/// Increments are lost, and `false` returned, when [`RelaxedQueue::CAPACITY`] increments are
+/// already waiting, or the thread is shutting down. Lost increments are counted under
+/// [`LOST`] when the queue is drained. Increments still queued when a thread stops without a
+/// final flush are lost silently.
+///
+/// [`MetricsContext`]: crate::metrics::MetricsContext
+pub fn try_increment_relaxed(key: &'static str, value: u64) -> bool {
+    RELAXED.try_with(|queue| queue.push(key, value)).unwrap_or(false)
+}
+
+/// Counter of increments [`try_increment_relaxed`] had to drop.
+pub const LOST: &str = "relaxed_increments_lost";
+
+/// Passes every increment queued on this thread to `f`, followed by the number of lost ones
+/// under [`LOST`], if any.
+pub fn drain(mut f: impl FnMut(&'static str, u64)) {
+    let _ = RELAXED.try_with(|queue| {
+        let lost = queue.drain(&mut f);
+        if lost > 0 {
+            f(LOST, lost);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use signal_hook::consts::SIGUSR1;
+    use signal_hook::low_level;
+    use crate::metrics::METRICS_CTX;
+    use crate::transport::snapshot_channel;
+    use crate::relaxed::{LOST, RelaxedQueue, try_increment_relaxed};
+    use crate::test_utils::shared_heap;
+
+    static QUEUED: AtomicU64 = AtomicU64::new(0);
+
+    #[test]
+    fn signal_handler_increments() {
+        let _heap = shared_heap();
+        // handlers run on the thread that raises, which is the only thread recording here
+        let handler = unsafe {
+            low_level::register(SIGUSR1, || {
+                if try_increment_relaxed("signals", 1) {
+                    QUEUED.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        }.unwrap();
+
+        let (tx, _rx) = snapshot_channel(false);
+        METRICS_CTX.with(|m| m.connect(tx));
+        let (mut recorded, mut lost) = (0, 0);
+        for round in 0..1_000 {
+            // every tenth round overflows the queue before it is drained
+            let signals = if round % 10 == 0 { RelaxedQueue::CAPACITY + 10 } else { round % 50 };
+            for _ in 0..signals {
+                low_level::raise(SIGUSR1).unwrap();
+            }
+            let snapshot = METRICS_CTX.with(|m| m.take_snapshot());
+            recorded += snapshot.get_all_dims("signals").unwrap_or_default();
+            lost += snapshot.get_all_dims(LOST).unwrap_or_default();
+        }
+        low_level::unregister(handler);
+
+        assert_eq!(QUEUED.load(Ordering::Relaxed), recorded);
+        assert_eq!(100 * 10, lost);
+    }
+}
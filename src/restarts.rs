@@ -0,0 +1,152 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::aggregator::SnapshotProcessor;
+use crate::ids::Identity;
+use crate::metrics::Snapshot;
+
+/// Detects producers that restarted, so a soak test reports them instead of showing a dip in
+/// rates nobody can explain.
+///
+/// Every snapshot carries the events its producer recorded up to that point, see
+/// [`Snapshot::recorded`], which only goes down when the producer starts over. Producers are
+/// told apart by [`Snapshot::origin`] and, where known, [`Snapshot::thread`]. Snapshots read
+/// back from other processes have no thread, so processes that send from several threads
+/// should give each one its own origin. A snapshot whose total dropped but that was captured
+/// before the latest one merged is late rather than restarted, and is ignored. Snapshots
+/// without an origin are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct RestartCheck {
+    producers: Arc<Mutex<Vec<ProducerRestarts>>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProducerRestarts {
+    pub origin: Identity,
+    pub thread: Option<ThreadId>,
+    /// Events recorded as of the latest snapshot merged from this producer.
+    pub recorded: u64,
+    pub restarts: u64,
+    /// Capture time of the latest snapshot merged from this producer.
+    captured: Option<SystemTime>,
+}
+
+impl ProducerRestarts {
+    /// When the process of this producer started, as its process id says.
+    pub fn started(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.origin.process_id.timestamp_ms())
+    }
+}
+
+impl RestartCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self) -> RestartReport {
+        RestartReport { producers: self.producers.lock().unwrap().clone() }
+    }
+}
+
+impl SnapshotProcessor for RestartCheck {
+    fn on_merge(&mut self, snapshot: &mut Snapshot) {
+        let Some(origin) = snapshot.origin() else {
+            return
+        };
+        let (thread, recorded, captured) = (snapshot.thread(), snapshot.recorded(), snapshot.captured());
+        let mut producers = self.producers.lock().unwrap();
+        match producers.iter_mut().find(|producer| producer.origin == origin && producer.thread == thread) {
+            Some(producer) => {
+                let late = matches!((captured, producer.captured), (Some(captured), Some(latest)) if captured < latest);
+                if late {
+                    return
+                }
+                if recorded < producer.recorded {
+                    producer.restarts += 1;
+                }
+                producer.recorded = recorded;
+                producer.captured = captured;
+            }
+            None => producers.push(ProducerRestarts { origin, thread, recorded, restarts: 0, captured }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RestartReport {
+    pub producers: Vec<ProducerRestarts>,
+}
+
+impl RestartReport {
+    pub fn restarted(&self) -> impl Iterator<Item = &ProducerRestarts> {
+        self.producers.iter().filter(|producer| producer.restarts > 0)
+    }
+}
+
+impl Display for RestartReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let restarts = self.producers.iter().map(|producer| producer.restarts).sum::<u64>();
+        write!(f, "restarts: {restarts} across {} producers", self.producers.len())?;
+        for producer in self.restarted() {
+            let started = producer.started().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            write!(f, "\n  process {} (started at {started}ms) thread {:?}: restarted {} times, {} recorded since",
+                   producer.origin.process_id, producer.thread, producer.restarts, producer.recorded)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::aggregator::Aggregator;
+    use crate::ids::{Identity, Ulid};
+    use crate::metrics::{Counter, KEY, Records, Snapshot};
+    use crate::restarts::RestartCheck;
+    use crate::test_utils::shared_heap;
+
+    /// Snapshots of a producer that records `events` before each of them.
+    fn producer(origin: Identity, events: &[u64]) -> Vec<Snapshot> {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_origin(Some(origin));
+        events.iter().map(|&events| {
+            for _ in 0..events {
+                snapshot.record(Counter(KEY, 1));
+            }
+            snapshot.take()
+        }).collect()
+    }
+
+    #[test]
+    fn detects_restarts_per_origin() {
+        let _heap = shared_heap();
+        let (worker, other) = (
+            Identity { run_id: Ulid::from_parts(1, 0), process_id: Ulid::from_parts(1_000, 1) },
+            Identity { run_id: Ulid::from_parts(1, 0), process_id: Ulid::from_parts(2_000, 2) },
+        );
+        let check = RestartCheck::new();
+        let mut aggregator = Aggregator::new();
+        aggregator.add_processor(check.clone());
+
+        let mut before = producer(worker, &[10, 10, 10]);
+        let mut late = before.remove(1);
+        late.set_captured(Some(SystemTime::now() - Duration::from_secs(60)));
+        before.into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+        // the worker comes back with the same identity and counts from 0 again
+        producer(worker, &[5, 5]).into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+        aggregator.merge(late);
+        producer(other, &[1, 1]).into_iter().for_each(|snapshot| aggregator.merge(snapshot));
+        // snapshots without an origin can't be attributed
+        aggregator.merge(Snapshot::new());
+
+        let report = check.report();
+        assert_eq!(2, report.producers.len());
+        let restarted = report.restarted().collect::<Vec<_>>();
+        assert_eq!(vec![(worker, 1, 10)], restarted.iter().map(|producer| (producer.origin, producer.restarts, producer.recorded)).collect::<Vec<_>>());
+        assert_eq!(SystemTime::UNIX_EPOCH + Duration::from_secs(1), restarted[0].started());
+        assert!(report.to_string().starts_with("restarts: 1 across 2 producers\n"), "{report}");
+        assert_eq!(Some(42), aggregator.get_all_dims(KEY));
+    }
+}
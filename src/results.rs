@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::dimensions::{KindStats, MetricKind, SeriesValue};
+use crate::ids::Identity;
+use crate::metadata::metadata;
+use crate::metrics::Snapshot;
+
+/// Outcome of a single benchmark run. Every output sink renders this type, so new measured
+/// dimensions only need to be added here to show up everywhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunResult {
+    #[serde(flatten)]
+    pub identity: Identity,
+    pub mode: String,
+    pub tasks: u64,
+    pub threads: Option<u64>,
+    pub work_ns: u64,
+    pub metric: u64,
+    pub spawn_ns: u64,
+    /// From spawning the first task until the target value was reached, setup and shutdown
+    /// excluded
+    pub elapsed_ns: u64,
+    #[serde(default)]
+    pub costs: SetupCosts,
+    /// The run was stopped by a signal before reaching the target value
+    #[serde(default)]
+    pub interrupted: bool,
+    /// Work done by the aggregating modes, to check that different modes did the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<StoreStats>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub series: Vec<SeriesSample>,
+}
+
+/// One-off costs of bringing a mode up and down, reported apart from `elapsed` because they
+/// dominate short-lived processes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SetupCosts {
+    pub runtime_build_ns: u64,
+    /// Configuring the runtime's thread hooks. The hooks themselves run in `runtime_build_ns`,
+    /// when the runtime starts its workers
+    pub hooks_ns: u64,
+    /// Installing the global recorder, `ext-metrics` only
+    pub recorder_ns: u64,
+    /// Stopping producers and shutting the runtime down once the target was reached
+    pub shutdown_ns: u64,
+}
+
+/// Snapshots the aggregator received, and the work done per kind of series.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub flushes: u64,
+    pub kinds: BTreeMap<MetricKind, KindStats>,
+}
+
+/// Final value of a single series, with labels rendered to strings.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SeriesSample {
+    pub key: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl SeriesSample {
+    /// Counter and info series of `snapshot`, info series with a value of 1. Samples are
+    /// counts, so other kinds are left out.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Vec<Self> {
+        let mut samples = snapshot.store().iter().filter_map(|(key, labels, value)| {
+            let value = match value {
+                SeriesValue::Counter(value) => *value,
+                SeriesValue::Info(_) => 1,
+                _ => return None,
+            };
+            let metadata = metadata(key);
+            Some(Self {
+                key: key.to_string(),
+                labels: labels.map(|(label, value)| (label.to_string(), value.to_string())).collect(),
+                value,
+                unit: metadata.and_then(|m| m.unit).map(|unit| unit.to_string()),
+                description: metadata.map(|m| m.description.to_string()),
+            })
+        }).collect::<Vec<_>>();
+        samples.sort_by(|a, b| (&a.key, &a.labels).cmp(&(&b.key, &b.labels)));
+
+        samples
+    }
+}
+
+impl RunResult {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns)
+    }
+
+    pub fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => println!("{self}"),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self).unwrap()),
+        }
+    }
+}
+
+impl Display for RunResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mode: {}, metric: {:?}, elapsed {:?}", self.mode, self.metric, self.elapsed())?;
+        if self.interrupted {
+            write!(f, ", interrupted")?;
+        }
+        write!(f, "\n{}", self.costs)?;
+        if let Some(store) = &self.store {
+            write!(f, "\n{store}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for StoreStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "flushes: {}", self.flushes)?;
+        for (kind, stats) in &self.kinds {
+            write!(f, "\n{kind:?}: {} series, {} updates, {} merges", stats.series, stats.updates, stats.merges)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for SetupCosts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "setup: runtime {:?}, hooks {:?}, recorder {:?}; shutdown {:?}",
+               Duration::from_nanos(self.runtime_build_ns),
+               Duration::from_nanos(self.hooks_ns),
+               Duration::from_nanos(self.recorder_ns),
+               Duration::from_nanos(self.shutdown_ns))
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use crate::dense::DenseStrategy;
+use crate::harness::Milestone;
+use crate::work::Work;
+
+pub type Workload = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A candidate metric storage implementation that can be benchmarked by the harness without
+/// touching `main`. Implementations are registered by name in [`StrategyRegistry`] and become
+/// available as `--mode <name>`.
+pub trait StorageStrategy: Send + Sync + 'static {
+    /// Called on every runtime worker thread before it starts polling tasks.
+    fn setup_thread(&self) {}
+
+    /// Called when a worker thread runs out of tasks and is about to park.
+    fn on_thread_park(&self) {}
+
+    /// Called before a worker thread exits.
+    fn on_thread_stop(&self) {}
+
+    /// Records a single increment of the benchmark metric from the current thread.
+    fn record(&self, value: u64);
+
+    /// Total recorded so far, as observed by a reader outside the worker threads.
+    fn read_total(&self) -> u64;
+
+    /// Strategies that know the total on the recording path report it to `milestone`, which
+    /// lets the harness sleep until the run ends rather than poll [`Self::read_total`].
+    fn set_milestone(&self, _milestone: Arc<Milestone>) {}
+
+    /// Task spawned by the harness. Strategies can override it to record without going through
+    /// dynamic dispatch on every increment.
+    fn workload(self: Arc<Self>, work: Work) -> Workload {
+        Box::pin(do_work_async(self, work))
+    }
+}
+
+pub type StrategyFactory = fn() -> Arc<dyn StorageStrategy>;
+
+#[derive(Default)]
+pub struct StrategyRegistry {
+    factories: BTreeMap<&'static str, StrategyFactory>,
+}
+
+impl StrategyRegistry {
+    /// Registry with the strategies that ship with this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register("mutex", || Arc::new(MutexStrategy::default()));
+        registry.register("dense", || Arc::new(DenseStrategy::default()));
+
+        registry
+    }
+
+    /// Makes `name` available as a mode. Registering the same name twice replaces the
+    /// previous factory.
+    pub fn register(&mut self, name: &'static str, factory: StrategyFactory) {
+        self.factories.insert(name, factory);
+    }
+
+    pub fn create(&self, name: &str) -> Option<Arc<dyn StorageStrategy>> {
+        self.factories.get(name).map(|factory| factory())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.factories.keys().copied()
+    }
+}
+
+/// Single counter behind a global lock, the baseline for heavily contended shared state.
+#[derive(Default)]
+pub struct MutexStrategy {
+    total: Mutex<u64>,
+    milestone: OnceLock<Arc<Milestone>>,
+}
+
+impl StorageStrategy for MutexStrategy {
+    fn record(&self, value: u64) {
+        let mut total = self.total.lock().unwrap();
+        *total += value;
+        if let Some(milestone) = self.milestone.get() {
+            milestone.progress(*total);
+        }
+    }
+
+    fn read_total(&self) -> u64 {
+        *self.total.lock().unwrap()
+    }
+
+    fn set_milestone(&self, milestone: Arc<Milestone>) {
+        let _ = self.milestone.set(milestone);
+    }
+}
+
+pub async fn do_work_async<S: StorageStrategy + ?Sized>(strategy: Arc<S>, work: Work) {
+    let mut values = work.values();
+    let mut iter = 0_u64;
+    while !work.stopped() {
+        work.run();
+        strategy.record(values.next());
+        iter += 1;
+        if iter.is_multiple_of(100) {
+            tokio::task::yield_now().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::harness::Milestone;
+    use crate::strategy::{StrategyRegistry, Workload};
+    use crate::test_utils::shared_heap;
+    use crate::work::Work;
+    use crate::{atomic, external_metrics};
+
+    #[test]
+    fn workloads_return_once_stopped() {
+        let _heap = shared_heap();
+        let registry = StrategyRegistry::builtin();
+        let mut workloads = registry.names().map(|name| {
+            let strategy = registry.create(name).unwrap();
+            (name, Box::new(move |work| strategy.clone().workload(work)) as Box<dyn Fn(Work) -> Workload>)
+        }).collect::<Vec<_>>();
+        // the current thread runtime polls on this thread
+        atomic::ATOMIC_CTX.with(|ctx| ctx.connect(Arc::default(), Arc::new(Milestone::new(u64::MAX))));
+        workloads.push(("atomic", Box::new(|work| Box::pin(atomic::do_work_async(work)))));
+        workloads.push(("ext-metrics", Box::new(|work| Box::pin(external_metrics::do_work_async(work)))));
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        for (name, workload) in workloads {
+            let work = Work::default();
+            rt.block_on(async {
+                let task = tokio::spawn(workload(work.clone()));
+                // a few hundred increments in
+                for _ in 0..5 {
+                    tokio::task::yield_now().await;
+                }
+                work.stop();
+                let stopped = tokio::time::timeout(Duration::from_secs(10), task).await;
+                assert!(stopped.is_ok_and(|joined| joined.is_ok()), "{name} did not stop");
+            });
+        }
+    }
+}
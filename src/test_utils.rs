@@ -0,0 +1,13 @@
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// dhat heap stats are process-wide, so tests asserting on them can't run while other tests
+/// allocate. Those tests take the lock exclusively, everything else shares it.
+static HEAP: RwLock<()> = RwLock::new(());
+
+pub fn shared_heap() -> RwLockReadGuard<'static, ()> {
+    HEAP.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn exclusive_heap() -> RwLockWriteGuard<'static, ()> {
+    HEAP.write().unwrap_or_else(PoisonError::into_inner)
+}
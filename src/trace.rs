@@ -0,0 +1,164 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Instant;
+use serde::Serialize;
+
+/// Pipeline spans (spawn, flush, send, merge, read) in Chrome trace-event format, which
+/// `chrome://tracing` and Perfetto open as one timeline per thread. Created by the first
+/// [`enable`].
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+/// Whether spans are recorded, between [`enable`] and [`disable`]. Instrumented paths cost a
+/// relaxed load of it otherwise.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct Tracer {
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    /// Microseconds since tracing started.
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<ThreadName>,
+}
+
+#[derive(Debug, Serialize)]
+struct ThreadName {
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events: &'a [TraceEvent],
+}
+
+pub fn enable() {
+    TRACER.get_or_init(|| Tracer { start: Instant::now(), events: Mutex::default() });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops recording spans, including those still open. What was recorded is kept for [`write`].
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records the time until the returned guard is dropped, if tracing is enabled.
+#[must_use = "the span ends when dropped, dropping it right away records nothing"]
+pub fn span(name: &'static str) -> Span {
+    Span { name, start: enabled().then(Instant::now) }
+}
+
+pub struct Span {
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(start), Some(tracer), true) = (self.start, TRACER.get(), enabled()) {
+            let end = Instant::now();
+            let ts = start.saturating_duration_since(tracer.start).as_secs_f64() * 1e6;
+            let dur = end.duration_since(start).as_secs_f64() * 1e6;
+            tracer.push(|tid| TraceEvent { name: self.name, ph: "X", ts, dur: Some(dur), pid: 1, tid, args: None });
+        }
+    }
+}
+
+impl Tracer {
+    /// Numbers threads in the order they first record, naming each one in the trace.
+    fn push(&self, event: impl FnOnce(u64) -> TraceEvent) {
+        thread_local! {
+            static TID: Cell<u64> = const { Cell::new(0) };
+        }
+        static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+        let mut events = self.events.lock().unwrap();
+        let tid = TID.with(|tid| {
+            if tid.get() == 0 {
+                tid.set(NEXT_TID.fetch_add(1, Ordering::Relaxed));
+                let name = thread::current().name().unwrap_or("unnamed").to_string();
+                events.push(TraceEvent {
+                    name: "thread_name", ph: "M", ts: 0.0, dur: None, pid: 1, tid: tid.get(), args: Some(ThreadName { name }),
+                });
+            }
+            tid.get()
+        });
+        events.push(event(tid));
+    }
+}
+
+/// Writes every span recorded so far to `path`. Does nothing if tracing was never enabled.
+pub fn write(path: &Path) -> io::Result<()> {
+    let Some(tracer) = TRACER.get() else {
+        return Ok(())
+    };
+    let events = tracer.events.lock().unwrap();
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &Trace { trace_events: &events })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use serde_json::Value;
+    use crate::test_utils::exclusive_heap;
+    use crate::trace;
+
+    /// Tracing is process-wide, it is only on until the test ends, even if it fails.
+    struct Enabled;
+
+    impl Drop for Enabled {
+        fn drop(&mut self) {
+            trace::disable();
+        }
+    }
+
+    #[test]
+    fn chrome_trace() {
+        // no other test records spans while tracing is on, except those that don't take the heap
+        let _heap = exclusive_heap();
+        trace::enable();
+        let enabled = Enabled;
+        thread::Builder::new().name("producer".into()).spawn(|| drop(trace::span("flush"))).unwrap().join().unwrap();
+        drop(trace::span("merge"));
+        drop(enabled);
+        drop(trace::span("after_disable"));
+
+        let path = std::env::temp_dir().join(format!("metric-proto-trace-{}.json", std::process::id()));
+        trace::write(&path).unwrap();
+        let trace: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // tests that don't take the heap may still record spans, so only look for ours
+        let events = trace["traceEvents"].as_array().unwrap();
+        let producer = events.iter()
+            .find(|e| e["ph"] == "M" && e["args"]["name"] == "producer")
+            .map(|e| e["tid"].clone())
+            .unwrap();
+        let flush = events.iter().find(|e| e["name"] == "flush" && e["tid"] == producer).unwrap();
+        assert_eq!("X", flush["ph"]);
+        assert!(flush["dur"].as_f64().unwrap() >= 0.0);
+        assert!(events.iter().any(|e| e["name"] == "merge" && e["tid"] != producer));
+        assert!(!events.iter().any(|e| e["name"] == "after_disable"));
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::dimensions::{MetricName, SeriesId};
+use crate::metrics::{KEY, Snapshot};
+use crate::transport::SnapshotSender;
+
+/// Shape of the synthetic load produced by the `transfer` mode.
+#[derive(Copy, Clone, Debug)]
+pub struct TransferConfig {
+    /// Number of producer threads.
+    pub threads: usize,
+    /// Distinct series in every snapshot.
+    pub series: usize,
+    /// Snapshots per second sent by each producer, or as fast as possible if not set.
+    pub rate: Option<u64>,
+}
+
+/// Snapshot with `series` series of the benchmark metric, each incremented once. Every snapshot
+/// sent by the producers is a copy of it, so merged totals grow by `series` per snapshot.
+pub fn synthetic_snapshot(series: usize) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for id in 0..series as u64 {
+        snapshot.store_mut().update(&MetricName::with_one_label(KEY, "series", &SeriesId(id)), 1);
+    }
+
+    snapshot
+}
+
+/// Threads that send synthetic snapshots to the aggregator, bypassing the runtime and the
+/// thread-local recording path, so the harness measures channel and merge throughput only.
+pub struct Producers {
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Producers {
+    /// Producer `i` sends to `sinks[i % sinks.len()]`.
+    pub fn spawn(config: TransferConfig, sinks: &[SnapshotSender]) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let template = synthetic_snapshot(config.series);
+        let handles = (0..config.threads).map(|i| {
+            let stop = Arc::clone(&stop);
+            let tx = sinks[i % sinks.len()].clone();
+            let template = template.clone();
+            std::thread::spawn(move || produce(&template, &tx, config.rate, &stop))
+        }).collect();
+
+        Self { stop, handles }
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+fn produce(template: &Snapshot, tx: &SnapshotSender, rate: Option<u64>, stop: &AtomicBool) {
+    let period = rate.map(|rate| Duration::from_nanos(1_000_000_000 / rate.max(1)));
+    let mut next = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        tx.send(template.clone());
+        if let Some(period) = period {
+            next += period;
+            if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use clap::ValueEnum;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError, unbounded};
+use crate::metrics::Snapshot;
+use crate::trace;
+
+/// Read-only snapshot published by a worker thread. The aggregator merges it by reference,
+/// so keys of the series it already knows about are never copied.
+#[derive(Debug)]
+pub struct FrozenSnapshot<S = Snapshot>(S);
+
+impl<S> FrozenSnapshot<S> {
+    pub fn snapshot(&self) -> &S {
+        &self.0
+    }
+}
+
+/// Snapshots queued in a [`loopback`], shared by both ends. Producers are gone once the
+/// receiver holds the only reference.
+type LoopbackQueue<S> = Arc<Mutex<VecDeque<S>>>;
+
+/// What a producer does with a snapshot when a bounded channel is full.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Waits until the aggregator makes room.
+    #[default]
+    Block,
+    /// Drops the snapshot.
+    DropNewest,
+    /// Keeps recording into the snapshot, which is sent with the next flush.
+    Coalesce,
+}
+
+/// Snapshots a bounded channel didn't take, see [`SnapshotReceiver::losses`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Losses {
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+/// Shared by both ends of a bounded channel, read through [`SnapshotReceiver::losses`].
+#[derive(Debug, Default)]
+pub struct LossCounters {
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+/// Producer side of the snapshot channel. Snapshots are either moved through the channel,
+/// or frozen behind an `Arc` and shared with the aggregator.
+pub enum SnapshotSender<S = Snapshot> {
+    Owned(Sender<S>),
+    Shared(Sender<Arc<FrozenSnapshot<S>>>),
+    Loopback(LoopbackQueue<S>),
+    Bounded(Sender<S>, WhenFull, Arc<LossCounters>),
+}
+
+impl<S> Clone for SnapshotSender<S> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Owned(tx) => Self::Owned(tx.clone()),
+            Self::Shared(tx) => Self::Shared(tx.clone()),
+            Self::Loopback(queue) => Self::Loopback(Arc::clone(queue)),
+            Self::Bounded(tx, when_full, losses) => Self::Bounded(tx.clone(), *when_full, Arc::clone(losses)),
+        }
+    }
+}
+
+impl<S> SnapshotSender<S> {
+    /// Snapshots sent after the aggregator went away are dropped. A full bounded channel
+    /// that coalesces blocks here instead, as there is nothing to coalesce into.
+    pub fn send(&self, snapshot: S) {
+        if let Some(snapshot) = self.send_or_return(snapshot) {
+            let Self::Bounded(tx, ..) = self else {
+                unreachable!("only bounded channels return snapshots")
+            };
+            let _ = tx.send(snapshot);
+        }
+    }
+
+    /// Like [`Self::send`], but a full bounded channel that coalesces hands the snapshot back,
+    /// for the caller to keep recording into.
+    pub fn send_or_return(&self, snapshot: S) -> Option<S> {
+        let _span = trace::span("send");
+        let _ = match self {
+            Self::Owned(tx) => tx.send(snapshot).map_err(drop),
+            Self::Shared(tx) => tx.send(Arc::new(FrozenSnapshot(snapshot))).map_err(drop),
+            Self::Loopback(queue) => {
+                queue.lock().unwrap().push_back(snapshot);
+                Ok(())
+            }
+            Self::Bounded(tx, WhenFull::Block, _) => tx.send(snapshot).map_err(drop),
+            Self::Bounded(tx, when_full, losses) => match tx.try_send(snapshot) {
+                Err(TrySendError::Full(snapshot)) if *when_full == WhenFull::Coalesce => {
+                    losses.coalesced.fetch_add(1, Ordering::Relaxed);
+                    return Some(snapshot)
+                }
+                Err(TrySendError::Full(_)) => {
+                    losses.dropped.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                result => result.map_err(drop),
+            },
+        };
+
+        None
+    }
+}
+
+impl<S> From<Sender<S>> for SnapshotSender<S> {
+    fn from(tx: Sender<S>) -> Self {
+        Self::Owned(tx)
+    }
+}
+
+pub enum SnapshotReceiver<S = Snapshot> {
+    Owned(Receiver<S>),
+    Shared(Receiver<Arc<FrozenSnapshot<S>>>),
+    Loopback(LoopbackQueue<S>),
+    Bounded(Receiver<S>, Arc<LossCounters>),
+}
+
+pub enum Flushed<S = Snapshot> {
+    Owned(S),
+    Shared(Arc<FrozenSnapshot<S>>),
+}
+
+impl<S> SnapshotReceiver<S> {
+    /// Blocks until the next snapshot arrives, or until `timeout` expires if one is given.
+    /// A loopback never blocks: with nothing queued it times out right away, whatever the
+    /// timeout.
+    pub fn recv_timeout(&self, timeout: Option<Duration>) -> Result<Flushed<S>, RecvTimeoutError> {
+        match (self, timeout) {
+            (Self::Owned(rx), Some(timeout)) => rx.recv_timeout(timeout).map(Flushed::Owned),
+            (Self::Owned(rx), None) => rx.recv().map(Flushed::Owned).map_err(|_| RecvTimeoutError::Disconnected),
+            (Self::Shared(rx), Some(timeout)) => rx.recv_timeout(timeout).map(Flushed::Shared),
+            (Self::Shared(rx), None) => rx.recv().map(Flushed::Shared).map_err(|_| RecvTimeoutError::Disconnected),
+            (Self::Bounded(rx, _), Some(timeout)) => rx.recv_timeout(timeout).map(Flushed::Owned),
+            (Self::Bounded(rx, _), None) => rx.recv().map(Flushed::Owned).map_err(|_| RecvTimeoutError::Disconnected),
+            (Self::Loopback(queue), _) => match queue.lock().unwrap().pop_front() {
+                Some(snapshot) => Ok(Flushed::Owned(snapshot)),
+                None if Arc::strong_count(queue) == 1 => Err(RecvTimeoutError::Disconnected),
+                None => Err(RecvTimeoutError::Timeout),
+            },
+        }
+    }
+
+    /// Snapshots producers dropped or coalesced so far because the channel was full. `None`
+    /// for channels that never turn snapshots away.
+    pub fn losses(&self) -> Option<Losses> {
+        let Self::Bounded(_, losses) = self else {
+            return None
+        };
+        Some(Losses { dropped: losses.dropped.load(Ordering::Relaxed), coalesced: losses.coalesced.load(Ordering::Relaxed) })
+    }
+}
+
+/// Creates an unbounded channel that moves snapshots, or shares them via `Arc` if `shared` is set.
+pub fn snapshot_channel<S>(shared: bool) -> (SnapshotSender<S>, SnapshotReceiver<S>) {
+    if shared {
+        let (tx, rx) = unbounded();
+        (SnapshotSender::Shared(tx), SnapshotReceiver::Shared(rx))
+    } else {
+        let (tx, rx) = unbounded();
+        (SnapshotSender::Owned(tx), SnapshotReceiver::Owned(rx))
+    }
+}
+
+/// Creates a channel that holds up to `capacity` snapshots, so an aggregator that falls behind
+/// applies back pressure, or loses snapshots, as `when_full` says.
+pub fn bounded_snapshot_channel<S>(capacity: usize, when_full: WhenFull) -> (SnapshotSender<S>, SnapshotReceiver<S>) {
+    let (tx, rx) = bounded(capacity);
+    let losses = Arc::new(LossCounters::default());
+    (SnapshotSender::Bounded(tx, when_full, Arc::clone(&losses)), SnapshotReceiver::Bounded(rx, losses))
+}
+
+/// Creates an in-memory transport for tests of the pipeline. Snapshots come out in the order
+/// they were sent, and receiving never waits, so tests are deterministic without threads.
+pub fn loopback<S>() -> (SnapshotSender<S>, SnapshotReceiver<S>) {
+    let queue = LoopbackQueue::default();
+    (SnapshotSender::Loopback(Arc::clone(&queue)), SnapshotReceiver::Loopback(queue))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crossbeam::channel::RecvTimeoutError;
+    use crate::transport::{bounded_snapshot_channel, Flushed, loopback, Losses, WhenFull};
+
+    #[test]
+    fn loopback_in_order_until_disconnected() {
+        let (tx, rx) = loopback();
+        let other = tx.clone();
+        tx.send(1);
+        other.send(2);
+        drop(tx);
+
+        let mut received = Vec::new();
+        while let Ok(Flushed::Owned(value)) = rx.recv_timeout(None) {
+            received.push(value);
+        }
+        assert_eq!(vec![1, 2], received);
+        assert!(matches!(rx.recv_timeout(Some(Duration::from_secs(60))), Err(RecvTimeoutError::Timeout)));
+        drop(other);
+        assert!(matches!(rx.recv_timeout(None), Err(RecvTimeoutError::Disconnected)));
+    }
+
+    #[test]
+    fn bounded_when_full() {
+        let (tx, rx) = bounded_snapshot_channel(1, WhenFull::DropNewest);
+        tx.send(1);
+        tx.send(2);
+        assert!(matches!(rx.recv_timeout(None), Ok(Flushed::Owned(1))));
+        assert_eq!(Some(Losses { dropped: 1, coalesced: 0 }), rx.losses());
+
+        let (tx, rx) = bounded_snapshot_channel(1, WhenFull::Coalesce);
+        assert_eq!(None, tx.send_or_return(1));
+        assert_eq!(Some(2), tx.send_or_return(2));
+        assert!(matches!(rx.recv_timeout(None), Ok(Flushed::Owned(1))));
+        assert_eq!(None, tx.send_or_return(2));
+        assert_eq!(Some(Losses { dropped: 0, coalesced: 1 }), rx.losses());
+        assert_eq!(None, loopback::<u64>().1.losses());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::{Display, Formatter};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crossbeam::channel::RecvTimeoutError;
+use crate::metrics::Snapshot;
+use crate::transport::{Flushed, snapshot_channel, SnapshotReceiver, SnapshotSender};
+
+/// Intermediate aggregators between producers and the root aggregator, modelling fleet-scale
+/// deployments where every host reports to a nearby leaf. Each leaf merges what its producers
+/// send into one pending snapshot and forwards it upstream, compacted, every `interval`, so
+/// the root merges one snapshot per leaf and interval instead of one per producer flush.
+/// Forwarded snapshots carry deltas since the previous forward, so the root merges them as if
+/// they came from producers.
+pub struct Leaves {
+    handles: Vec<JoinHandle<LeafStats>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LeafStats {
+    pub merged: u64,
+    pub forwarded: u64,
+}
+
+impl Leaves {
+    /// Starts `count` leaves forwarding to `upstream`. Returns the sinks producers send to, one
+    /// per leaf. A leaf forwards what it has left and stops once every sender of its sink is gone.
+    pub fn spawn(count: usize, interval: Duration, upstream: SnapshotSender) -> (Vec<SnapshotSender>, Self) {
+        let (sinks, handles) = (0..count.max(1)).map(|_| {
+            let (tx, rx) = snapshot_channel(false);
+            let upstream = upstream.clone();
+            (tx, std::thread::spawn(move || run_leaf(&rx, &upstream, interval)))
+        }).unzip();
+
+        (sinks, Self { handles })
+    }
+
+    pub fn join(self) -> LeavesReport {
+        let stats = self.handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        LeavesReport { stats }
+    }
+}
+
+fn run_leaf(rx: &SnapshotReceiver, upstream: &SnapshotSender, interval: Duration) -> LeafStats {
+    let mut stats = LeafStats::default();
+    let mut pending = Snapshot::new();
+    let mut forward_at = Instant::now() + interval;
+    let forward = |pending: &mut Snapshot, stats: &mut LeafStats| {
+        if !pending.store().is_empty() {
+            pending.store_mut().compact();
+            upstream.send(pending.take());
+            stats.forwarded += 1;
+        }
+    };
+    loop {
+        match rx.recv_timeout(Some(forward_at.saturating_duration_since(Instant::now()))) {
+            Ok(Flushed::Owned(snapshot)) => {
+                pending.merge(snapshot);
+                stats.merged += 1;
+            }
+            Ok(Flushed::Shared(_)) => unreachable!("leaf channels move snapshots"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if Instant::now() >= forward_at {
+            forward(&mut pending, &mut stats);
+            forward_at = Instant::now() + interval;
+        }
+    }
+    forward(&mut pending, &mut stats);
+
+    stats
+}
+
+#[derive(Debug)]
+pub struct LeavesReport {
+    pub stats: Vec<LeafStats>,
+}
+
+impl Display for LeavesReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let merged = self.stats.iter().map(|s| s.merged).sum::<u64>();
+        let forwarded = self.stats.iter().map(|s| s.forwarded).sum::<u64>();
+        write!(f, "leaves: {}, merged {merged} snapshots, forwarded {forwarded}", self.stats.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::aggregator::Aggregator;
+    use crate::harness::merge_pending;
+    use crate::metrics::KEY;
+    use crate::transport::snapshot_channel;
+    use crate::test_utils::shared_heap;
+    use crate::transfer::synthetic_snapshot;
+    use crate::tree::{LeafStats, Leaves};
+
+    #[test]
+    fn leaves_forward_to_root() {
+        let _heap = shared_heap();
+        let (upstream, rx) = snapshot_channel(false);
+        // long interval: everything is forwarded once, when producers go away
+        let (sinks, leaves) = Leaves::spawn(2, Duration::from_secs(60), upstream);
+        for i in 0..100 {
+            sinks[i % 2].send(synthetic_snapshot(10));
+        }
+        drop(sinks);
+
+        let report = leaves.join();
+        assert_eq!(vec![LeafStats { merged: 50, forwarded: 1 }; 2], report.stats);
+        assert_eq!("leaves: 2, merged 100 snapshots, forwarded 2", report.to_string());
+        let mut root = Aggregator::new();
+        merge_pending(&rx, &mut root);
+        assert_eq!(Some(1_000), root.get_all_dims(KEY));
+        assert_eq!(10, root.total().store().len());
+    }
+}
@@ -0,0 +1,207 @@
+use std::hint::black_box;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Synthetic CPU payload that workloads execute between metric increments, so modes can be
+/// compared at realistic instrumentation densities rather than in pure metric-recording loops.
+/// Clones share the stop flag, so every task of a run stops together.
+#[derive(Clone, Debug, Default)]
+pub struct Work {
+    iterations: u64,
+    values: ValueDist,
+    stop: Arc<AtomicBool>,
+}
+
+impl Work {
+    const CALIBRATION_ITERATIONS: u64 = 10_000_000;
+
+    /// Measures how fast the checksum loop runs on this machine and picks the number of
+    /// iterations that takes roughly `ns` nanoseconds.
+    pub fn calibrate(ns: u64) -> Self {
+        if ns == 0 {
+            return Self::default();
+        }
+
+        let start = Instant::now();
+        black_box(checksum(Self::CALIBRATION_ITERATIONS));
+        let elapsed = start.elapsed().as_nanos().max(1);
+
+        let iterations = u128::from(ns) * u128::from(Self::CALIBRATION_ITERATIONS) / elapsed;
+        Self {
+            iterations: u64::try_from(iterations).unwrap_or(u64::MAX).max(1),
+            ..Self::default()
+        }
+    }
+
+    /// Workloads increment the benchmark metric by values drawn from `values` instead of 1.
+    pub fn with_values(self, values: ValueDist) -> Self {
+        Self { values, ..self }
+    }
+
+    /// Source of increment values for one task.
+    pub fn values(&self) -> Values {
+        Values::new(self.values)
+    }
+
+    pub fn iterations(&self) -> u64 {
+        self.iterations
+    }
+
+    /// Asks every workload of this run to return instead of starting another iteration. There
+    /// is no way back, it is meant for shutdown.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether workloads should return, checked once per iteration.
+    #[inline]
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn run(&self) {
+        if self.iterations > 0 {
+            black_box(checksum(self.iterations));
+        }
+    }
+}
+
+fn checksum(iterations: u64) -> u64 {
+    let mut acc = 0xcbf29ce484222325_u64;
+    for i in 0..black_box(iterations) {
+        acc = (acc ^ i).wrapping_mul(0x100000001b3);
+    }
+
+    acc
+}
+
+/// Distribution of the values workloads increment the benchmark metric by. Parsed from
+/// `const:N`, `uniform:A..B` (both ends inclusive), or `zipf[:N]` for 1 to N (1000 by default)
+/// with the probability of `k` proportional to `1/k`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ValueDist {
+    Const(u64),
+    Uniform(u64, u64),
+    /// Cumulative weights of 1, 2, .. N.
+    Zipf(&'static [f64]),
+}
+
+impl Default for ValueDist {
+    fn default() -> Self {
+        Self::Const(1)
+    }
+}
+
+impl ValueDist {
+    const ZIPF_MAX: usize = 1000;
+
+    fn zipf(max: usize) -> Self {
+        let cdf = (1..=max).scan(0.0, |total, k| {
+            *total += 1.0 / k as f64;
+            Some(*total)
+        }).collect::<Vec<_>>();
+
+        Self::Zipf(Box::leak(cdf.into_boxed_slice()))
+    }
+}
+
+impl FromStr for ValueDist {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, params) = s.split_once(':').unwrap_or((s, ""));
+        let parse = |v: &str| v.parse::<u64>().map_err(|e| format!("invalid value {v} in {s}: {e}"));
+        match kind {
+            "const" => Ok(Self::Const(parse(params)?)),
+            "uniform" => {
+                let (lo, hi) = params.split_once("..").ok_or_else(|| format!("expected uniform:A..B, got {s}"))?;
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+                if lo > hi {
+                    return Err(format!("empty range in {s}"))
+                }
+                Ok(Self::Uniform(lo, hi))
+            }
+            "zipf" => match params {
+                "" => Ok(Self::zipf(Self::ZIPF_MAX)),
+                max => match parse(max)? {
+                    0 => Err(format!("zipf needs at least one value, got {s}")),
+                    max => Ok(Self::zipf(max as usize)),
+                },
+            },
+            _ => Err(format!("expected const:N, uniform:A..B or zipf[:N], got {s}")),
+        }
+    }
+}
+
+/// Draws increment values for a single task, from a xorshift generator seeded differently for
+/// every task.
+#[derive(Debug)]
+pub struct Values {
+    dist: ValueDist,
+    state: u64,
+}
+
+impl Values {
+    fn new(dist: ValueDist) -> Self {
+        static SEED: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+        Self { dist, state: SEED.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed) | 1 }
+    }
+
+    #[inline]
+    #[allow(clippy::should_implement_trait)] // never runs out, so not an `Iterator`
+    pub fn next(&mut self) -> u64 {
+        match self.dist {
+            ValueDist::Const(value) => value,
+            ValueDist::Uniform(lo, hi) => match (hi - lo).checked_add(1) {
+                Some(span) => lo + self.random() % span,
+                None => self.random(),
+            },
+            ValueDist::Zipf(cdf) => {
+                let u = (self.random() >> 11) as f64 / (1_u64 << 53) as f64 * cdf[cdf.len() - 1];
+                cdf.partition_point(|&total| total <= u) as u64 + 1
+            }
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::work::{ValueDist, Work};
+
+    #[test]
+    fn value_dist() {
+        assert_eq!(Ok(ValueDist::Const(7)), "const:7".parse());
+        assert_eq!(Ok(ValueDist::Uniform(1, 100)), "uniform:1..100".parse());
+        assert!("uniform:100..1".parse::<ValueDist>().is_err());
+        assert!("normal:5".parse::<ValueDist>().is_err());
+
+        let mut values = Work::default().with_values("uniform:1..3".parse().unwrap()).values();
+        assert!((0..1000).map(|_| values.next()).all(|v| (1..=3).contains(&v)));
+
+        // half of the zipf mass is on values below ~sqrt(N) for s = 1
+        let mut values = Work::default().with_values("zipf:10000".parse().unwrap()).values();
+        let samples = (0..10_000).map(|_| values.next()).collect::<Vec<_>>();
+        assert!(samples.iter().all(|v| (1..=10_000).contains(v)));
+        let small = samples.iter().filter(|&&v| v <= 100).count();
+        assert!((4_000..6_000).contains(&small), "{small}");
+    }
+
+    #[test]
+    fn stop_is_per_run() {
+        let (run, other) = (Work::default(), Work::default());
+        let task = run.clone();
+        run.stop();
+        assert!(task.stopped());
+        assert!(!other.stopped());
+    }
+}